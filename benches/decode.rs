@@ -0,0 +1,146 @@
+use chrono::{DateTime, Utc};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use v_authorization::common::{Storage, Trace};
+use v_authorization::config::AzConfig;
+use v_authorization::engine::AzEngine;
+use v_authorization::membership_cache::MembershipCache;
+use v_authorization::{authorize_with_config, ACLRecord, ACLRecordSet, ACLRecordVec, RightsCounters};
+
+const RECORD: &str = "v-s:AllResourcesGroup;15;;d:org_Company1;2;X;td:Group_1;6;;td:Group_2;14;N;td:Group_3;15;";
+
+fn decode_into(src: &str, mut push: impl FnMut(ACLRecord)) {
+    let mut it = src.split(';');
+    while let (Some(id), Some(access), Some(marker)) = (it.next(), it.next(), it.next()) {
+        let mut rec = ACLRecord::new_with_access(id, access.parse().unwrap_or(0));
+        rec.marker = marker.chars().next().unwrap_or(0 as char);
+        push(rec);
+    }
+}
+
+struct BenchStorage {
+    data: HashMap<String, String>,
+}
+
+impl Storage for BenchStorage {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        Ok(self.data.get(key).cloned())
+    }
+
+    fn fiber_yield(&self) {}
+
+    fn decode_rec_to_rights(&self, src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+        decode_into(src, |rec| result.push(rec));
+        (true, None)
+    }
+
+    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+        decode_into(src, |rec| {
+            new_rights.insert(rec.id.clone(), rec);
+        });
+        (true, None)
+    }
+
+    fn decode_filter(&self, _filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+        (None, None)
+    }
+
+    fn epoch(&self) -> Option<u64> {
+        Some(1)
+    }
+}
+
+fn bench_storage() -> BenchStorage {
+    let mut data = HashMap::new();
+    data.insert("Muser1".to_owned(), "td:Group_1;15;;td:Group_2;15;".to_owned());
+    data.insert("Mtd:Group_1".to_owned(), "td:Group_3;15;;d:org_Company1;15;".to_owned());
+    data.insert("Mdoc1".to_owned(), "td:Doc_group;15;;v-s:AllResourcesGroup;15;".to_owned());
+    data.insert("Mtd:Doc_group".to_owned(), "td:Folder_group;15;".to_owned());
+    data.insert("Ptd:Folder_group".to_owned(), "td:Group_3;6;;td:Group_9;15;".to_owned());
+    BenchStorage {
+        data,
+    }
+}
+
+fn decode(c: &mut Criterion) {
+    c.bench_function("decode_vec", |b| {
+        b.iter(|| {
+            let mut res: Vec<ACLRecord> = Vec::new();
+            decode_into(black_box(RECORD), |rec| res.push(rec));
+            res
+        })
+    });
+
+    c.bench_function("decode_smallvec", |b| {
+        b.iter(|| {
+            let mut res = ACLRecordVec::new();
+            decode_into(black_box(RECORD), |rec| res.push(rec));
+            res
+        })
+    });
+}
+
+fn counters(c: &mut Criterion) {
+    c.bench_function("counters_hashmap", |b| {
+        b.iter(|| {
+            let mut counters: HashMap<char, u16> = HashMap::default();
+            counters.insert(black_box('R'), 1);
+            counters
+        })
+    });
+
+    c.bench_function("counters_inline", |b| {
+        b.iter(|| {
+            let mut counters = RightsCounters::None;
+            counters.increment(black_box('R'));
+            counters
+        })
+    });
+}
+
+fn traversal(c: &mut Criterion) {
+    let mut db = bench_storage();
+
+    c.bench_function("authorize_nested", |b| {
+        b.iter(|| {
+            let (mut acl, mut group, mut info) = (String::new(), String::new(), String::new());
+            let mut trace = Trace {
+                acl: &mut acl,
+                is_acl: false,
+                group: &mut group,
+                is_group: false,
+                info: &mut info,
+                is_info: false,
+                str_num: 0,
+            };
+            authorize_with_config(black_box("doc1"), black_box("user1"), 2, &mut db, &mut trace, &AzConfig::default())
+        })
+    });
+
+    let plain = AzEngine::default();
+    let mut cached = AzEngine::default();
+    cached.set_membership_cache(Arc::new(MembershipCache::new(1024)));
+
+    for (name, engine) in [("authorize_engine", &plain), ("authorize_engine_membership_cache", &cached)] {
+        c.bench_function(name, |b| {
+            b.iter(|| {
+                let (mut acl, mut group, mut info) = (String::new(), String::new(), String::new());
+                let mut trace = Trace {
+                    acl: &mut acl,
+                    is_acl: false,
+                    group: &mut group,
+                    is_group: false,
+                    info: &mut info,
+                    is_info: false,
+                    str_num: 0,
+                };
+                engine.authorize(black_box("doc1"), black_box("user1"), 2, &mut db, &mut trace)
+            })
+        });
+    }
+}
+
+criterion_group!(benches, decode, counters, traversal);
+criterion_main!(benches);
@@ -0,0 +1,113 @@
+//! Writes an ACL graph, converted from platform individuals with the `manage` module, into an LMDB
+//! environment laid out as the Veda indexer writes it, then answers and explains requests through
+//! `LmdbStorage`. Run with `cargo run --example lmdb_demo --features lmdb -- [PATH]`; the environment
+//! is created under the temp directory unless a path is given.
+
+use heed::types::Str;
+use heed::{Database, EnvOpenOptions};
+use std::io;
+use std::path::Path;
+use v_authorization::authorize_principals;
+use v_authorization::common::Trace;
+use v_authorization::config::AzConfig;
+use v_authorization::decision::Decision;
+use v_authorization::engine::AzEngine;
+use v_authorization::explain::explain_passes;
+use v_authorization::manage::{apply_change, from_individual, IndividualProps};
+use v_authorization::presets::format_access_expr;
+use v_authorization::storage::lmdb::LmdbStorage;
+use v_authorization::storage::memory::MemoryStorage;
+
+fn individual(props: &[(&str, &str)]) -> IndividualProps {
+    let mut res = IndividualProps::new();
+    for (predicate, value) in props {
+        res.entry(predicate.to_string()).or_default().push(value.to_string());
+    }
+    res
+}
+
+// Записи строятся в памяти тем же кодом, что у индексатора, и переносятся в LMDB
+fn records() -> io::Result<MemoryStorage> {
+    let individuals = [
+        individual(&[("rdf:type", "v-s:Membership"), ("v-s:resource", "u:alice"), ("v-s:memberOf", "g:editors")]),
+        individual(&[("rdf:type", "v-s:Membership"), ("v-s:resource", "d:report"), ("v-s:memberOf", "f:reports")]),
+        individual(&[
+            ("rdf:type", "v-s:PermissionStatement"),
+            ("v-s:permissionSubject", "g:editors"),
+            ("v-s:permissionObject", "f:reports"),
+            ("v-s:canRead", "true"),
+            ("v-s:canUpdate", "true"),
+        ]),
+        individual(&[
+            ("rdf:type", "v-s:PermissionStatement"),
+            ("v-s:permissionSubject", "u:bob"),
+            ("v-s:permissionObject", "d:report"),
+            ("v-s:canRead", "true"),
+        ]),
+    ];
+
+    let mut db = MemoryStorage::new();
+    for props in &individuals {
+        for change in from_individual(props)? {
+            apply_change(&change, &mut db)?;
+        }
+    }
+    Ok(db)
+}
+
+fn write_env(path: &Path, records: &MemoryStorage) -> heed::Result<()> {
+    let mut options = EnvOpenOptions::new();
+    options.map_size(16 * 1024 * 1024);
+    // SAFETY: окружение открывается в процессе один раз, до LmdbStorage
+    let env = unsafe { options.open(path)? };
+    let mut wtxn = env.write_txn()?;
+    let db: Database<Str, Str> = env.create_database(&mut wtxn, None)?;
+    db.clear(&mut wtxn)?;
+    for (key, value) in records.iter() {
+        db.put(&mut wtxn, key, value)?;
+    }
+    wtxn.commit()
+}
+
+fn main() -> io::Result<()> {
+    let path = std::env::args().nth(1).map_or_else(|| std::env::temp_dir().join("v_authorization_lmdb_demo"), Into::into);
+    std::fs::create_dir_all(&path)?;
+
+    let records = records()?;
+    write_env(&path, &records).map_err(|e| io::Error::other(format!("lmdb {}: {}", path.display(), e)))?;
+    println!("wrote {} records to {}", records.len(), path.display());
+
+    let mut db = LmdbStorage::new(&path);
+    let engine = AzEngine::new(AzConfig::default());
+    for (user, access) in [("u:alice", 6), ("u:bob", 6), ("u:carol", 2)] {
+        let (mut acl, mut group, mut info) = (String::new(), String::new(), String::new());
+        let mut trace = Trace {
+            acl: &mut acl,
+            is_acl: true,
+            group: &mut group,
+            is_group: true,
+            info: &mut info,
+            is_info: true,
+            str_num: 0,
+        };
+        let res = engine.authorize("d:report", user, access, &mut db, &mut trace)?;
+        println!("\n{} asks {} on d:report: {}", user, format_access_expr(access), format_access_expr(res));
+        println!("-- groups:\n{}-- acl:\n{}-- info:\n{}", group, acl, info);
+
+        let mut decision = Decision::default();
+        let (mut acl, mut group, mut info) = (String::new(), String::new(), String::new());
+        let mut trace = Trace {
+            acl: &mut acl,
+            is_acl: false,
+            group: &mut group,
+            is_group: false,
+            info: &mut info,
+            is_info: false,
+            str_num: 0,
+        };
+        authorize_principals("d:report", &[user], access, &mut db, &mut trace, &mut decision)?;
+        println!("-- explain: {}", explain_passes(&decision));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,114 @@
+//! Seeds an in-memory store from platform individuals with the `manage` module, then answers and explains
+//! a few requests. Run with `cargo run --example memory_demo`.
+
+use std::io;
+use v_authorization::authorize_principals;
+use v_authorization::common::Trace;
+use v_authorization::config::AzConfig;
+use v_authorization::decision::Decision;
+use v_authorization::engine::AzEngine;
+use v_authorization::explain::{denial_message, explain_passes};
+use v_authorization::manage::{apply_change, from_individual, IndividualProps};
+use v_authorization::presets::format_access_expr;
+use v_authorization::storage::memory::MemoryStorage;
+
+fn individual(props: &[(&str, &str)]) -> IndividualProps {
+    let mut res = IndividualProps::new();
+    for (predicate, value) in props {
+        res.entry(predicate.to_string()).or_default().push(value.to_string());
+    }
+    res
+}
+
+fn seed(db: &mut MemoryStorage) -> io::Result<()> {
+    let individuals = [
+        individual(&[("rdf:type", "v-s:Membership"), ("v-s:resource", "u:alice"), ("v-s:memberOf", "g:editors")]),
+        individual(&[("rdf:type", "v-s:Membership"), ("v-s:resource", "u:bob"), ("v-s:memberOf", "g:editors")]),
+        individual(&[("rdf:type", "v-s:Membership"), ("v-s:resource", "d:report"), ("v-s:memberOf", "f:reports")]),
+        individual(&[
+            ("rdf:type", "v-s:PermissionStatement"),
+            ("v-s:permissionSubject", "g:editors"),
+            ("v-s:permissionObject", "f:reports"),
+            ("v-s:canRead", "true"),
+            ("v-s:canUpdate", "true"),
+        ]),
+        individual(&[
+            ("rdf:type", "v-s:PermissionStatement"),
+            ("v-s:permissionSubject", "u:bob"),
+            ("v-s:permissionObject", "d:report"),
+            ("v-s:canUpdate", "false"),
+        ]),
+    ];
+
+    for props in &individuals {
+        for change in from_individual(props)? {
+            apply_change(&change, db)?;
+        }
+    }
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    let mut db = MemoryStorage::new();
+    seed(&mut db)?;
+
+    println!("store:");
+    for (key, value) in db.iter() {
+        println!("  {} = {}", key, value);
+    }
+
+    let engine = AzEngine::new(AzConfig::default());
+    for (user, access) in [("u:alice", 6), ("u:bob", 6), ("u:carol", 2)] {
+        let (mut acl, mut group, mut info) = (String::new(), String::new(), String::new());
+        let mut trace = Trace {
+            acl: &mut acl,
+            is_acl: true,
+            group: &mut group,
+            is_group: true,
+            info: &mut info,
+            is_info: true,
+            str_num: 0,
+        };
+        let res = engine.authorize("d:report", user, access, &mut db, &mut trace)?;
+        println!("\n{} asks {} on d:report: {}", user, format_access_expr(access), format_access_expr(res));
+        println!("-- groups:\n{}-- acl:\n{}-- info:\n{}", group, acl, info);
+
+        // Решение в структурированном виде для объяснения
+        let mut decision = Decision::default();
+        let (mut acl, mut group, mut info) = (String::new(), String::new(), String::new());
+        let mut trace = Trace {
+            acl: &mut acl,
+            is_acl: false,
+            group: &mut group,
+            is_group: false,
+            info: &mut info,
+            is_info: false,
+            str_num: 0,
+        };
+        authorize_principals("d:report", &[user], access, &mut db, &mut trace, &mut decision)?;
+        println!("-- explain: {}", explain_passes(&decision));
+        if let Some(message) = denial_message(&decision, "en") {
+            println!("-- denial: {}", message);
+        }
+    }
+
+    // Запрет u:bob на изменение снимает право только в режиме deny_override
+    let strict = AzEngine::new(AzConfig {
+        deny_override: true,
+        ..AzConfig::default()
+    });
+    let (mut acl, mut group, mut info) = (String::new(), String::new(), String::new());
+    let mut trace = Trace {
+        acl: &mut acl,
+        is_acl: false,
+        group: &mut group,
+        is_group: false,
+        info: &mut info,
+        is_info: false,
+        str_num: 0,
+    };
+    let res = strict.authorize("d:report", "u:bob", 6, &mut db, &mut trace)?;
+    println!("\nu:bob asks RU on d:report with deny_override: {}", format_access_expr(res));
+
+    Ok(())
+}
@@ -0,0 +1,318 @@
+//! Runs one workload through each way of calling the crate and reports throughput and latency, so the
+//! integration mode can be chosen with numbers from your own store:
+//!
+//! - `authorize`: an engine call per check, every read goes to the store;
+//! - `batch`: checks grouped by user, each group answered within one `RequestScope`;
+//! - `cached`: `CachedStorage` in front of the store and a `MembershipCache` on the engine;
+//! - `parallel`: an engine call per check from one thread per core, each with its own store handle.
+//!
+//! ```text
+//! cargo run --release --example perf_report -- [--backend memory|lmdb:PATH|redis:URL] [--checks FILE]
+//!     [--iterations N] [--format md|json]
+//! ```
+//!
+//! `memory` builds a synthetic graph; other backends need `--checks`, a file of `user_id;resource_id;access`
+//! lines (the corpus format of the `integration` feature, the expected result is ignored). `lmdb` and
+//! `redis` are available when the crate is built with the features of those names.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+use v_authorization::cache::CachedStorage;
+use v_authorization::common::{Storage, Trace};
+use v_authorization::config::AzConfig;
+use v_authorization::engine::AzEngine;
+use v_authorization::membership_cache::MembershipCache;
+use v_authorization::request_scope::RequestScope;
+use v_authorization::storage::memory::MemoryStorage;
+
+const SYNTHETIC_USERS: usize = 200;
+const SYNTHETIC_SUBJECT_GROUPS: usize = 20;
+const SYNTHETIC_OBJECT_GROUPS: usize = 50;
+const SYNTHETIC_RESOURCES: usize = 2000;
+const SYNTHETIC_CHECKS: usize = 5000;
+const CACHE_CAPACITY: usize = 100_000;
+
+struct Check {
+    user_id: String,
+    id: String,
+    access: u8,
+}
+
+struct ApiReport {
+    api: &'static str,
+    calls: usize,
+    elapsed: Duration,
+    // задержки отдельных вызовов, по возрастанию
+    latencies: Vec<Duration>,
+}
+
+impl ApiReport {
+    fn new(api: &'static str, elapsed: Duration, mut latencies: Vec<Duration>) -> Self {
+        latencies.sort_unstable();
+        ApiReport {
+            api,
+            calls: latencies.len(),
+            elapsed,
+            latencies,
+        }
+    }
+
+    fn throughput(&self) -> f64 {
+        self.calls as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    fn percentile_us(&self, p: f64) -> f64 {
+        if self.latencies.is_empty() {
+            return 0.0;
+        }
+        let idx = ((self.latencies.len() - 1) as f64 * p).round() as usize;
+        self.latencies[idx].as_secs_f64() * 1e6
+    }
+}
+
+// Простой детерминированный генератор, чтобы граф был одинаковым при каждом запуске
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, bound: usize) -> usize {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((self.0 >> 33) % bound as u64) as usize
+    }
+}
+
+fn synthetic_storage() -> io::Result<MemoryStorage> {
+    let mut db = MemoryStorage::new();
+    let mut rnd = Lcg(1);
+    for u in 0..SYNTHETIC_USERS {
+        for _ in 0..3 {
+            db.add_membership(&format!("u:{}", u), &format!("g:s{}", rnd.next(SYNTHETIC_SUBJECT_GROUPS)), 15)?;
+        }
+    }
+    for g in 0..SYNTHETIC_OBJECT_GROUPS {
+        for _ in 0..4 {
+            db.add_permission(&format!("g:o{}", g), &format!("g:s{}", rnd.next(SYNTHETIC_SUBJECT_GROUPS)), [2, 6, 14][rnd.next(3)])?;
+        }
+    }
+    for d in 0..SYNTHETIC_RESOURCES {
+        for _ in 0..2 {
+            db.add_membership(&format!("d:{}", d), &format!("g:o{}", rnd.next(SYNTHETIC_OBJECT_GROUPS)), 15)?;
+        }
+    }
+    Ok(db)
+}
+
+fn synthetic_checks() -> Vec<Check> {
+    let mut rnd = Lcg(2);
+    (0..SYNTHETIC_CHECKS)
+        .map(|_| Check {
+            user_id: format!("u:{}", rnd.next(SYNTHETIC_USERS)),
+            id: format!("d:{}", rnd.next(SYNTHETIC_RESOURCES)),
+            access: [2, 6][rnd.next(2)],
+        })
+        .collect()
+}
+
+fn read_checks(path: &str) -> io::Result<Vec<Check>> {
+    let mut res = Vec::new();
+    for (n, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(';').collect();
+        let access = fields.get(2).and_then(|a| a.trim().parse().ok());
+        let (Some(access), 3..=4) = (access, fields.len()) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} line {}: expected user_id;resource_id;access", path, n + 1)));
+        };
+        res.push(Check {
+            user_id: fields[0].trim().to_owned(),
+            id: fields[1].trim().to_owned(),
+            access,
+        });
+    }
+    Ok(res)
+}
+
+fn call(engine: &AzEngine, check: &Check, db: &mut dyn Storage) -> io::Result<u8> {
+    let (mut acl, mut group, mut info) = (String::new(), String::new(), String::new());
+    let mut trace = Trace {
+        acl: &mut acl,
+        is_acl: false,
+        group: &mut group,
+        is_group: false,
+        info: &mut info,
+        is_info: false,
+        str_num: 0,
+    };
+    engine.authorize(&check.id, &check.user_id, check.access, db, &mut trace)
+}
+
+fn timed(latencies: &mut Vec<Duration>, f: impl FnOnce() -> io::Result<u8>) -> io::Result<()> {
+    let started = Instant::now();
+    f()?;
+    latencies.push(started.elapsed());
+    Ok(())
+}
+
+fn run<S, F>(open: F, checks: &[Check], iterations: usize) -> io::Result<Vec<ApiReport>>
+where
+    S: Storage,
+    F: Fn() -> io::Result<S> + Sync,
+{
+    let mut reports = Vec::new();
+    let engine = AzEngine::new(AzConfig::default());
+
+    let mut db = open()?;
+    let mut latencies = Vec::new();
+    let started = Instant::now();
+    for _ in 0..iterations {
+        for check in checks {
+            timed(&mut latencies, || call(&engine, check, &mut db))?;
+        }
+    }
+    reports.push(ApiReport::new("authorize", started.elapsed(), latencies));
+
+    let mut by_user: BTreeMap<&str, Vec<&Check>> = BTreeMap::new();
+    for check in checks {
+        by_user.entry(&check.user_id).or_default().push(check);
+    }
+    let mut latencies = Vec::new();
+    let started = Instant::now();
+    for _ in 0..iterations {
+        for user_checks in by_user.values() {
+            let mut scope = RequestScope::new(AzConfig::default());
+            for check in user_checks {
+                timed(&mut latencies, || scope.authorize(&check.id, &check.user_id, check.access, &mut db))?;
+            }
+        }
+    }
+    reports.push(ApiReport::new("batch", started.elapsed(), latencies));
+
+    let mut cached_engine = AzEngine::new(AzConfig::default());
+    cached_engine.set_membership_cache(Arc::new(MembershipCache::new(CACHE_CAPACITY)));
+    let mut cached_db = CachedStorage::new(open()?, CACHE_CAPACITY, None);
+    let mut latencies = Vec::new();
+    let started = Instant::now();
+    for _ in 0..iterations {
+        for check in checks {
+            timed(&mut latencies, || call(&cached_engine, check, &mut cached_db))?;
+        }
+    }
+    reports.push(ApiReport::new("cached", started.elapsed(), latencies));
+
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let parts: Vec<&[Check]> = checks.chunks(checks.len().div_ceil(threads).max(1)).collect();
+    // отсчет начинается, когда все потоки открыли хранилище
+    let opened = Barrier::new(parts.len() + 1);
+    let (per_thread, elapsed): (Vec<io::Result<Vec<Duration>>>, Duration) = thread::scope(|s| {
+        let workers: Vec<_> = parts
+            .iter()
+            .map(|part| {
+                let (engine, open, opened) = (&engine, &open, &opened);
+                s.spawn(move || {
+                    let db = open();
+                    opened.wait();
+                    let mut db = db?;
+                    let mut latencies = Vec::new();
+                    for _ in 0..iterations {
+                        for check in *part {
+                            timed(&mut latencies, || call(engine, check, &mut db))?;
+                        }
+                    }
+                    Ok(latencies)
+                })
+            })
+            .collect();
+        opened.wait();
+        let started = Instant::now();
+        let res = workers.into_iter().map(|w| w.join().unwrap_or_else(|_| Err(io::Error::other("worker panicked")))).collect();
+        (res, started.elapsed())
+    });
+    let mut latencies = Vec::new();
+    for res in per_thread {
+        latencies.extend(res?);
+    }
+    reports.push(ApiReport::new("parallel", elapsed, latencies));
+
+    Ok(reports)
+}
+
+fn to_markdown(backend: &str, checks: usize, iterations: usize, reports: &[ApiReport]) -> String {
+    let mut res = format!("backend: {}, checks: {}, iterations: {}\n\n", backend, checks, iterations);
+    res.push_str("| api | calls | seconds | calls/s | p50 us | p95 us | p99 us |\n|---|---:|---:|---:|---:|---:|---:|\n");
+    for r in reports {
+        res.push_str(&format!(
+            "| {} | {} | {:.3} | {:.0} | {:.1} | {:.1} | {:.1} |\n",
+            r.api,
+            r.calls,
+            r.elapsed.as_secs_f64(),
+            r.throughput(),
+            r.percentile_us(0.5),
+            r.percentile_us(0.95),
+            r.percentile_us(0.99)
+        ));
+    }
+    res
+}
+
+fn to_json(backend: &str, checks: usize, iterations: usize, reports: &[ApiReport]) -> String {
+    let apis: Vec<String> = reports
+        .iter()
+        .map(|r| {
+            format!(
+                "{{\"api\":\"{}\",\"calls\":{},\"seconds\":{:.6},\"calls_per_second\":{:.1},\"p50_us\":{:.1},\"p95_us\":{:.1},\"p99_us\":{:.1}}}",
+                r.api,
+                r.calls,
+                r.elapsed.as_secs_f64(),
+                r.throughput(),
+                r.percentile_us(0.5),
+                r.percentile_us(0.95),
+                r.percentile_us(0.99)
+            )
+        })
+        .collect();
+    format!("{{\"backend\":{:?},\"checks\":{},\"iterations\":{},\"apis\":[{}]}}", backend, checks, iterations, apis.join(","))
+}
+
+fn usage(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}; see the doc comment of examples/perf_report.rs", msg))
+}
+
+fn main() -> io::Result<()> {
+    let (mut backend, mut checks_path, mut iterations, mut format) = ("memory".to_owned(), None, 3, "md".to_owned());
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or_else(|| usage(&format!("{} needs a value", arg)))?;
+        match arg.as_str() {
+            "--backend" => backend = value,
+            "--checks" => checks_path = Some(value),
+            "--iterations" => iterations = value.parse().map_err(|_| usage("--iterations is not a number"))?,
+            "--format" => format = value,
+            _ => return Err(usage(&format!("unknown argument {}", arg))),
+        }
+    }
+
+    let checks = match &checks_path {
+        Some(path) => read_checks(path)?,
+        None if backend == "memory" => synthetic_checks(),
+        None => return Err(usage("--checks is required for this backend")),
+    };
+
+    let reports = match backend.split_once(':') {
+        None if backend == "memory" => run(synthetic_storage, &checks, iterations)?,
+        #[cfg(feature = "lmdb")]
+        Some(("lmdb", path)) => run(|| Ok(v_authorization::storage::lmdb::LmdbStorage::new(path)), &checks, iterations)?,
+        #[cfg(feature = "redis")]
+        Some(("redis", url)) => run(|| v_authorization::storage::redis::RedisStorage::open(url), &checks, iterations)?,
+        _ => return Err(usage(&format!("unknown or disabled backend {}", backend))),
+    };
+
+    match format.as_str() {
+        "json" => println!("{}", to_json(&backend, checks.len(), iterations, &reports)),
+        _ => print!("{}", to_markdown(&backend, checks.len(), iterations, &reports)),
+    }
+    Ok(())
+}
@@ -0,0 +1,116 @@
+//! Writes an ACL graph, converted from platform individuals with the `manage` module, into Redis under
+//! a key prefix, then answers and explains requests through `RedisStorage` with its client cache on.
+//! Run with `cargo run --example redis_demo --features redis -- [URL]`, `redis://127.0.0.1:6379/0` by
+//! default; the keys are removed at the end.
+
+use std::io;
+use std::time::Duration;
+use v_authorization::authorize_principals;
+use v_authorization::common::Trace;
+use v_authorization::config::AzConfig;
+use v_authorization::decision::Decision;
+use v_authorization::engine::AzEngine;
+use v_authorization::explain::explain_passes;
+use v_authorization::manage::{apply_change, from_individual, IndividualProps};
+use v_authorization::presets::format_access_expr;
+use v_authorization::storage::memory::MemoryStorage;
+use v_authorization::storage::redis::RedisStorage;
+
+const KEY_PREFIX: &str = "v-az-demo:";
+
+fn individual(props: &[(&str, &str)]) -> IndividualProps {
+    let mut res = IndividualProps::new();
+    for (predicate, value) in props {
+        res.entry(predicate.to_string()).or_default().push(value.to_string());
+    }
+    res
+}
+
+// Записи строятся в памяти тем же кодом, что у индексатора, и переносятся в Redis
+fn records() -> io::Result<MemoryStorage> {
+    let individuals = [
+        individual(&[("rdf:type", "v-s:Membership"), ("v-s:resource", "u:alice"), ("v-s:memberOf", "g:editors")]),
+        individual(&[("rdf:type", "v-s:Membership"), ("v-s:resource", "d:report"), ("v-s:memberOf", "f:reports")]),
+        individual(&[
+            ("rdf:type", "v-s:PermissionStatement"),
+            ("v-s:permissionSubject", "g:editors"),
+            ("v-s:permissionObject", "f:reports"),
+            ("v-s:canRead", "true"),
+            ("v-s:canUpdate", "true"),
+        ]),
+        individual(&[
+            ("rdf:type", "v-s:PermissionStatement"),
+            ("v-s:permissionSubject", "u:bob"),
+            ("v-s:permissionObject", "d:report"),
+            ("v-s:canRead", "true"),
+        ]),
+    ];
+
+    let mut db = MemoryStorage::new();
+    for props in &individuals {
+        for change in from_individual(props)? {
+            apply_change(&change, &mut db)?;
+        }
+    }
+    Ok(db)
+}
+
+// Все ключи одним конвейером: запись значений или их удаление
+fn write_keys(url: &str, records: &MemoryStorage, remove: bool) -> redis::RedisResult<()> {
+    let mut conn = redis::Client::open(url)?.get_connection()?;
+    let mut pipe = redis::pipe();
+    for (key, value) in records.iter() {
+        if remove {
+            pipe.cmd("DEL").arg(KEY_PREFIX.to_owned() + key);
+        } else {
+            pipe.cmd("SET").arg(KEY_PREFIX.to_owned() + key).arg(value);
+        }
+    }
+    pipe.query(&mut conn)
+}
+
+fn main() -> io::Result<()> {
+    let url = std::env::args().nth(1).unwrap_or_else(|| "redis://127.0.0.1:6379/0".to_owned());
+    let to_io = |e: redis::RedisError| io::Error::other(format!("redis {}: {}", url, e));
+
+    let records = records()?;
+    write_keys(&url, &records, false).map_err(to_io)?;
+    println!("wrote {} records to {} under {}", records.len(), url, KEY_PREFIX);
+
+    let mut db = RedisStorage::open(&url)?;
+    db.set_key_prefix(KEY_PREFIX);
+    db.set_client_cache(Some(Duration::from_secs(5)), 1000);
+
+    let engine = AzEngine::new(AzConfig::default());
+    for (user, access) in [("u:alice", 6), ("u:bob", 6), ("u:carol", 2)] {
+        let (mut acl, mut group, mut info) = (String::new(), String::new(), String::new());
+        let mut trace = Trace {
+            acl: &mut acl,
+            is_acl: true,
+            group: &mut group,
+            is_group: true,
+            info: &mut info,
+            is_info: true,
+            str_num: 0,
+        };
+        let res = engine.authorize("d:report", user, access, &mut db, &mut trace)?;
+        println!("\n{} asks {} on d:report: {}", user, format_access_expr(access), format_access_expr(res));
+        println!("-- groups:\n{}-- acl:\n{}-- info:\n{}", group, acl, info);
+
+        let mut decision = Decision::default();
+        let (mut acl, mut group, mut info) = (String::new(), String::new(), String::new());
+        let mut trace = Trace {
+            acl: &mut acl,
+            is_acl: false,
+            group: &mut group,
+            is_group: false,
+            info: &mut info,
+            is_info: false,
+            str_num: 0,
+        };
+        authorize_principals("d:report", &[user], access, &mut db, &mut trace, &mut decision)?;
+        println!("-- explain: {}", explain_passes(&decision));
+    }
+
+    write_keys(&url, &records, true).map_err(to_io)
+}
@@ -0,0 +1,161 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Hook watching per-user decisions to detect enumeration attempts
+pub trait AbuseDetector: Send + Sync {
+    /// Called before evaluation, `false` denies the request without reading the storage
+    fn admit(&self, user_id: &str) -> bool;
+
+    /// Called after every decision
+    fn on_decision(&self, user_id: &str, id: &str, request_access: u8, result: u8);
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket over denials: every denial takes a token, tokens are refilled at `refill_per_sec`.
+/// A user with an empty bucket is flagged and, if `throttle` is set, not admitted until it refills.
+/// Buckets that have refilled to capacity are dropped, so idle users do not accumulate.
+pub struct TokenBucketDetector {
+    capacity: f64,
+    refill_per_sec: f64,
+    throttle: bool,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    swept: Mutex<Instant>,
+    flagged: Mutex<HashSet<String>>,
+}
+
+impl TokenBucketDetector {
+    pub fn new(capacity: u32, refill_per_sec: f64, throttle: bool) -> Self {
+        TokenBucketDetector {
+            capacity: capacity as f64,
+            refill_per_sec,
+            throttle,
+            buckets: Mutex::new(HashMap::new()),
+            swept: Mutex::new(Instant::now()),
+            flagged: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Users that have emptied their bucket at least once
+    pub fn flagged_users(&self) -> Vec<String> {
+        match self.flagged.lock() {
+            Ok(f) => f.iter().cloned().collect(),
+            Err(e) => e.into_inner().iter().cloned().collect(),
+        }
+    }
+
+    pub fn clear_flag(&self, user_id: &str) {
+        if let Ok(mut f) = self.flagged.lock() {
+            f.remove(user_id);
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.updated = now;
+    }
+
+    // Полная корзина ничем не отличается от отсутствующей; обход не чаще, чем за время полного пополнения
+    fn evict_idle(&self, buckets: &mut HashMap<String, Bucket>) {
+        let now = Instant::now();
+        let mut swept = match self.swept.lock() {
+            Ok(s) => s,
+            Err(e) => e.into_inner(),
+        };
+        if now.duration_since(*swept).as_secs_f64() * self.refill_per_sec < self.capacity {
+            return;
+        }
+        *swept = now;
+
+        buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.refill_per_sec < self.capacity);
+    }
+}
+
+impl AbuseDetector for TokenBucketDetector {
+    fn admit(&self, user_id: &str) -> bool {
+        if !self.throttle {
+            return true;
+        }
+
+        let mut buckets = match self.buckets.lock() {
+            Ok(b) => b,
+            Err(e) => e.into_inner(),
+        };
+
+        match buckets.get_mut(user_id) {
+            Some(bucket) => {
+                self.refill(bucket);
+                bucket.tokens >= 1.0
+            },
+            None => true,
+        }
+    }
+
+    fn on_decision(&self, user_id: &str, id: &str, request_access: u8, result: u8) {
+        if result & request_access == request_access {
+            return;
+        }
+
+        let mut buckets = match self.buckets.lock() {
+            Ok(b) => b,
+            Err(e) => e.into_inner(),
+        };
+        self.evict_idle(&mut buckets);
+
+        let bucket = buckets.entry(user_id.to_owned()).or_insert_with(|| Bucket {
+            tokens: self.capacity,
+            updated: Instant::now(),
+        });
+        self.refill(bucket);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return;
+        }
+
+        if let Ok(mut f) = self.flagged.lock() {
+            if f.insert(user_id.to_owned()) {
+                eprintln!("WARN! Authorize: too many denials, user={}, last uri={}", user_id, id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn refilled_buckets_are_evicted() {
+        let detector = TokenBucketDetector::new(2, 1000.0, true);
+        detector.on_decision("u1", "d1", 2, 0);
+        detector.on_decision("u2", "d1", 2, 0);
+        assert_eq!(detector.buckets.lock().unwrap().len(), 2);
+
+        thread::sleep(Duration::from_millis(20));
+        detector.on_decision("u3", "d1", 2, 0);
+        let buckets = detector.buckets.lock().unwrap();
+        assert_eq!(buckets.keys().collect::<Vec<_>>(), vec!["u3"]);
+    }
+
+    #[test]
+    fn draining_buckets_are_kept() {
+        let detector = TokenBucketDetector::new(2, 0.001, true);
+        for _ in 0..3 {
+            detector.on_decision("u1", "d1", 2, 0);
+        }
+        detector.on_decision("u2", "d1", 2, 0);
+
+        assert!(!detector.admit("u1"));
+        assert!(detector.admit("u2"));
+        assert_eq!(detector.flagged_users(), vec!["u1".to_owned()]);
+    }
+}
@@ -0,0 +1,75 @@
+//! Pre-aggregated permissions of an object group: one allow mask and one deny mask per subject group.
+//! Stored under `AGGREGATE_PREFIX` + the suffix of the corresponding P-record key.
+
+use crate::common::{Storage, AGGREGATE_PREFIX};
+use crate::ACLRecord;
+use std::collections::BTreeMap;
+use std::io;
+
+/// Effective allow bits of a permission record, deny bits (high nibble) removed
+pub(crate) fn permission_allow_bits(access: u8) -> u8 {
+    if access > 15 {
+        (((access & 0xF0) >> 4) ^ 0x0F) & access
+    } else {
+        access
+    }
+}
+
+/// Folds live P-record entries into union (allow, deny) masks per subject; deny bits stay in the high nibble
+pub fn aggregate_permissions(permissions: &[ACLRecord]) -> BTreeMap<String, (u8, u8)> {
+    let mut res = BTreeMap::new();
+    for perm in permissions {
+        if perm.id.is_empty() || perm.is_deleted {
+            continue;
+        }
+        let entry = res.entry(perm.id.clone()).or_insert((0, 0));
+        entry.0 |= permission_allow_bits(perm.access) & 0x0F;
+        entry.1 |= perm.access & 0xF0;
+    }
+    res
+}
+
+pub fn encode_aggregate(epoch: u64, entries: &BTreeMap<String, (u8, u8)>) -> String {
+    let mut res = format!("{}\n", epoch);
+    for (subj, (allow, deny)) in entries {
+        res.push_str(&format!("{}\t{}\t{}\n", subj, allow, deny));
+    }
+    res
+}
+
+/// Subject group id with its allow and deny masks
+pub type AggregateEntry = (String, u8, u8);
+
+pub fn decode_aggregate(src: &str) -> Option<(u64, Vec<AggregateEntry>)> {
+    let mut lines = src.lines();
+    let epoch = lines.next()?.parse::<u64>().ok()?;
+
+    let mut entries = Vec::new();
+    for line in lines {
+        let mut fields = line.split('\t');
+        let subj = fields.next()?;
+        let allow = fields.next()?.parse::<u8>().ok()?;
+        let deny = match fields.next() {
+            Some(d) => d.parse::<u8>().ok()?,
+            None => 0,
+        };
+        entries.push((subj.to_owned(), allow, deny));
+    }
+
+    Some((epoch, entries))
+}
+
+/// Aggregate for `key_suffix` if present and built at or after `min_epoch`; `None` means full scan
+pub(crate) fn get_fresh_aggregate(key_suffix: &str, min_epoch: u64, db: &mut dyn Storage) -> io::Result<Option<Vec<AggregateEntry>>> {
+    match db.get(&(AGGREGATE_PREFIX.to_owned() + key_suffix))? {
+        Some(val) => match decode_aggregate(&val) {
+            Some((epoch, entries)) if epoch >= min_epoch => Ok(Some(entries)),
+            Some(_) => Ok(None),
+            None => {
+                eprintln!("WARN! Authorize: invalid permission aggregate, key={}", key_suffix);
+                Ok(None)
+            },
+        },
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,124 @@
+//! Authorization over storages with asynchronous reads, for services running on an async executor.
+//!
+//! The traversal itself stays synchronous: it runs over the values fetched so far, records every key it
+//! wanted and did not have, the keys are fetched with `AsyncStorage::get` and the traversal is run again.
+//! Each round reads at least one new key, the decision of the first round that misses nothing is the
+//! result. Deep group graphs cost several rounds, so the rounds are worth it only where blocking a thread
+//! on the store is not an option.
+
+use crate::common::{Storage, Trace, TraceBuffers};
+use crate::config::AzConfig;
+use crate::decision::Decision;
+use crate::record_formats;
+use crate::{authorize_impl, ACLRecord, ACLRecordSet, ACLRecordVec, AzHooks};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+
+/// Same as `Storage`, with an asynchronous `get`; decoding stays synchronous and defaults to `record_formats`
+pub trait AsyncStorage {
+    fn get(&mut self, key: &str) -> impl Future<Output = io::Result<Option<String>>> + Send;
+
+    fn decode_rec_to_rights(&self, src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+        record_formats::decode_rec_to_rights(src, result)
+    }
+
+    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+        record_formats::decode_rec_to_rightset(src, new_rights)
+    }
+
+    fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+        record_formats::decode_filter(&filter_value)
+    }
+
+    fn decode_valid_from(&self, src: &str) -> Option<DateTime<Utc>> {
+        record_formats::record_valid_from(src)
+    }
+}
+
+// Отдаёт уже прочитанные значения, остальные ключи запоминает для следующего чтения
+struct PrefetchStorage<'a, S: AsyncStorage> {
+    inner: &'a S,
+    fetched: &'a HashMap<String, Option<String>>,
+    missing: Vec<String>,
+}
+
+impl<S: AsyncStorage> Storage for PrefetchStorage<'_, S> {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        match self.fetched.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => {
+                if !self.missing.iter().any(|k| k == key) {
+                    self.missing.push(key.to_owned());
+                }
+                Ok(None)
+            },
+        }
+    }
+
+    fn fiber_yield(&self) {}
+
+    fn decode_rec_to_rights(&self, src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+        self.inner.decode_rec_to_rights(src, result)
+    }
+
+    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+        self.inner.decode_rec_to_rightset(src, new_rights)
+    }
+
+    fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+        self.inner.decode_filter(filter_value)
+    }
+
+    fn decode_valid_from(&self, src: &str) -> Option<DateTime<Utc>> {
+        self.inner.decode_valid_from(src)
+    }
+}
+
+/// Same as `authorize`, reading through `AsyncStorage`
+pub async fn authorize_async<S: AsyncStorage>(id: &str, user_id: &str, request_access: u8, db: &mut S, trace: &mut Trace<'_>) -> io::Result<u8> {
+    authorize_async_with_config(id, user_id, request_access, db, trace, &AzConfig::default()).await
+}
+
+/// Same as `authorize_with_config`, reading through `AsyncStorage`.
+/// `AzConfig::deny_min_duration` is not applied: the padding would block the executor thread.
+pub async fn authorize_async_with_config<S: AsyncStorage>(
+    id: &str,
+    user_id: &str,
+    request_access: u8,
+    db: &mut S,
+    trace: &mut Trace<'_>,
+    cfg: &AzConfig,
+) -> io::Result<u8> {
+    let mut fetched = HashMap::new();
+    loop {
+        let mut pdb = PrefetchStorage {
+            inner: db,
+            fetched: &fetched,
+            missing: Vec::new(),
+        };
+        let mut buf = TraceBuffers::default();
+        let res =
+            authorize_impl(id, user_id, request_access, &mut pdb, &mut buf.trace(false, false, false), cfg, &AzHooks::default(), &mut Decision::default());
+
+        let missing = pdb.missing;
+        if missing.is_empty() {
+            // все ключи прочитаны: трассировка пишется только по полному проходу
+            if trace.is_acl || trace.is_group || trace.is_info {
+                let mut pdb = PrefetchStorage {
+                    inner: db,
+                    fetched: &fetched,
+                    missing: Vec::new(),
+                };
+                return authorize_impl(id, user_id, request_access, &mut pdb, trace, cfg, &AzHooks::default(), &mut Decision::default());
+            }
+            return res;
+        }
+
+        for key in missing {
+            let value = db.get(&key).await?;
+            fetched.insert(key, value);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use crate::common::{access_predicate, parse_acl_trace, Storage, TraceBuffers, ACCESS_8_LIST};
+use crate::config::AzConfig;
+use crate::decision::Decision;
+use crate::presets::MANAGER;
+use crate::{authorize_with_config, ACLRecord, ACLRecordSet, ACLRecordVec};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::io;
+
+/// Запись журнала об одном решении авторизации
+pub struct AuditEvent<'a> {
+    /// Caller-provided id tying together all decisions made for one user request
+    pub correlation_id: Option<&'a str>,
+    pub id: &'a str,
+    pub user_id: &'a str,
+    /// Service account that asked on behalf of `user_id`, see `AzEngine::authorize_as`
+    pub service_user: Option<&'a str>,
+    pub request_access: u8,
+    pub result: &'a io::Result<u8>,
+    pub decision: &'a Decision,
+    /// Present when `AzConfig::audit_provenance` is enabled
+    pub provenance: Option<&'a ProvenanceRecord>,
+}
+
+/// Receives an event for every decision made through `AzEngine`
+pub trait AuditSink: Send + Sync {
+    fn on_decision(&self, event: &AuditEvent);
+
+    /// Called instead of `on_decision` for decisions made by the superuser bypass (`Decision::superuser`)
+    fn on_superuser(&self, event: &AuditEvent) {
+        self.on_decision(event);
+    }
+}
+
+/// Receives decisions on resources in some object groups, see `AzEngine::add_group_hook`
+pub trait GroupHook: Send + Sync {
+    /// `groups` are the groups of the hook met while the decision was made
+    fn on_group_decision(&self, event: &AuditEvent, groups: &[&str]);
+}
+
+/// Conditions worth reporting even when the call is not traced
+#[derive(Debug)]
+pub enum AzWarning<'a> {
+    /// Rights were found, but the user is in exclusive groups and the resource is in none of them
+    ExclusiveDenied { id: &'a str, user_id: &'a str, found: u8, exclusive_groups: &'a [String] },
+}
+
+pub trait WarningSink: Send + Sync {
+    fn on_warning(&self, warning: &AzWarning);
+}
+
+/// Full trace of a decision that touched a watched user or resource
+pub struct WatchTrace<'a> {
+    pub correlation_id: Option<&'a str>,
+    pub id: &'a str,
+    pub user_id: &'a str,
+    pub result: &'a io::Result<u8>,
+    pub acl: &'a str,
+    pub group: &'a str,
+    pub info: &'a str,
+}
+
+/// Receives traces of decisions on watched ids, see `AzEngine::watch`
+pub trait TraceSink: Send + Sync {
+    fn on_trace(&self, trace: &WatchTrace);
+}
+
+/// Storage key read during a decision with the hash of the value seen
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TouchedKey {
+    pub key: String,
+    pub value_hash: Option<[u8; 32]>,
+}
+
+/// Tamper-evident trail of one decision.
+///
+/// `hash` covers the previous record hash, the request, every touched key with its value hash and the result,
+/// so records form a chain and each one can be checked against a snapshot of the ACL store.
+#[derive(Clone, Debug)]
+pub struct ProvenanceRecord {
+    pub prev_hash: [u8; 32],
+    pub id: String,
+    pub user_id: String,
+    pub request_access: u8,
+    /// `None` when the decision ended with a storage error
+    pub result: Option<u8>,
+    pub touched: Vec<TouchedKey>,
+    pub hash: [u8; 32],
+}
+
+impl ProvenanceRecord {
+    pub(crate) fn new(prev_hash: [u8; 32], id: &str, user_id: &str, request_access: u8, result: Option<u8>, touched: Vec<TouchedKey>) -> Self {
+        let hash = provenance_hash(&prev_hash, id, user_id, request_access, result, &touched);
+        ProvenanceRecord {
+            prev_hash,
+            id: id.to_owned(),
+            user_id: user_id.to_owned(),
+            request_access,
+            result,
+            touched,
+            hash,
+        }
+    }
+}
+
+pub fn value_hash(value: &str) -> [u8; 32] {
+    Sha256::digest(value.as_bytes()).into()
+}
+
+fn provenance_hash(prev_hash: &[u8; 32], id: &str, user_id: &str, request_access: u8, result: Option<u8>, touched: &[TouchedKey]) -> [u8; 32] {
+    let mut h = Sha256::new();
+    h.update(prev_hash);
+    for part in [id, user_id] {
+        h.update((part.len() as u64).to_be_bytes());
+        h.update(part.as_bytes());
+    }
+    h.update([request_access]);
+    for tk in touched {
+        h.update((tk.key.len() as u64).to_be_bytes());
+        h.update(tk.key.as_bytes());
+        match &tk.value_hash {
+            Some(vh) => {
+                h.update([1]);
+                h.update(vh);
+            },
+            None => h.update([0]),
+        }
+    }
+    match result {
+        Some(r) => h.update([1, r]),
+        None => h.update([0, 0]),
+    }
+    h.finalize().into()
+}
+
+/// Recomputes the record hash, and, if `snapshot` is given, checks that every touched key still has the recorded value
+pub fn verify_provenance(record: &ProvenanceRecord, snapshot: Option<&mut dyn Storage>) -> io::Result<bool> {
+    if provenance_hash(&record.prev_hash, &record.id, &record.user_id, record.request_access, record.result, &record.touched) != record.hash {
+        return Ok(false);
+    }
+
+    if let Some(db) = snapshot {
+        for tk in &record.touched {
+            if db.get(&tk.key)?.map(|v| value_hash(&v)) != tk.value_hash {
+                return Ok(false);
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+/// Checks that records follow each other in the chain and none of them was altered
+pub fn verify_chain(records: &[ProvenanceRecord]) -> bool {
+    records.windows(2).all(|w| w[1].prev_hash == w[0].hash) && records.iter().all(|r| verify_provenance(r, None).unwrap_or(false))
+}
+
+/// Rights a user gained on a resource between two snapshots of the store
+#[derive(Debug)]
+pub struct Escalation {
+    pub user_id: String,
+    pub id: String,
+    pub gained: u8,
+    /// Permissions in the newer snapshot that produce the gained bits
+    pub causes: Vec<EscalationCause>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct EscalationCause {
+    pub object_group: String,
+    pub subject_group: String,
+    pub predicate: String,
+}
+
+/// Evaluates every (user, resource) pair of the samples on both snapshots and reports the pairs where `snapshot_b`
+/// grants bits `snapshot_a` does not; both snapshots are evaluated with `cfg`
+pub fn detect_escalations(
+    snapshot_a: &mut dyn Storage,
+    snapshot_b: &mut dyn Storage,
+    sample_users: &[&str],
+    sample_resources: &[&str],
+    cfg: &AzConfig,
+) -> io::Result<Vec<Escalation>> {
+    let mut res = Vec::new();
+
+    for user_id in sample_users {
+        for id in sample_resources {
+            let (before, _) = authorize_with_acl_trace(id, user_id, snapshot_a, cfg)?;
+            let (after, acl) = authorize_with_acl_trace(id, user_id, snapshot_b, cfg)?;
+
+            let gained = after & !before & 0x0F;
+            if gained == 0 {
+                continue;
+            }
+
+            let gained_predicates: Vec<&str> = ACCESS_8_LIST.iter().filter(|a| gained & **a != 0).filter_map(|a| access_predicate(*a)).collect();
+
+            let mut causes = Vec::new();
+            for entry in parse_acl_trace(&acl) {
+                if gained_predicates.contains(&entry.predicate.as_str()) {
+                    let cause = EscalationCause {
+                        object_group: entry.object_group,
+                        subject_group: entry.subject_group,
+                        predicate: entry.predicate,
+                    };
+                    if !causes.contains(&cause) {
+                        causes.push(cause);
+                    }
+                }
+            }
+
+            res.push(Escalation {
+                user_id: user_id.to_string(),
+                id: id.to_string(),
+                gained,
+                causes,
+            });
+        }
+    }
+
+    Ok(res)
+}
+
+fn authorize_with_acl_trace(id: &str, user_id: &str, db: &mut dyn Storage, cfg: &AzConfig) -> io::Result<(u8, String)> {
+    let mut buf = TraceBuffers::default();
+    let res = authorize_with_config(id, user_id, MANAGER, db, &mut buf.trace(true, false, false), cfg)?;
+    Ok((res, buf.acl))
+}
+
+/// Storage wrapper remembering every key read and the hash of its value
+pub(crate) struct RecordingStorage<'a> {
+    pub(crate) inner: &'a mut dyn Storage,
+    pub(crate) touched: Vec<TouchedKey>,
+    #[cfg(feature = "bench")]
+    pub(crate) hash_ns: u64,
+}
+
+impl Storage for RecordingStorage<'_> {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        let res = self.inner.get(key)?;
+        #[cfg(feature = "bench")]
+        let start = std::time::Instant::now();
+        self.touched.push(TouchedKey {
+            key: key.to_owned(),
+            value_hash: res.as_deref().map(value_hash),
+        });
+        #[cfg(feature = "bench")]
+        {
+            self.hash_ns += start.elapsed().as_nanos() as u64;
+        }
+        Ok(res)
+    }
+
+    fn fiber_yield(&self) {
+        self.inner.fiber_yield()
+    }
+
+    fn decode_rec_to_rights(&self, src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+        self.inner.decode_rec_to_rights(src, result)
+    }
+
+    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+        self.inner.decode_rec_to_rightset(src, new_rights)
+    }
+
+    fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+        self.inner.decode_filter(filter_value)
+    }
+
+    fn decode_valid_from(&self, src: &str) -> Option<DateTime<Utc>> {
+        self.inner.decode_valid_from(src)
+    }
+}
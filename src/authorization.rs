@@ -1,21 +1,160 @@
+pub mod abuse;
+pub mod aggregate;
+pub mod async_storage;
+pub mod audit;
 mod authorize_obj_group;
+pub mod authorizer;
+pub mod cache;
+pub mod cache_metrics;
+pub mod clock;
+pub mod closure;
 /// This module gives function to check access of user to object
 pub mod common;
+pub mod config;
+pub mod consistency;
+pub mod context;
+pub mod decision;
+pub mod engine;
+pub mod exclusive;
+pub mod explain;
+pub mod graph;
+pub mod heatmap;
+pub mod implicit_groups;
+#[cfg(feature = "indexer")]
+pub mod indexer;
+// тесты крейта прогоняют наборы testkit и без признаков
+#[cfg(any(test, feature = "integration"))]
+pub mod integration;
+pub mod keys;
+pub mod manage;
+pub mod matrix;
+pub mod membership_cache;
+pub mod patterns;
 mod prepare_obj_group;
+pub mod presets;
+pub mod principals;
+pub mod reachability;
+pub mod record_formats;
+pub mod record_set;
+pub mod replication;
+pub mod request_scope;
+pub mod shadowing;
+pub mod sharding;
+#[cfg(feature = "signing")]
+pub mod signing;
+pub mod stats;
+pub mod storage;
+pub mod subscribe;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+pub mod trace;
+pub mod validating;
+pub mod workload;
 
+use crate::audit::{AzWarning, WarningSink};
 use crate::authorize_obj_group::authorize_obj_group;
+use crate::clock::Clock;
 use crate::common::*;
+use crate::config::{AzConfig, BlankIdPolicy, SubjectOverflowStrategy};
+use crate::decision::{Decision, MatchedPermission};
+use crate::implicit_groups::ImplicitGroupProvider;
+use crate::membership_cache::MembershipCache;
 use crate::prepare_obj_group::prepare_obj_group;
-use std::collections::HashMap;
+use crate::principals::{PrincipalResolver, PrincipalSet};
+use crate::reachability::get_fresh_reachability;
+use crate::record_formats::read_continued;
+use crate::record_set::merge_marker;
+use crate::request_scope::{SubjectMemo, SubjectMemoMap};
+use crate::trace::TraceInfo;
+use chrono::{DateTime, Utc};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::io;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ACLRecord {
     pub id: String,
     pub access: u8,
     pub marker: char,
     pub is_deleted: bool,
     pub level: u8,
-    pub counters: HashMap<char, u16>,
+    pub counters: RightsCounters,
+    /// Who gave the grant, when and why; kept by v2 records only
+    pub provenance: Option<Box<GrantProvenance>>,
+}
+
+/// Context of a grant that otherwise lives only in the individual it was indexed from
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct GrantProvenance {
+    pub granted_by: Option<String>,
+    pub granted_at: Option<DateTime<Utc>>,
+    /// Free text or a ticket reference
+    pub reason: Option<String>,
+}
+
+/// Reference counters per right, indexed as `ACCESS_C_FULL_LIST`.
+/// Records built during traversal carry `None`, counters are materialized on first increment.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum RightsCounters {
+    #[default]
+    None,
+    Inline([u16; 8]),
+}
+
+impl RightsCounters {
+    pub fn get(&self, right: char) -> u16 {
+        match (self, counter_index(right)) {
+            (RightsCounters::Inline(c), Some(idx)) => c[idx],
+            _ => 0,
+        }
+    }
+
+    pub fn increment(&mut self, right: char) -> u16 {
+        let Some(idx) = counter_index(right) else {
+            return 0;
+        };
+        if *self == RightsCounters::None {
+            *self = RightsCounters::Inline([0; 8]);
+        }
+        match self {
+            RightsCounters::Inline(c) => {
+                c[idx] = c[idx].saturating_add(1);
+                c[idx]
+            },
+            RightsCounters::None => 0,
+        }
+    }
+
+    pub fn decrement(&mut self, right: char) -> u16 {
+        match (self, counter_index(right)) {
+            (RightsCounters::Inline(c), Some(idx)) => {
+                c[idx] = c[idx].saturating_sub(1);
+                c[idx]
+            },
+            _ => 0,
+        }
+    }
+
+    /// Sums two counter sets, `None` stays `None` only if both are `None`
+    pub fn merge(&mut self, other: &RightsCounters) {
+        match (self, other) {
+            (_, RightsCounters::None) => {},
+            (dst @ RightsCounters::None, src) => *dst = *src,
+            (RightsCounters::Inline(d), RightsCounters::Inline(s)) => {
+                for (c, v) in d.iter_mut().zip(s.iter()) {
+                    *c = c.saturating_add(*v);
+                }
+            },
+        }
+    }
 }
 
 impl ACLRecord {
@@ -26,7 +165,8 @@ impl ACLRecord {
             marker: 0 as char,
             is_deleted: false,
             level: 0,
-            counters: HashMap::default(),
+            counters: RightsCounters::None,
+            provenance: None,
         }
     }
     pub fn new_with_access(id: &str, access: u8) -> Self {
@@ -36,20 +176,51 @@ impl ACLRecord {
             marker: 0 as char,
             is_deleted: false,
             level: 0,
-            counters: HashMap::default(),
+            counters: RightsCounters::None,
+            provenance: None,
+        }
+    }
+
+    /// Grants `right` (a char of `ACCESS_C_FULL_LIST`) once more, with reference counting
+    pub fn add_right(&mut self, right: char) {
+        if let Some(idx) = counter_index(right) {
+            self.counters.increment(right);
+            self.access |= ACCESS_8_FULL_LIST[idx];
+        }
+    }
+
+    /// Drops one reference to `right`, the access bit is cleared when the last one goes away
+    pub fn remove_right(&mut self, right: char) {
+        if let Some(idx) = counter_index(right) {
+            if self.counters.decrement(right) == 0 {
+                self.access &= !ACCESS_8_FULL_LIST[idx];
+            }
         }
     }
 }
 
 pub type ACLRecordSet = HashMap<String, ACLRecord>;
 
+/// Decoded membership/permission record; most of them hold fewer than 8 entries
+pub type ACLRecordVec = SmallVec<[ACLRecord; 8]>;
+
 pub(crate) struct AzContext<'a> {
     id: &'a str,
     user_id: &'a str,
     request_access: u8,
     calc_right_res: u8,
+    calc_deny_res: u8,
+    granted_via: Vec<(String, u8)>,
+    matched: Vec<MatchedPermission>,
+    first_pass_res: u8,
+    filter_pass_res: Option<u8>,
+    pending: Vec<(String, String, u8)>,
+    exclusive_groups: Vec<String>,
+    // ребра член -> индивид в роли группы
+    individual_hops: Vec<(String, String)>,
     is_need_exclusive_az: bool,
     is_found_exclusive_az: bool,
+    max_object_level: u8,
     walked_groups_s: &'a mut HashMap<String, (u8, char)>,
     tree_groups_s: &'a mut HashMap<String, String>,
     walked_groups_o: &'a mut HashMap<String, u8>,
@@ -57,6 +228,48 @@ pub(crate) struct AzContext<'a> {
     subject_groups: &'a mut HashMap<String, ACLRecord>,
     checked_groups: &'a mut HashMap<String, u8>,
     filter_value: String,
+    cfg: &'a AzConfig,
+    // время для сроков действия записей
+    clock: &'a dyn Clock,
+    hooks: &'a AzHooks<'a>,
+}
+
+/// Подключаемые обработчики, действующие во время одного вызова
+#[derive(Default)]
+pub(crate) struct AzHooks<'a> {
+    pub(crate) implicit_groups: Option<&'a dyn ImplicitGroupProvider>,
+    pub(crate) membership_cache: Option<&'a MembershipCache>,
+    pub(crate) warnings: Option<&'a dyn WarningSink>,
+    pub(crate) principals: Option<&'a dyn PrincipalResolver>,
+    pub(crate) subject_memo: Option<&'a RefCell<SubjectMemoMap>>,
+    /// Structured copy of the traces, filled alongside the text buffers
+    pub(crate) trace_info: Option<&'a RefCell<TraceInfo>>,
+    pub(crate) object_group_hits: Option<&'a ObjectGroupHits<'a>>,
+}
+
+/// Object groups watched by group hooks and those of them met during one call
+pub(crate) struct ObjectGroupHits<'a> {
+    pub(crate) watched: &'a HashSet<String>,
+    pub(crate) met: RefCell<Vec<String>>,
+}
+
+impl ObjectGroupHits<'_> {
+    pub(crate) fn visit(&self, object_group_id: &str) {
+        if self.watched.contains(object_group_id) {
+            let mut met = self.met.borrow_mut();
+            if !met.iter().any(|gr| gr == object_group_id) {
+                met.push(object_group_id.to_owned());
+            }
+        }
+    }
+}
+
+impl AzContext<'_> {
+    // Обход можно прервать на первом полном наборе прав: без трассировки, без учета запретов со всех путей
+    // и без наблюдения за группами объектов
+    fn may_stop_early(&self, trace: &Trace) -> bool {
+        !trace.is_info && !trace.is_group && !trace.is_acl && !self.cfg.deny_override && self.hooks.object_group_hits.is_none()
+    }
 }
 
 impl<'a> Default for AzContext<'a> {
@@ -91,16 +304,407 @@ fn authorize_obj_groups(id: &str, request_access: u8, db: &mut dyn Storage, trac
     None
 }
 
+/// Kept for existing call sites; evaluates through `engine::default_engine`
+#[deprecated(note = "use AzEngine::authorize; engine::install_default_engine configures the calls not yet migrated")]
 pub fn authorize(id: &str, user_id: &str, request_access: u8, db: &mut dyn Storage, trace: &mut Trace) -> Result<u8, std::io::Error> {
+    engine::default_engine().authorize(id, user_id, request_access, db, trace)
+}
+
+/// Same as `authorize`, with the whole decision instead of the granted mask
+pub fn authorize_ex(id: &str, user_id: &str, request_access: u8, db: &mut dyn Storage) -> io::Result<Decision> {
+    engine::default_engine().authorize_ex(id, user_id, request_access, db)
+}
+
+pub fn authorize_with_config(id: &str, user_id: &str, request_access: u8, db: &mut dyn Storage, trace: &mut Trace, cfg: &AzConfig) -> io::Result<u8> {
+    authorize_with_hooks(id, user_id, request_access, db, trace, cfg, &AzHooks::default(), &mut Decision::default())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn authorize_with_hooks(
+    id: &str,
+    user_id: &str,
+    request_access: u8,
+    db: &mut dyn Storage,
+    trace: &mut Trace,
+    cfg: &AzConfig,
+    hooks: &AzHooks,
+    decision: &mut Decision,
+) -> io::Result<u8> {
+    let start = Instant::now();
+
+    decision.id = id.to_owned();
+    decision.requested = request_access;
+    let res = authorize_impl(id, user_id, request_access, db, trace, cfg, hooks, decision);
+    #[cfg(feature = "bench")]
+    {
+        decision.phases.traversal_ns = start.elapsed().as_nanos() as u64;
+    }
+    if let Ok(r) = res {
+        decision.granted = r;
+    }
+
+    if let Ok(0) = res {
+        pad_denial_time(start, cfg);
+    }
+
+    res
+}
+
+// Отказ по ACL и отказ из-за отсутствия записей о ресурсе должны выглядеть для вызывающего одинаково
+pub(crate) fn pad_denial_time(start: Instant, cfg: &AzConfig) {
+    let Some(min) = cfg.deny_min_duration else {
+        return;
+    };
+
+    let mut target = min;
+    if let Some(jitter) = cfg.deny_jitter {
+        let max_nanos = jitter.as_nanos() as u64;
+        if max_nanos > 0 {
+            let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos() as u64).unwrap_or(0);
+            target += Duration::from_nanos(seed.wrapping_mul(6364136223846793005) % max_nanos);
+        }
+    }
+
+    let elapsed = start.elapsed();
+    if elapsed < target {
+        thread::sleep(target - elapsed);
+    }
+}
+
+/// Returns the granted mask and, separately, the deny bits (`Cant*`) of matched permissions,
+/// so "nothing matched" can be told apart from "granted, then explicitly denied"
+pub fn authorize_split(id: &str, user_id: &str, request_access: u8, db: &mut dyn Storage) -> io::Result<(u8, u8)> {
+    let mut buf = TraceBuffers::default();
+    let mut decision = Decision::default();
+
+    let allow =
+        authorize_with_hooks(id, user_id, request_access, db, &mut buf.trace(false, false, false), &AzConfig::default(), &AzHooks::default(), &mut decision)?;
+
+    Ok((allow, decision.denied))
+}
+
+/// Evaluates the request for several principals at once: their subject groups are united in one traversal,
+/// `Decision::principals` tells what each of them gave
+pub fn authorize_principals(
+    id: &str,
+    principals: &[&str],
+    request_access: u8,
+    db: &mut dyn Storage,
+    trace: &mut Trace,
+    decision: &mut Decision,
+) -> io::Result<u8> {
+    let Some(user_id) = principals.first() else {
+        return Ok(0);
+    };
+    let set = PrincipalSet::new(principals);
+    let hooks = AzHooks {
+        principals: Some(&set),
+        ..AzHooks::default()
+    };
+
+    authorize_with_hooks(id, user_id, request_access, db, trace, &AzConfig::default(), &hooks, decision)
+}
+
+/// Side-effect-free check for analyzers and simulations: reads bypass caching layers, nothing is traced or counted
+pub fn authorize_dry(id: &str, user_id: &str, request_access: u8, db: &mut dyn Storage) -> io::Result<u8> {
+    authorize_dry_with_hooks(id, user_id, request_access, db, &AzConfig::default(), &AzHooks::default())
+}
+
+pub(crate) fn authorize_dry_with_hooks(id: &str, user_id: &str, request_access: u8, db: &mut dyn Storage, cfg: &AzConfig, hooks: &AzHooks) -> io::Result<u8> {
+    let mut buf = TraceBuffers::default();
+    let mut udb = UncachedStorage {
+        inner: db,
+    };
+
+    // без выравнивания времени отказа
+    authorize_impl(id, user_id, request_access, &mut udb, &mut buf.trace(false, false, false), cfg, hooks, &mut Decision::default())
+}
+
+/// Transitive groups of the user with their effective access masks, markers and levels, the user itself
+/// included; no resource is looked at
+pub fn get_user_groups(user_id: &str, db: &mut dyn Storage) -> io::Result<ACLRecordSet> {
+    get_user_groups_with_config(user_id, db, &AzConfig::default())
+}
+
+/// Same as `get_user_groups`; depth, scope, aliases and key schema are taken from `cfg`
+pub fn get_user_groups_with_config(user_id: &str, db: &mut dyn Storage, cfg: &AzConfig) -> io::Result<ACLRecordSet> {
+    resolve_subject_groups(&cfg.key_schema.encode_id(user_id), db, cfg)
+}
+
+/// Object groups of the resource reached through its M-records, in the order the traversal meets them, with
+/// the access passed down to each; groups entered with the exclusive marker are left out, as the traversal
+/// skips them. The resource itself and `v-s:AllResourcesGroup`, also checked by `authorize`, are not listed
+pub fn get_object_groups(id: &str, db: &mut dyn Storage) -> io::Result<Vec<ACLRecord>> {
+    get_object_groups_with_config(id, db, &AzConfig::default())
+}
+
+/// Same as `get_object_groups`; depth, scope, aliases, access accumulation and key schema are taken from `cfg`
+pub fn get_object_groups_with_config(id: &str, db: &mut dyn Storage, cfg: &AzConfig) -> io::Result<Vec<ACLRecord>> {
+    let mut res = Vec::new();
+    collect_object_groups(&cfg.key_schema.encode_id(id), 15, 0, cfg, db, &mut HashMap::new(), &mut res)?;
+    Ok(res)
+}
+
+/// F-record of a resource or of one of its object groups
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FilterInfo {
+    /// Id the F-record is kept under, the resource or an object group
+    pub object_id: String,
+    pub filter_id: String,
+    /// Bits left to permissions given without the filter
+    pub allowed: u8,
+    pub valid_until: Option<DateTime<Utc>>,
+    /// Membership edges from the resource to `object_id`
+    pub level: u8,
+    /// The filter `authorize` applies: the first one found on the resource or on its direct groups
+    pub applied: bool,
+}
+
+/// Unexpired F-records of the resource and of its object groups, the resource first, then in the order
+/// of `get_object_groups`, and the one `authorize` applies in any case; for admin screens and for caches
+/// that have to follow filter changes
+pub fn get_active_filters(id: &str, db: &mut dyn Storage) -> io::Result<Vec<FilterInfo>> {
+    get_active_filters_with_config(id, db, &AzConfig::default())
+}
+
+/// Same as `get_active_filters`; groups are walked as by `get_object_groups_with_config`, expiry is checked by `cfg.clock`
+pub fn get_active_filters_with_config(id: &str, db: &mut dyn Storage, cfg: &AzConfig) -> io::Result<Vec<FilterInfo>> {
+    let id = cfg.key_schema.encode_id(id);
+
+    // authorize берет первый фильтр среди ресурса и всех его прямых групп
+    let mut first_level = vec![id.to_string()];
+    if let Some(src) = read_continued(&(MEMBERSHIP_PREFIX.to_owned() + &id), db)? {
+        let mut groups = ACLRecordVec::new();
+        decode_memberships(&src, &id, db, cfg, &*cfg.clock, &mut groups)?;
+        first_level.extend(groups.into_iter().map(|gr| gr.id));
+    }
+    let mut applied = None;
+    for gr in &first_level {
+        if let (Some(f), _) = get_filter(gr, db) {
+            applied = (!f.id.is_empty()).then(|| gr.clone());
+            break;
+        }
+    }
+
+    let mut groups = Vec::new();
+    collect_object_groups(&id, 15, 0, cfg, db, &mut HashMap::new(), &mut groups)?;
+    let mut candidates: Vec<(String, u8)> = std::iter::once((id.to_string(), 0)).chain(groups.into_iter().map(|gr| (gr.id, gr.level.saturating_add(1)))).collect();
+    // прямая группа с фильтром могла быть пропущена обходом, например исключительная
+    if let Some(gr) = applied.as_ref().filter(|gr| !candidates.iter().any(|(id, _)| id == *gr)) {
+        candidates.insert(1, (gr.clone(), 1));
+    }
+
+    let now = cfg.now();
+    let mut res = Vec::new();
+    for (object_id, level) in candidates {
+        let (Some(f), valid_until) = get_filter(&object_id, db) else {
+            continue;
+        };
+        let is_applied = applied.as_ref() == Some(&object_id);
+        if f.id.is_empty() || (!is_applied && valid_until.is_some_and(|t| t <= now)) {
+            continue;
+        }
+        res.push(FilterInfo {
+            applied: is_applied,
+            object_id,
+            filter_id: f.id,
+            allowed: f.access,
+            valid_until,
+            level,
+        });
+    }
+    Ok(res)
+}
+
+// Обход M-записей объекта по правилам prepare_obj_group, без проверки прав
+fn collect_object_groups(
+    uri: &str,
+    access: u8,
+    level: u8,
+    cfg: &AzConfig,
+    db: &mut dyn Storage,
+    walked: &mut HashMap<String, u8>,
+    res: &mut Vec<ACLRecord>,
+) -> io::Result<()> {
+    if level > cfg.max_object_depth {
+        return Ok(());
+    }
+
+    let mut groups = ACLRecordVec::new();
+    if let Some(groups_str) = read_continued(&(MEMBERSHIP_PREFIX.to_owned() + uri), db)? {
+        decode_memberships(&groups_str, uri, db, cfg, &*cfg.clock, &mut groups)?;
+    }
+    if cfg.group_aliases {
+        get_aliases(uri, db, &mut groups)?;
+    }
+
+    for group in groups {
+        if group.id.is_empty() || !cfg.is_in_scope(&group.id) || group.marker == M_IS_EXCLUSIVE {
+            continue;
+        }
+
+        let new_access = accumulate_access(group.access, access, cfg.access_accumulation);
+        let prev_access = walked.get(&group.id).copied().unwrap_or(0);
+        if walked.contains_key(&group.id) && (prev_access & new_access) == new_access {
+            continue;
+        }
+        walked.insert(group.id.clone(), new_access | prev_access);
+
+        if uri == group.id {
+            on_self_reference(cfg, uri)?;
+            continue;
+        }
+
+        match res.iter_mut().find(|gr| gr.id == group.id) {
+            Some(gr) => {
+                gr.access |= new_access;
+                gr.level = gr.level.min(level);
+            },
+            None => {
+                let mut gr = ACLRecord::new_with_access(&group.id, new_access);
+                gr.level = level;
+                res.push(gr);
+            },
+        }
+
+        if let Some(next_level) = level.checked_add(1) {
+            collect_object_groups(&group.id, new_access, next_level, cfg, db, walked, res)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Группы субъекта без проверки доступа к объекту
+pub(crate) fn resolve_subject_groups(user_id: &str, db: &mut dyn Storage, cfg: &AzConfig) -> io::Result<HashMap<String, ACLRecord>> {
+    resolve_subject(user_id, db, cfg).map(|(groups, _)| groups)
+}
+
+// Группы субъекта и те из них, что требуют исключительного доступа
+pub(crate) fn resolve_subject(user_id: &str, db: &mut dyn Storage, cfg: &AzConfig) -> io::Result<(HashMap<String, ACLRecord>, Vec<String>)> {
+    let mut buf = TraceBuffers::default();
+    let mut trace = buf.trace(false, false, false);
+
+    let mut s_groups = HashMap::new();
+
+    let mut azc = AzContext {
+        id: "",
+        user_id,
+        request_access: 15,
+        calc_right_res: 0,
+        calc_deny_res: 0,
+        granted_via: Vec::new(),
+        matched: Vec::new(),
+        first_pass_res: 0,
+        filter_pass_res: None,
+        pending: Vec::new(),
+        exclusive_groups: Vec::new(),
+        individual_hops: Vec::new(),
+        is_need_exclusive_az: false,
+        is_found_exclusive_az: false,
+        max_object_level: 0,
+        walked_groups_s: &mut HashMap::new(),
+        tree_groups_s: &mut HashMap::new(),
+        walked_groups_o: &mut HashMap::new(),
+        tree_groups_o: &mut HashMap::new(),
+        subject_groups: &mut HashMap::new(),
+        checked_groups: &mut HashMap::new(),
+        filter_value: String::default(),
+        cfg,
+        clock: &*cfg.clock,
+        hooks: &AzHooks::default(),
+    };
+
+    get_resource_groups(&mut azc, &mut trace, user_id, 15, &mut s_groups, 0, db, false)?;
+    s_groups.insert(user_id.to_string(), ACLRecord::new(user_id));
+
+    Ok((s_groups, azc.exclusive_groups))
+}
+
+// Ограничение числа групп субъекта, сам пользователь сохраняется всегда
+fn apply_subject_group_cap(user_id: &str, request_access: u8, groups: &mut HashMap<String, ACLRecord>, cfg: &AzConfig) -> io::Result<bool> {
+    let Some(cap) = cfg.max_subject_groups else {
+        return Ok(false);
+    };
+    if groups.len() <= cap {
+        return Ok(false);
+    }
+
+    let mut order: Vec<(String, u8, u32)> =
+        groups.values().filter(|gr| gr.id != user_id).map(|gr| (gr.id.clone(), gr.level, (gr.access & request_access).count_ones())).collect();
+
+    match cfg.subject_overflow {
+        SubjectOverflowStrategy::Error => {
+            return Err(io::Error::other(format!("user {} has {} subject groups, limit is {}", user_id, groups.len(), cap)));
+        },
+        SubjectOverflowStrategy::TruncateByLevel => order.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0))),
+        SubjectOverflowStrategy::TruncateByAccess => order.sort_by(|a, b| b.2.cmp(&a.2).then(a.1.cmp(&b.1)).then_with(|| a.0.cmp(&b.0))),
+    }
+
+    for (id, _, _) in order.into_iter().skip(cap.saturating_sub(1)) {
+        groups.remove(&id);
+    }
+
+    Ok(true)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn authorize_impl(
+    id: &str,
+    user_id: &str,
+    request_access: u8,
+    db: &mut dyn Storage,
+    trace: &mut Trace,
+    cfg: &AzConfig,
+    hooks: &AzHooks,
+    decision: &mut Decision,
+) -> io::Result<u8> {
+    // Пустые идентификаторы не доходят до составления ключей хранилища
+    if id.trim().is_empty() || user_id.trim().is_empty() {
+        if trace.is_info {
+            print_step(hooks, trace, format!("blank id: uri=[{}], user=[{}]\n", id, user_id));
+        }
+        return match cfg.blank_id_policy {
+            BlankIdPolicy::Deny => Ok(0),
+            BlankIdPolicy::Error => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("blank id: uri=[{}], user=[{}]", id, user_id))),
+        };
+    }
+
+    // Дальше обход работает только с идентификаторами в форме схемы ключей
+    let (id, user_id) = (cfg.key_schema.encode_id(id), cfg.key_schema.encode_id(user_id));
+    let (id, user_id) = (id.as_ref(), user_id.as_ref());
+
     let s_groups = &mut HashMap::new();
 
+    // Внешний идентификатор (email, логин) заменяется каноническими uri
+    let mut principals = Vec::new();
+    if let Some(resolver) = hooks.principals {
+        resolver.resolve(user_id, db, &mut principals);
+    }
+    if principals.is_empty() {
+        principals.push(user_id.to_owned());
+    } else if trace.is_info {
+        print_step(hooks, trace, format!("user {} resolved to {}\n", user_id, principals.join(", ")));
+    }
+    let user_id = principals[0].as_str();
+
     let mut azc = AzContext {
         id,
         user_id,
         request_access,
         calc_right_res: 0,
+        calc_deny_res: 0,
+        granted_via: Vec::new(),
+        matched: Vec::new(),
+        first_pass_res: 0,
+        filter_pass_res: None,
+        pending: Vec::new(),
+        exclusive_groups: Vec::new(),
+        individual_hops: Vec::new(),
         is_need_exclusive_az: false,
         is_found_exclusive_az: false,
+        max_object_level: 0,
         walked_groups_s: &mut HashMap::new(),
         tree_groups_s: &mut HashMap::new(),
         walked_groups_o: &mut HashMap::new(),
@@ -108,25 +712,214 @@ pub fn authorize(id: &str, user_id: &str, request_access: u8, db: &mut dyn Stora
         subject_groups: &mut HashMap::new(),
         checked_groups: &mut HashMap::new(),
         filter_value: String::default(),
+        cfg,
+        clock: &*cfg.clock,
+        hooks,
     };
 
     // читаем группы subject (ticket.user_uri)
     if trace.is_info {
-        print_to_trace_info(trace, format!("authorize uri={}, user={}, request_access={}\n", id, user_id, access_to_pretty_string(request_access)));
+        print_step(hooks, trace, format!("authorize uri={}, user={}, request_access={}\n", id, user_id, access_to_pretty_string(request_access)));
     }
 
-    get_resource_groups(&mut azc, trace, user_id, 15, s_groups, 0, db, false)?;
+    if !cfg.is_in_scope(id) || !cfg.is_in_scope(user_id) {
+        if trace.is_info {
+            print_step(hooks, trace, format!("out of scope: uri={}, user={}\n", id, user_id));
+        }
+        return Ok(0);
+    }
+
+    // Группы субъекта запоминаются в пределах области запроса, если вызов не трассируется
+    let memo = hooks.subject_memo.filter(|_| !trace.is_info && !trace.is_group && !trace.is_acl);
+    let memoized = memo.and_then(|m| m.borrow().get(&principals).cloned());
+    let mut principal_of = match memoized {
+        Some(m) => {
+            *s_groups = m.groups;
+            azc.is_need_exclusive_az = m.is_need_exclusive_az;
+            azc.exclusive_groups = m.exclusive_groups;
+            azc.individual_hops = m.individual_hops;
+            m.principal_of
+        },
+        None => {
+            let principal_of = collect_subject_groups(&mut azc, trace, &principals, s_groups, db)?;
+            if let Some(memo) = memo {
+                memo.borrow_mut().insert(
+                    principals.clone(),
+                    SubjectMemo {
+                        groups: s_groups.clone(),
+                        is_need_exclusive_az: azc.is_need_exclusive_az,
+                        exclusive_groups: azc.exclusive_groups.clone(),
+                        individual_hops: azc.individual_hops.clone(),
+                        principal_of: principal_of.clone(),
+                    },
+                );
+            }
+            principal_of
+        },
+    };
 
     db.fiber_yield();
 
     azc.subject_groups = s_groups;
-    azc.subject_groups.insert(user_id.to_string(), ACLRecord::new(user_id));
+    for principal in principals.iter().filter(|p| cfg.is_in_scope(p)) {
+        azc.subject_groups.insert(principal.to_string(), ACLRecord::new(principal));
+    }
+
+    // Без учета регистра группы субъекта ищутся по идентификаторам в нижнем регистре
+    if cfg.case_insensitive_ids {
+        fold_subject_case(azc.subject_groups, cfg);
+        principal_of = principal_of.into_iter().map(|(gr, idx)| (cfg.subject_key(&gr).into_owned(), idx)).collect();
+    }
+
+    // Суперпользователь получает всё запрошенное в пределах потолка, без обхода групп объекта
+    if let Some(su) = cfg.superusers.iter().find(|su| azc.subject_groups.contains_key(cfg.subject_key(su).as_ref())) {
+        let res = request_access & cfg.capability_ceiling;
+        if trace.is_info {
+            print_step(hooks, trace, format!("superuser access via {}: uri={}, user={}, access={}\n", su, id, user_id, access_to_pretty_string(res)));
+        }
+        decision.superuser = Some(su.clone());
+        decision.exclusive_required = azc.is_need_exclusive_az;
+        decision.exclusive_satisfied = true;
+        decision.first_pass = res;
+        return Ok(res);
+    }
+
+    if apply_subject_group_cap(user_id, request_access, azc.subject_groups, cfg)? {
+        decision.subject_groups_truncated = true;
+        if trace.is_info {
+            print_step(hooks, trace, format!("subject groups truncated to {}\n", azc.subject_groups.len()));
+        }
+    }
+
+    // Быстрый отказ: ни одна группа субъекта не упоминается в правах на группы объекта
+    if cfg.reachability_precheck && !cfg.pattern_grants && !cfg.case_insensitive_ids && !cfg.group_aliases && hooks.implicit_groups.is_none() {
+        if let Some(bloom) = get_fresh_reachability(id, cfg.reachability_min_epoch, db)? {
+            if !azc.subject_groups.keys().any(|gr| bloom.may_contain(gr)) {
+                if trace.is_info {
+                    print_step(hooks, trace, format!("no reachable permissions: uri={}, user={}\n", id, user_id));
+                }
+                return Ok(0);
+            }
+        }
+    }
+
+    let res = authorize_object(&mut azc, id, request_access, db, trace);
+    decision.denied = azc.calc_deny_res;
+    decision.granted_via = std::mem::take(&mut azc.granted_via);
+    decision.matched_permissions = std::mem::take(&mut azc.matched);
+    decision.exclusive_required = azc.is_need_exclusive_az;
+    decision.exclusive_satisfied = !azc.is_need_exclusive_az || azc.is_found_exclusive_az;
+    if principals.len() > 1 {
+        decision.principals = principals.iter().map(|p| (p.clone(), 0)).collect();
+        for (gr, bits) in &decision.granted_via {
+            if let Some(idx) = principal_of.get(cfg.subject_key(gr).as_ref()) {
+                decision.principals[*idx].1 |= bits;
+            }
+        }
+    }
+    decision.max_subject_depth = azc.subject_groups.iter().map(|(gr, rec)| subject_depth(gr, rec, &principals, cfg)).max().unwrap_or(0);
+    decision.max_object_depth = azc.max_object_level;
+    decision.group_depths = decision
+        .granted_via
+        .iter()
+        .filter_map(|(gr, _)| azc.subject_groups.get(cfg.subject_key(gr).as_ref()).map(|rec| (gr.clone(), subject_depth(&rec.id, rec, &principals, cfg))))
+        .collect();
+    decision.individual_hops = std::mem::take(&mut azc.individual_hops);
+    decision.pending = std::mem::take(&mut azc.pending);
+    decision.first_pass = azc.first_pass_res;
+    decision.filter_pass = azc.filter_pass_res;
+    if !azc.filter_value.is_empty() {
+        decision.filter = Some(azc.filter_value.clone());
+    }
+
+    res
+}
+
+// Группы, различающиеся только регистром, сливаются в одну
+pub(crate) fn fold_subject_case(subject_groups: &mut HashMap<String, ACLRecord>, cfg: &AzConfig) {
+    let mut folded: HashMap<String, ACLRecord> = HashMap::with_capacity(subject_groups.len());
+    for (_, mut rec) in subject_groups.drain() {
+        rec.id = cfg.subject_key(&rec.id).into_owned();
+        match folded.get_mut(&rec.id) {
+            Some(cur) => {
+                cur.access |= rec.access;
+                cur.marker = merge_marker(cur.marker, rec.marker, cfg.marker_precedence);
+                cur.level = cur.level.min(rec.level);
+            },
+            None => {
+                folded.insert(rec.id.clone(), rec);
+            },
+        }
+    }
+    *subject_groups = folded;
+}
+
+// Число ребер членства от пользователя до группы; сам пользователь и его принципалы на глубине 0
+fn subject_depth(gr: &str, rec: &ACLRecord, principals: &[String], cfg: &AzConfig) -> u8 {
+    if principals.iter().any(|p| cfg.subject_key(p) == cfg.subject_key(gr)) {
+        0
+    } else {
+        rec.level.saturating_add(1)
+    }
+}
+
+// Строка трассировки info, она же шаг структурированной трассировки
+pub(crate) fn print_step(hooks: &AzHooks, trace: &mut Trace, text: String) {
+    if let Some(info) = hooks.trace_info {
+        info.borrow_mut().start_step(text.trim_end());
+    }
+    print_to_trace_info(trace, text);
+}
+
+pub(crate) fn print_group(hooks: &AzHooks, trace: &mut Trace, group: &str) {
+    if let Some(info) = hooks.trace_info {
+        info.borrow_mut().add_group(group);
+    }
+    print_to_trace_group(trace, format!("{}\n", group));
+}
+
+pub(crate) fn print_acl(hooks: &AzHooks, trace: &mut Trace, object_group: &str, subject_group: &str, predicate: &str) {
+    if let Some(info) = hooks.trace_info {
+        info.borrow_mut().add_permission(AclTraceEntry {
+            object_group: object_group.to_owned(),
+            subject_group: subject_group.to_owned(),
+            predicate: predicate.to_owned(),
+            is_deny: ACCESS_8_PREDICATE_LIST[4..].contains(&predicate),
+        });
+    }
+    print_to_trace_acl(trace, format!("{};{};{}\n", object_group, subject_group, predicate));
+}
+
+// Группа субъекта относится к первому принципалу, через которого она найдена
+fn collect_subject_groups(
+    azc: &mut AzContext,
+    trace: &mut Trace,
+    principals: &[String],
+    s_groups: &mut HashMap<String, ACLRecord>,
+    db: &mut dyn Storage,
+) -> io::Result<HashMap<String, usize>> {
+    let mut principal_of = HashMap::new();
+    for (idx, principal) in principals.iter().enumerate().filter(|(_, p)| azc.cfg.is_in_scope(p)) {
+        get_resource_groups(azc, trace, principal, 15, s_groups, 0, db, false)?;
+        if principals.len() > 1 {
+            principal_of.entry(principal.clone()).or_insert(idx);
+            for gr in s_groups.keys() {
+                if !principal_of.contains_key(gr) {
+                    principal_of.insert(gr.clone(), idx);
+                }
+            }
+        }
+    }
+    Ok(principal_of)
+}
 
-    let first_level_object_groups: &mut Vec<ACLRecord> = &mut Vec::new();
+// Обход групп объекта для уже вычисленных групп субъекта
+fn authorize_object(azc: &mut AzContext, id: &str, request_access: u8, db: &mut dyn Storage, trace: &mut Trace) -> io::Result<u8> {
+    let first_level_object_groups = &mut ACLRecordVec::new();
     first_level_object_groups.push(ACLRecord::new(id));
-    match db.get(&(MEMBERSHIP_PREFIX.to_owned() + id)) {
+    match read_continued(&(MEMBERSHIP_PREFIX.to_owned() + id), db) {
         Ok(Some(groups_str)) => {
-            db.decode_rec_to_rights(&groups_str, first_level_object_groups);
+            decode_memberships(&groups_str, id, db, azc.cfg, azc.clock, first_level_object_groups)?;
         },
         Err(_e) => {},
         _ => {},
@@ -148,7 +941,9 @@ pub fn authorize(id: &str, user_id: &str, request_access: u8, db: &mut dyn Stora
         }
     }
 
-    if let Some(r) = authorize_obj_groups(id, request_access_with_filter, db, trace, &mut azc) {
+    let res = authorize_obj_groups(id, request_access_with_filter, db, trace, azc);
+    azc.first_pass_res = azc.calc_right_res;
+    if let Some(r) = res {
         return r;
     }
 
@@ -158,20 +953,39 @@ pub fn authorize(id: &str, user_id: &str, request_access: u8, db: &mut dyn Stora
         azc.checked_groups.clear();
         azc.walked_groups_o.clear();
 
-        if let Some(r) = authorize_obj_groups(id, request_access, db, trace, &mut azc) {
+        // Второй проход по правам, выданным с фильтром
+        let res = authorize_obj_groups(id, request_access, db, trace, azc);
+        azc.filter_pass_res = Some(azc.calc_right_res & !azc.first_pass_res);
+        if let Some(r) = res {
             return r;
         }
     }
 
-    if final_check(&mut azc, trace) {
+    if final_check(azc, trace) {
         Ok(azc.calc_right_res)
     } else {
+        // Отказ из-за исключительных групп без трассировки иначе ничем не объяснить
+        if let (true, false, Some(sink)) = (azc.cfg.report_exclusive_denials, trace.is_info, azc.hooks.warnings) {
+            if azc.calc_right_res & request_access != 0 {
+                sink.on_warning(&AzWarning::ExclusiveDenied {
+                    id: azc.id,
+                    user_id: azc.user_id,
+                    found: azc.calc_right_res & request_access,
+                    exclusive_groups: &azc.exclusive_groups,
+                });
+            }
+        }
+
         if trace.is_acl {
             trace.acl.clear();
         }
+        if let Some(info) = azc.hooks.trace_info {
+            info.borrow_mut().permissions.clear();
+        }
 
         if trace.is_info {
-            print_to_trace_info(
+            print_step(
+                azc.hooks,
                 trace,
                 format!(
                     "result: uri={}, user={}, request={}, answer={}\n\n",
@@ -1,8 +1,11 @@
+use crate::aggregate::{get_fresh_aggregate, permission_allow_bits};
 use crate::common::{
-    access_to_pretty_string, get_path, print_to_trace_acl, print_to_trace_group, print_to_trace_info, Storage, Trace, ACCESS_8_LIST, ACCESS_PREDICATE_LIST,
-    PERMISSION_PREFIX,
+    access_predicate, access_to_pretty_string, decode_in_force, get_path, Storage, Trace, ACCESS_8_FULL_LIST, ACCESS_8_LIST, COSIGN_PREFIX, PERMISSION_PREFIX,
 };
-use crate::{ACLRecord, AzContext};
+use crate::decision::MatchedPermission;
+use crate::patterns::find_pattern_subject;
+use crate::record_formats::{merge_duplicates, read_continued, DuplicateEntries, Validity};
+use crate::{print_acl, print_group, print_step, ACLRecordSet, ACLRecordVec, AzContext, GrantProvenance};
 use std::io;
 
 pub(crate) fn authorize_obj_group(
@@ -17,8 +20,17 @@ pub(crate) fn authorize_obj_group(
     let mut is_authorized = false;
     let mut calc_bits;
 
+    // Группы вне области видимости считаются отсутствующими
+    if !azc.cfg.is_in_scope(object_group_id) {
+        return Ok(is_authorized);
+    }
+
+    if let Some(hits) = azc.hooks.object_group_hits {
+        hits.visit(object_group_id);
+    }
+
     // Проверяем, необходимо ли дальнейшее рассмотрение доступа
-    if !trace.is_info && !trace.is_group && !trace.is_acl {
+    if azc.may_stop_early(trace) {
         // Расчет оставшихся прав на доступ для проверки
         let left_to_check = (azc.calc_right_res ^ request_access) & request_access;
 
@@ -42,39 +54,162 @@ pub(crate) fn authorize_obj_group(
 
     // Вывод информации о группе, если включена соответствующая трассировка
     if trace.is_group {
-        print_to_trace_group(trace, format!("{}\n", object_group_id));
+        print_group(azc.hooks, trace, object_group_id);
     }
 
     // Формирование ключа для получения данных ACL
-    let acl_key = if !azc.filter_value.is_empty() {
-        PERMISSION_PREFIX.to_owned() + &azc.filter_value + object_group_id
+    let acl_key_suffix = azc.cfg.key_schema.permission_suffix(&azc.filter_value, object_group_id);
+    let acl_key = PERMISSION_PREFIX.to_owned() + &acl_key_suffix;
+
+    // Права на чувствительные группы действуют только в пределах подписанного вторым лицом
+    let cosigned = if azc.cfg.sensitive_groups.iter().any(|gr| gr == object_group_id) {
+        let mut cosigned = ACLRecordVec::new();
+        if let Some(src) = db.get(&(COSIGN_PREFIX.to_owned() + &acl_key_suffix))? {
+            decode_in_force(&src, db, azc.clock, &mut cosigned);
+        }
+        Some(cosigned)
     } else {
-        PERMISSION_PREFIX.to_owned() + object_group_id
+        None
+    };
+
+    // Предагрегированные права: одна маска на группу субъекта, без трассировки
+    if cosigned.is_none()
+        && azc.cfg.use_permission_aggregates
+        && azc.cfg.duplicate_entries == DuplicateEntries::Or
+        && !azc.cfg.pattern_grants
+        && !trace.is_info
+        && !trace.is_group
+        && !trace.is_acl
+    {
+        if let Some(entries) = get_fresh_aggregate(&acl_key_suffix, azc.cfg.aggregate_min_epoch, db)? {
+            for (subj_id, permission_access, deny_access) in entries {
+                if let Some(subj_gr) = azc.subject_groups.get(azc.cfg.subject_key(&subj_id).as_ref()) {
+                    let calc_bits = request_access & object_group_access & subj_gr.access & permission_access & 0x0F;
+                    azc.calc_right_res |= calc_bits;
+                    note_grant(&mut azc.granted_via, &subj_id, calc_bits);
+                    let deny_bits = deny_access & ((request_access & object_group_access & subj_gr.access & 0x0F) << 4);
+                    azc.calc_deny_res |= deny_bits;
+                    note_match(azc, object_group_id, &subj_id, None, calc_bits, deny_bits);
+
+                    if (azc.calc_right_res & request_access) == request_access && !azc.cfg.deny_override {
+                        return Ok(true);
+                    }
+                }
+            }
+            return Ok(false);
+        }
+    }
+
+    // Права, выданные на шаблоны uri, покрывающие группу
+    let mut pattern_permissions = ACLRecordVec::new();
+    if azc.cfg.pattern_grants && azc.filter_value.is_empty() {
+        for (_, val) in db.get_pattern_permissions(object_group_id)? {
+            decode_in_force(&val, db, azc.clock, &mut pattern_permissions);
+        }
+    }
+
+    let acl = match read_continued(&acl_key, db) {
+        Ok(None) if !pattern_permissions.is_empty() => Ok(Some(String::new())),
+        res => res,
     };
 
     // Попытка получения данных об ACL из базы данных
-    match db.get(&acl_key) {
+    match acl {
         Ok(Some(str)) => {
-            let permissions: &mut Vec<ACLRecord> = &mut Vec::new();
+            let permissions = &mut ACLRecordVec::new();
 
-            // Декодирование прав доступа из полученной строки
-            db.decode_rec_to_rights(&str, permissions);
+            // Декодирование прав доступа из полученной строки; большую запись выгоднее искать по группам субъекта
+            let mut per_entry = !str.is_empty();
+            let is_large = azc.cfg.rightset_min_len.is_some_and(|min| str.len() >= min);
+            if is_large && azc.cfg.duplicate_entries == DuplicateEntries::Or && !azc.cfg.pattern_grants && !azc.cfg.case_insensitive_ids {
+                let rightset = &mut ACLRecordSet::new();
+                let validity = Validity {
+                    valid_until: db.decode_rec_to_rightset(&str, rightset).1,
+                    valid_from: db.decode_valid_from(&str),
+                };
+                if !validity.is_in_force(azc.clock.now()) {
+                    rightset.clear();
+                }
+                rightset.retain(|_, rec| !rec.is_deleted);
+                let mut found: Vec<&str> = azc.subject_groups.keys().filter(|gr| rightset.contains_key(*gr)).map(|gr| gr.as_str()).collect();
+
+                // Разрешение и запрет одного права из разных записей субъекта объединенная маска не передает,
+                // такая запись разбирается по записям
+                if found.iter().all(|gr| !has_allow_and_deny(rightset[*gr].access)) {
+                    found.sort_unstable();
+                    for gr in found {
+                        if let Some(permission) = rightset.remove(gr) {
+                            permissions.push(permission);
+                        }
+                    }
+                    per_entry = false;
+                }
+            }
+            if per_entry {
+                let validity = decode_in_force(&str, db, azc.clock, permissions);
+                if trace.is_info && permissions.is_empty() {
+                    if let Some(t) = validity.valid_from.filter(|t| *t > azc.clock.now()) {
+                        print_step(azc.hooks, trace, format!("permissions O:[{}] not active before {}\n", object_group_id, t.to_rfc3339()));
+                    } else if let Some(t) = validity.valid_until {
+                        print_step(azc.hooks, trace, format!("permissions O:[{}] expired at {}\n", object_group_id, t.to_rfc3339()));
+                    }
+                }
+                if azc.cfg.duplicate_entries != DuplicateEntries::Or {
+                    merge_duplicates(permissions, azc.cfg.duplicate_entries);
+                }
+            }
+            permissions.extend(pattern_permissions);
 
             // Перебор полученных прав доступа
             for permission in permissions {
                 // Поиск субъекта среди известных прав доступа
                 let subj_id = &permission.id;
-                if let Some(subj_gr) = azc.subject_groups.get(subj_id) {
+                let subj_gr = match azc.subject_groups.get(azc.cfg.subject_key(subj_id).as_ref()) {
+                    None if azc.cfg.pattern_grants => find_pattern_subject(azc.subject_groups, subj_id),
+                    gr => gr,
+                };
+                if let Some(subj_gr) = subj_gr {
                     // Сравнение доступа объекта и субъекта с учетом ограничений
                     let obj_restriction_access = object_group_access;
                     let subj_restriction_access = subj_gr.access;
 
                     // Расчет реального доступа на основе данных правила
-                    let permission_access = if permission.access > 15 {
-                        (((permission.access & 0xF0) >> 4) ^ 0x0F) & permission.access
-                    } else {
-                        permission.access
-                    };
+                    let mut permission_access = permission_allow_bits(permission.access);
+
+                    if let Some(cosigned) = &cosigned {
+                        let subj_key = azc.cfg.subject_key(subj_id);
+                        let signed = cosigned.iter().filter(|c| azc.cfg.subject_key(&c.id) == subj_key).fold(0, |acc, c| acc | c.access);
+                        let pending = permission_access & !signed & request_access & obj_restriction_access & subj_restriction_access;
+                        if pending != 0 {
+                            if trace.is_info {
+                                print_step(
+                                    azc.hooks,
+                                    trace,
+                                    format!(
+                                        "pending permission S:[{}], O:[{}], access={}, not co-signed\n",
+                                        subj_id,
+                                        object_group_id,
+                                        access_to_pretty_string(pending)
+                                    ),
+                                );
+                            }
+                            azc.pending.push((object_group_id.to_owned(), subj_id.to_owned(), pending));
+                        }
+                        permission_access &= signed;
+                    }
+
+                    // Явные запреты в пределах запрошенного доступа
+                    let deny_bits = permission.access & ((request_access & obj_restriction_access & subj_restriction_access & 0x0F) << 4);
+                    azc.calc_deny_res |= deny_bits;
+                    note_match(azc, object_group_id, subj_id, permission.provenance.as_deref(), 0, deny_bits);
+
+                    if trace.is_acl && deny_bits != 0 {
+                        for bit in ACCESS_8_FULL_LIST[4..].iter().filter(|b| deny_bits & **b != 0) {
+                            if let Some(predicate) = access_predicate(*bit) {
+                                print_acl(azc.hooks, trace, object_group_id, subj_id, predicate);
+                            }
+                        }
+                    }
 
                     // Перебор стандартного набора прав доступа
                     for i_access in ACCESS_8_LIST.iter() {
@@ -88,14 +223,13 @@ pub(crate) fn authorize_obj_group(
                                 let prev_res = azc.calc_right_res;
 
                                 azc.calc_right_res |= calc_bits;
+                                note_grant(&mut azc.granted_via, subj_id, calc_bits);
+                                note_match(azc, object_group_id, subj_id, permission.provenance.as_deref(), calc_bits, 0);
 
                                 // Если достигнут полный запрашиваемый доступ, завершаем проверку
-                                if (azc.calc_right_res & request_access) == request_access {
-                                    if trace.is_info {
-                                    } else if !trace.is_group && !trace.is_acl {
-                                        is_authorized = true;
-                                        return Ok(is_authorized);
-                                    }
+                                if (azc.calc_right_res & request_access) == request_access && azc.may_stop_early(trace) {
+                                    is_authorized = true;
+                                    return Ok(is_authorized);
                                 }
 
                                 // Регистрация информации о найденных правах в трассировку
@@ -107,7 +241,8 @@ pub(crate) fn authorize_obj_group(
                                         "".to_owned()
                                     };
 
-                                    print_to_trace_info(
+                                    print_step(
+                                        azc.hooks,
                                         trace,
                                         format!(
                                             "found permission S:[{}], O:[{}], access={} {}\n",
@@ -118,7 +253,8 @@ pub(crate) fn authorize_obj_group(
                                         ),
                                     );
 
-                                    print_to_trace_info(
+                                    print_step(
+                                        azc.hooks,
                                         trace,
                                         format!(
                                             "access: request={}, calc={}, total={}\n",
@@ -129,13 +265,15 @@ pub(crate) fn authorize_obj_group(
                                     );
 
                                     // Вывод информации о пути доступа
-                                    print_to_trace_info(trace, "O-PATH".to_owned() + &get_path(azc.tree_groups_o, object_group_id.to_string()) + "\n");
-                                    print_to_trace_info(trace, "S-PATH".to_owned() + &get_path(azc.tree_groups_s, subj_id.to_string()) + "\n");
+                                    print_step(azc.hooks, trace, "O-PATH".to_owned() + &get_path(azc.tree_groups_o, object_group_id.to_string()) + "\n");
+                                    print_step(azc.hooks, trace, "S-PATH".to_owned() + &get_path(azc.tree_groups_s, subj_id.to_string()) + "\n");
                                 }
 
                                 // Регистрация информации о правах доступа в трассировку ACL
                                 if trace.is_acl {
-                                    print_to_trace_acl(trace, format!("{};{};{}\n", object_group_id, subj_id, ACCESS_PREDICATE_LIST[*i_access as usize]));
+                                    if let Some(predicate) = access_predicate(*i_access) {
+                                        print_acl(azc.hooks, trace, object_group_id, subj_id, predicate);
+                                    }
                                 }
                             }
                         }
@@ -150,12 +288,51 @@ pub(crate) fn authorize_obj_group(
         _ => {},
     }
 
-    if (azc.calc_right_res & request_access) == request_access {
-        if !trace.is_info && !trace.is_group && !trace.is_acl {
-            is_authorized = true;
-            return Ok(is_authorized);
-        }
+    if (azc.calc_right_res & request_access) == request_access && azc.may_stop_early(trace) {
+        is_authorized = true;
+        return Ok(is_authorized);
     }
 
     Ok(false)
 }
+
+// Запоминаем сработавшую запись прав; повторная встреча той же пары групп дополняет биты
+fn note_match(azc: &mut AzContext, object_group_id: &str, subj_id: &str, provenance: Option<&GrantProvenance>, granted: u8, denied: u8) {
+    if granted == 0 && denied == 0 {
+        return;
+    }
+    let filter = (!azc.filter_value.is_empty()).then(|| azc.filter_value.clone());
+    match azc.matched.iter_mut().find(|m| m.object_group == object_group_id && m.subject_group == subj_id && m.filter == filter) {
+        Some(m) => {
+            m.granted |= granted;
+            m.denied |= denied;
+            if m.provenance.is_none() {
+                m.provenance = provenance.cloned();
+            }
+        },
+        None => azc.matched.push(MatchedPermission {
+            object_group: object_group_id.to_owned(),
+            subject_group: subj_id.to_owned(),
+            filter,
+            granted,
+            denied,
+            provenance: provenance.cloned(),
+        }),
+    }
+}
+
+// Запоминаем, через какую группу субъекта получены права
+fn note_grant(granted_via: &mut Vec<(String, u8)>, subj_id: &str, bits: u8) {
+    if bits == 0 {
+        return;
+    }
+    match granted_via.iter_mut().find(|(id, _)| id == subj_id) {
+        Some((_, b)) => *b |= bits,
+        None => granted_via.push((subj_id.to_owned(), bits)),
+    }
+}
+
+// Право и запрет на него в одной маске
+fn has_allow_and_deny(access: u8) -> bool {
+    access & (access >> 4) & 0x0F != 0
+}
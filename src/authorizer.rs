@@ -0,0 +1,168 @@
+//! Long-lived authorizer over one storage, for services answering many requests.
+//!
+//! The subject groups of a user are collected once and reused for `ttl`, so a membership change reaches
+//! a cached user after at most that long. A store reporting `Storage::epoch` drops the cache whenever the
+//! epoch moves. Permissions and the groups of resources are read on every call.
+
+use crate::common::{Storage, TraceBuffers};
+use crate::config::AzConfig;
+use crate::decision::Decision;
+use crate::request_scope::SubjectMemoMap;
+use crate::{authorize_with_hooks, AzHooks};
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Users whose subject groups are kept, by default
+pub const DEFAULT_SUBJECT_CACHE_CAPACITY: usize = 10_000;
+
+pub struct Authorizer<S: Storage> {
+    storage: S,
+    cfg: AzConfig,
+    ttl: Option<Duration>,
+    capacity: usize,
+    subjects: RefCell<SubjectMemoMap>,
+    // ключи subjects в порядке добавления, они же истекают первыми
+    added: VecDeque<(Vec<String>, Instant)>,
+    epoch: Option<u64>,
+    buf: TraceBuffers,
+}
+
+pub struct AuthorizerBuilder<S: Storage> {
+    storage: Option<S>,
+    cfg: AzConfig,
+    ttl: Option<Duration>,
+    capacity: usize,
+}
+
+impl<S: Storage> Authorizer<S> {
+    pub fn builder() -> AuthorizerBuilder<S> {
+        AuthorizerBuilder {
+            storage: None,
+            cfg: AzConfig::default(),
+            ttl: None,
+            capacity: DEFAULT_SUBJECT_CACHE_CAPACITY,
+        }
+    }
+
+    /// Same as `authorize` without trace; the user's groups come from the cache while they are fresh
+    pub fn authorize(&mut self, id: &str, user_id: &str, request_access: u8) -> io::Result<u8> {
+        self.authorize_ex(id, user_id, request_access).map(|decision| decision.granted)
+    }
+
+    pub fn authorize_ex(&mut self, id: &str, user_id: &str, request_access: u8) -> io::Result<Decision> {
+        let mut decision = Decision::default();
+        self.evict();
+        let hooks = AzHooks {
+            subject_memo: self.ttl.is_some().then_some(&self.subjects),
+            ..AzHooks::default()
+        };
+        authorize_with_hooks(id, user_id, request_access, &mut self.storage, &mut self.buf.trace(false, false, false), &self.cfg, &hooks, &mut decision)?;
+        self.note_added();
+        Ok(decision)
+    }
+
+    pub fn config(&self) -> &AzConfig {
+        &self.cfg
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Writes through the returned reference reach cached users after the ttl, unless the store bumps its epoch
+    pub fn storage_mut(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    /// Users whose groups are cached
+    pub fn cached_users(&self) -> usize {
+        self.subjects.borrow().len()
+    }
+
+    pub fn clear_cache(&mut self) {
+        self.subjects.borrow_mut().clear();
+        self.added.clear();
+    }
+
+    // Устаревшие группы удаляются перед вызовом, смена эпохи хранилища сбрасывает все
+    fn evict(&mut self) {
+        let epoch = self.storage.epoch();
+        if epoch != self.epoch {
+            self.epoch = epoch;
+            self.clear_cache();
+            return;
+        }
+
+        let (Some(ttl), subjects) = (self.ttl, self.subjects.get_mut()) else {
+            return;
+        };
+        while let Some((key, at)) = self.added.front() {
+            if at.elapsed() < ttl && self.added.len() < self.capacity {
+                break;
+            }
+            subjects.remove(key);
+            self.added.pop_front();
+        }
+    }
+
+    // Новые ключи появляются только при промахе, тогда и ищутся
+    fn note_added(&mut self) {
+        let subjects = self.subjects.get_mut();
+        if subjects.len() == self.added.len() {
+            return;
+        }
+        let known: HashSet<&Vec<String>> = self.added.iter().map(|(key, _)| key).collect();
+        let new: Vec<Vec<String>> = subjects.keys().filter(|key| !known.contains(key)).cloned().collect();
+        let now = Instant::now();
+        self.added.extend(new.into_iter().map(|key| (key, now)));
+    }
+}
+
+impl<S: Storage> AuthorizerBuilder<S> {
+    pub fn storage(mut self, storage: S) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Settings other than the ones set on the builder; replaces what was set before
+    pub fn config(mut self, cfg: AzConfig) -> Self {
+        self.cfg = cfg;
+        self
+    }
+
+    /// Depth of both the subject and the object group traversal
+    pub fn max_depth(mut self, depth: u8) -> Self {
+        self.cfg.max_subject_depth = depth;
+        self.cfg.max_object_depth = depth;
+        self
+    }
+
+    /// Keeps the subject groups of up to `capacity` users for `ttl`; without it every call collects them
+    pub fn cache(mut self, ttl: Duration, capacity: usize) -> Self {
+        self.ttl = Some(ttl);
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    pub fn build(self) -> io::Result<Authorizer<S>> {
+        let Some(storage) = self.storage else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "authorizer without storage"));
+        };
+        Ok(Authorizer {
+            epoch: storage.epoch(),
+            storage,
+            cfg: self.cfg,
+            ttl: self.ttl,
+            capacity: self.capacity,
+            subjects: RefCell::default(),
+            added: VecDeque::new(),
+            buf: TraceBuffers::default(),
+        })
+    }
+}
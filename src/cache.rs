@@ -0,0 +1,252 @@
+//! Least-recently-used cache of M-, P- and F-records in front of a slow `Storage`.
+//!
+//! Absent records are cached as well. Writes made through the wrapper drop the keys they touch; writes made
+//! elsewhere are seen after `ttl`, after an explicit `invalidate_prefix`, or at once when the backend has an
+//! epoch (`Storage::epoch`), since a new epoch empties the cache.
+
+use crate::cache_metrics::{CacheIntrospection, CacheMetrics};
+use crate::common::{Storage, FILTER_PREFIX, MEMBERSHIP_PREFIX, PERMISSION_PREFIX};
+use crate::manage::MutableStorage;
+use crate::{ACLRecord, ACLRecordSet, ACLRecordVec};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Prefixes of the keys kept in the cache
+pub const CACHED_PREFIXES: [&str; 3] = [MEMBERSHIP_PREFIX, PERMISSION_PREFIX, FILTER_PREFIX];
+
+struct CacheEntry {
+    value: Option<String>,
+    stored_at: Instant,
+    last_used: u64,
+}
+
+pub struct CachedStorage<S: Storage> {
+    inner: S,
+    capacity: usize,
+    ttl: Option<Duration>,
+    entries: HashMap<String, CacheEntry>,
+    // порядок использования: счетчик обращения -> ключ
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    epoch: Option<u64>,
+    metrics: CacheMetrics,
+}
+
+impl<S: Storage> CachedStorage<S> {
+    /// Keeps up to `capacity` records, each for at most `ttl` when it is set
+    pub fn new(inner: S, capacity: usize, ttl: Option<Duration>) -> Self {
+        CachedStorage {
+            inner,
+            capacity: capacity.max(1),
+            ttl,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            epoch: None,
+            metrics: CacheMetrics::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Writes through the returned reference bypass invalidation
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    pub fn metrics(&self) -> &CacheMetrics {
+        &self.metrics
+    }
+
+    pub fn invalidate(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+        }
+    }
+
+    /// Drops every cached key starting with `prefix`; an empty prefix empties the cache
+    pub fn invalidate_prefix(&mut self, prefix: &str) {
+        let lru = &mut self.lru;
+        self.entries.retain(|key, entry| {
+            let keep = !key.starts_with(prefix);
+            if !keep {
+                lru.remove(&entry.last_used);
+            }
+            keep
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+    }
+
+    fn is_cached_key(key: &str) -> bool {
+        CACHED_PREFIXES.iter().any(|p| key.starts_with(p))
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    // Новая эпоха хранилища делает устаревшими все записи
+    fn check_epoch(&mut self) {
+        let epoch = self.inner.epoch();
+        if epoch != self.epoch {
+            if !self.entries.is_empty() {
+                self.metrics.on_eviction(self.entries.len() as u64);
+                self.clear();
+            }
+            self.epoch = epoch;
+        }
+    }
+
+    fn lookup(&mut self, key: &str) -> Option<Option<String>> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(key)?;
+        if self.ttl.is_some_and(|ttl| entry.stored_at.elapsed() >= ttl) {
+            self.invalidate(key);
+            self.metrics.on_eviction(1);
+            return None;
+        }
+
+        self.lru.remove(&entry.last_used);
+        entry.last_used = tick;
+        self.lru.insert(tick, key.to_owned());
+        Some(entry.value.clone())
+    }
+
+    fn store(&mut self, key: &str, value: Option<String>) {
+        self.invalidate(key);
+        while self.entries.len() >= self.capacity {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+            self.metrics.on_eviction(1);
+        }
+
+        let tick = self.next_tick();
+        self.lru.insert(tick, key.to_owned());
+        self.entries.insert(
+            key.to_owned(),
+            CacheEntry {
+                value,
+                stored_at: Instant::now(),
+                last_used: tick,
+            },
+        );
+    }
+}
+
+impl<S: Storage> Storage for CachedStorage<S> {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        if !Self::is_cached_key(key) {
+            return self.inner.get(key);
+        }
+
+        self.check_epoch();
+        if let Some(value) = self.lookup(key) {
+            self.metrics.on_hit(key);
+            return Ok(value);
+        }
+
+        self.metrics.on_miss();
+        let value = self.inner.get(key)?;
+        self.store(key, value.clone());
+        Ok(value)
+    }
+
+    fn fiber_yield(&self) {
+        self.inner.fiber_yield()
+    }
+
+    fn decode_rec_to_rights(&self, src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+        self.inner.decode_rec_to_rights(src, result)
+    }
+
+    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+        self.inner.decode_rec_to_rightset(src, new_rights)
+    }
+
+    fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+        self.inner.decode_filter(filter_value)
+    }
+
+    fn decode_valid_from(&self, src: &str) -> Option<DateTime<Utc>> {
+        self.inner.decode_valid_from(src)
+    }
+
+    fn epoch(&self) -> Option<u64> {
+        self.inner.epoch()
+    }
+
+    fn get_uncached(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.inner.get_uncached(key)
+    }
+}
+
+impl<S: MutableStorage> MutableStorage for CachedStorage<S> {
+    fn put(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.invalidate(key);
+        self.inner.put(key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        self.invalidate(key);
+        self.inner.remove(key)
+    }
+
+    fn apply_batch(&mut self, batch: &[(String, Option<String>)]) -> io::Result<()> {
+        for (key, _) in batch {
+            self.invalidate(key);
+        }
+        self.inner.apply_batch(batch)
+    }
+
+    fn max_value_len(&self) -> Option<usize> {
+        self.inner.max_value_len()
+    }
+
+    fn emit_invalidation(&mut self, keys: &[String]) {
+        for key in keys {
+            self.invalidate(key);
+        }
+        self.inner.emit_invalidation(keys)
+    }
+
+    fn scan_prefix(&mut self, prefix: &str) -> io::Result<Vec<(String, String)>> {
+        self.inner.scan_prefix(prefix)
+    }
+
+    fn keys_referencing(&mut self, subject_id: &str) -> io::Result<Vec<String>> {
+        self.inner.keys_referencing(subject_id)
+    }
+}
+
+impl<S: Storage> CacheIntrospection for CachedStorage<S> {
+    fn entries(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn hit_rate(&self) -> f64 {
+        self.metrics.hit_rate()
+    }
+
+    fn hot_keys(&self, n: usize) -> Vec<(String, u64)> {
+        self.metrics.hot_keys(n)
+    }
+
+    fn evictions(&self) -> u64 {
+        self.metrics.evictions()
+    }
+}
@@ -0,0 +1,113 @@
+//! Introspection of caching layers over `Storage`.
+//!
+//! Caches embed a `CacheMetrics`, count their hits, misses and evictions through it and expose the
+//! result with `CacheIntrospection`; `CacheStatsSnapshot` is the plain form for reports.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Distinct keys tracked for `hot_keys`; hits on keys beyond it are only counted in the totals
+const MAX_TRACKED_KEYS: usize = 4096;
+
+pub trait CacheIntrospection {
+    fn entries(&self) -> usize;
+    fn hit_rate(&self) -> f64;
+    /// The `n` keys with the most hits, most hit first
+    fn hot_keys(&self, n: usize) -> Vec<(String, u64)>;
+    fn evictions(&self) -> u64;
+
+    fn stats(&self) -> CacheStatsSnapshot {
+        CacheStatsSnapshot {
+            entries: self.entries(),
+            hit_rate: self.hit_rate(),
+            evictions: self.evictions(),
+            hot_keys: self.hot_keys(10),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct CacheMetrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    key_hits: Mutex<HashMap<String, u64>>,
+}
+
+impl CacheMetrics {
+    pub fn on_hit(&self, key: &str) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        let mut key_hits = self.key_hits.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(n) = key_hits.get_mut(key) {
+            *n += 1;
+        } else if key_hits.len() < MAX_TRACKED_KEYS {
+            key_hits.insert(key.to_owned(), 1);
+        }
+    }
+
+    pub fn on_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_eviction(&self, count: u64) {
+        self.evictions.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Hits over all lookups, 0 before the first lookup
+    pub fn hit_rate(&self) -> f64 {
+        let (hits, misses) = (self.hits(), self.misses());
+        if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        }
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    pub fn hot_keys(&self, n: usize) -> Vec<(String, u64)> {
+        let key_hits = self.key_hits.lock().unwrap_or_else(|e| e.into_inner());
+        let mut res: Vec<(String, u64)> = key_hits.iter().map(|(k, v)| (k.clone(), *v)).collect();
+        res.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        res.truncate(n);
+        res
+    }
+
+    pub fn reset(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+        self.evictions.store(0, Ordering::Relaxed);
+        self.key_hits.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheStatsSnapshot {
+    pub entries: usize,
+    pub hit_rate: f64,
+    pub evictions: u64,
+    pub hot_keys: Vec<(String, u64)>,
+}
+
+impl fmt::Display for CacheStatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "entries={} hit_rate={:.3} evictions={}", self.entries, self.hit_rate, self.evictions)?;
+        for (key, hits) in &self.hot_keys {
+            writeln!(f, "  {} {}", hits, key)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,56 @@
+//! Source of the current time for the time-based rules: expiry and activation of records.
+//!
+//! The engine reads the time through `AzConfig::clock`, so tests pin it with `FixedClock` and the platform
+//! may evaluate a request at the timestamp of its transaction instead of the wall clock.
+
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::sync::RwLock;
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// Wall clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Always the same time, for tests and replays
+#[derive(Clone, Copy, Debug)]
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+/// Time set by the caller, e.g. to the timestamp of the transaction being applied
+#[derive(Debug)]
+pub struct ManualClock {
+    now: RwLock<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        ManualClock {
+            now: RwLock::new(now),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.write().unwrap_or_else(|e| e.into_inner()) = now;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,108 @@
+use crate::common::{Storage, M_IGNORE_EXCLUSIVE, M_IS_EXCLUSIVE};
+use crate::config::AzConfig;
+use crate::record_set::RecordSet;
+use crate::{get_user_groups_with_config, ACLRecord};
+use std::io;
+
+const CLOSURE_FORMAT: &str = "VAC1";
+
+/// Transitive group set of a user, exported for offline evaluation.
+///
+/// `epoch` is the revocation epoch of the store at export time; the server rejects closures
+/// older than its current epoch when the client reconnects.
+pub struct SubjectClosure {
+    pub user_id: String,
+    pub epoch: u64,
+    pub groups: Vec<ACLRecord>,
+}
+
+/// Resolves the user's groups with their masks and markers; depth, scope, aliases and key schema are taken
+/// from `cfg`, which should be the configuration the closure will be evaluated against
+pub fn export_subject_closure(user_id: &str, db: &mut dyn Storage, epoch: u64, cfg: &AzConfig) -> io::Result<SubjectClosure> {
+    let groups = RecordSet::from(get_user_groups_with_config(user_id, db, cfg)?).to_sorted_vec();
+
+    Ok(SubjectClosure {
+        user_id: user_id.to_owned(),
+        epoch,
+        groups,
+    })
+}
+
+/// A closure is valid while no revocation happened after it was exported
+pub fn verify_closure_epoch(closure: &SubjectClosure, current_epoch: u64) -> bool {
+    closure.epoch >= current_epoch
+}
+
+impl SubjectClosure {
+    /// Compact text form: header, epoch, user and one `id\taccess\tmarker` line per group,
+    /// terminated by a checksum line over everything above it
+    pub fn to_blob(&self) -> String {
+        let mut body = format!("{}\n{}\n{}\n", CLOSURE_FORMAT, self.epoch, self.user_id);
+        for gr in &self.groups {
+            let marker = if gr.marker == M_IS_EXCLUSIVE || gr.marker == M_IGNORE_EXCLUSIVE {
+                gr.marker
+            } else {
+                '-'
+            };
+            body.push_str(&format!("{}\t{}\t{}\t{}\n", gr.id, gr.access, marker, gr.level));
+        }
+        let sum = checksum(body.as_bytes());
+        body.push_str(&format!("{:016x}", sum));
+        body
+    }
+
+    pub fn from_blob(blob: &str) -> io::Result<SubjectClosure> {
+        let (body, sum) = match blob.rfind('\n') {
+            Some(pos) => (&blob[..pos + 1], &blob[pos + 1..]),
+            None => return Err(invalid("closure blob is truncated")),
+        };
+
+        if u64::from_str_radix(sum, 16).ok() != Some(checksum(body.as_bytes())) {
+            return Err(invalid("closure blob checksum mismatch"));
+        }
+
+        let mut lines = body.lines();
+        if lines.next() != Some(CLOSURE_FORMAT) {
+            return Err(invalid("unknown closure blob format"));
+        }
+        let epoch = lines.next().and_then(|l| l.parse::<u64>().ok()).ok_or_else(|| invalid("closure blob has no epoch"))?;
+        let user_id = lines.next().ok_or_else(|| invalid("closure blob has no user"))?.to_owned();
+
+        let mut groups = Vec::new();
+        for line in lines {
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 4 {
+                return Err(invalid("closure blob has malformed group line"));
+            }
+            let access = fields[1].parse::<u8>().map_err(|_| invalid("closure blob has malformed access"))?;
+            let mut rec = ACLRecord::new_with_access(fields[0], access);
+            rec.marker = match fields[2].chars().next() {
+                Some(M_IS_EXCLUSIVE) => M_IS_EXCLUSIVE,
+                Some(M_IGNORE_EXCLUSIVE) => M_IGNORE_EXCLUSIVE,
+                _ => 0 as char,
+            };
+            rec.level = fields[3].parse::<u8>().unwrap_or(0);
+            groups.push(rec);
+        }
+
+        Ok(SubjectClosure {
+            user_id,
+            epoch,
+            groups,
+        })
+    }
+}
+
+// FNV-1a, защищает только от повреждения, не от подделки
+fn checksum(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in data {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
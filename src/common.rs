@@ -1,21 +1,80 @@
-use crate::{ACLRecord, ACLRecordSet, AzContext};
+use crate::clock::Clock;
+use crate::config::{AccessAccumulation, AzConfig, IndividualAsGroup, SelfReferencePolicy, UnknownMarkerPolicy};
+use crate::patterns::pattern_candidates;
+use crate::record_formats::{self, read_continued, Validity};
+use crate::record_set::merge_marker;
+use crate::{print_step, ACLRecord, ACLRecordSet, ACLRecordVec, AzContext, RightsCounters};
 use chrono::DateTime;
 use chrono::Utc;
 use core::fmt;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::io;
 
 pub const PERMISSION_PREFIX: &str = "P";
 pub const FILTER_PREFIX: &str = "F";
 pub const MEMBERSHIP_PREFIX: &str = "M";
+pub const AGGREGATE_PREFIX: &str = "A";
+pub const PATTERN_PREFIX: &str = "W";
+pub const REACHABILITY_PREFIX: &str = "B";
+/// Generation of the M-, P- and F-records, bumped by the `manage` writers once a reachability summary was built
+pub const ACL_GENERATION_KEY: &str = "G";
+/// Earlier ids of a group, stored under its current id in the membership format
+pub const ALIAS_PREFIX: &str = "L";
+/// Co-signatures of grants on sensitive groups, in the permission format under the key suffix of the P-record
+pub const COSIGN_PREFIX: &str = "Q";
+/// Access reviews of grants, one `subject\taccess\treviewer\ttime` line per subject, under the key suffix of the P-record
+pub const ATTESTATION_PREFIX: &str = "R";
 pub static ACCESS_8_LIST: [u8; 4] = [1, 2, 4, 8];
 pub static ACCESS_8_FULL_LIST: [u8; 8] = [1, 2, 4, 8, 16, 32, 64, 128];
 pub static ACCESS_PREDICATE_LIST: [&str; 9] = ["", "v-s:canCreate", "v-s:canRead", "", "v-s:canUpdate", "", "", "", "v-s:canDelete"];
+/// Predicates of all 8 access bits, indexed as `ACCESS_8_FULL_LIST`
+pub static ACCESS_8_PREDICATE_LIST: [&str; 8] =
+    ["v-s:canCreate", "v-s:canRead", "v-s:canUpdate", "v-s:canDelete", "v-s:cantCreate", "v-s:cantRead", "v-s:cantUpdate", "v-s:cantDelete"];
+
+/// Predicate of a single access bit, deny bits included; `None` if `bit` is not exactly one bit
+pub fn access_predicate(bit: u8) -> Option<&'static str> {
+    ACCESS_8_FULL_LIST.iter().position(|b| *b == bit).map(|idx| ACCESS_8_PREDICATE_LIST[idx])
+}
+
+/// Line of the ACL trace: `object_group;subject_group;predicate`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AclTraceEntry {
+    pub object_group: String,
+    pub subject_group: String,
+    pub predicate: String,
+    /// The predicate is one of the `v-s:cant*` denials
+    pub is_deny: bool,
+}
+
+pub fn parse_acl_trace(src: &str) -> Vec<AclTraceEntry> {
+    src.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(';').collect();
+            if fields.len() != 3 {
+                return None;
+            }
+            Some(AclTraceEntry {
+                object_group: fields[0].to_owned(),
+                subject_group: fields[1].to_owned(),
+                predicate: fields[2].to_owned(),
+                is_deny: ACCESS_8_PREDICATE_LIST[4..].contains(&fields[2]),
+            })
+        })
+        .collect()
+}
 
 pub const M_IS_EXCLUSIVE: char = 'X';
 pub const M_IGNORE_EXCLUSIVE: char = 'N';
 pub static ACCESS_C_FULL_LIST: [char; 8] = ['M', 'R', 'U', 'P', 'm', 'r', 'u', 'p'];
 
+/// Position of a right character in `ACCESS_C_FULL_LIST`, used to index `ACLRecord::counters`
+pub fn counter_index(c: char) -> Option<usize> {
+    ACCESS_C_FULL_LIST.iter().position(|x| *x == c)
+}
+
 /// Битовые поля для прав
 #[derive(PartialEq, Eq)]
 #[repr(u8)]
@@ -50,12 +109,85 @@ pub trait AuthorizationContext {
     fn authorize_and_trace(&mut self, uri: &str, user_uri: &str, request_access: u8, _is_check_for_reload: bool, trace: &mut Trace) -> io::Result<u8>;
 }
 
+/// Read access to the ACL records. A backend implements `get` and `fiber_yield`; the other methods have
+/// defaults, the `decode_*` ones parse both versions of `record_formats`
 pub trait Storage {
     fn get(&mut self, key: &str) -> io::Result<Option<String>>;
     fn fiber_yield(&self);
-    fn decode_rec_to_rights(&self, src: &str, result: &mut Vec<ACLRecord>) -> (bool, Option<DateTime<Utc>>);
-    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>);
-    fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>);
+
+    fn decode_rec_to_rights(&self, src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+        record_formats::decode_rec_to_rights(src, result)
+    }
+
+    /// Ids listed more than once get their access OR-ed, as in `record_formats::decode_rec_to_rightset`
+    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+        record_formats::decode_rec_to_rightset(src, new_rights)
+    }
+
+    fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+        record_formats::decode_filter(&filter_value)
+    }
+
+    /// Activation time of an M- or P-record, `None` when it is in force from the start
+    fn decode_valid_from(&self, src: &str) -> Option<DateTime<Utc>> {
+        record_formats::record_valid_from(src)
+    }
+
+    /// Pattern permission records covering `uri`, as (key, value) pairs.
+    /// The default probes every pattern from `pattern_candidates`, backends with ordered keys may replace it with a range scan.
+    fn get_pattern_permissions(&mut self, uri: &str) -> io::Result<Vec<(String, String)>> {
+        let mut res = Vec::new();
+        for pattern in pattern_candidates(uri) {
+            let key = PATTERN_PREFIX.to_owned() + &pattern;
+            if let Some(val) = self.get(&key)? {
+                res.push((key, val));
+            }
+        }
+        Ok(res)
+    }
+
+    /// Revision of the stored data, bumped on every write; enables the shared `MembershipCache`.
+    /// Wrappers that must see every read keep the default.
+    fn epoch(&self) -> Option<u64> {
+        None
+    }
+
+    /// Read that leaves no trace in caching layers: no population, no hit counting.
+    /// Caching wrappers must override it to read through to the backend.
+    fn get_uncached(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.get(key)
+    }
+}
+
+/// Routes every read to `get_uncached`, for side-effect-free evaluation
+pub(crate) struct UncachedStorage<'a> {
+    pub(crate) inner: &'a mut dyn Storage,
+}
+
+impl Storage for UncachedStorage<'_> {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.inner.get_uncached(key)
+    }
+
+    fn fiber_yield(&self) {
+        self.inner.fiber_yield()
+    }
+
+    fn decode_rec_to_rights(&self, src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+        self.inner.decode_rec_to_rights(src, result)
+    }
+
+    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+        self.inner.decode_rec_to_rightset(src, new_rights)
+    }
+
+    fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+        self.inner.decode_filter(filter_value)
+    }
+
+    fn decode_valid_from(&self, src: &str) -> Option<DateTime<Utc>> {
+        self.inner.decode_valid_from(src)
+    }
 }
 
 impl fmt::Debug for ACLRecord {
@@ -85,6 +217,29 @@ pub struct Trace<'a> {
     pub str_num: u32,
 }
 
+/// Строки для трассировки внутренних вызовов
+#[derive(Default)]
+pub(crate) struct TraceBuffers {
+    pub(crate) acl: String,
+    pub(crate) group: String,
+    pub(crate) info: String,
+}
+
+impl TraceBuffers {
+    pub(crate) fn trace(&mut self, is_acl: bool, is_group: bool, is_info: bool) -> Trace<'_> {
+        Trace {
+            acl: &mut self.acl,
+            is_acl,
+            group: &mut self.group,
+            is_group,
+            info: &mut self.info,
+            is_info,
+            str_num: 0,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn get_resource_groups(
     ctx: &mut AzContext,
     trace: &mut Trace,
@@ -95,14 +250,27 @@ pub(crate) fn get_resource_groups(
     db: &mut dyn Storage,
     ignore_exclusive: bool,
 ) -> io::Result<bool> {
-    if level > 32 {
+    if level > ctx.cfg.max_subject_depth {
         return Ok(true);
     }
 
-    match db.get(&(MEMBERSHIP_PREFIX.to_owned() + uri)) {
+    let mut aliases = ACLRecordVec::new();
+    if ctx.cfg.group_aliases {
+        get_aliases(uri, db, &mut aliases)?;
+    }
+
+    let membership = match read_continued(&(MEMBERSHIP_PREFIX.to_owned() + uri), db) {
+        Ok(None) if !aliases.is_empty() => Ok(Some(String::new())),
+        res => res,
+    };
+
+    match membership {
         Ok(Some(groups_str)) => {
-            let groups_set: &mut Vec<ACLRecord> = &mut Vec::new();
-            db.decode_rec_to_rights(&groups_str, groups_set);
+            let groups_set = &mut ACLRecordVec::new();
+            if !groups_str.is_empty() {
+                decode_memberships(&groups_str, uri, db, ctx.cfg, ctx.clock, groups_set)?;
+            }
+            groups_set.extend(aliases);
 
             for (idx, group) in groups_set.iter_mut().enumerate() {
                 if group.id.is_empty() {
@@ -110,7 +278,11 @@ pub(crate) fn get_resource_groups(
                     continue;
                 }
 
-                let new_access = group.access & access;
+                if !ctx.cfg.is_in_scope(&group.id) {
+                    continue;
+                }
+
+                let new_access = accumulate_access(group.access, access, ctx.cfg.access_accumulation);
                 group.access = new_access;
 
                 let mut preur_access = 0;
@@ -128,40 +300,58 @@ pub(crate) fn get_resource_groups(
                 }
 
                 if uri == group.id {
+                    on_self_reference(ctx.cfg, uri)?;
                     continue;
                 }
 
+                if let Some(info) = ctx.hooks.trace_info {
+                    info.borrow_mut().add_subject_edge(uri, &group.id);
+                }
+
                 let t_ignore_exclusive = if !ignore_exclusive && group.marker == M_IGNORE_EXCLUSIVE {
                     true
                 } else {
                     ignore_exclusive
                 };
 
+                // Индивид в роли группы; при Narrowed маска ребра к нему сцепляется со всем, что найдено через него
+                let is_individual = ctx.cfg.is_individual(&group.id);
+                if is_individual {
+                    ctx.individual_hops.push((uri.to_owned(), group.id.clone()));
+                    if trace.is_info {
+                        print_step(
+                            ctx.hooks,
+                            trace,
+                            format!("individual as group: {} -> {}, access={}\n", uri, group.id, access_to_pretty_string(new_access).trim()),
+                        );
+                    }
+                }
+                let next_access = if ctx.cfg.individual_as_group == IndividualAsGroup::Narrowed && (is_individual || access != 15) {
+                    new_access
+                } else {
+                    15
+                };
+
                 db.fiber_yield();
 
-                get_resource_groups(ctx, trace, &group.id, 15, results, level + 1, db, t_ignore_exclusive)?;
+                if let Some(next_level) = level.checked_add(1) {
+                    get_resource_groups(ctx, trace, &group.id, next_access, results, next_level, db, t_ignore_exclusive)?;
+                }
 
                 if !ignore_exclusive && group.marker == M_IS_EXCLUSIVE {
                     if trace.is_info {
-                        print_to_trace_info(trace, format!("FOUND EXCLUSIVE RESTRICTIONS, PATH={} \n", &get_path(ctx.tree_groups_s, group.id.clone())));
+                        print_step(ctx.hooks, trace, format!("FOUND EXCLUSIVE RESTRICTIONS, PATH={} \n", &get_path(ctx.tree_groups_s, group.id.clone())));
                     }
                     ctx.is_need_exclusive_az = true;
+                    if !ctx.exclusive_groups.contains(&group.id) {
+                        ctx.exclusive_groups.push(group.id.clone());
+                    }
                 }
 
-                let new_group_marker;
-
-                match results.get(&group.id) {
-                    Some(val) => {
-                        if val.marker == 0 as char {
-                            new_group_marker = group.marker;
-                        } else {
-                            new_group_marker = val.marker;
-                        }
-                    },
-                    None => {
-                        new_group_marker = group.marker;
-                    },
-                }
+                let (new_group_marker, new_group_level) = match results.get(&group.id) {
+                    Some(val) => (merge_marker(val.marker, group.marker, ctx.cfg.marker_precedence), val.level.min(level)),
+                    None => (group.marker, level),
+                };
 
                 results.insert(
                     group.id.clone(),
@@ -170,8 +360,9 @@ pub(crate) fn get_resource_groups(
                         access: group.access,
                         marker: new_group_marker,
                         is_deleted: group.is_deleted,
-                        level,
-                        counters: HashMap::default(),
+                        level: new_group_level,
+                        counters: RightsCounters::None,
+                        provenance: None,
                     },
                 );
             }
@@ -188,6 +379,64 @@ pub(crate) fn get_resource_groups(
     Ok(false)
 }
 
+/// Прежние идентификаторы группы обходятся как ее дополнительные группы, так что права,
+/// выданные на старый идентификатор, продолжают действовать. Повторный обход исключают walked_groups.
+pub(crate) fn get_aliases(uri: &str, db: &mut dyn Storage, result: &mut ACLRecordVec) -> io::Result<()> {
+    if let Some(src) = db.get(&(ALIAS_PREFIX.to_owned() + uri))? {
+        let mut aliases = ACLRecordVec::new();
+        db.decode_rec_to_rights(&src, &mut aliases);
+        // ссылка на себя дала бы только лишний шаг
+        result.extend(aliases.into_iter().filter(|a| a.id != uri));
+    }
+    Ok(())
+}
+
+/// Access carried to a group through a membership edge with mask `edge`, when `parent` reached its member
+pub fn accumulate_access(edge: u8, parent: u8, policy: AccessAccumulation) -> u8 {
+    match policy {
+        AccessAccumulation::Intersect => edge & parent,
+        AccessAccumulation::InheritFull => parent,
+        AccessAccumulation::MinBits => {
+            if edge.count_ones() < parent.count_ones() {
+                edge
+            } else {
+                parent
+            }
+        },
+    }
+}
+
+/// Decodes an M-record as `decode_in_force` does; markers other than `X` and `N` are handled by `cfg.unknown_marker_policy`
+pub(crate) fn decode_memberships(src: &str, uri: &str, db: &dyn Storage, cfg: &AzConfig, clock: &dyn Clock, result: &mut ACLRecordVec) -> io::Result<Validity> {
+    let start = result.len();
+    let validity = decode_in_force(src, db, clock, result);
+    for group in result[start..].iter_mut().filter(|gr| ![0 as char, M_IS_EXCLUSIVE, M_IGNORE_EXCLUSIVE].contains(&gr.marker)) {
+        group.marker = match cfg.unknown_marker_policy {
+            UnknownMarkerPolicy::Ignore => 0 as char,
+            UnknownMarkerPolicy::Warn => {
+                eprintln!("WARN! Authorize: unknown marker {:?}, uri={}, group={}", group.marker, uri, group.id);
+                0 as char
+            },
+            UnknownMarkerPolicy::TreatAsExclusive => M_IS_EXCLUSIVE,
+            UnknownMarkerPolicy::Error => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown marker {:?}, uri={}, group={}", group.marker, uri, group.id)));
+            },
+        };
+    }
+    Ok(validity)
+}
+
+pub(crate) fn on_self_reference(cfg: &AzConfig, uri: &str) -> io::Result<()> {
+    match cfg.self_reference_policy {
+        SelfReferencePolicy::Skip => Ok(()),
+        SelfReferencePolicy::Warn => {
+            eprintln!("WARN! Authorize: membership record references itself, uri={}", uri);
+            Ok(())
+        },
+        SelfReferencePolicy::Error => Err(io::Error::new(io::ErrorKind::InvalidData, format!("membership record references itself, uri={}", uri))),
+    }
+}
+
 pub(crate) fn print_to_trace_acl(trace: &mut Trace, text: String) {
     trace.acl.push_str(&text);
 }
@@ -213,7 +462,7 @@ pub(crate) fn get_path(mopc: &mut HashMap<String, String>, el: String) -> String
     }
 }
 
-pub(crate) fn access_to_pretty_string(src: u8) -> String {
+pub fn access_to_pretty_string(src: u8) -> String {
     let mut res: String = "".to_owned();
 
     if src & 1 == 1 {
@@ -252,6 +501,14 @@ pub(crate) fn access_to_pretty_string(src: u8) -> String {
 }
 
 pub(crate) fn final_check(azc: &mut AzContext, trace: &mut Trace) -> bool {
+    // Потолок прав экземпляра движка
+    azc.calc_right_res &= azc.cfg.capability_ceiling;
+
+    // Запрет, найденный на любом пути, снимает соответствующее право
+    if azc.cfg.deny_override {
+        azc.calc_right_res &= !(azc.calc_deny_res >> 4);
+    }
+
     let res = if azc.is_need_exclusive_az && azc.is_found_exclusive_az {
         true
     } else {
@@ -259,7 +516,8 @@ pub(crate) fn final_check(azc: &mut AzContext, trace: &mut Trace) -> bool {
     };
 
     if trace.is_info && res {
-        print_to_trace_info(
+        print_step(
+            azc.hooks,
             trace,
             format!(
                 "result: uri={}, user={}, request={}, answer={}\n\n",
@@ -274,6 +532,23 @@ pub(crate) fn final_check(azc: &mut AzContext, trace: &mut Trace) -> bool {
     res
 }
 
+/// Decodes the live entries of an M- or P-record into `result`, nothing if by `clock` it has expired or is not
+/// active yet; gives the validity of the record
+pub(crate) fn decode_in_force(src: &str, db: &dyn Storage, clock: &dyn Clock, result: &mut ACLRecordVec) -> Validity {
+    let start = result.len();
+    let validity = Validity {
+        valid_until: db.decode_rec_to_rights(src, result).1,
+        valid_from: db.decode_valid_from(src),
+    };
+    if !validity.is_in_force(clock.now()) {
+        result.truncate(start);
+    }
+    // надгробия не действуют
+    let live: ACLRecordVec = result.drain(start..).filter(|rec| !rec.is_deleted).collect();
+    result.extend(live);
+    validity
+}
+
 pub(crate) fn get_filter(id: &str, db: &mut dyn Storage) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
     let filter_value = match db.get(&(FILTER_PREFIX.to_owned() + id)) {
         Ok(Some(data)) => data,
@@ -284,6 +559,5 @@ pub(crate) fn get_filter(id: &str, db: &mut dyn Storage) -> (Option<ACLRecord>,
         _ => "".to_string(),
     };
 
-    let res = db.decode_filter(filter_value);
-    res
+    db.decode_filter(filter_value)
 }
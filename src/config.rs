@@ -0,0 +1,494 @@
+use crate::clock::{Clock, SystemClock};
+use crate::keys::KeySchema;
+use crate::presets::{format_access_expr, MANAGER};
+use crate::record_formats::DuplicateEntries;
+use crate::record_set::MarkerPrecedence;
+use chrono::{DateTime, Utc};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// What the traversal does with a membership record that lists its own uri as a group
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SelfReferencePolicy {
+    #[default]
+    Skip,
+    Warn,
+    Error,
+}
+
+/// What the traversal does with a membership marker other than `X`, `N` or none, e.g. a typo of the indexer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum UnknownMarkerPolicy {
+    /// Treated as no marker
+    #[default]
+    Ignore,
+    /// Treated as no marker, with a warning
+    Warn,
+    /// Treated as `X`, so a mistyped exclusive marker still restricts
+    TreatAsExclusive,
+    /// Fail the call
+    Error,
+}
+
+/// What to do when a user belongs to more groups than `AzConfig::max_subject_groups`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SubjectOverflowStrategy {
+    /// Fail the call
+    #[default]
+    Error,
+    /// Keep the groups closest to the user
+    TruncateByLevel,
+    /// Keep the groups whose access mask covers most of the requested bits
+    TruncateByAccess,
+}
+
+/// How the access mask of a membership edge combines with the access that reached the member
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum AccessAccumulation {
+    /// Each edge restricts: `edge & parent`
+    #[default]
+    Intersect,
+    /// Edges are labels only, the member's access passes through unchanged
+    InheritFull,
+    /// Whichever of the two masks has fewer bits
+    MinBits,
+}
+
+/// What a subject gets through an individual acting as its group, e.g. a user listed in the M-record of another
+/// user. Masks do not chain on the subject side: a group is given with the mask of its own edge only
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum IndividualAsGroup {
+    /// The individual is one more subject group: its grants come with the mask of the edge to it,
+    /// its own groups with the masks of their edges
+    #[default]
+    Inherit,
+    /// The mask of the edge to the individual is chained, with `AzConfig::access_accumulation`, into every
+    /// group reached through it
+    Narrowed,
+}
+
+/// What an authorize call does with an empty or blank user id or resource id
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum BlankIdPolicy {
+    /// Answer 0 without reading the store
+    #[default]
+    Deny,
+    /// Fail the call with `InvalidInput`
+    Error,
+}
+
+/// Параметры движка авторизации
+#[derive(Clone, Debug)]
+pub struct AzConfig {
+    /// Максимальная глубина обхода групп субъекта
+    pub max_subject_depth: u8,
+
+    /// Максимальная глубина обхода групп объекта
+    pub max_object_depth: u8,
+
+    /// Minimum duration of a call that ends in denial.
+    /// Keeps "resource has no ACL records" indistinguishable by timing from "denied by ACL".
+    pub deny_min_duration: Option<Duration>,
+
+    /// Random extra delay, up to this value, added on top of `deny_min_duration`
+    pub deny_jitter: Option<Duration>,
+
+    /// Marker kept for a subject group reached by several membership paths
+    pub marker_precedence: MarkerPrecedence,
+
+    /// Use permission aggregates (`A` records) instead of P-records when not tracing. The `manage` writers keep
+    /// aggregates in step with their P-records, see `manage::rebuild_permission_aggregate`. Aggregates keep no validity,
+    /// a store with expiring or scheduled grants has to rebuild them when a grant expires or comes in force
+    pub use_permission_aggregates: bool,
+
+    /// Aggregates built before this epoch are stale and fall back to the P-record scan
+    pub aggregate_min_epoch: u64,
+
+    /// Honor grants on uri patterns (`prj:alpha/*`) for objects and subjects
+    pub pattern_grants: bool,
+
+    /// Namespace prefixes the evaluation is limited to; groups and resources outside of them are treated as absent.
+    /// Empty means no restriction.
+    pub scope_prefixes: Vec<String>,
+
+    /// Attach a hash-chained `ProvenanceRecord` to audit events
+    pub audit_provenance: bool,
+
+    /// Deny without walking object groups when the reachability summary (`B` record) of the resource
+    /// contains none of the user's groups. Not applied with pattern grants, group aliases, case-insensitive ids
+    /// or implicit groups. Summaries go stale on any write made through `manage`, see `manage::rebuild_reachability`.
+    pub reachability_precheck: bool,
+
+    /// Summaries built before this epoch are stale and ignored
+    pub reachability_min_epoch: u64,
+
+    pub self_reference_policy: SelfReferencePolicy,
+
+    /// Applies to membership markers of both record versions; a v1 marker longer than one char reaches it as U+FFFD.
+    /// `consistency::check_memberships` reports them
+    pub unknown_marker_policy: UnknownMarkerPolicy,
+
+    /// Cap on the resolved subject groups of a user, the user included
+    pub max_subject_groups: Option<usize>,
+
+    pub subject_overflow: SubjectOverflowStrategy,
+
+    /// Used by both the subject and the object group traversal
+    pub access_accumulation: AccessAccumulation,
+
+    /// Send `AzWarning::ExclusiveDenied` to the warning sink when exclusivity blocks a grant and info tracing is off
+    pub report_exclusive_denials: bool,
+
+    /// Follow alias records (`ALIAS_PREFIX`): earlier ids of a group are treated as its groups in both traversals
+    pub group_aliases: bool,
+
+    /// Bits the engine may ever grant, whatever the ACL says; e.g. `presets::VIEWER` for a read-only replica
+    pub capability_ceiling: u8,
+
+    /// Ceiling of each service account acting on behalf of users; accounts not listed are not narrowed
+    pub service_ceilings: HashMap<String, u8>,
+
+    /// Object groups whose grants count only for the bits co-signed in a `COSIGN_PREFIX` record; the rest stay pending
+    pub sensitive_groups: Vec<String>,
+
+    pub blank_id_policy: BlankIdPolicy,
+
+    /// Form of ids in keys and records; must match the writers of the store
+    pub key_schema: KeySchema,
+
+    /// Principals or subject groups given every requested bit up to the ceiling without looking at the resource;
+    /// empty disables the bypass. Audited through `AuditSink::on_superuser`
+    pub superusers: Vec<String>,
+
+    /// Rights of a subject listed more than once in a P-record; anything but `Or` bypasses permission aggregates
+    pub duplicate_entries: DuplicateEntries,
+
+    /// P-records at least this long are decoded with `Storage::decode_rec_to_rightset` and looked up per subject group
+    /// instead of scanned; `None` always scans. The set form must merge duplicates as `DuplicateEntries::Or`
+    pub rightset_min_len: Option<usize>,
+
+    /// Match subject ids of permissions and memberships against the user's groups ignoring case, for legacy
+    /// mixed-case logins; store keys are still read as written. Disables the reachability precheck
+    pub case_insensitive_ids: bool,
+
+    /// Platform deny semantics: a Cant* bit of any matching permission, on any path, removes the Can* bit from
+    /// the result. Every path is walked, as with a trace, so the first grant no longer ends the traversal
+    pub deny_override: bool,
+
+    /// Id prefixes of individuals, users and documents; a subject group matching one is an individual acting
+    /// as a group, labeled in traces and decisions. Empty means no group is taken for an individual
+    pub individual_prefixes: Vec<String>,
+
+    /// Applies to groups matching `individual_prefixes`. Exclusive markers met beyond the individual restrict
+    /// its members with either value, as they restrict the individual
+    pub individual_as_group: IndividualAsGroup,
+
+    /// Time the validity of M- and P-records is checked against; `FixedClock` for tests and replays
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for AzConfig {
+    fn default() -> Self {
+        AzConfig {
+            max_subject_depth: 32,
+            max_object_depth: 32,
+            deny_min_duration: None,
+            deny_jitter: None,
+            marker_precedence: MarkerPrecedence::default(),
+            use_permission_aggregates: false,
+            aggregate_min_epoch: 0,
+            pattern_grants: false,
+            scope_prefixes: Vec::new(),
+            audit_provenance: false,
+            reachability_precheck: false,
+            reachability_min_epoch: 0,
+            self_reference_policy: SelfReferencePolicy::default(),
+            unknown_marker_policy: UnknownMarkerPolicy::default(),
+            max_subject_groups: None,
+            subject_overflow: SubjectOverflowStrategy::default(),
+            access_accumulation: AccessAccumulation::default(),
+            report_exclusive_denials: false,
+            group_aliases: false,
+            capability_ceiling: MANAGER,
+            service_ceilings: HashMap::new(),
+            sensitive_groups: Vec::new(),
+            blank_id_policy: BlankIdPolicy::default(),
+            key_schema: KeySchema::default(),
+            superusers: Vec::new(),
+            duplicate_entries: DuplicateEntries::default(),
+            rightset_min_len: Some(64 * 1024),
+            case_insensitive_ids: false,
+            deny_override: false,
+            individual_prefixes: Vec::new(),
+            individual_as_group: IndividualAsGroup::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl AzConfig {
+    pub fn is_in_scope(&self, id: &str) -> bool {
+        self.scope_prefixes.is_empty() || self.scope_prefixes.iter().any(|p| id.starts_with(p.as_str()))
+    }
+
+    pub fn is_individual(&self, id: &str) -> bool {
+        self.individual_prefixes.iter().any(|p| id.starts_with(p.as_str()))
+    }
+
+    /// Every setting as `(name, value)`, in declaration order; durations in milliseconds, masks as access expressions
+    pub fn settings(&self) -> Vec<(&'static str, String)> {
+        let ms = |d: Option<Duration>| d.map_or("none".to_owned(), |d| d.as_millis().to_string());
+        let list = |items: &[String]| items.join(",");
+        let mut service_ceilings: Vec<String> = self.service_ceilings.iter().map(|(svc, c)| format!("{}:{}", svc, format_access_expr(*c))).collect();
+        service_ceilings.sort();
+
+        vec![
+            ("max_subject_depth", self.max_subject_depth.to_string()),
+            ("max_object_depth", self.max_object_depth.to_string()),
+            ("deny_min_duration_ms", ms(self.deny_min_duration)),
+            ("deny_jitter_ms", ms(self.deny_jitter)),
+            ("marker_precedence", format!("{:?}", self.marker_precedence)),
+            ("use_permission_aggregates", self.use_permission_aggregates.to_string()),
+            ("aggregate_min_epoch", self.aggregate_min_epoch.to_string()),
+            ("pattern_grants", self.pattern_grants.to_string()),
+            ("scope_prefixes", list(&self.scope_prefixes)),
+            ("audit_provenance", self.audit_provenance.to_string()),
+            ("reachability_precheck", self.reachability_precheck.to_string()),
+            ("reachability_min_epoch", self.reachability_min_epoch.to_string()),
+            ("self_reference_policy", format!("{:?}", self.self_reference_policy)),
+            ("unknown_marker_policy", format!("{:?}", self.unknown_marker_policy)),
+            ("max_subject_groups", self.max_subject_groups.map_or("none".to_owned(), |n| n.to_string())),
+            ("subject_overflow", format!("{:?}", self.subject_overflow)),
+            ("access_accumulation", format!("{:?}", self.access_accumulation)),
+            ("report_exclusive_denials", self.report_exclusive_denials.to_string()),
+            ("group_aliases", self.group_aliases.to_string()),
+            ("capability_ceiling", format_access_expr(self.capability_ceiling)),
+            ("service_ceilings", service_ceilings.join(",")),
+            ("sensitive_groups", list(&self.sensitive_groups)),
+            ("blank_id_policy", format!("{:?}", self.blank_id_policy)),
+            ("key_schema", format!("{:?}", self.key_schema)),
+            ("superusers", list(&self.superusers)),
+            ("duplicate_entries", format!("{:?}", self.duplicate_entries)),
+            ("rightset_min_len", self.rightset_min_len.map_or("none".to_owned(), |n| n.to_string())),
+            ("case_insensitive_ids", self.case_insensitive_ids.to_string()),
+            ("deny_override", self.deny_override.to_string()),
+            ("individual_prefixes", list(&self.individual_prefixes)),
+            ("individual_as_group", format!("{:?}", self.individual_as_group)),
+            ("clock", format!("{:?}", self.clock)),
+        ]
+    }
+
+    /// Time a record's validity is compared with
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    /// Form of a subject id the user's groups are looked up by
+    pub fn subject_key<'a>(&self, id: &'a str) -> Cow<'a, str> {
+        if self.case_insensitive_ids {
+            Cow::Owned(id.to_lowercase())
+        } else {
+            Cow::Borrowed(id)
+        }
+    }
+}
+
+/// Atomic handle to the engine configuration.
+///
+/// Every authorize call takes a snapshot via `load`, so a config replaced with `store` or `update`
+/// is picked up by the next call while calls already in progress finish with the old values.
+#[derive(Clone)]
+pub struct AzConfigHandle {
+    inner: Arc<RwLock<Arc<AzConfig>>>,
+    ceiling: u8,
+}
+
+impl Default for AzConfigHandle {
+    fn default() -> Self {
+        AzConfigHandle::new(AzConfig::default())
+    }
+}
+
+impl AzConfigHandle {
+    pub fn new(cfg: AzConfig) -> Self {
+        AzConfigHandle::with_ceiling(cfg, 0xFF)
+    }
+
+    /// Every config published through the handle has its `capability_ceiling` narrowed to `ceiling`,
+    /// so a later `store` or `update` can not widen it
+    pub fn with_ceiling(mut cfg: AzConfig, ceiling: u8) -> Self {
+        cfg.capability_ceiling &= ceiling;
+        AzConfigHandle {
+            inner: Arc::new(RwLock::new(Arc::new(cfg))),
+            ceiling,
+        }
+    }
+
+    pub fn ceiling(&self) -> u8 {
+        self.ceiling
+    }
+
+    pub fn load(&self) -> Arc<AzConfig> {
+        match self.inner.read() {
+            Ok(cfg) => cfg.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    pub fn store(&self, mut cfg: AzConfig) {
+        cfg.capability_ceiling &= self.ceiling;
+        let cfg = Arc::new(cfg);
+        match self.inner.write() {
+            Ok(mut cur) => *cur = cfg,
+            Err(e) => *e.into_inner() = cfg,
+        }
+    }
+
+    /// Applies `f` to a copy of the current config and publishes the result
+    pub fn update<F: FnOnce(&mut AzConfig)>(&self, f: F) {
+        let mut guard = match self.inner.write() {
+            Ok(g) => g,
+            Err(e) => e.into_inner(),
+        };
+        let mut cfg = (**guard).clone();
+        f(&mut cfg);
+        cfg.capability_ceiling &= self.ceiling;
+        *guard = Arc::new(cfg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{M_IGNORE_EXCLUSIVE, M_IS_EXCLUSIVE};
+    use crate::engine::AzEngine;
+    use crate::manage::MutableStorage;
+    use crate::storage::memory::MemoryStorage;
+    use crate::{get_user_groups_with_config, ACLRecordSet};
+    use std::io;
+
+    // Результат с учетом и без учета регистра
+    fn both(id: &str, user_id: &str, access: u8, db: &mut MemoryStorage) -> (u8, u8) {
+        let insensitive = AzConfig {
+            case_insensitive_ids: true,
+            ..AzConfig::default()
+        };
+        (
+            AzEngine::new(AzConfig::default()).authorize_dry(id, user_id, access, db).unwrap(),
+            AzEngine::new(insensitive).authorize_dry(id, user_id, access, db).unwrap(),
+        )
+    }
+
+    #[test]
+    fn subject_case_of_groups() {
+        let mut db = MemoryStorage::new();
+        db.add_membership("u1", "TD:Group", 15).unwrap();
+        db.add_membership("doc", "docs", 15).unwrap();
+        db.add_permission("docs", "td:group", 2).unwrap();
+
+        assert_eq!(both("doc", "u1", 2, &mut db), (0, 2));
+    }
+
+    #[test]
+    fn subject_case_of_the_user() {
+        let mut db = MemoryStorage::new();
+        db.add_permission("doc", "TD:User", 6).unwrap();
+
+        assert_eq!(both("doc", "td:user", 6, &mut db), (0, 6));
+        assert_eq!(both("doc", "TD:User", 6, &mut db), (6, 6));
+    }
+
+    #[test]
+    fn groups_differing_in_case_are_merged() {
+        let mut db = MemoryStorage::new();
+        db.add_membership("u1", "G", 2).unwrap();
+        db.add_membership("u1", "g", 4).unwrap();
+        db.add_permission("doc", "g", 6).unwrap();
+
+        assert_eq!(both("doc", "u1", 6, &mut db), (4, 6));
+    }
+
+    #[test]
+    fn object_keys_are_read_as_written() {
+        let mut db = MemoryStorage::new();
+        db.add_membership("doc", "Docs", 15).unwrap();
+        db.add_permission("docs", "u1", 2).unwrap();
+        db.add_permission("Doc", "u1", 4).unwrap();
+
+        assert_eq!(both("doc", "u1", 6, &mut db), (0, 0));
+        assert_eq!(both("Doc", "u1", 6, &mut db), (4, 4));
+    }
+
+    #[test]
+    fn superusers_ignore_case() {
+        let mut db = MemoryStorage::new();
+        db.add_membership("u1", "CFG:SuperUser", 15).unwrap();
+
+        let cfg = AzConfig {
+            superusers: vec!["cfg:superuser".to_owned()],
+            ..AzConfig::default()
+        };
+        let insensitive = AzConfig {
+            case_insensitive_ids: true,
+            ..cfg.clone()
+        };
+        assert_eq!(AzEngine::new(cfg).authorize_dry("doc", "u1", 2, &mut db).unwrap(), 0);
+        assert_eq!(AzEngine::new(insensitive).authorize_dry("doc", "u1", 2, &mut db).unwrap(), 2);
+    }
+
+    #[test]
+    fn deepest_chains_at_max_depth() {
+        let mut db = MemoryStorage::new();
+        for i in 0..300 {
+            db.add_membership(&format!("u{}", i), &format!("u{}", i + 1), 15).unwrap();
+            db.add_membership(&format!("d{}", i), &format!("d{}", i + 1), 15).unwrap();
+        }
+        db.add_permission("d255", "u255", 2).unwrap();
+        db.add_permission("d300", "u300", 4).unwrap();
+
+        let cfg = AzConfig {
+            max_subject_depth: 255,
+            max_object_depth: 255,
+            ..AzConfig::default()
+        };
+        assert_eq!(AzEngine::new(cfg).authorize_dry("d0", "u0", 6, &mut db).unwrap(), 2);
+    }
+
+    // v1-запись с опечаткой в метке членства
+    fn mistyped_marker(marker: &str, policy: UnknownMarkerPolicy) -> io::Result<ACLRecordSet> {
+        let mut db = MemoryStorage::new();
+        db.put("Mu1", &format!("g1;15;{};g2;15;;", marker)).unwrap();
+        let cfg = AzConfig {
+            unknown_marker_policy: policy,
+            ..AzConfig::default()
+        };
+        get_user_groups_with_config("u1", &mut db, &cfg)
+    }
+
+    #[test]
+    fn unknown_v1_markers_follow_the_policy() {
+        for marker in ["Y", "XX"] {
+            for policy in [UnknownMarkerPolicy::Ignore, UnknownMarkerPolicy::Warn] {
+                let groups = mistyped_marker(marker, policy).unwrap();
+                assert_eq!(groups.get("g1").map(|gr| gr.marker), Some(0 as char), "{} {:?}", marker, policy);
+            }
+
+            let groups = mistyped_marker(marker, UnknownMarkerPolicy::TreatAsExclusive).unwrap();
+            assert_eq!(groups.get("g1").map(|gr| gr.marker), Some(M_IS_EXCLUSIVE), "{}", marker);
+            assert_eq!(groups.get("g2").map(|gr| gr.marker), Some(0 as char));
+
+            let err = mistyped_marker(marker, UnknownMarkerPolicy::Error).err();
+            assert_eq!(err.map(|e| e.kind()), Some(io::ErrorKind::InvalidData), "{}", marker);
+        }
+    }
+
+    #[test]
+    fn known_v1_markers_are_not_affected() {
+        let groups = mistyped_marker("X", UnknownMarkerPolicy::Error).unwrap();
+        assert_eq!(groups.get("g1").map(|gr| gr.marker), Some(M_IS_EXCLUSIVE));
+        let groups = mistyped_marker("N", UnknownMarkerPolicy::TreatAsExclusive).unwrap();
+        assert_eq!(groups.get("g1").map(|gr| gr.marker), Some(M_IGNORE_EXCLUSIVE));
+    }
+}
@@ -0,0 +1,58 @@
+//! Checks of ACL data that the traversal tolerates but that usually point to indexer bugs upstream
+
+use crate::common::{Storage, MEMBERSHIP_PREFIX, M_IGNORE_EXCLUSIVE, M_IS_EXCLUSIVE};
+use crate::record_formats::{read_continued, record_version, RecordVersion};
+use crate::ACLRecordVec;
+use std::io;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConsistencyIssue {
+    /// The M-record of `uri` lists `uri` itself as a group
+    SelfReference { uri: String },
+    /// The M-record of `uri` gives `group` a marker other than `X`, `N` or none; see `AzConfig::unknown_marker_policy`
+    UnknownMarker { uri: String, group: String, marker: String },
+}
+
+/// Inspects the M-records of the given uris
+pub fn check_memberships(uris: &[&str], db: &mut dyn Storage) -> io::Result<Vec<ConsistencyIssue>> {
+    let mut res = Vec::new();
+
+    for uri in uris {
+        let Some(src) = read_continued(&(MEMBERSHIP_PREFIX.to_owned() + uri), db)? else {
+            continue;
+        };
+
+        let mut groups = ACLRecordVec::new();
+        db.decode_rec_to_rights(&src, &mut groups);
+
+        if groups.iter().any(|gr| gr.id == *uri) {
+            res.push(ConsistencyIssue::SelfReference {
+                uri: uri.to_string(),
+            });
+        }
+
+        for (group, marker) in unknown_markers(&src, &groups) {
+            res.push(ConsistencyIssue::UnknownMarker {
+                uri: uri.to_string(),
+                group,
+                marker,
+            });
+        }
+    }
+
+    Ok(res)
+}
+
+// v1 декодер заменяет длинную метку на U+FFFD, поэтому для отчета текст разбирается здесь
+fn unknown_markers(src: &str, groups: &ACLRecordVec) -> Vec<(String, String)> {
+    if record_version(src) == RecordVersion::V2 {
+        return groups
+            .iter()
+            .filter(|gr| ![0 as char, M_IS_EXCLUSIVE, M_IGNORE_EXCLUSIVE].contains(&gr.marker))
+            .map(|gr| (gr.id.clone(), gr.marker.to_string()))
+            .collect();
+    }
+
+    let tokens: Vec<&str> = src.split(';').collect();
+    tokens.chunks(3).filter(|chunk| chunk.len() == 3 && !["", "X", "N"].contains(&chunk[2])).map(|chunk| (chunk[0].to_owned(), chunk[2].to_owned())).collect()
+}
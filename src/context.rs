@@ -0,0 +1,57 @@
+//! Ready implementation of `AuthorizationContext` over any `Storage`.
+
+use crate::authorize_with_config;
+use crate::common::{AuthorizationContext, Storage, Trace, TraceBuffers};
+use crate::config::AzConfig;
+use std::io;
+
+/// Owns the storage and the configuration; every call starts from fresh traversal state
+pub struct DefaultAuthorizationContext<S: Storage> {
+    storage: S,
+    cfg: AzConfig,
+}
+
+impl<S: Storage> DefaultAuthorizationContext<S> {
+    pub fn new(storage: S) -> Self {
+        DefaultAuthorizationContext::with_config(storage, AzConfig::default())
+    }
+
+    pub fn with_config(storage: S, cfg: AzConfig) -> Self {
+        DefaultAuthorizationContext {
+            storage,
+            cfg,
+        }
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn storage_mut(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    pub fn config(&self) -> &AzConfig {
+        &self.cfg
+    }
+
+    pub fn set_config(&mut self, cfg: AzConfig) {
+        self.cfg = cfg;
+    }
+}
+
+impl<S: Storage> AuthorizationContext for DefaultAuthorizationContext<S> {
+    /// `_is_check_for_reload` is not used: the storage keeps its own data fresh
+    fn authorize(&mut self, uri: &str, user_uri: &str, request_access: u8, _is_check_for_reload: bool) -> io::Result<u8> {
+        let mut buf = TraceBuffers::default();
+        authorize_with_config(uri, user_uri, request_access, &mut self.storage, &mut buf.trace(false, false, false), &self.cfg)
+    }
+
+    fn authorize_and_trace(&mut self, uri: &str, user_uri: &str, request_access: u8, _is_check_for_reload: bool, trace: &mut Trace) -> io::Result<u8> {
+        authorize_with_config(uri, user_uri, request_access, &mut self.storage, trace, &self.cfg)
+    }
+}
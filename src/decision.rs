@@ -0,0 +1,67 @@
+#[cfg(feature = "bench")]
+use crate::stats::PhaseTimes;
+use crate::GrantProvenance;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Permission entry whose subject is one of the user's groups, on an object group the traversal reached
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MatchedPermission {
+    pub object_group: String,
+    pub subject_group: String,
+    /// Filter the permission was given with
+    pub filter: Option<String>,
+    /// Requested bits it gave
+    pub granted: u8,
+    /// Deny bits (`Cant*`) it carried within the requested access
+    pub denied: u8,
+    /// Who gave the permission, when and why, if the record keeps it
+    pub provenance: Option<GrantProvenance>,
+}
+
+/// Details of one authorization decision beyond the granted mask
+#[derive(Default, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Decision {
+    /// Resource the decision was made on
+    pub id: String,
+    pub requested: u8,
+    pub granted: u8,
+    /// Deny bits (`Cant*`) of matched permissions within the requested access
+    pub denied: u8,
+    /// Subject groups whose permissions contributed to `granted`, with the bits each one gave
+    pub granted_via: Vec<(String, u8)>,
+    /// Bits found by the first pass, over unfiltered permissions with the request cut down by the filter
+    pub first_pass: u8,
+    /// Bits added by the second pass over the permissions given with `filter`; `None` when no filter applies
+    pub filter_pass: Option<u8>,
+    /// Filter applied to the resource, if any
+    pub filter: Option<String>,
+    /// Permissions that gave or denied requested bits, in the order the traversal met them
+    pub matched_permissions: Vec<MatchedPermission>,
+    /// The user is an exclusive member of some group (`X` marker), so the resource has to belong to one
+    pub exclusive_required: bool,
+    /// The exclusive restriction is met, or there is none; false when the call ended before looking at the resource
+    pub exclusive_satisfied: bool,
+    /// Grants on sensitive groups that are not co-signed yet and were not honored: object group, subject group, bits
+    pub pending: Vec<(String, String, u8)>,
+    /// Bits each principal gave, when the user was resolved to several principals; a subject group reached
+    /// by more than one of them is counted for the first
+    pub principals: Vec<(String, u8)>,
+    /// The user's group set hit `AzConfig::max_subject_groups` and was cut down
+    pub subject_groups_truncated: bool,
+    /// Entry of `AzConfig::superusers` the user matched; the resource was not looked at
+    pub superuser: Option<String>,
+    /// Membership edges from the user to the deepest subject group found
+    pub max_subject_depth: u8,
+    /// Membership edges from the resource to the deepest object group visited
+    pub max_object_depth: u8,
+    /// Depth of each subject group of `granted_via`, 0 for the user itself
+    pub group_depths: Vec<(String, u8)>,
+    /// Membership edges from a subject to an individual acting as its group (`AzConfig::individual_prefixes`),
+    /// as member and individual, in the order the subject traversal met them
+    pub individual_hops: Vec<(String, String)>,
+    #[cfg(feature = "bench")]
+    pub phases: PhaseTimes,
+}
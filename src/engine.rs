@@ -0,0 +1,532 @@
+use crate::abuse::AbuseDetector;
+use crate::audit::{AuditEvent, AuditSink, GroupHook, ProvenanceRecord, RecordingStorage, TraceSink, WarningSink, WatchTrace};
+use crate::common::{print_to_trace_info, Storage, Trace, TraceBuffers};
+use crate::config::{AzConfig, AzConfigHandle};
+use crate::decision::Decision;
+use crate::heatmap::json_string;
+use crate::implicit_groups::ImplicitGroupProvider;
+use crate::membership_cache::MembershipCache;
+use crate::principals::{PrincipalResolver, PrincipalSet};
+use crate::stats::StatsAggregator;
+#[cfg(feature = "bench")]
+use crate::stats::TimingStorage;
+use crate::workload::{Lane, LaneConfig, Workload};
+use crate::{authorize_dry_with_hooks, authorize_with_hooks, pad_denial_time, AzHooks, ObjectGroupHits};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Instant;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Cargo features the crate was built with
+const FEATURES: [(&str, bool); 8] = [
+    ("signing", cfg!(feature = "signing")),
+    ("serde", cfg!(feature = "serde")),
+    ("integration", cfg!(feature = "integration")),
+    ("indexer", cfg!(feature = "indexer")),
+    ("testkit", cfg!(feature = "testkit")),
+    ("bench", cfg!(feature = "bench")),
+    ("lmdb", cfg!(feature = "lmdb")),
+    ("redis", cfg!(feature = "redis")),
+];
+
+/// What a running engine actually does: the config in effect, the handle ceiling, the features compiled in
+/// and the hooks installed
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EngineDescription {
+    pub settings: Vec<(String, String)>,
+    /// Ceiling of the config handle, no config update can raise `capability_ceiling` above it
+    pub handle_ceiling: u8,
+    pub features: Vec<String>,
+    pub hooks: Vec<String>,
+    pub membership_cache_capacity: Option<usize>,
+    pub watchpoints: Vec<String>,
+    /// Configured workload lanes with their settings
+    pub lanes: Vec<(String, String)>,
+}
+
+impl EngineDescription {
+    /// Serialized without serde, for support dumps and health endpoints
+    pub fn to_json(&self) -> String {
+        let strings = |items: &[String]| items.iter().map(|s| json_string(s)).collect::<Vec<_>>().join(",");
+        let settings: Vec<String> = self.settings.iter().map(|(name, value)| format!("{}:{}", json_string(name), json_string(value))).collect();
+
+        format!(
+            "{{\"settings\":{{{}}},\"handle_ceiling\":{},\"features\":[{}],\"hooks\":[{}],\"membership_cache_capacity\":{},\"watchpoints\":[{}],\"lanes\":{{{}}}}}",
+            settings.join(","),
+            self.handle_ceiling,
+            strings(&self.features),
+            strings(&self.hooks),
+            self.membership_cache_capacity.map_or("null".to_owned(), |n| n.to_string()),
+            strings(&self.watchpoints),
+            self.lanes.iter().map(|(name, value)| format!("{}:{}", json_string(name), json_string(value))).collect::<Vec<_>>().join(",")
+        )
+    }
+}
+
+/// Hooks scoped to object groups, with the union of their groups looked for during the traversal
+#[derive(Clone, Default)]
+struct GroupHooks {
+    hooks: Vec<(HashSet<String>, Arc<dyn GroupHook>)>,
+    watched: HashSet<String>,
+}
+
+static DEFAULT_ENGINE: OnceLock<AzEngine> = OnceLock::new();
+
+/// Makes `engine` the one behind the free `authorize` function, so call sites not yet migrated to an engine
+/// get its configuration, audit and stats. Only the first call wins, and only before the first `authorize`;
+/// false when the default engine was already in place
+pub fn install_default_engine(engine: AzEngine) -> bool {
+    DEFAULT_ENGINE.set(engine).is_ok()
+}
+
+/// Engine behind the free `authorize` function; default configuration unless one was installed
+pub fn default_engine() -> &'static AzEngine {
+    DEFAULT_ENGINE.get_or_init(AzEngine::default)
+}
+
+/// Long-lived authorization engine.
+///
+/// Holds the configuration handle shared with operator tooling; the storage stays with the caller,
+/// as with the free `authorize` function.
+#[derive(Clone, Default)]
+pub struct AzEngine {
+    config: AzConfigHandle,
+    audit_sink: Option<Arc<dyn AuditSink>>,
+    group_hooks: Arc<GroupHooks>,
+    abuse_detector: Option<Arc<dyn AbuseDetector>>,
+    implicit_groups: Option<Arc<dyn ImplicitGroupProvider>>,
+    last_provenance_hash: Arc<Mutex<[u8; 32]>>,
+    stats: Option<Arc<StatsAggregator>>,
+    trace_sink: Option<Arc<dyn TraceSink>>,
+    membership_cache: Option<Arc<MembershipCache>>,
+    warning_sink: Option<Arc<dyn WarningSink>>,
+    principal_resolver: Option<Arc<dyn PrincipalResolver>>,
+    watchpoints: Arc<RwLock<HashSet<String>>>,
+    lanes: [Option<Arc<Lane>>; 2],
+}
+
+impl AzEngine {
+    pub fn new(cfg: AzConfig) -> Self {
+        AzEngine {
+            config: AzConfigHandle::new(cfg),
+            audit_sink: None,
+            group_hooks: Arc::default(),
+            abuse_detector: None,
+            implicit_groups: None,
+            last_provenance_hash: Arc::default(),
+            stats: None,
+            trace_sink: None,
+            membership_cache: None,
+            warning_sink: None,
+            principal_resolver: None,
+            watchpoints: Arc::default(),
+            lanes: Default::default(),
+        }
+    }
+
+    pub fn with_config_handle(config: AzConfigHandle) -> Self {
+        AzEngine {
+            config,
+            audit_sink: None,
+            group_hooks: Arc::default(),
+            abuse_detector: None,
+            implicit_groups: None,
+            last_provenance_hash: Arc::default(),
+            stats: None,
+            trace_sink: None,
+            membership_cache: None,
+            warning_sink: None,
+            principal_resolver: None,
+            watchpoints: Arc::default(),
+            lanes: Default::default(),
+        }
+    }
+
+    /// Engine that never grants bits outside of `ceiling`, whatever the ACL content and later config updates;
+    /// e.g. `presets::VIEWER` for a read-only replica
+    pub fn with_capability_ceiling(cfg: AzConfig, ceiling: u8) -> Self {
+        AzEngine::with_config_handle(AzConfigHandle::with_ceiling(cfg, ceiling))
+    }
+
+    pub fn config(&self) -> &AzConfigHandle {
+        &self.config
+    }
+
+    pub fn set_audit_sink(&mut self, sink: Arc<dyn AuditSink>) {
+        self.audit_sink = Some(sink);
+    }
+
+    /// `hook` gets the decisions on resources found in any of `object_groups` during the traversal. While
+    /// group hooks are installed the traversal does not stop at the first full set of rights, so every group
+    /// of the resource is seen; superuser decisions are made without a traversal and are not reported
+    pub fn add_group_hook(&mut self, object_groups: &[&str], hook: Arc<dyn GroupHook>) {
+        let group_hooks = Arc::make_mut(&mut self.group_hooks);
+        group_hooks.watched.extend(object_groups.iter().map(|gr| gr.to_string()));
+        group_hooks.hooks.push((object_groups.iter().map(|gr| gr.to_string()).collect(), hook));
+    }
+
+    pub fn set_abuse_detector(&mut self, detector: Arc<dyn AbuseDetector>) {
+        self.abuse_detector = Some(detector);
+    }
+
+    pub fn set_implicit_group_provider(&mut self, provider: Arc<dyn ImplicitGroupProvider>) {
+        self.implicit_groups = Some(provider);
+    }
+
+    /// Every decision made through the engine is counted in `stats`
+    pub fn set_stats_aggregator(&mut self, stats: Arc<StatsAggregator>) {
+        self.stats = Some(stats);
+    }
+
+    /// Shared between engines that read the same store
+    pub fn set_membership_cache(&mut self, cache: Arc<MembershipCache>) {
+        self.membership_cache = Some(cache);
+    }
+
+    pub fn set_warning_sink(&mut self, sink: Arc<dyn WarningSink>) {
+        self.warning_sink = Some(sink);
+    }
+
+    /// User ids passed to the engine are resolved to canonical principals before evaluation
+    pub fn set_principal_resolver(&mut self, resolver: Arc<dyn PrincipalResolver>) {
+        self.principal_resolver = Some(resolver);
+    }
+
+    pub fn set_trace_sink(&mut self, sink: Arc<dyn TraceSink>) {
+        self.trace_sink = Some(sink);
+    }
+
+    /// Settings of the lane for `workload`, used by `authorize_in`
+    pub fn set_lane(&mut self, workload: Workload, lane: LaneConfig) {
+        self.lanes[workload.index()] = Some(Arc::new(Lane::new(lane)));
+    }
+
+    /// Calls of `workload` running now
+    pub fn lane_in_flight(&self, workload: Workload) -> usize {
+        self.lanes[workload.index()].as_ref().map_or(0, |lane| lane.in_flight())
+    }
+
+    /// Decisions on `id` as resource or as user are traced in full and sent to the trace sink,
+    /// whatever trace the caller asked for
+    pub fn watch(&self, id: &str) {
+        self.watchpoints.write().unwrap_or_else(|e| e.into_inner()).insert(id.to_owned());
+    }
+
+    pub fn unwatch(&self, id: &str) {
+        self.watchpoints.write().unwrap_or_else(|e| e.into_inner()).remove(id);
+    }
+
+    /// Snapshot of the effective configuration, taken as the next authorize call would see it
+    pub fn describe(&self) -> EngineDescription {
+        let hooks = [
+            ("audit_sink", self.audit_sink.is_some()),
+            ("group_hooks", !self.group_hooks.hooks.is_empty()),
+            ("abuse_detector", self.abuse_detector.is_some()),
+            ("implicit_groups", self.implicit_groups.is_some()),
+            ("stats", self.stats.is_some()),
+            ("trace_sink", self.trace_sink.is_some()),
+            ("membership_cache", self.membership_cache.is_some()),
+            ("warning_sink", self.warning_sink.is_some()),
+            ("principal_resolver", self.principal_resolver.is_some()),
+        ];
+        let mut watchpoints: Vec<String> = self.watchpoints.read().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect();
+        watchpoints.sort();
+
+        EngineDescription {
+            settings: self.config.load().settings().into_iter().map(|(name, value)| (name.to_owned(), value)).collect(),
+            handle_ceiling: self.config.ceiling(),
+            features: FEATURES.iter().filter(|(_, on)| *on).map(|(name, _)| name.to_string()).collect(),
+            hooks: hooks.iter().filter(|(_, on)| *on).map(|(name, _)| name.to_string()).collect(),
+            membership_cache_capacity: self.membership_cache.as_ref().map(|c| c.capacity()),
+            watchpoints,
+            lanes: Workload::ALL.iter().filter_map(|w| self.lanes[w.index()].as_ref().map(|lane| (w.name().to_owned(), describe_lane(&lane.cfg)))).collect(),
+        }
+    }
+
+    fn is_watched(&self, id: &str, user_id: &str) -> bool {
+        if self.trace_sink.is_none() {
+            return false;
+        }
+        let watchpoints = self.watchpoints.read().unwrap_or_else(|e| e.into_inner());
+        !watchpoints.is_empty() && (watchpoints.contains(id) || watchpoints.contains(user_id))
+    }
+
+    fn hooks(&self) -> AzHooks<'_> {
+        AzHooks {
+            implicit_groups: self.implicit_groups.as_deref(),
+            membership_cache: self.membership_cache.as_deref(),
+            warnings: self.warning_sink.as_deref(),
+            principals: self.principal_resolver.as_deref(),
+            subject_memo: None,
+            trace_info: None,
+            object_group_hits: None,
+        }
+    }
+
+    pub fn authorize(&self, id: &str, user_id: &str, request_access: u8, db: &mut dyn Storage, trace: &mut Trace) -> io::Result<u8> {
+        self.authorize_correlated(id, user_id, request_access, None, db, trace)
+    }
+
+    /// Same as `authorize`, in the lane of `workload`: waits for a free slot when the lane is full, uses the
+    /// lane's membership cache and stats where the lane has them. Without lane settings it is plain `authorize`
+    pub fn authorize_in(&self, workload: Workload, id: &str, user_id: &str, request_access: u8, db: &mut dyn Storage, trace: &mut Trace) -> io::Result<u8> {
+        let Some(lane) = &self.lanes[workload.index()] else {
+            return self.authorize(id, user_id, request_access, db, trace);
+        };
+
+        let _permit = lane.acquire();
+        let engine = AzEngine {
+            membership_cache: lane.cfg.membership_cache.clone().or_else(|| self.membership_cache.clone()),
+            stats: lane.cfg.stats.clone().or_else(|| self.stats.clone()),
+            ..self.clone()
+        };
+        engine.authorize(id, user_id, request_access, db, trace)
+    }
+
+    /// Evaluates the request for the user together with other principals (`cfg:Guest`, `cfg:AllUsers`, ...) in one traversal;
+    /// the engine's principal resolver is not applied
+    pub fn authorize_principals(&self, id: &str, principals: &[&str], request_access: u8, db: &mut dyn Storage, trace: &mut Trace) -> io::Result<u8> {
+        let Some(user_id) = principals.first() else {
+            return Ok(0);
+        };
+        let engine = AzEngine {
+            principal_resolver: Some(Arc::new(PrincipalSet::new(principals))),
+            ..self.clone()
+        };
+        engine.authorize(id, user_id, request_access, db, trace)
+    }
+
+    /// Evaluates with the engine's configuration only: no audit, stats, abuse detection or watchpoints,
+    /// reads bypass caching layers
+    pub fn authorize_dry(&self, id: &str, user_id: &str, request_access: u8, db: &mut dyn Storage) -> io::Result<u8> {
+        authorize_dry_with_hooks(id, user_id, request_access, db, &self.config.load(), &self.hooks())
+    }
+
+    /// Same as `authorize`, the `correlation_id` is written to the trace, passed to the audit sink and kept
+    /// as a stats exemplar
+    pub fn authorize_correlated(
+        &self,
+        id: &str,
+        user_id: &str,
+        request_access: u8,
+        correlation_id: Option<&str>,
+        db: &mut dyn Storage,
+        trace: &mut Trace,
+    ) -> io::Result<u8> {
+        self.authorize_traced(id, user_id, request_access, correlation_id, None, db, trace, &mut Decision::default())
+    }
+
+    /// Same as `authorize`, returns the whole decision: the permissions that gave the bits, the filter applied,
+    /// the exclusivity outcome. Permissions are listed up to the one completing the request, as the traversal
+    /// stops there
+    pub fn authorize_ex(&self, id: &str, user_id: &str, request_access: u8, db: &mut dyn Storage) -> io::Result<Decision> {
+        let mut buf = TraceBuffers::default();
+        let mut decision = Decision::default();
+        self.authorize_traced(id, user_id, request_access, None, None, db, &mut buf.trace(false, false, false), &mut decision)?;
+        Ok(decision)
+    }
+
+    /// `service_user` asks on behalf of `on_behalf_of`: the rights are those of `on_behalf_of`, narrowed to the
+    /// ceiling of the service account (`AzConfig::service_ceilings`); audit events carry both identities
+    pub fn authorize_as(
+        &self,
+        service_user: &str,
+        on_behalf_of: &str,
+        id: &str,
+        request_access: u8,
+        db: &mut dyn Storage,
+        trace: &mut Trace,
+    ) -> io::Result<u8> {
+        if trace.is_info {
+            print_to_trace_info(trace, format!("service user {} acts on behalf of {}\n", service_user, on_behalf_of));
+        }
+        self.authorize_traced(id, on_behalf_of, request_access, None, Some(service_user), db, trace, &mut Decision::default())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn authorize_traced(
+        &self,
+        id: &str,
+        user_id: &str,
+        request_access: u8,
+        correlation_id: Option<&str>,
+        service_user: Option<&str>,
+        db: &mut dyn Storage,
+        trace: &mut Trace,
+        decision: &mut Decision,
+    ) -> io::Result<u8> {
+        // Наблюдаемые идентификаторы трассируются полностью в отдельные буферы
+        if let (true, Some(sink)) = (self.is_watched(id, user_id), &self.trace_sink) {
+            let mut buf = TraceBuffers::default();
+            let res = self.decide(id, user_id, request_access, correlation_id, service_user, db, &mut buf.trace(true, true, true), decision);
+            sink.on_trace(&WatchTrace {
+                correlation_id,
+                id,
+                user_id,
+                result: &res,
+                acl: &buf.acl,
+                group: &buf.group,
+                info: &buf.info,
+            });
+            return res;
+        }
+
+        self.decide(id, user_id, request_access, correlation_id, service_user, db, trace, decision)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn decide(
+        &self,
+        id: &str,
+        user_id: &str,
+        request_access: u8,
+        correlation_id: Option<&str>,
+        service_user: Option<&str>,
+        db: &mut dyn Storage,
+        trace: &mut Trace,
+        decision: &mut Decision,
+    ) -> io::Result<u8> {
+        let mut cfg = self.config.load();
+
+        // Права пользователя не шире потолка сервисной учетной записи, от имени которой идет запрос
+        if let Some(ceiling) = service_user.and_then(|s| cfg.service_ceilings.get(s)) {
+            if cfg.capability_ceiling & !ceiling != 0 {
+                let mut narrowed = (*cfg).clone();
+                narrowed.capability_ceiling &= ceiling;
+                cfg = Arc::new(narrowed);
+            }
+        }
+
+        if trace.is_info {
+            if let Some(cid) = correlation_id {
+                print_to_trace_info(trace, format!("correlation_id={}\n", cid));
+            }
+        }
+
+        let mut touched = None;
+        decision.requested = request_access;
+
+        #[cfg(feature = "bench")]
+        let mut tdb = TimingStorage::new(db);
+        #[cfg(feature = "bench")]
+        let db: &mut dyn Storage = &mut tdb;
+        #[cfg(feature = "bench")]
+        let mut recording_hash_ns = 0;
+
+        // Группы объектов отмечаются только при установленных обработчиках групп
+        let group_hits = (!self.group_hooks.hooks.is_empty()).then(|| ObjectGroupHits {
+            watched: &self.group_hooks.watched,
+            met: RefCell::new(Vec::new()),
+        });
+        let hooks = AzHooks {
+            object_group_hits: group_hits.as_ref(),
+            ..self.hooks()
+        };
+
+        let start = Instant::now();
+        let res = match &self.abuse_detector {
+            Some(detector) if !detector.admit(user_id) => {
+                if trace.is_info {
+                    print_to_trace_info(trace, format!("user {} is throttled by abuse detector\n", user_id));
+                }
+                // Отказ по ограничению не должен отличаться по времени от прочих отказов
+                pad_denial_time(start, &cfg);
+                Ok(0)
+            },
+            _ if cfg.audit_provenance && self.audit_sink.is_some() => {
+                let mut rdb = RecordingStorage {
+                    inner: db,
+                    touched: Vec::new(),
+                    #[cfg(feature = "bench")]
+                    hash_ns: 0,
+                };
+                let res = authorize_with_hooks(id, user_id, request_access, &mut rdb, trace, &cfg, &hooks, decision);
+                #[cfg(feature = "bench")]
+                {
+                    recording_hash_ns = rdb.hash_ns;
+                }
+                touched = Some(rdb.touched);
+                res
+            },
+            _ => authorize_with_hooks(id, user_id, request_access, db, trace, &cfg, &hooks, decision),
+        };
+
+        if let (Some(detector), Ok(r)) = (&self.abuse_detector, &res) {
+            detector.on_decision(user_id, id, request_access, *r);
+        }
+
+        #[cfg(feature = "bench")]
+        {
+            let phases = &mut decision.phases;
+            phases.storage_ns = tdb.storage_ns;
+            phases.decode_ns = tdb.decode_ns.get();
+            phases.hash_ns = recording_hash_ns;
+            phases.traversal_ns = phases.traversal_ns.saturating_sub(phases.storage_ns + phases.decode_ns + phases.hash_ns);
+        }
+
+        #[cfg(feature = "bench")]
+        let start = std::time::Instant::now();
+        let provenance = touched.map(|touched| {
+            let mut last = self.last_provenance_hash.lock().unwrap_or_else(|e| e.into_inner());
+            let rec = ProvenanceRecord::new(*last, id, user_id, request_access, res.as_ref().ok().copied(), touched);
+            *last = rec.hash;
+            rec
+        });
+        #[cfg(feature = "bench")]
+        {
+            decision.phases.hash_ns += start.elapsed().as_nanos() as u64;
+        }
+
+        let event = AuditEvent {
+            correlation_id,
+            id,
+            user_id,
+            service_user,
+            request_access,
+            result: &res,
+            decision,
+            provenance: provenance.as_ref(),
+        };
+        if let Some(sink) = &self.audit_sink {
+            if decision.superuser.is_some() {
+                sink.on_superuser(&event);
+            } else {
+                sink.on_decision(&event);
+            }
+        }
+
+        if let Some(hits) = &group_hits {
+            let met = hits.met.borrow();
+            for (groups, hook) in &self.group_hooks.hooks {
+                let matched: Vec<&str> = met.iter().filter(|gr| groups.contains(*gr)).map(|gr| gr.as_str()).collect();
+                if !matched.is_empty() {
+                    hook.on_group_decision(&event, &matched);
+                }
+            }
+        }
+
+        if let Some(stats) = &self.stats {
+            stats.record_correlated(decision, &res, correlation_id);
+        }
+
+        res
+    }
+}
+
+fn describe_lane(lane: &LaneConfig) -> String {
+    format!(
+        "max_concurrent={},membership_cache={},stats={}",
+        lane.max_concurrent.map_or("none".to_owned(), |n| n.to_string()),
+        lane.membership_cache.as_ref().map_or("shared".to_owned(), |c| c.capacity().to_string()),
+        if lane.stats.is_some() {
+            "own"
+        } else {
+            "shared"
+        }
+    )
+}
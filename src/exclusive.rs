@@ -0,0 +1,147 @@
+//! Exclusive restrictions of one user checked against a set of resources, for bulk operations such as
+//! mass archiving.
+//!
+//! `authorize` repeats the whole analysis for every document: the user's groups, the X-marked ones among
+//! them, then the object groups of the document. Here the subject side is resolved once and each resource
+//! only walks its M-records looking for an exclusive group, as `authorize` does; P-records are not read,
+//! so the result says nothing about the rights themselves.
+
+use crate::common::{decode_memberships, get_aliases, on_self_reference, Storage, MEMBERSHIP_PREFIX, M_IS_EXCLUSIVE};
+use crate::config::{AzConfig, BlankIdPolicy};
+use crate::record_formats::read_continued;
+use crate::{fold_subject_case, resolve_subject, ACLRecord, ACLRecordVec};
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+pub struct ExclusiveCheck {
+    /// X-marked groups of the user; empty means no resource is restricted for them
+    pub exclusive_groups: Vec<String>,
+    /// One flag per requested resource, in the order given: the restrictions are satisfied
+    pub satisfied: Vec<bool>,
+}
+
+impl ExclusiveCheck {
+    pub fn all_satisfied(&self) -> bool {
+        self.satisfied.iter().all(|s| *s)
+    }
+}
+
+/// Evaluates the exclusive restrictions of `user_id` for every id in `ids` at once.
+/// A blank resource id is unsatisfied under `BlankIdPolicy::Deny` and an error under `BlankIdPolicy::Error`
+pub fn check_exclusive(ids: &[&str], user_id: &str, db: &mut dyn Storage, cfg: &AzConfig) -> io::Result<ExclusiveCheck> {
+    if let Some(blank) = std::iter::once(&user_id).chain(ids.iter()).find(|id| id.trim().is_empty()) {
+        if cfg.blank_id_policy == BlankIdPolicy::Error {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("blank id: uri=[{}], user=[{}]", blank, user_id)));
+        }
+    }
+    if user_id.trim().is_empty() {
+        return Ok(ExclusiveCheck {
+            exclusive_groups: Vec::new(),
+            satisfied: vec![false; ids.len()],
+        });
+    }
+
+    let user_id = cfg.key_schema.encode_id(user_id);
+    let (mut subject_groups, exclusive_groups) = resolve_subject(&user_id, db, cfg)?;
+    if cfg.case_insensitive_ids {
+        fold_subject_case(&mut subject_groups, cfg);
+    }
+
+    // Суперпользователь и пользователь без исключительных групп ограничений не имеют
+    let is_superuser = cfg.superusers.iter().any(|su| subject_groups.contains_key(cfg.subject_key(su).as_ref()));
+    if exclusive_groups.is_empty() || is_superuser {
+        return Ok(ExclusiveCheck {
+            exclusive_groups,
+            satisfied: ids.iter().map(|id| !id.trim().is_empty()).collect(),
+        });
+    }
+
+    let mut satisfied = Vec::with_capacity(ids.len());
+    for id in ids {
+        if id.trim().is_empty() {
+            satisfied.push(false);
+            continue;
+        }
+        let id = cfg.key_schema.encode_id(id);
+        satisfied.push(find_exclusive(&id, 0, &subject_groups, &mut HashSet::new(), db, cfg)?);
+    }
+
+    Ok(ExclusiveCheck {
+        exclusive_groups,
+        satisfied,
+    })
+}
+
+// Повторяет поиск исключительной группы из prepare_obj_group без чтения прав
+fn find_exclusive(
+    uri: &str,
+    level: u8,
+    subject_groups: &HashMap<String, ACLRecord>,
+    walked: &mut HashSet<String>,
+    db: &mut dyn Storage,
+    cfg: &AzConfig,
+) -> io::Result<bool> {
+    if level > cfg.max_object_depth {
+        return Ok(false);
+    }
+
+    db.fiber_yield();
+
+    let mut aliases = ACLRecordVec::new();
+    if cfg.group_aliases {
+        get_aliases(uri, db, &mut aliases)?;
+    }
+
+    let groups = &mut ACLRecordVec::new();
+    match read_continued(&(MEMBERSHIP_PREFIX.to_owned() + uri), db)? {
+        None if aliases.is_empty() => return Ok(level == 0),
+        Some(groups_str) if !groups_str.is_empty() => {
+            decode_memberships(&groups_str, uri, db, cfg, &*cfg.clock, groups)?;
+        },
+        _ => {},
+    }
+    groups.extend(aliases);
+
+    if groups.is_empty() {
+        return Ok(true);
+    }
+
+    let mut is_contain_suffix_group = false;
+    for (idx, group) in groups.iter().enumerate() {
+        if group.id.is_empty() || !cfg.is_in_scope(&group.id) {
+            continue;
+        }
+
+        if level == 0 {
+            if group.id.contains("_group") {
+                is_contain_suffix_group = true;
+            }
+
+            if (idx == groups.len() - 1 && !is_contain_suffix_group) || group.id.contains("cfg:TTLResourcesGroup") {
+                return Ok(true);
+            }
+        }
+
+        if (level == 0 || uri.contains("_group")) && subject_groups.get(cfg.subject_key(&group.id).as_ref()).is_some_and(|gr| gr.marker == M_IS_EXCLUSIVE) {
+            return Ok(true);
+        }
+
+        if group.marker == M_IS_EXCLUSIVE || !walked.insert(group.id.clone()) {
+            continue;
+        }
+
+        if uri == group.id {
+            on_self_reference(cfg, uri)?;
+            continue;
+        }
+
+        let Some(next_level) = level.checked_add(1) else {
+            continue;
+        };
+        if find_exclusive(&group.id, next_level, subject_groups, walked, db, cfg)? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
@@ -0,0 +1,223 @@
+//! Explanations built from `Decision`: denial messages for end users and the filter passes for administrators.
+//!
+//! Message templates use the placeholders `{id}`, `{need}`, `{have}` and `{via}`; bundles for `en` and `ru`
+//! are built in, other languages come from a caller-provided `MessageCatalog`.
+
+use crate::common::{access_to_pretty_string, ACCESS_8_LIST};
+use crate::decision::Decision;
+
+pub struct MessageBundle {
+    /// Names of Create, Read, Update, Delete
+    pub rights: [String; 4],
+    pub separator: String,
+    pub need: String,
+    pub have_via: String,
+    pub have_nothing: String,
+    /// Used instead of `need` when the missing bits are denied explicitly
+    pub denied: String,
+}
+
+impl MessageBundle {
+    pub fn en() -> Self {
+        MessageBundle {
+            rights: ["Create".to_owned(), "Read".to_owned(), "Update".to_owned(), "Delete".to_owned()],
+            separator: ", ".to_owned(),
+            need: "You need {need} permission on {id}".to_owned(),
+            have_via: "; you only have {have} via group {via}".to_owned(),
+            have_nothing: "; you have no permissions on it".to_owned(),
+            denied: "{need} on {id} is explicitly denied to you".to_owned(),
+        }
+    }
+
+    pub fn ru() -> Self {
+        MessageBundle {
+            rights: ["Создание".to_owned(), "Чтение".to_owned(), "Изменение".to_owned(), "Удаление".to_owned()],
+            separator: ", ".to_owned(),
+            need: "Необходимо право «{need}» на {id}".to_owned(),
+            have_via: "; у вас есть только «{have}» через группу {via}".to_owned(),
+            have_nothing: "; у вас нет прав на этот объект".to_owned(),
+            denied: "Право «{need}» на {id} вам явно запрещено".to_owned(),
+        }
+    }
+
+    fn rights_to_string(&self, access: u8) -> String {
+        let names: Vec<&str> = ACCESS_8_LIST.iter().zip(self.rights.iter()).filter(|(bit, _)| access & **bit != 0).map(|(_, n)| n.as_str()).collect();
+        names.join(&self.separator)
+    }
+}
+
+/// Source of message bundles by locale (`en`, `ru-RU`, ...)
+pub trait MessageCatalog {
+    fn bundle(&self, locale: &str) -> Option<&MessageBundle>;
+}
+
+pub struct BuiltinCatalog {
+    en: MessageBundle,
+    ru: MessageBundle,
+}
+
+impl Default for BuiltinCatalog {
+    fn default() -> Self {
+        BuiltinCatalog {
+            en: MessageBundle::en(),
+            ru: MessageBundle::ru(),
+        }
+    }
+}
+
+impl MessageCatalog for BuiltinCatalog {
+    fn bundle(&self, locale: &str) -> Option<&MessageBundle> {
+        match language(locale).as_str() {
+            "en" => Some(&self.en),
+            "ru" => Some(&self.ru),
+            _ => None,
+        }
+    }
+}
+
+fn language(locale: &str) -> String {
+    locale.split(['-', '_']).next().unwrap_or_default().to_ascii_lowercase()
+}
+
+/// Message for a decision that did not grant everything requested, `None` otherwise; unknown locales fall back to `en`
+pub fn denial_message(decision: &Decision, locale: &str) -> Option<String> {
+    denial_message_with(decision, locale, &BuiltinCatalog::default())
+}
+
+/// Same as `denial_message`, looking the locale up in `catalog` first
+pub fn denial_message_with(decision: &Decision, locale: &str, catalog: &dyn MessageCatalog) -> Option<String> {
+    let missing = decision.requested & !decision.granted & 0x0F;
+    if missing == 0 {
+        return None;
+    }
+
+    let builtin;
+    let bundle = match catalog.bundle(locale) {
+        Some(b) => b,
+        None => {
+            builtin = BuiltinCatalog::default();
+            builtin.bundle(locale).unwrap_or(&builtin.en)
+        },
+    };
+
+    let explicit = (decision.denied >> 4) & missing;
+    if explicit != 0 {
+        return Some(bundle.denied.replace("{id}", &decision.id).replace("{need}", &bundle.rights_to_string(explicit)));
+    }
+
+    let mut res = bundle.need.replace("{id}", &decision.id).replace("{need}", &bundle.rights_to_string(missing));
+
+    let have = decision.granted & 0x0F;
+    if have == 0 {
+        res.push_str(&bundle.have_nothing);
+    } else {
+        let via: Vec<&str> = decision.granted_via.iter().filter(|(_, bits)| bits & have != 0).map(|(gr, _)| gr.as_str()).collect();
+        res.push_str(&bundle.have_via.replace("{have}", &bundle.rights_to_string(have)).replace("{via}", &via.join(&bundle.separator)));
+    }
+
+    Some(res)
+}
+
+/// Where a granted bit came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BitSource {
+    /// Unfiltered permission, found by the first pass
+    BaseGrant,
+    /// Permission given with the filter, found by the second pass
+    Filter(String),
+}
+
+/// Source of every granted bit, in `ACCESS_8_LIST` order
+pub fn bit_sources(decision: &Decision) -> Vec<(u8, BitSource)> {
+    let mut res = Vec::new();
+    for bit in ACCESS_8_LIST.iter().filter(|b| decision.granted & **b != 0) {
+        let source = match (&decision.filter, decision.filter_pass) {
+            (Some(filter), Some(bits)) if bits & bit != 0 => BitSource::Filter(filter.clone()),
+            _ => BitSource::BaseGrant,
+        };
+        res.push((*bit, source));
+    }
+    res
+}
+
+/// Both pass masks and the source of each granted bit, for administrators
+pub fn explain_passes(decision: &Decision) -> String {
+    if let Some(su) = &decision.superuser {
+        return format!("superuser {}: {}\n", su, access_to_pretty_string(decision.granted).trim());
+    }
+    let mut res = format!("first pass: {}", access_to_pretty_string(decision.first_pass));
+    match (&decision.filter, decision.filter_pass) {
+        (Some(filter), Some(bits)) => res.push_str(&format!(", filter pass [{}]: {}", filter, access_to_pretty_string(bits))),
+        _ => res.push_str(", no filter"),
+    }
+    res.push('\n');
+
+    for (bit, source) in bit_sources(decision) {
+        let source = match source {
+            BitSource::BaseGrant => "base grant".to_owned(),
+            BitSource::Filter(filter) => format!("filter {}", filter),
+        };
+        res.push_str(&format!("{}: {}\n", access_to_pretty_string(bit).trim(), source));
+    }
+    for (object_group, subject_group, bits) in &decision.pending {
+        res.push_str(&format!("{}: pending co-signature, group {} on {}\n", access_to_pretty_string(*bits).trim(), subject_group, object_group));
+    }
+    res
+}
+
+/// Bits given by each principal of a decision made for a principal set
+pub fn explain_principals(decision: &Decision) -> String {
+    let mut res = String::new();
+    for (principal, bits) in &decision.principals {
+        res.push_str(&format!("{}: {}\n", principal, access_to_pretty_string(*bits).trim()));
+    }
+    res
+}
+
+/// Subject groups that gave rights with their depth, how deep both traversals went and the hops through
+/// individuals acting as groups
+pub fn explain_depths(decision: &Decision) -> String {
+    let mut res = format!("subject depth: {}, object depth: {}\n", decision.max_subject_depth, decision.max_object_depth);
+    for (group, depth) in &decision.group_depths {
+        res.push_str(&format!("{}: level {}{}\n", group, depth, individual_label(decision, group)));
+    }
+    for (member, individual) in &decision.individual_hops {
+        res.push_str(&format!("{} -> {}: individual as group\n", member, individual));
+    }
+    res
+}
+
+/// Matched permissions with who gave them, when and why, where the records keep it
+pub fn explain_grants(decision: &Decision) -> String {
+    let mut res = String::new();
+    for m in &decision.matched_permissions {
+        res.push_str(&format!(
+            "{}{} on {}: {}",
+            m.subject_group,
+            individual_label(decision, &m.subject_group),
+            m.object_group,
+            access_to_pretty_string(m.granted | m.denied).trim()
+        ));
+        if let Some(p) = &m.provenance {
+            if let Some(granted_by) = &p.granted_by {
+                res.push_str(&format!(", by {}", granted_by));
+            }
+            if let Some(t) = p.granted_at {
+                res.push_str(&format!(", at {}", t.to_rfc3339()));
+            }
+            if let Some(reason) = &p.reason {
+                res.push_str(&format!(", reason: {}", reason));
+            }
+        }
+        res.push('\n');
+    }
+    res
+}
+
+fn individual_label(decision: &Decision, group: &str) -> &'static str {
+    if decision.individual_hops.iter().any(|(_, individual)| individual == group) {
+        " (individual as group)"
+    } else {
+        ""
+    }
+}
@@ -0,0 +1,236 @@
+//! Export of the ACL graph around a set of ids for analysis tools: GraphML for Gephi, yEd or
+//! `networkx.read_graphml`, and the JSON Graph Format.
+//!
+//! Memberships are followed upwards from the roots as the traversal does, M-records are read with their
+//! continuation parts and decoded by the storage, out-of-scope groups are left out. Permissions given on the
+//! nodes reached are added as edges from the subject to the object, so a user and a resource meet at the
+//! subject groups.
+
+use crate::common::{Storage, MEMBERSHIP_PREFIX, PERMISSION_PREFIX};
+use crate::config::AzConfig;
+use crate::heatmap::json_string;
+use crate::record_formats::read_continued;
+use crate::ACLRecordVec;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphFormat {
+    GraphMl,
+    /// JSON Graph Format, https://jsongraphformat.info
+    JsonGraph,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NodeKind {
+    /// Has members in the export
+    Group,
+    /// Granted rights, directly or through one of its groups
+    Subject,
+    Resource,
+}
+
+impl NodeKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NodeKind::Group => "group",
+            NodeKind::Subject => "subject",
+            NodeKind::Resource => "resource",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EdgeKind {
+    /// From the member to the group
+    Membership,
+    /// From the subject to the object the rights are given on
+    Permission,
+}
+
+impl EdgeKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EdgeKind::Membership => "membership",
+            EdgeKind::Permission => "permission",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GraphEdge {
+    pub source: String,
+    pub target: String,
+    pub kind: EdgeKind,
+    pub access: u8,
+    /// 0 when the entry has no marker
+    pub marker: char,
+}
+
+/// Nodes by id and edges, both sorted
+#[derive(Clone, Debug, Default)]
+pub struct AclGraph {
+    pub nodes: BTreeMap<String, NodeKind>,
+    pub edges: Vec<GraphEdge>,
+}
+
+/// Graph reachable from `roots`, users or resources, in `format`
+pub fn export_graph(db: &mut dyn Storage, roots: &[&str], format: GraphFormat) -> io::Result<String> {
+    let graph = collect_graph(db, roots, &AzConfig::default())?;
+    Ok(match format {
+        GraphFormat::GraphMl => graph.to_graphml(),
+        GraphFormat::JsonGraph => graph.to_json_graph(),
+    })
+}
+
+/// Memberships are followed up to the larger of `max_subject_depth` and `max_object_depth` of `cfg`
+pub fn collect_graph(db: &mut dyn Storage, roots: &[&str], cfg: &AzConfig) -> io::Result<AclGraph> {
+    let max_depth = cfg.max_subject_depth.max(cfg.max_object_depth);
+    let mut edges = Vec::new();
+    let mut groups = HashSet::new();
+    let mut visited: HashSet<String> = roots.iter().map(|id| id.to_string()).collect();
+    let mut queue: VecDeque<(String, u8)> = roots.iter().map(|id| (id.to_string(), 0)).collect();
+
+    while let Some((uri, level)) = queue.pop_front() {
+        if let Some(src) = read_continued(&(PERMISSION_PREFIX.to_owned() + &uri), db)? {
+            let mut permissions = ACLRecordVec::new();
+            db.decode_rec_to_rights(&src, &mut permissions);
+            for p in permissions.into_iter().filter(|p| !p.id.is_empty() && !p.is_deleted) {
+                edges.push(GraphEdge {
+                    source: p.id,
+                    target: uri.clone(),
+                    kind: EdgeKind::Permission,
+                    access: p.access,
+                    marker: p.marker,
+                });
+            }
+        }
+
+        if level >= max_depth {
+            continue;
+        }
+        let Some(src) = read_continued(&(MEMBERSHIP_PREFIX.to_owned() + &uri), db)? else {
+            continue;
+        };
+        let mut memberships = ACLRecordVec::new();
+        db.decode_rec_to_rights(&src, &mut memberships);
+
+        for gr in memberships {
+            if gr.id.is_empty() || gr.is_deleted || gr.id == uri || !cfg.is_in_scope(&gr.id) {
+                continue;
+            }
+            groups.insert(gr.id.clone());
+            if visited.insert(gr.id.clone()) {
+                queue.push_back((gr.id.clone(), level + 1));
+            }
+            edges.push(GraphEdge {
+                source: uri.clone(),
+                target: gr.id,
+                kind: EdgeKind::Membership,
+                access: gr.access,
+                marker: gr.marker,
+            });
+        }
+    }
+
+    // Субъект - тот, кому даны права, сам или через свои группы
+    let grantees: HashSet<&str> = edges.iter().filter(|e| e.kind == EdgeKind::Permission).map(|e| e.source.as_str()).collect();
+    let subjects: HashSet<&str> = edges
+        .iter()
+        .filter(|e| grantees.contains(e.source.as_str()) || (e.kind == EdgeKind::Membership && grantees.contains(e.target.as_str())))
+        .map(|e| e.source.as_str())
+        .collect();
+    let mut nodes = BTreeMap::new();
+    for e in &edges {
+        for id in [&e.source, &e.target] {
+            let kind = if groups.contains(id) {
+                NodeKind::Group
+            } else if subjects.contains(id.as_str()) {
+                NodeKind::Subject
+            } else {
+                NodeKind::Resource
+            };
+            nodes.insert(id.clone(), kind);
+        }
+    }
+    for id in roots {
+        nodes.entry(id.to_string()).or_insert(NodeKind::Resource);
+    }
+
+    edges.sort();
+    edges.dedup();
+    Ok(AclGraph {
+        nodes,
+        edges,
+    })
+}
+
+impl AclGraph {
+    /// Directed graph; nodes carry `kind`, edges `kind`, `access` and `marker`
+    pub fn to_graphml(&self) -> String {
+        let mut res = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n");
+        res.push_str("  <key id=\"kind\" for=\"node\" attr.name=\"kind\" attr.type=\"string\"/>\n");
+        res.push_str("  <key id=\"edge_kind\" for=\"edge\" attr.name=\"kind\" attr.type=\"string\"/>\n");
+        res.push_str("  <key id=\"access\" for=\"edge\" attr.name=\"access\" attr.type=\"int\"/>\n");
+        res.push_str("  <key id=\"marker\" for=\"edge\" attr.name=\"marker\" attr.type=\"string\"/>\n");
+        res.push_str("  <graph id=\"acl\" edgedefault=\"directed\">\n");
+        for (id, kind) in &self.nodes {
+            res.push_str(&format!("    <node id=\"{}\"><data key=\"kind\">{}</data></node>\n", xml_escape(id), kind.name()));
+        }
+        for e in &self.edges {
+            res.push_str(&format!(
+                "    <edge source=\"{}\" target=\"{}\"><data key=\"edge_kind\">{}</data><data key=\"access\">{}</data><data key=\"marker\">{}</data></edge>\n",
+                xml_escape(&e.source),
+                xml_escape(&e.target),
+                e.kind.name(),
+                e.access,
+                xml_escape(&marker_str(e.marker))
+            ));
+        }
+        res.push_str("  </graph>\n</graphml>\n");
+        res
+    }
+
+    /// `{"graph":{"directed":true,"nodes":{id:{"metadata":{"kind":..}}},"edges":[{"source":..,"target":..,"relation":..,"metadata":{"access":..,"marker":..}}]}}`
+    pub fn to_json_graph(&self) -> String {
+        let nodes: Vec<String> = self.nodes.iter().map(|(id, kind)| format!("{}:{{\"metadata\":{{\"kind\":\"{}\"}}}}", json_string(id), kind.name())).collect();
+        let edges: Vec<String> = self
+            .edges
+            .iter()
+            .map(|e| {
+                format!(
+                    "{{\"source\":{},\"target\":{},\"relation\":\"{}\",\"metadata\":{{\"access\":{},\"marker\":{}}}}}",
+                    json_string(&e.source),
+                    json_string(&e.target),
+                    e.kind.name(),
+                    e.access,
+                    json_string(&marker_str(e.marker))
+                )
+            })
+            .collect();
+        format!("{{\"graph\":{{\"directed\":true,\"nodes\":{{{}}},\"edges\":[{}]}}}}", nodes.join(","), edges.join(","))
+    }
+}
+
+fn marker_str(marker: char) -> String {
+    if marker == 0 as char {
+        String::new()
+    } else {
+        marker.to_string()
+    }
+}
+
+fn xml_escape(src: &str) -> String {
+    let mut res = String::with_capacity(src.len());
+    for c in src.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&apos;"),
+            _ => res.push(c),
+        }
+    }
+    res
+}
@@ -0,0 +1,147 @@
+//! Access frequency per resource and per subject group over a sliding time window.
+//!
+//! Attached to the engine as an audit sink; the table guides cache sizing and data retention.
+//! Counts are kept in buckets of `bucket` length, buckets older than `window` are dropped.
+
+use crate::audit::{AuditEvent, AuditSink};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct Bucket {
+    resources: HashMap<String, u64>,
+    groups: HashMap<String, u64>,
+}
+
+pub struct AccessHeatmap {
+    window: Duration,
+    bucket: Duration,
+    buckets: Mutex<VecDeque<(Instant, Bucket)>>,
+}
+
+/// Counts over the window, most accessed first
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HeatmapTable {
+    pub resources: Vec<(String, u64)>,
+    /// Subject groups through which rights were granted
+    pub groups: Vec<(String, u64)>,
+}
+
+impl AccessHeatmap {
+    pub fn new(window: Duration, bucket: Duration) -> Self {
+        AccessHeatmap {
+            window,
+            bucket: bucket.max(Duration::from_millis(1)),
+            buckets: Mutex::default(),
+        }
+    }
+
+    pub fn record(&self, id: &str, groups: &[(String, u8)]) {
+        self.record_at(Instant::now(), id, groups);
+    }
+
+    pub fn record_at(&self, now: Instant, id: &str, groups: &[(String, u8)]) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        self.expire(&mut buckets, now);
+
+        let is_current = matches!(buckets.back(), Some((start, _)) if now.saturating_duration_since(*start) < self.bucket);
+        if !is_current {
+            buckets.push_back((now, Bucket::default()));
+        }
+
+        if let Some((_, bucket)) = buckets.back_mut() {
+            *bucket.resources.entry(id.to_owned()).or_default() += 1;
+            for (group, _) in groups {
+                *bucket.groups.entry(group.clone()).or_default() += 1;
+            }
+        }
+    }
+
+    pub fn table(&self) -> HeatmapTable {
+        self.table_at(Instant::now())
+    }
+
+    pub fn table_at(&self, now: Instant) -> HeatmapTable {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        self.expire(&mut buckets, now);
+
+        let mut resources: HashMap<&str, u64> = HashMap::new();
+        let mut groups: HashMap<&str, u64> = HashMap::new();
+        for (_, bucket) in buckets.iter() {
+            for (id, n) in &bucket.resources {
+                *resources.entry(id).or_default() += n;
+            }
+            for (id, n) in &bucket.groups {
+                *groups.entry(id).or_default() += n;
+            }
+        }
+
+        HeatmapTable {
+            resources: sorted(resources),
+            groups: sorted(groups),
+        }
+    }
+
+    fn expire(&self, buckets: &mut VecDeque<(Instant, Bucket)>, now: Instant) {
+        while matches!(buckets.front(), Some((start, _)) if now.saturating_duration_since(*start) >= self.window) {
+            buckets.pop_front();
+        }
+    }
+}
+
+impl AuditSink for AccessHeatmap {
+    fn on_decision(&self, event: &AuditEvent) {
+        self.record(event.id, &event.decision.granted_via);
+    }
+}
+
+fn sorted(counts: HashMap<&str, u64>) -> Vec<(String, u64)> {
+    let mut res: Vec<(String, u64)> = counts.into_iter().map(|(id, n)| (id.to_owned(), n)).collect();
+    res.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    res
+}
+
+impl HeatmapTable {
+    /// `kind,id,count`, kind is `resource` or `group`
+    pub fn to_csv(&self) -> String {
+        let mut res = String::from("kind,id,count\n");
+        for (kind, rows) in [("resource", &self.resources), ("group", &self.groups)] {
+            for (id, n) in rows {
+                res.push_str(&format!("{},{},{}\n", kind, csv_field(id), n));
+            }
+        }
+        res
+    }
+
+    /// `{"resources":[{"id":..,"count":..}],"groups":[..]}`
+    pub fn to_json(&self) -> String {
+        let rows = |rows: &[(String, u64)]| {
+            let items: Vec<String> = rows.iter().map(|(id, n)| format!("{{\"id\":{},\"count\":{}}}", json_string(id), n)).collect();
+            format!("[{}]", items.join(","))
+        };
+        format!("{{\"resources\":{},\"groups\":{}}}", rows(&self.resources), rows(&self.groups))
+    }
+}
+
+fn csv_field(src: &str) -> String {
+    if src.contains([',', '"', '\n']) {
+        format!("\"{}\"", src.replace('"', "\"\""))
+    } else {
+        src.to_owned()
+    }
+}
+
+pub(crate) fn json_string(src: &str) -> String {
+    let mut res = String::from("\"");
+    for c in src.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
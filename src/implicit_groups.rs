@@ -0,0 +1,77 @@
+use crate::common::Storage;
+use crate::{ACLRecord, ACLRecordVec};
+
+/// Supplies object groups of a resource that are not stored as M-records.
+/// Called by the object group traversal for every uri it visits, alongside the uri's M-record.
+pub trait ImplicitGroupProvider: Send + Sync {
+    fn implicit_groups(&self, uri: &str, db: &mut dyn Storage, result: &mut ACLRecordVec);
+}
+
+/// Resources whose uri starts with a given prefix belong to a group
+#[derive(Default)]
+pub struct PrefixGroupProvider {
+    rules: Vec<(String, String, u8)>,
+}
+
+impl PrefixGroupProvider {
+    pub fn add_rule(&mut self, uri_prefix: &str, group_id: &str, access: u8) -> &mut Self {
+        self.rules.push((uri_prefix.to_owned(), group_id.to_owned(), access));
+        self
+    }
+}
+
+impl ImplicitGroupProvider for PrefixGroupProvider {
+    fn implicit_groups(&self, uri: &str, _db: &mut dyn Storage, result: &mut ACLRecordVec) {
+        for (prefix, group_id, access) in &self.rules {
+            if uri.starts_with(prefix.as_str()) && uri != group_id {
+                result.push(ACLRecord::new_with_access(group_id, *access));
+            }
+        }
+    }
+}
+
+/// Resources of a given rdf:type belong to a group.
+/// The type lookup is supplied by the caller, as the ACL store holds no document data.
+pub struct TypeGroupProvider<F>
+where
+    F: Fn(&str, &mut dyn Storage) -> Vec<String> + Send + Sync,
+{
+    get_types: F,
+    rules: Vec<(String, String, u8)>,
+}
+
+impl<F> TypeGroupProvider<F>
+where
+    F: Fn(&str, &mut dyn Storage) -> Vec<String> + Send + Sync,
+{
+    pub fn new(get_types: F) -> Self {
+        TypeGroupProvider {
+            get_types,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn add_rule(&mut self, rdf_type: &str, group_id: &str, access: u8) -> &mut Self {
+        self.rules.push((rdf_type.to_owned(), group_id.to_owned(), access));
+        self
+    }
+}
+
+impl<F> ImplicitGroupProvider for TypeGroupProvider<F>
+where
+    F: Fn(&str, &mut dyn Storage) -> Vec<String> + Send + Sync,
+{
+    fn implicit_groups(&self, uri: &str, db: &mut dyn Storage, result: &mut ACLRecordVec) {
+        if self.rules.is_empty() {
+            return;
+        }
+
+        for rdf_type in (self.get_types)(uri, db) {
+            for (t, group_id, access) in &self.rules {
+                if *t == rdf_type {
+                    result.push(ACLRecord::new_with_access(group_id, *access));
+                }
+            }
+        }
+    }
+}
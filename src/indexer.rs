@@ -0,0 +1,126 @@
+//! Maintenance of P-, M- and F-records from a stream of individual changes, as the platform's az-indexer does
+
+use crate::keys::KeySchema;
+use crate::manage::{apply_change, from_individual_with_schema, validate_changes, AclChange, ChangeValidator, IndividualProps, MutableStorage};
+use std::io;
+
+pub enum IndividualEvent {
+    Create(IndividualProps),
+    Update { prev: IndividualProps, new: IndividualProps },
+    Delete(IndividualProps),
+}
+
+/// Source of individual changes, e.g. a queue consumer; `None` when the stream is exhausted
+pub trait EventSource {
+    fn next_event(&mut self) -> io::Result<Option<IndividualEvent>>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IndexerStats {
+    pub events: usize,
+    pub changes: usize,
+    /// Individuals that could not be converted, see `manage::from_individual`
+    pub skipped: usize,
+    /// Events refused by the validator, none of their changes written
+    pub rejected: usize,
+}
+
+pub struct Indexer<'a> {
+    db: &'a mut dyn MutableStorage,
+    validator: Option<&'a dyn ChangeValidator>,
+    schema: KeySchema,
+    stats: IndexerStats,
+}
+
+impl<'a> Indexer<'a> {
+    pub fn new(db: &'a mut dyn MutableStorage) -> Self {
+        Indexer {
+            db,
+            validator: None,
+            schema: KeySchema::default(),
+            stats: IndexerStats::default(),
+        }
+    }
+
+    /// Must match `AzConfig::key_schema` of the readers
+    pub fn set_key_schema(&mut self, schema: KeySchema) {
+        self.schema = schema;
+    }
+
+    /// Changes of an event are checked together before any of them is written
+    pub fn set_validator(&mut self, validator: &'a dyn ChangeValidator) {
+        self.validator = Some(validator);
+    }
+
+    pub fn stats(&self) -> IndexerStats {
+        self.stats
+    }
+
+    /// Consumes the source up to its end; stops at the first storage error
+    pub fn run(&mut self, source: &mut dyn EventSource) -> io::Result<IndexerStats> {
+        while let Some(event) = source.next_event()? {
+            self.process(&event)?;
+        }
+        Ok(self.stats)
+    }
+
+    /// An update takes back everything the previous version granted, then applies the new one.
+    /// A version marked `v-s:deleted` grants nothing, so rights are taken back once when it is marked
+    pub fn process(&mut self, event: &IndividualEvent) -> io::Result<()> {
+        self.stats.events += 1;
+
+        let mut changes = Vec::new();
+        match event {
+            IndividualEvent::Create(props) => changes.extend(self.convert(props, false)),
+            IndividualEvent::Update {
+                prev,
+                new,
+            } => {
+                changes.extend(self.convert(prev, true));
+                changes.extend(self.convert(new, false));
+            },
+            IndividualEvent::Delete(props) => changes.extend(self.convert(props, true)),
+        }
+
+        if let Some(validator) = self.validator {
+            let rejections = validate_changes(&changes, validator, self.db);
+            if !rejections.is_empty() {
+                for r in &rejections {
+                    eprintln!("WARN! indexer: change rejected, {}", r);
+                }
+                self.stats.rejected += 1;
+                return Ok(());
+            }
+        }
+
+        for change in &changes {
+            apply_change(change, self.db)?;
+        }
+        self.stats.changes += changes.len();
+
+        if !changes.is_empty() {
+            let keys: Vec<String> = changes.into_iter().map(|c| c.key).collect();
+            self.db.emit_invalidation(&keys);
+        }
+
+        Ok(())
+    }
+
+    fn convert(&mut self, props: &IndividualProps, is_remove: bool) -> Vec<AclChange> {
+        match from_individual_with_schema(props, self.schema) {
+            // права удаленной версии уже сняты или не выдавались, счетчики не уменьшаются второй раз
+            Ok(changes) if changes.iter().any(|c| c.is_remove) => Vec::new(),
+            Ok(mut changes) => {
+                for change in changes.iter_mut() {
+                    change.is_remove = is_remove;
+                }
+                changes
+            },
+            Err(e) => {
+                eprintln!("WARN! indexer: skip individual {:?}, {}", props.get("@"), e);
+                self.stats.skipped += 1;
+                Vec::new()
+            },
+        }
+    }
+}
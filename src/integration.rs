@@ -0,0 +1,107 @@
+//! End-to-end check of codec and traversal against ACL data produced by the Veda acl-indexer.
+//!
+//! The corpus is exported from the platform, one check per line: `user_id;resource_id;access;expected`,
+//! access fields in decimal or in the record letter form (see `record_formats`). Empty lines and lines
+//! starting with `#` are skipped. The store itself is opened by the caller; `VEDA_AZ_DB` carries its path/DSN.
+
+use crate::common::{Storage, TraceBuffers};
+use crate::config::AzConfig;
+use crate::record_formats::parse_access;
+use crate::{authorize_with_config, RightsCounters};
+use std::{env, fs, io};
+
+/// Path or DSN of the ACL database under test
+pub const DB_ENV: &str = "VEDA_AZ_DB";
+/// Path of the exported corpus of expected results
+pub const CORPUS_ENV: &str = "VEDA_AZ_CORPUS";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorpusCheck {
+    pub user_id: String,
+    pub id: String,
+    pub request_access: u8,
+    pub expected: u8,
+}
+
+#[derive(Debug)]
+pub struct Mismatch {
+    pub check: CorpusCheck,
+    /// `None` when the decision ended with a storage error
+    pub actual: Option<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct IntegrationReport {
+    pub total: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl IntegrationReport {
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Returns the values of `VEDA_AZ_DB` and `VEDA_AZ_CORPUS`, `None` if either is unset
+pub fn env_config() -> Option<(String, String)> {
+    Some((env::var(DB_ENV).ok()?, env::var(CORPUS_ENV).ok()?))
+}
+
+pub fn parse_corpus(src: &str) -> io::Result<Vec<CorpusCheck>> {
+    let mut res = Vec::new();
+
+    for (n, line) in src.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(';').collect();
+        if fields.len() != 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("corpus line {}: expected 4 fields", n + 1)));
+        }
+
+        let access = |src: &str| {
+            parse_access(src, &mut RightsCounters::None)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("corpus line {}: invalid access [{}]", n + 1, src)))
+        };
+
+        res.push(CorpusCheck {
+            user_id: fields[0].to_owned(),
+            id: fields[1].to_owned(),
+            request_access: access(fields[2])?,
+            expected: access(fields[3])?,
+        });
+    }
+
+    Ok(res)
+}
+
+pub fn load_corpus(path: &str) -> io::Result<Vec<CorpusCheck>> {
+    parse_corpus(&fs::read_to_string(path)?)
+}
+
+/// Runs every check against `db` and collects the ones whose result differs from the exported one
+pub fn run_corpus(corpus: &[CorpusCheck], db: &mut dyn Storage) -> IntegrationReport {
+    run_corpus_with_config(corpus, db, &AzConfig::default())
+}
+
+pub fn run_corpus_with_config(corpus: &[CorpusCheck], db: &mut dyn Storage, cfg: &AzConfig) -> IntegrationReport {
+    let mut report = IntegrationReport::default();
+
+    for check in corpus {
+        report.total += 1;
+
+        let mut buf = TraceBuffers::default();
+        let actual = authorize_with_config(&check.id, &check.user_id, check.request_access, db, &mut buf.trace(false, false, false), cfg).ok();
+
+        if actual != Some(check.expected) {
+            report.mismatches.push(Mismatch {
+                check: check.clone(),
+                actual,
+            });
+        }
+    }
+
+    report
+}
@@ -0,0 +1,204 @@
+//! How ids become record keys and record entries.
+//!
+//! Stores written by the platform use the legacy scheme: key = prefix + id, entries separated by `;`.
+//! Two things break in it: an id containing `;` corrupts the record it is written to, and the key of a
+//! permission given with a filter (prefix + filter + group) is ambiguous, `ab`+`c` and `a`+`bc` collide.
+//!
+//! The escaped scheme percent-encodes `%`, `;`, `#` and control characters in every id and separates filter
+//! and group with `;`, which can no longer occur inside an id. Without `#` no key of an id can be taken for
+//! a continuation part (`key#1`) of another record. The traversal then works on escaped ids
+//! throughout: `authorize` escapes the user and resource ids it gets, and ids in decisions and traces
+//! come out escaped (`unescape_id` gives them back).
+//!
+//! Migration: ids without `%`, `;`, `#` and control characters are the same in both schemes, so only records
+//! mentioning such ids and the P-records of filtered permissions have to be rewritten. Rebuild them from
+//! the individuals with `manage::from_individual_with_schema` into an empty store, or rewrite them in
+//! place while no writer is running, then switch `AzConfig::key_schema` of all readers at once.
+
+use std::borrow::Cow;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum KeySchema {
+    #[default]
+    Legacy,
+    Escaped,
+}
+
+impl KeySchema {
+    /// Form of `id` used in keys and records
+    pub fn encode_id<'a>(&self, id: &'a str) -> Cow<'a, str> {
+        match self {
+            KeySchema::Legacy => Cow::Borrowed(id),
+            KeySchema::Escaped => escape_id(id),
+        }
+    }
+
+    pub fn key(&self, prefix: &str, id: &str) -> String {
+        prefix.to_owned() + &self.encode_id(id)
+    }
+
+    /// Key suffix of the P-record of `group`, for permissions given with `filter` when it is not empty;
+    /// both ids are already encoded
+    pub fn permission_suffix(&self, filter: &str, group: &str) -> String {
+        match (self, filter.is_empty()) {
+            (_, true) => group.to_owned(),
+            (KeySchema::Legacy, false) => filter.to_owned() + group,
+            (KeySchema::Escaped, false) => format!("{};{}", filter, group),
+        }
+    }
+}
+
+fn is_reserved(c: char) -> bool {
+    c == '%' || c == ';' || c == '#' || c.is_control()
+}
+
+/// Percent-encodes `%`, `;`, `#` and control characters
+pub fn escape_id(id: &str) -> Cow<'_, str> {
+    if !id.contains(is_reserved) {
+        return Cow::Borrowed(id);
+    }
+
+    let mut res = String::with_capacity(id.len() + 8);
+    for c in id.chars() {
+        if is_reserved(c) {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                res.push_str(&format!("%{:02X}", b));
+            }
+        } else {
+            res.push(c);
+        }
+    }
+    Cow::Owned(res)
+}
+
+/// Reverse of `escape_id`; `None` on a malformed escape sequence
+pub fn unescape_id(id: &str) -> Option<Cow<'_, str>> {
+    if !id.contains('%') {
+        return Some(Cow::Borrowed(id));
+    }
+
+    let mut bytes = Vec::with_capacity(id.len());
+    let mut src = id.bytes();
+    while let Some(b) = src.next() {
+        if b == b'%' {
+            let hi = (src.next()? as char).to_digit(16)?;
+            let lo = (src.next()? as char).to_digit(16)?;
+            bytes.push((hi * 16 + lo) as u8);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok().map(Cow::Owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record_formats::{decode_rec_to_rights, encode_rights};
+    use crate::{ACLRecord, ACLRecordVec};
+
+    const ALPHABET: &[char] = &['%', ';', '#', 'P', 'M', 'F', 'a', 'Z', '0', '9', ':', '/', ' ', '\0', '\t', '\n', '\u{7f}', '\u{85}', 'я', '€', '🔑'];
+
+    // Детерминированный генератор, чтобы падение воспроизводилось
+    fn ids(count: usize) -> Vec<String> {
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let mut res = vec![String::new(), "%".to_owned(), "%%".to_owned(), ";;".to_owned(), "P%3B".to_owned()];
+        for _ in 0..count {
+            let len = (next() % 12) as usize;
+            let id: String = (0..len)
+                .map(|_| {
+                    let r = next();
+                    if r % 4 == 0 {
+                        char::from_u32((r >> 8) as u32 % 0x11_0000).unwrap_or('?')
+                    } else {
+                        ALPHABET[(r >> 8) as usize % ALPHABET.len()]
+                    }
+                })
+                .collect();
+            res.push(id);
+        }
+        res
+    }
+
+    #[test]
+    fn escape_round_trip() {
+        for id in ids(5000) {
+            let escaped = escape_id(&id);
+            assert!(!escaped.contains(|c: char| c == ';' || c == '#' || c.is_control()), "{:?} -> {:?}", id, escaped);
+            assert_eq!(unescape_id(&escaped).as_deref(), Some(id.as_str()), "{:?} -> {:?}", id, escaped);
+        }
+    }
+
+    #[test]
+    fn escape_keeps_plain_ids() {
+        for id in ["v-s:AllResourcesGroup", "td:RomanKarpov", "Pdoc", "M", "F1"] {
+            assert!(matches!(escape_id(id), Cow::Borrowed(_)));
+            assert_eq!(KeySchema::Escaped.encode_id(id), KeySchema::Legacy.encode_id(id));
+        }
+    }
+
+    #[test]
+    fn escaped_keys_are_not_continuation_parts() {
+        assert_eq!(escape_id("doc#1"), "doc%231");
+        assert_eq!(KeySchema::Escaped.key("P", "doc#1"), "Pdoc%231");
+        assert_eq!(KeySchema::Legacy.key("P", "doc#1"), "Pdoc#1");
+    }
+
+    #[test]
+    fn escaped_ids_survive_records() {
+        let ids = ids(500);
+        let records: Vec<ACLRecord> = ids.iter().filter(|id| !id.is_empty()).map(|id| ACLRecord::new_with_access(&escape_id(id), 2)).collect();
+
+        let mut decoded = ACLRecordVec::new();
+        decode_rec_to_rights(&encode_rights(&records), &mut decoded);
+        assert_eq!(decoded.len(), records.len());
+        for (rec, src) in decoded.iter().zip(&records) {
+            assert_eq!(rec.id, src.id);
+            assert_eq!(rec.access, 2);
+        }
+    }
+
+    #[test]
+    fn keys_keep_prefix() {
+        for id in ids(500) {
+            for prefix in ["P", "M", "F"] {
+                let key = KeySchema::Escaped.key(prefix, &id);
+                let rest = key.strip_prefix(prefix).unwrap();
+                assert_eq!(unescape_id(rest).as_deref(), Some(id.as_str()));
+            }
+        }
+    }
+
+    #[test]
+    fn filtered_suffixes_do_not_collide() {
+        let schema = KeySchema::Escaped;
+        assert_ne!(schema.permission_suffix("ab", "c"), schema.permission_suffix("a", "bc"));
+        assert_eq!(KeySchema::Legacy.permission_suffix("ab", "c"), KeySchema::Legacy.permission_suffix("a", "bc"));
+
+        let ids = ids(60);
+        let mut seen = std::collections::HashMap::new();
+        for filter in ids.iter().filter(|id| !id.is_empty()) {
+            for group in &ids {
+                let suffix = schema.permission_suffix(&escape_id(filter), &escape_id(group));
+                if let Some(prev) = seen.insert(suffix.clone(), (filter, group)) {
+                    assert_eq!(prev, (filter, group), "{:?}", suffix);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_escapes() {
+        for id in ["%", "%4", "%G1", "a%", "%FF", "%C3"] {
+            assert_eq!(unescape_id(id), None, "{:?}", id);
+        }
+    }
+}
@@ -0,0 +1,1502 @@
+use crate::aggregate::{aggregate_permissions, decode_aggregate, encode_aggregate};
+use crate::common::{
+    counter_index, Storage, ACCESS_8_FULL_LIST, ACCESS_8_PREDICATE_LIST, ACCESS_C_FULL_LIST, ACL_GENERATION_KEY, AGGREGATE_PREFIX, ALIAS_PREFIX,
+    ATTESTATION_PREFIX, COSIGN_PREFIX, FILTER_PREFIX, MEMBERSHIP_PREFIX, M_IGNORE_EXCLUSIVE, M_IS_EXCLUSIVE, PATTERN_PREFIX, PERMISSION_PREFIX,
+    REACHABILITY_PREFIX,
+};
+use crate::engine::AzEngine;
+use crate::keys::{unescape_id, KeySchema};
+use crate::presets::MANAGER;
+use crate::reachability::{acl_generation, collect_reachable_subjects, encode_summary, GroupBloom};
+use crate::record_formats::{continuation_key, continuation_parts, encode_record_auto, encode_rights, missing_part, read_continued, split_continued, Validity};
+use crate::record_set::RecordSet;
+use crate::{ACLRecord, ACLRecordVec, GrantProvenance};
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+
+/// Prefixes of the records whose entries are subjects: memberships, permissions, pattern grants and co-signatures
+pub const SUBJECT_RECORD_PREFIXES: [&str; 4] = [MEMBERSHIP_PREFIX, PERMISSION_PREFIX, PATTERN_PREFIX, COSIGN_PREFIX];
+
+/// Storage that also accepts writes, used by the maintenance functions of this module
+pub trait MutableStorage: Storage {
+    fn put(&mut self, key: &str, value: &str) -> io::Result<()>;
+    fn remove(&mut self, key: &str) -> io::Result<()>;
+
+    /// Writes (`Some`) or removes (`None`) all keys or none of them.
+    /// The default applies them one by one and restores the previous values if a write fails;
+    /// stores with native transactions should override it.
+    fn apply_batch(&mut self, batch: &[(String, Option<String>)]) -> io::Result<()> {
+        let mut undo: Vec<(String, Option<String>)> = Vec::with_capacity(batch.len());
+
+        for (key, value) in batch {
+            let prev = self.get(key)?;
+            let res = match value {
+                Some(v) => self.put(key, v),
+                None => self.remove(key),
+            };
+            if let Err(e) = res {
+                for (key, prev) in undo.into_iter().rev() {
+                    let restored = match &prev {
+                        Some(v) => self.put(&key, v),
+                        None => self.remove(&key),
+                    };
+                    if restored.is_err() {
+                        eprintln!("ERR! apply_batch: failed to restore key {}", key);
+                    }
+                }
+                return Err(e);
+            }
+            undo.push((key.clone(), prev));
+        }
+
+        Ok(())
+    }
+
+    /// Largest value the backend accepts; bigger records are written with continuation parts
+    fn max_value_len(&self) -> Option<usize> {
+        None
+    }
+
+    /// Called after a bulk operation of this module changed `keys`; caching layers drop their entries here
+    fn emit_invalidation(&mut self, _keys: &[String]) {}
+
+    /// All keys starting with `prefix` with their values; needed by the bulk operations of this module
+    fn scan_prefix(&mut self, _prefix: &str) -> io::Result<Vec<(String, String)>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "scan_prefix is not supported by this storage"))
+    }
+
+    /// Keys of the M-, P-, W- and Q-records with a live entry of `subject_id` (encoded).
+    /// The default does a full scan; stores that keep a reverse index should override it.
+    fn keys_referencing(&mut self, subject_id: &str) -> io::Result<Vec<String>> {
+        let mut res = Vec::new();
+        for prefix in SUBJECT_RECORD_PREFIXES {
+            for (key, value) in join_continued(self.scan_prefix(prefix)?)? {
+                let mut records = ACLRecordVec::new();
+                self.decode_rec_to_rights(&value, &mut records);
+                if records.iter().any(|r| r.id == subject_id && !r.is_deleted) {
+                    res.push(key);
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Rebuilds the permission aggregate of an object group from its P-record.
+/// `key_suffix` is the P-record key without the prefix (filter value + group id for filtered records).
+///
+/// Once built, the aggregate is kept in step by the writers of this module and by the indexer: they rebuild it,
+/// with the epoch it has, in the same batch as the P-record, and remove it with the record. Writes made past
+/// this module leave it stale, rebuild it after them or raise `AzConfig::aggregate_min_epoch`.
+pub fn rebuild_permission_aggregate(key_suffix: &str, epoch: u64, db: &mut dyn MutableStorage) -> io::Result<()> {
+    let agg_key = AGGREGATE_PREFIX.to_owned() + key_suffix;
+
+    match read_continued(&(PERMISSION_PREFIX.to_owned() + key_suffix), db)? {
+        Some(src) => {
+            let value = aggregate_value(&src, epoch, db);
+            db.put(&agg_key, &value)
+        },
+        None => db.remove(&agg_key),
+    }
+}
+
+fn aggregate_value(src: &str, epoch: u64, db: &dyn Storage) -> String {
+    let mut permissions = ACLRecordVec::new();
+    db.decode_rec_to_rights(src, &mut permissions);
+    encode_aggregate(epoch, &aggregate_permissions(&permissions))
+}
+
+// Агрегат P-записи меняется вместе с ней, если он уже ведется
+fn aggregate_batch(key_suffix: &str, value: Option<&str>, db: &mut dyn MutableStorage) -> io::Result<Option<(String, Option<String>)>> {
+    let agg_key = AGGREGATE_PREFIX.to_owned() + key_suffix;
+    let Some(prev) = db.get(&agg_key)? else {
+        return Ok(None);
+    };
+    let epoch = decode_aggregate(&prev).map_or(0, |(epoch, _)| epoch);
+    Ok(Some((agg_key, value.map(|v| aggregate_value(v, epoch, db)))))
+}
+
+/// Rebuilds the reachability summary of a resource. The writers of this module, and the indexer through them, make
+/// every summary stale on a write of memberships, permissions or filters; writes made past them are not noticed
+pub fn rebuild_reachability(id: &str, epoch: u64, max_depth: u8, db: &mut dyn MutableStorage) -> io::Result<()> {
+    rebuild_reachability_with_schema(id, epoch, max_depth, KeySchema::Legacy, db)
+}
+
+/// Same as `rebuild_reachability`, with `id` and the record keys in the form `schema` gives them
+pub fn rebuild_reachability_with_schema(id: &str, epoch: u64, max_depth: u8, schema: KeySchema, db: &mut dyn MutableStorage) -> io::Result<()> {
+    // поколение читается до обхода: запись, сделанная во время обхода, сразу делает сводку устаревшей
+    let generation = match acl_generation(db)? {
+        Some(generation) => generation,
+        None => {
+            db.put(ACL_GENERATION_KEY, "0")?;
+            0
+        },
+    };
+
+    let id = schema.encode_id(id);
+    let subjects = collect_reachable_subjects(&id, max_depth, schema, db)?;
+
+    let mut bloom = GroupBloom::with_capacity(subjects.len());
+    for subj in &subjects {
+        bloom.insert(subj);
+    }
+
+    db.put(&(REACHABILITY_PREFIX.to_owned() + &id), &encode_summary(&bloom, epoch, generation))
+}
+
+/// Initial ACL of a freshly created resource
+pub struct NewResourceTemplate {
+    pub owner_id: String,
+    /// Defaults to `presets::MANAGER`
+    pub owner_access: u8,
+    /// Groups the resource is put in, usually the groups of its type, with the access they pass on
+    pub groups: Vec<(String, u8)>,
+    /// Filter id and the mask it leaves, written to the F-record of the resource
+    pub filter: Option<(String, u8)>,
+}
+
+impl NewResourceTemplate {
+    pub fn new(owner_id: &str) -> Self {
+        NewResourceTemplate {
+            owner_id: owner_id.to_owned(),
+            owner_access: MANAGER,
+            groups: Vec::new(),
+            filter: None,
+        }
+    }
+}
+
+/// Writes the owner permission, the memberships and the filter of a new resource in one batch.
+/// Entries already present in the P- and M-records are kept, with the validity of the records.
+pub fn stamp_new_resource(resource_id: &str, template: &NewResourceTemplate, db: &mut dyn MutableStorage) -> io::Result<()> {
+    stamp_new_resource_with_schema(resource_id, template, KeySchema::Legacy, db)
+}
+
+/// Same as `stamp_new_resource`, ids are written in the form `schema` gives them
+pub fn stamp_new_resource_with_schema(resource_id: &str, template: &NewResourceTemplate, schema: KeySchema, db: &mut dyn MutableStorage) -> io::Result<()> {
+    let mut batch = Vec::new();
+
+    let p_key = schema.key(PERMISSION_PREFIX, resource_id);
+    let (mut permissions, validity) = read_record_set_until(&p_key, db)?;
+    permissions.insert(ACLRecord::new_with_access(&schema.encode_id(&template.owner_id), template.owner_access));
+    batch.extend(record_batch(&p_key, Some(&encode_record_auto(&permissions.to_sorted_vec(), validity)), db)?);
+
+    if !template.groups.is_empty() {
+        let m_key = schema.key(MEMBERSHIP_PREFIX, resource_id);
+        let (mut groups, validity) = read_record_set_until(&m_key, db)?;
+        for (id, access) in &template.groups {
+            groups.insert(ACLRecord::new_with_access(&schema.encode_id(id), *access));
+        }
+        batch.extend(record_batch(&m_key, Some(&encode_record_auto(&groups.to_sorted_vec(), validity)), db)?);
+    }
+
+    if let Some((id, access)) = &template.filter {
+        let f_key = schema.key(FILTER_PREFIX, resource_id);
+        batch.extend(record_batch(&f_key, Some(&encode_rights(&[ACLRecord::new_with_access(&schema.encode_id(id), *access)])), db)?);
+    }
+
+    db.apply_batch(&batch)
+}
+
+// Действующие записи; повторяющиеся записи одного субъекта объединяются
+fn read_record_set(key: &str, db: &mut dyn Storage) -> io::Result<RecordSet> {
+    let mut records = read_record_set_until(key, db)?.0.into_inner();
+    records.retain(|_, rec| !rec.is_deleted);
+    Ok(RecordSet::from(records))
+}
+
+// С надгробиями и сроками действия записи, чтобы перезапись их сохранила
+fn read_record_set_until(key: &str, db: &mut dyn Storage) -> io::Result<(RecordSet, Validity)> {
+    let mut res = RecordSet::new();
+    let mut validity = Validity::default();
+    if let Some(src) = read_continued(key, db)? {
+        let mut records = ACLRecordVec::new();
+        validity = read_validity(&src, db, &mut records);
+        for rec in records {
+            res.insert(rec);
+        }
+    }
+    Ok((res, validity))
+}
+
+fn read_validity(src: &str, db: &dyn Storage, records: &mut ACLRecordVec) -> Validity {
+    Validity {
+        valid_until: db.decode_rec_to_rights(src, records).1,
+        valid_from: db.decode_valid_from(src),
+    }
+}
+
+/// What a bulk revoke changed, or would change on a dry run
+#[derive(Debug, Default)]
+pub struct RevokeReport {
+    /// Membership entries removed
+    pub memberships: usize,
+    /// Permission entries removed, pattern grants included
+    pub permissions: usize,
+    /// Co-signatures removed
+    pub cosignatures: usize,
+    /// Ids (keys without prefix) of the records changed
+    pub resources: Vec<String>,
+}
+
+/// Revokes every membership, permission, pattern grant and co-signature of a subject, for offboarding.
+/// The entries are kept as tombstones (`is_deleted`), which the traversal skips and `compact` drops; where
+/// the record would not fit `MutableStorage::max_value_len` (tombstones need the v2 format, which is not
+/// split into parts) the entries are removed instead.
+pub fn revoke_all(subject_id: &str, db: &mut dyn MutableStorage, dry_run: bool) -> io::Result<RevokeReport> {
+    revoke_all_with_schema(subject_id, KeySchema::Legacy, db, dry_run)
+}
+
+/// Same as `revoke_all`, with the id encoded as `schema` encodes it
+pub fn revoke_all_with_schema(subject_id: &str, schema: KeySchema, db: &mut dyn MutableStorage, dry_run: bool) -> io::Result<RevokeReport> {
+    let subject_id = schema.encode_id(subject_id);
+    let mut report = RevokeReport::default();
+    let mut batch = Vec::new();
+
+    // Собственные членства субъекта
+    let own_key = MEMBERSHIP_PREFIX.to_owned() + &subject_id;
+    let mut keys = vec![own_key.clone()];
+    keys.extend(db.keys_referencing(&subject_id)?.into_iter().filter(|key| *key != own_key));
+
+    for key in keys {
+        let Some(src) = read_continued(&key, db)? else {
+            continue;
+        };
+
+        let mut records = ACLRecordVec::new();
+        let validity = read_validity(&src, db, &mut records);
+        let mut revoked = 0;
+        for rec in records.iter_mut().filter(|r| !r.is_deleted && (key == own_key || r.id == *subject_id)) {
+            rec.is_deleted = true;
+            revoked += 1;
+        }
+        if revoked == 0 {
+            continue;
+        }
+
+        if key.starts_with(COSIGN_PREFIX) {
+            report.cosignatures += revoked;
+        } else if key.starts_with(MEMBERSHIP_PREFIX) {
+            report.memberships += revoked;
+        } else {
+            report.permissions += revoked;
+        }
+        report.resources.push(key[1..].to_owned());
+
+        let mut value = Some(encode_record_auto(records.iter(), validity));
+        // запись с надгробиями двоичная и на части не делится; если она не помещается, записи удаляются
+        if value.as_ref().is_some_and(|v| db.max_value_len().is_some_and(|max_len| v.len() > max_len)) {
+            records.retain(|r| !r.is_deleted);
+            value = (!records.is_empty()).then(|| encode_record_auto(records.iter(), validity));
+        }
+        batch.extend(record_batch(&key, value.as_deref(), db)?);
+    }
+
+    commit_batch(batch, db, dry_run)?;
+
+    Ok(report)
+}
+
+/// Removes all permissions on the resources or object groups whose id starts with `prefix`, filtered ones
+/// included, along with their aggregates and the pattern grants under `prefix`, e.g. when a project is archived
+pub fn revoke_resource(prefix: &str, db: &mut dyn MutableStorage, dry_run: bool) -> io::Result<RevokeReport> {
+    revoke_resource_with_schema(prefix, KeySchema::Legacy, db, dry_run)
+}
+
+/// Same as `revoke_resource`, with `prefix` encoded and filtered keys built as `schema` does it
+pub fn revoke_resource_with_schema(prefix: &str, schema: KeySchema, db: &mut dyn MutableStorage, dry_run: bool) -> io::Result<RevokeReport> {
+    let prefix = schema.encode_id(prefix);
+    let mut report = RevokeReport::default();
+    let mut batch = Vec::new();
+
+    // Фильтрованные права лежат под ключом из фильтра и id группы
+    let filters = filter_ids(db)?;
+    let mut scans = vec![(PERMISSION_PREFIX.to_owned() + &prefix, false), (PATTERN_PREFIX.to_owned() + &prefix, true)];
+    scans.extend(filters.iter().map(|f| (PERMISSION_PREFIX.to_owned() + &schema.permission_suffix(f, &prefix), true)));
+
+    let mut seen = HashSet::new();
+    for (scan_prefix, exact) in scans {
+        for (key, value) in scan_records(&scan_prefix, db)? {
+            // в схеме Escaped ключ с ';' - фильтрованное право, его фильтр может лишь начинаться с `prefix`
+            if !exact && schema == KeySchema::Escaped && key.contains(';') || !seen.insert(key.clone()) {
+                continue;
+            }
+
+            let mut records = ACLRecordVec::new();
+            db.decode_rec_to_rights(&value, &mut records);
+            report.permissions += records.iter().filter(|r| !r.is_deleted).count();
+
+            report.resources.push(key[PERMISSION_PREFIX.len()..].to_owned());
+            batch.extend(record_batch(&key, None, db)?);
+        }
+    }
+
+    commit_batch(batch, db, dry_run)?;
+
+    Ok(report)
+}
+
+/// Distinct filter ids of the F-records, encoded
+pub(crate) fn filter_ids(db: &mut dyn MutableStorage) -> io::Result<Vec<String>> {
+    let mut filters: Vec<String> = Vec::new();
+    for (_, value) in scan_records(FILTER_PREFIX, db)? {
+        if let (Some(filter), _) = db.decode_filter(value) {
+            if !filter.id.is_empty() && !filters.contains(&filter.id) {
+                filters.push(filter.id);
+            }
+        }
+    }
+    Ok(filters)
+}
+
+fn commit_batch(batch: Vec<(String, Option<String>)>, db: &mut dyn MutableStorage, dry_run: bool) -> io::Result<()> {
+    if dry_run || batch.is_empty() {
+        return Ok(());
+    }
+
+    db.apply_batch(&batch)?;
+    db.emit_invalidation(&batch.into_iter().map(|(key, _)| key).collect::<Vec<_>>());
+    Ok(())
+}
+
+/// Entry counts of a record before and after compaction
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CompactStats {
+    pub records: usize,
+    pub entries_before: usize,
+    pub entries_after: usize,
+}
+
+/// Merges duplicate entries of a record per subject (masks OR-ed, counters summed), drops deleted ones
+/// and rewrites the value if it changed; a record left empty is removed.
+///
+/// Only entries that decide the same together as apart are merged: same marker, and either all allow bits
+/// or all deny bits. An allow and a deny of the same subject stay separate entries, as the traversal checks
+/// them one by one. Decisions are kept for stores read with `DuplicateEntries::Or`, the default.
+pub fn compact(key: &str, db: &mut dyn MutableStorage) -> io::Result<CompactStats> {
+    let Some(src) = read_continued(key, db)? else {
+        return Ok(CompactStats::default());
+    };
+
+    let mut records = ACLRecordVec::new();
+    let validity = read_validity(&src, db, &mut records);
+
+    let mut merged = ACLRecordVec::new();
+    let mut merged_at: HashMap<(String, char, u8), usize> = HashMap::new();
+    for rec in records.iter().filter(|r| !r.is_deleted) {
+        match merged_at.get(&(rec.id.clone(), rec.marker, merge_class(rec.access))) {
+            Some(idx) => {
+                let cur = &mut merged[*idx];
+                cur.access |= rec.access;
+                cur.counters.merge(&rec.counters);
+                if cur.provenance.is_none() {
+                    cur.provenance = rec.provenance.clone();
+                }
+            },
+            None => {
+                merged_at.insert((rec.id.clone(), rec.marker, merge_class(rec.access)), merged.len());
+                merged.push(rec.clone());
+            },
+        }
+    }
+
+    let stats = CompactStats {
+        records: 1,
+        entries_before: records.len(),
+        entries_after: merged.len(),
+    };
+
+    if merged.is_empty() {
+        write_record(key, None, db)?;
+    } else if stats.entries_after != stats.entries_before {
+        merged.sort_by(|a, b| a.id.cmp(&b.id));
+        write_record(key, Some(&encode_record_auto(&merged, validity)), db)?;
+    }
+
+    Ok(stats)
+}
+
+// Записи только с разрешениями или только с запретами объединяются между собой, смешанные - лишь с такими же
+fn merge_class(access: u8) -> u8 {
+    if access & 0xF0 == 0 {
+        0x0F
+    } else if access & 0x0F == 0 {
+        0xF0
+    } else {
+        access
+    }
+}
+
+/// Compacts all records under `prefix`, pausing for `pause` after every `batch_size` records
+/// so the store is not saturated
+pub fn compact_prefix(prefix: &str, batch_size: usize, pause: Duration, db: &mut dyn MutableStorage) -> io::Result<CompactStats> {
+    let mut total = CompactStats::default();
+
+    let keys: Vec<String> = scan_records(prefix, db)?.into_iter().map(|(key, _)| key).collect();
+    for (n, key) in keys.iter().enumerate() {
+        let stats = compact(key, db)?;
+        total.records += stats.records;
+        total.entries_before += stats.entries_before;
+        total.entries_after += stats.entries_after;
+
+        if batch_size > 0 && (n + 1) % batch_size == 0 && !pause.is_zero() {
+            thread::sleep(pause);
+        }
+    }
+
+    Ok(total)
+}
+
+/// Grants `right` (a char of `ACCESS_C_FULL_LIST`) to the subject in the record under `key`, one more reference.
+/// A grant written before counters were kept counts as one reference.
+pub fn add_grant(key: &str, subject_id: &str, right: char, db: &mut dyn MutableStorage) -> io::Result<()> {
+    let Some(idx) = counter_index(right) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown right {:?}", right)));
+    };
+
+    update_grant(key, subject_id, db, |rec| {
+        if rec.access & ACCESS_8_FULL_LIST[idx] != 0 && rec.counters.get(right) == 0 {
+            rec.counters.increment(right);
+        }
+        rec.add_right(right);
+    })
+}
+
+/// Drops one reference to `right`; the right is gone only when no other grant holds it
+pub fn remove_grant(key: &str, subject_id: &str, right: char, db: &mut dyn MutableStorage) -> io::Result<()> {
+    update_grant(key, subject_id, db, |rec| rec.remove_right(right))
+}
+
+fn update_grant(key: &str, subject_id: &str, db: &mut dyn MutableStorage, f: impl FnOnce(&mut ACLRecord)) -> io::Result<()> {
+    let (records, validity) = read_record_set_until(key, db)?;
+    let mut records = records.into_inner();
+
+    let rec = records.entry(subject_id.to_owned()).or_insert_with(|| ACLRecord::new_with_access(subject_id, 0));
+    if rec.is_deleted {
+        // надгробие заменяет только новая выдача
+        let mut live = ACLRecord::new_with_access(subject_id, 0);
+        f(&mut live);
+        if live.access != 0 {
+            *rec = live;
+        }
+    } else {
+        f(rec);
+        if rec.access == 0 {
+            records.remove(subject_id);
+        }
+    }
+
+    if records.is_empty() {
+        write_record(key, None, db)
+    } else {
+        write_record(key, Some(&encode_record_auto(&RecordSet::from(records).to_sorted_vec(), validity)), db)
+    }
+}
+
+/// Sets when the M- or P-record under `key` is in force, keeping its entries; a record with no
+/// bounds left is written back in v1 form
+pub fn set_validity(key: &str, validity: Validity, db: &mut dyn MutableStorage) -> io::Result<()> {
+    let (records, _) = read_record_set_until(key, db)?;
+    if records.is_empty() {
+        return Ok(());
+    }
+    write_record(key, Some(&encode_record_auto(&records.to_sorted_vec(), validity)), db)
+}
+
+/// The soonest expiry or activation after `now` among the P-, M- and F-records of ids starting with `scope`,
+/// for scheduling cache invalidation and notifications instead of polling
+pub fn next_expiry_after(db: &mut dyn MutableStorage, now: DateTime<Utc>, scope: &str) -> io::Result<Option<DateTime<Utc>>> {
+    let mut res: Option<DateTime<Utc>> = None;
+
+    for prefix in [PERMISSION_PREFIX, MEMBERSHIP_PREFIX, FILTER_PREFIX] {
+        for (_, value) in scan_records(&(prefix.to_owned() + scope), db)? {
+            let times = if prefix == FILTER_PREFIX {
+                [db.decode_filter(value).1, None]
+            } else {
+                [db.decode_rec_to_rights(&value, &mut ACLRecordVec::new()).1, db.decode_valid_from(&value)]
+            };
+
+            for t in times.into_iter().flatten().filter(|t| *t > now) {
+                res = Some(res.map_or(t, |cur| cur.min(t)));
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+/// Property map of an individual: predicate to its values in text form, the uri itself under `@`
+pub type IndividualProps = HashMap<String, Vec<String>>;
+
+/// Entries to add to, or remove from, the record under `key`
+#[derive(Debug, Clone)]
+pub struct AclChange {
+    pub key: String,
+    pub entries: Vec<ACLRecord>,
+    /// Set for deleted individuals: `entries` are to be taken out of the record
+    pub is_remove: bool,
+}
+
+/// Translates a `v-s:PermissionStatement`, `v-s:Membership` or `v-s:PermissionFilter` individual
+/// into record changes; other types give no changes.
+///
+/// `v-s:canRead true` grants the bit, `false` sets the matching `cant*` bit. A membership without
+/// any `can*` predicate passes full access. `v-s:creator`, `v-s:created` and `rdfs:comment` become the
+/// provenance of the grants.
+pub fn from_individual(props: &IndividualProps) -> io::Result<Vec<AclChange>> {
+    from_individual_with_schema(props, KeySchema::Legacy)
+}
+
+/// Same as `from_individual`, ids are written in the form `schema` gives them
+pub fn from_individual_with_schema(props: &IndividualProps, schema: KeySchema) -> io::Result<Vec<AclChange>> {
+    let types = values(props, "rdf:type");
+    let is_remove = first(props, "v-s:deleted") == Some("true");
+
+    let mut res = Vec::new();
+
+    if types.contains(&"v-s:PermissionStatement") {
+        let filter = schema.encode_id(first(props, "v-s:useFilter").unwrap_or_default());
+        let access = individual_access(props).unwrap_or(0);
+        if access == 0 {
+            return Err(invalid_individual("permission statement without rights"));
+        }
+        let subjects: Vec<ACLRecord> = values(props, "v-s:permissionSubject")
+            .iter()
+            .map(|s| {
+                let mut rec = ACLRecord::new_with_access(&schema.encode_id(s), access);
+                rec.provenance = individual_provenance(props);
+                rec
+            })
+            .collect();
+        for object in values(props, "v-s:permissionObject") {
+            res.push(AclChange {
+                key: PERMISSION_PREFIX.to_owned() + &schema.permission_suffix(&filter, &schema.encode_id(object)),
+                entries: subjects.clone(),
+                is_remove,
+            });
+        }
+    } else if types.contains(&"v-s:Membership") {
+        let mut group = ACLRecord::new_with_access("", individual_access(props).unwrap_or(15));
+        group.provenance = individual_provenance(props);
+        if first(props, "v-s:isExclusive") == Some("true") {
+            group.marker = M_IS_EXCLUSIVE;
+        } else if first(props, "v-s:ignoreExclusive") == Some("true") {
+            group.marker = M_IGNORE_EXCLUSIVE;
+        }
+        let groups: Vec<ACLRecord> = values(props, "v-s:memberOf")
+            .iter()
+            .map(|g| {
+                let mut rec = group.clone();
+                rec.id = schema.encode_id(g).into_owned();
+                rec
+            })
+            .collect();
+        for resource in values(props, "v-s:resource") {
+            res.push(AclChange {
+                key: schema.key(MEMBERSHIP_PREFIX, resource),
+                entries: groups.clone(),
+                is_remove,
+            });
+        }
+    } else if types.contains(&"v-s:PermissionFilter") {
+        let Some(filter) = first(props, "@") else {
+            return Err(invalid_individual("permission filter without uri"));
+        };
+        let entry = ACLRecord::new_with_access(&schema.encode_id(filter), individual_access(props).unwrap_or(0));
+        for object in values(props, "v-s:permissionObject") {
+            res.push(AclChange {
+                key: schema.key(FILTER_PREFIX, object),
+                entries: vec![entry.clone()],
+                is_remove,
+            });
+        }
+    }
+
+    Ok(res)
+}
+
+// Биты прав из предикатов v-s:canCreate .. v-s:canDelete; None, если ни один не задан
+fn individual_access(props: &IndividualProps) -> Option<u8> {
+    let mut access = None;
+    for (idx, predicate) in ACCESS_8_PREDICATE_LIST[..4].iter().enumerate() {
+        match first(props, predicate) {
+            Some("true") => *access.get_or_insert(0) |= ACCESS_8_FULL_LIST[idx],
+            Some("false") => *access.get_or_insert(0) |= ACCESS_8_FULL_LIST[idx + 4],
+            _ => {},
+        }
+    }
+    access
+}
+
+// Кем, когда и почему выдано: v-s:creator, v-s:created и rdfs:comment
+fn individual_provenance(props: &IndividualProps) -> Option<Box<GrantProvenance>> {
+    let res = GrantProvenance {
+        granted_by: first(props, "v-s:creator").map(|s| s.to_owned()),
+        granted_at: first(props, "v-s:created").and_then(|s| DateTime::parse_from_rfc3339(s).ok()).map(|t| t.with_timezone(&Utc)),
+        reason: first(props, "rdfs:comment").map(|s| s.to_owned()),
+    };
+    if res == GrantProvenance::default() {
+        None
+    } else {
+        Some(Box::new(res))
+    }
+}
+
+fn values<'a>(props: &'a IndividualProps, predicate: &str) -> Vec<&'a str> {
+    props.get(predicate).map(|v| v.iter().map(|s| s.as_str()).collect()).unwrap_or_default()
+}
+
+fn first<'a>(props: &'a IndividualProps, predicate: &str) -> Option<&'a str> {
+    props.get(predicate).and_then(|v| v.first()).map(|s| s.as_str())
+}
+
+/// Applies a change with reference counting: adding an entry grants each of its rights once more,
+/// removing it drops one reference; F-records are replaced or removed as a whole
+pub fn apply_change(change: &AclChange, db: &mut dyn MutableStorage) -> io::Result<()> {
+    if change.key.starts_with(FILTER_PREFIX) {
+        return match (change.is_remove, change.entries.first()) {
+            (false, Some(entry)) => write_record(&change.key, Some(&encode_rights(std::slice::from_ref(entry))), db),
+            _ => write_record(&change.key, None, db),
+        };
+    }
+
+    let (records, validity) = read_record_set_until(&change.key, db)?;
+    let mut records = records.into_inner();
+
+    for entry in &change.entries {
+        let rec = records.entry(entry.id.clone()).or_insert_with(|| ACLRecord::new_with_access(&entry.id, 0));
+        if rec.is_deleted {
+            // надгробие заменяет только новая выдача
+            if change.is_remove {
+                continue;
+            }
+            *rec = ACLRecord::new_with_access(&entry.id, 0);
+        }
+        for (idx, c) in ACCESS_C_FULL_LIST.iter().enumerate() {
+            if entry.access & ACCESS_8_FULL_LIST[idx] == 0 {
+                continue;
+            }
+            if change.is_remove {
+                rec.remove_right(*c);
+            } else {
+                if rec.access & ACCESS_8_FULL_LIST[idx] != 0 && rec.counters.get(*c) == 0 {
+                    rec.counters.increment(*c);
+                }
+                rec.add_right(*c);
+            }
+        }
+        if !change.is_remove && entry.marker != 0 as char {
+            rec.marker = entry.marker;
+        }
+        // происхождение последней выдачи
+        if !change.is_remove && entry.provenance.is_some() {
+            rec.provenance = entry.provenance.clone();
+        }
+        if rec.access == 0 {
+            records.remove(&entry.id);
+        }
+    }
+
+    if records.is_empty() {
+        write_record(&change.key, None, db)
+    } else {
+        write_record(&change.key, Some(&encode_record_auto(&RecordSet::from(records).to_sorted_vec(), validity)), db)
+    }
+}
+
+/// Writes or removes the record under `key` with its continuation parts, split by `MutableStorage::max_value_len`;
+/// parts left over from a bigger previous version are removed
+pub fn write_record(key: &str, value: Option<&str>, db: &mut dyn MutableStorage) -> io::Result<()> {
+    let batch = record_batch(key, value, db)?;
+    match (batch.as_slice(), value) {
+        ([_], Some(value)) => db.put(key, value),
+        ([_], None) => db.remove(key),
+        _ => db.apply_batch(&batch),
+    }
+}
+
+// Ключи, которые надо записать или удалить, чтобы под `key` оказалось `value`, для пакетов из нескольких записей;
+// вместе с частями записи в пакет входит ее агрегат
+fn record_batch(key: &str, value: Option<&str>, db: &mut dyn MutableStorage) -> io::Result<Vec<(String, Option<String>)>> {
+    let prev_parts = match db.get(key)? {
+        Some(head) => continuation_parts(&head).1,
+        None => 0,
+    };
+
+    let mut batch: Vec<(String, Option<String>)> = match (value, db.max_value_len()) {
+        (Some(value), Some(max_len)) => split_continued(key, value, max_len).into_iter().map(|(k, v)| (k, Some(v))).collect(),
+        (Some(value), None) => vec![(key.to_owned(), Some(value.to_owned()))],
+        (None, _) => vec![(key.to_owned(), None)],
+    };
+
+    let parts = batch.len() - 1;
+    // под ключами сверх прежних частей в схеме Legacy может лежать запись id, оканчивающегося на #<n>
+    for part in prev_parts + 1..=parts {
+        let part_key = continuation_key(key, part);
+        if db.get(&part_key)?.is_some() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("record {}: continuation part {} would overwrite {}", key, part, part_key)));
+        }
+    }
+    for part in parts + 1..=prev_parts {
+        batch.push((continuation_key(key, part), None));
+    }
+
+    if let Some(key_suffix) = key.strip_prefix(PERMISSION_PREFIX) {
+        batch.extend(aggregate_batch(key_suffix, value, db)?);
+    }
+    // сводки достижимости строятся по M-, P- и F-записям
+    if [MEMBERSHIP_PREFIX, PERMISSION_PREFIX, FILTER_PREFIX].iter().any(|prefix| key.starts_with(prefix)) {
+        if let Some(generation) = acl_generation(db)? {
+            batch.push((ACL_GENERATION_KEY.to_owned(), Some(generation.wrapping_add(1).to_string())));
+        }
+    }
+    Ok(batch)
+}
+
+/// Records under `prefix` with their continuation parts joined, as `read_continued` gives them; the parts
+/// are not listed on their own
+pub fn scan_records(prefix: &str, db: &mut dyn MutableStorage) -> io::Result<Vec<(String, String)>> {
+    join_continued(db.scan_prefix(prefix)?)
+}
+
+fn join_continued(scanned: Vec<(String, String)>) -> io::Result<Vec<(String, String)>> {
+    let announced: HashMap<String, usize> = scanned.iter().map(|(key, value)| (key.clone(), continuation_parts(value).1)).filter(|(_, n)| *n > 0).collect();
+    let (heads, parts): (Vec<_>, Vec<_>) = scanned.into_iter().partition(|(key, _)| !is_continuation_part(key, &announced));
+    let parts: HashMap<String, String> = parts.into_iter().collect();
+
+    let mut res = Vec::with_capacity(heads.len());
+    for (key, head) in heads {
+        let (entries, count) = continuation_parts(&head);
+        if count == 0 {
+            res.push((key, head));
+            continue;
+        }
+
+        let mut value = entries.to_owned();
+        for part in 1..=count {
+            match parts.get(&continuation_key(&key, part)) {
+                Some(src) => value.push_str(src),
+                None => return Err(missing_part(&key, part, count)),
+            }
+        }
+        res.push((key, value));
+    }
+    res.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(res)
+}
+
+/// Pause between two checks of `await_visibility`
+pub const VISIBILITY_POLL: Duration = Duration::from_millis(10);
+
+/// Waits until an applied change can be seen by readers, so "share then open" flows do not race the indexer;
+/// `false` when `timeout` passed first.
+///
+/// The record must hold every added entry with all of its bits. A grant without a filter must in addition be
+/// given to the subject on the object group by `engine.authorize_dry`, which reads past the caching layers
+/// of `db`; anything in the engine config keeping the grant from taking effect (exclusivity, ceiling,
+/// co-signature) makes the wait time out. Readers behind a cache may see the change only once it drops
+/// the old record (invalidation, ttl or a new epoch).
+/// A removal drops one reference and may leave the bits in place, so it is visible at once.
+pub fn await_visibility(change: &AclChange, engine: &AzEngine, db: &mut dyn Storage, timeout: Duration) -> io::Result<bool> {
+    let started = Instant::now();
+    loop {
+        if is_visible(change, engine, db)? {
+            return Ok(true);
+        }
+        if started.elapsed() >= timeout {
+            return Ok(false);
+        }
+        db.fiber_yield();
+        thread::sleep(VISIBILITY_POLL.min(timeout.saturating_sub(started.elapsed())));
+    }
+}
+
+fn is_visible(change: &AclChange, engine: &AzEngine, db: &mut dyn Storage) -> io::Result<bool> {
+    if change.is_remove {
+        return Ok(true);
+    }
+
+    if change.key.starts_with(FILTER_PREFIX) {
+        let mut records = ACLRecordVec::new();
+        if let Some(src) = db.get(&change.key)? {
+            db.decode_rec_to_rights(&src, &mut records);
+        }
+        return Ok(change.entries.first().is_none_or(|entry| records.first().is_some_and(|f| f.id == entry.id && f.access == entry.access)));
+    }
+
+    let records = read_record_set(&change.key, db)?;
+    if !change.entries.iter().all(|entry| records.get(&entry.id).is_some_and(|rec| rec.access & entry.access == entry.access)) {
+        return Ok(false);
+    }
+
+    let Some(suffix) = change.key.strip_prefix(PERMISSION_PREFIX) else {
+        return Ok(true);
+    };
+    // ключи схемы Escaped: фильтр отделён ';', идентификаторы возвращаются к исходной форме для authorize
+    let schema = engine.config().load().key_schema;
+    if schema == KeySchema::Escaped && suffix.contains(';') {
+        return Ok(true);
+    }
+    let decode = |id: &str| match schema {
+        KeySchema::Legacy => Some(id.to_owned()),
+        KeySchema::Escaped => unescape_id(id).map(|id| id.into_owned()),
+    };
+    let Some(object_group) = decode(suffix) else {
+        return Ok(true);
+    };
+
+    for entry in &change.entries {
+        let bits = entry.access & MANAGER;
+        if bits == 0 {
+            continue;
+        }
+        let Some(subject) = decode(&entry.id) else {
+            continue;
+        };
+        if engine.authorize_dry(&object_group, &subject, bits, db)? & bits != bits {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Why a validator refused a change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub key: String,
+    /// Entry the rule objects to, `None` for the change as a whole
+    pub entry: Option<String>,
+    /// Name of the rule, for operators and metrics
+    pub rule: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.entry {
+            Some(entry) => write!(f, "{}: {} of {}: {}", self.rule, entry, self.key, self.reason),
+            None => write!(f, "{}: {}: {}", self.rule, self.key, self.reason),
+        }
+    }
+}
+
+/// Error payload of a refused change, kind `PermissionDenied`; get it back with
+/// `err.get_ref().and_then(|e| e.downcast_ref::<ChangeRejected>())`
+#[derive(Debug)]
+pub struct ChangeRejected {
+    pub rejections: Vec<Rejection>,
+}
+
+impl fmt::Display for ChangeRejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reasons: Vec<String> = self.rejections.iter().map(|r| r.to_string()).collect();
+        write!(f, "change rejected: {}", reasons.join("; "))
+    }
+}
+
+impl std::error::Error for ChangeRejected {}
+
+/// Ontology-aware veto on changes before they are written, e.g. "no DELETE on v-s:Journal resources for non-admin groups".
+/// Adds a `Rejection` for every rule the change breaks.
+pub trait ChangeValidator: Send + Sync {
+    fn validate(&self, change: &AclChange, db: &mut dyn Storage, rejections: &mut Vec<Rejection>);
+}
+
+impl<F> ChangeValidator for F
+where
+    F: Fn(&AclChange, &mut dyn Storage, &mut Vec<Rejection>) + Send + Sync,
+{
+    fn validate(&self, change: &AclChange, db: &mut dyn Storage, rejections: &mut Vec<Rejection>) {
+        self(change, db, rejections)
+    }
+}
+
+/// What `PermissionQuota` does with a change over the limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaAction {
+    /// Logs a warning and lets the change through
+    Warn,
+    /// Rejects the change
+    Block,
+}
+
+/// Advisory limit on direct entries of one P-record, so that grants to many individual users give way to
+/// group-based ones before decoding the record gets slow. Only changes adding new subjects are checked;
+/// use it as a `ChangeValidator`, on its own or from the indexer
+#[derive(Debug, Clone)]
+pub struct PermissionQuota {
+    pub max_entries: usize,
+    pub action: QuotaAction,
+}
+
+impl ChangeValidator for PermissionQuota {
+    fn validate(&self, change: &AclChange, db: &mut dyn Storage, rejections: &mut Vec<Rejection>) {
+        if change.is_remove || !change.key.starts_with(PERMISSION_PREFIX) {
+            return;
+        }
+
+        let records = match read_record_set(&change.key, db) {
+            Ok(records) => records,
+            Err(e) => {
+                eprintln!("WARN! permission quota: fail read {}, err={:?}", change.key, e);
+                return;
+            },
+        };
+        let new_subjects: HashSet<&str> = change.entries.iter().map(|e| e.id.as_str()).filter(|id| !records.contains(id)).collect();
+        let total = records.len() + new_subjects.len();
+        if new_subjects.is_empty() || total <= self.max_entries {
+            return;
+        }
+
+        let reason = format!("{} direct entries, limit {}; grant to a group instead", total, self.max_entries);
+        match self.action {
+            QuotaAction::Warn => eprintln!("WARN! permission quota: {}: {}", change.key, reason),
+            QuotaAction::Block => rejections.push(Rejection {
+                key: change.key.clone(),
+                entry: None,
+                rule: "permission-quota".to_owned(),
+                reason,
+            }),
+        }
+    }
+}
+
+/// Runs `validator` on every change; all of them are acceptable when the result is empty
+pub fn validate_changes(changes: &[AclChange], validator: &dyn ChangeValidator, db: &mut dyn MutableStorage) -> Vec<Rejection> {
+    let mut rejections = Vec::new();
+    for change in changes {
+        validator.validate(change, db, &mut rejections);
+    }
+    rejections
+}
+
+/// Same as `apply_change`, unless the validator rejects the change: nothing is written then
+/// and the error carries `ChangeRejected`. To validate every write, not only this call, write through
+/// `validating::ValidatingStorage`.
+pub fn apply_change_validated(change: &AclChange, validator: &dyn ChangeValidator, db: &mut dyn MutableStorage) -> io::Result<()> {
+    let rejections = validate_changes(std::slice::from_ref(change), validator, db);
+    if !rejections.is_empty() {
+        return Err(rejected(rejections));
+    }
+    apply_change(change, db)
+}
+
+pub(crate) fn rejected(rejections: Vec<Rejection>) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        ChangeRejected {
+            rejections,
+        },
+    )
+}
+
+fn invalid_individual(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// Co-signs `access` of the grant to `subject_id` on a sensitive group; `key_suffix` is the P-record key
+/// without the prefix. Bits co-signed before are kept.
+pub fn add_cosignature(key_suffix: &str, subject_id: &str, access: u8, db: &mut dyn MutableStorage) -> io::Result<()> {
+    let key = COSIGN_PREFIX.to_owned() + key_suffix;
+    let mut cosigned = read_record_set(&key, db)?;
+    cosigned.insert(ACLRecord::new_with_access(subject_id, access));
+    db.put(&key, &encode_rights(&cosigned.to_sorted_vec()))
+}
+
+/// Records `old_id` as an earlier id of `new_id`, so grants on `old_id` keep applying after a rename
+pub fn add_alias(old_id: &str, new_id: &str, db: &mut dyn MutableStorage) -> io::Result<()> {
+    if old_id == new_id {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("alias of {} to itself", old_id)));
+    }
+
+    let key = ALIAS_PREFIX.to_owned() + new_id;
+    let mut aliases = read_record_set(&key, db)?;
+    aliases.insert(ACLRecord::new(old_id));
+    db.put(&key, &encode_rights(&aliases.to_sorted_vec()))
+}
+
+/// Review of a grant: `reviewer` confirmed the `access` bits of `subject_id` in the P-record `key_suffix` at `at`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attestation {
+    pub key_suffix: String,
+    pub subject_id: String,
+    pub access: u8,
+    pub reviewer: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Grant whose last review is missing, older than asked or does not cover all of its bits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnattestedGrant {
+    pub key_suffix: String,
+    pub subject_id: String,
+    pub access: u8,
+    pub last: Option<Attestation>,
+}
+
+/// Records that `reviewer` confirmed the current grant to `subject_id` in the P-record `key_suffix`;
+/// replaces the previous review of the same grant. Fails if the record has no such grant
+pub fn attest_grant(key_suffix: &str, subject_id: &str, reviewer: &str, at: DateTime<Utc>, db: &mut dyn MutableStorage) -> io::Result<Attestation> {
+    let grants = read_record_set(&(PERMISSION_PREFIX.to_owned() + key_suffix), db)?;
+    let Some(grant) = grants.get(subject_id) else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no grant to {} in {}", subject_id, key_suffix)));
+    };
+
+    let attestation = Attestation {
+        key_suffix: key_suffix.to_owned(),
+        subject_id: subject_id.to_owned(),
+        access: grant.access,
+        reviewer: reviewer.to_owned(),
+        at,
+    };
+
+    let mut attestations = get_attestations(key_suffix, db)?;
+    attestations.retain(|a| a.subject_id != subject_id);
+    attestations.push(attestation.clone());
+    attestations.sort_by(|a, b| a.subject_id.cmp(&b.subject_id));
+    db.put(&(ATTESTATION_PREFIX.to_owned() + key_suffix), &encode_attestations(&attestations))?;
+
+    Ok(attestation)
+}
+
+/// Reviews recorded for the grants of the P-record `key_suffix`; malformed lines are skipped
+pub fn get_attestations(key_suffix: &str, db: &mut dyn Storage) -> io::Result<Vec<Attestation>> {
+    let Some(src) = db.get(&(ATTESTATION_PREFIX.to_owned() + key_suffix))? else {
+        return Ok(Vec::new());
+    };
+
+    let mut res = Vec::new();
+    for line in src.lines().filter(|l| !l.is_empty()) {
+        match decode_attestation(key_suffix, line) {
+            Some(a) => res.push(a),
+            None => eprintln!("WARN! get_attestations: malformed line in {}: {:?}", key_suffix, line),
+        }
+    }
+    Ok(res)
+}
+
+/// Grants in the P-records of ids starting with `scope` that were not reviewed at or after `since`,
+/// or whose bits grew since the review, for recurring certification campaigns
+pub fn unattested_grants(scope: &str, since: DateTime<Utc>, db: &mut dyn MutableStorage) -> io::Result<Vec<UnattestedGrant>> {
+    let mut res = Vec::new();
+    for (key, _) in scan_records(&(PERMISSION_PREFIX.to_owned() + scope), db)? {
+        let key_suffix = &key[PERMISSION_PREFIX.len()..];
+        let attestations = get_attestations(key_suffix, db)?;
+        for grant in read_record_set(&key, db)?.to_sorted_vec() {
+            let last = attestations.iter().find(|a| a.subject_id == grant.id);
+            if last.is_some_and(|a| a.at >= since && grant.access & !a.access == 0) {
+                continue;
+            }
+            res.push(UnattestedGrant {
+                key_suffix: key_suffix.to_owned(),
+                subject_id: grant.id.clone(),
+                access: grant.access,
+                last: last.cloned(),
+            });
+        }
+    }
+    Ok(res)
+}
+
+// Продолжения длинной записи читаются вместе с ее головой; `X#n` без объявленной в голове X части n - запись другого id
+fn is_continuation_part(key: &str, announced: &HashMap<String, usize>) -> bool {
+    match key.rsplit_once('#') {
+        Some((head, part)) => part.parse::<usize>().is_ok_and(|n| n > 0 && announced.get(head).is_some_and(|parts| n <= *parts)),
+        None => false,
+    }
+}
+
+fn encode_attestations(attestations: &[Attestation]) -> String {
+    attestations.iter().map(|a| format!("{}\t{}\t{}\t{}\n", a.subject_id, a.access, a.reviewer, a.at.to_rfc3339())).collect()
+}
+
+fn decode_attestation(key_suffix: &str, line: &str) -> Option<Attestation> {
+    let mut fields = line.split('\t');
+    let (subject_id, access, reviewer, at) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+    Some(Attestation {
+        key_suffix: key_suffix.to_owned(),
+        subject_id: subject_id.to_owned(),
+        access: access.parse().ok()?,
+        reviewer: reviewer.to_owned(),
+        at: DateTime::parse_from_rfc3339(at).ok()?.with_timezone(&Utc),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorize_ex;
+    use crate::config::AzConfig;
+    use crate::record_formats::CONTINUATION_ID;
+    use crate::storage::memory::MemoryStorage;
+
+    // Хранилище с маленьким пределом значения, чтобы записи делились на части
+    #[derive(Default)]
+    struct SmallValues(MemoryStorage);
+
+    impl Storage for SmallValues {
+        fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+            self.0.get(key)
+        }
+
+        fn fiber_yield(&self) {}
+    }
+
+    impl MutableStorage for SmallValues {
+        fn put(&mut self, key: &str, value: &str) -> io::Result<()> {
+            assert!(value.len() <= 40, "{} = {:?}", key, value);
+            self.0.put(key, value)
+        }
+
+        fn remove(&mut self, key: &str) -> io::Result<()> {
+            self.0.remove(key)
+        }
+
+        fn max_value_len(&self) -> Option<usize> {
+            Some(40)
+        }
+
+        fn scan_prefix(&mut self, prefix: &str) -> io::Result<Vec<(String, String)>> {
+            self.0.scan_prefix(prefix)
+        }
+    }
+
+    fn split_record() -> SmallValues {
+        let mut db = SmallValues::default();
+        for n in 0..10 {
+            let change = AclChange {
+                key: "Pdoc".to_owned(),
+                entries: vec![ACLRecord::new_with_access(&format!("user{}", n), 2)],
+                is_remove: false,
+            };
+            apply_change(&change, &mut db).unwrap();
+        }
+        assert!(db.0.len() > 1);
+        db
+    }
+
+    fn subjects(key: &str, db: &mut SmallValues) -> Vec<String> {
+        read_record_set(key, db).unwrap().to_sorted_vec().into_iter().map(|r| r.id).collect()
+    }
+
+    fn users(range: std::ops::Range<usize>) -> Vec<String> {
+        range.map(|n| format!("user{}", n)).collect()
+    }
+
+    #[test]
+    fn grants_in_parts_are_seen() {
+        let mut db = split_record();
+        for user in users(0..10) {
+            assert_eq!(authorize_ex("doc", &user, 2, &mut db).unwrap().granted, 2, "{}", user);
+        }
+        assert_eq!(authorize_ex("doc", CONTINUATION_ID, 2, &mut db).unwrap().granted, 0);
+    }
+
+    #[test]
+    fn compact_keeps_parts() {
+        let mut db = split_record();
+        db.put("Pdoc#3", "user0;2;;").unwrap();
+        let head = db.get("Pdoc").unwrap().unwrap();
+        let (entries, _) = continuation_parts(&head);
+        db.put("Pdoc", &format!("{}#;3;;", entries)).unwrap();
+
+        let stats = compact("Pdoc", &mut db).unwrap();
+        assert_eq!((stats.entries_before, stats.entries_after), (11, 10));
+        assert_eq!(subjects("Pdoc", &mut db), users(0..10));
+    }
+
+    #[test]
+    fn compact_keeps_decisions() {
+        let mut db = MemoryStorage::new();
+        db.put("Mdoc", "dg;15;;").unwrap();
+        db.put("Pdg", "u1;2;;u1;4;;u1;32;;u1;64;;u1;34;;u1;34;;g1;2;X;g1;2;;").unwrap();
+
+        let deny_override = AzConfig {
+            deny_override: true,
+            ..AzConfig::default()
+        };
+        let decide = |db: &mut MemoryStorage| {
+            [AzConfig::default(), deny_override.clone()]
+                .map(|cfg| [2, 4, 6].map(|access| AzEngine::new(cfg.clone()).authorize_dry("doc", "u1", access, db).unwrap()))
+        };
+        let before = decide(&mut db);
+
+        let stats = compact("Pdg", &mut db).unwrap();
+        assert_eq!((stats.entries_before, stats.entries_after), (8, 5));
+        assert_eq!(db.get("Pdg").unwrap().as_deref(), Some("g1;2;X;g1;2;;u1;6;;u1;96;;u1;34;;"));
+        assert_eq!(decide(&mut db), before);
+    }
+
+    #[test]
+    fn compact_keeps_allow_and_deny_apart() {
+        let mut db = MemoryStorage::new();
+        db.put("Md1", "dg;15;;").unwrap();
+        db.put("Pdg", "u1;2;;u1;32;;").unwrap();
+        let before = AzEngine::new(AzConfig::default()).authorize_dry("d1", "u1", 2, &mut db).unwrap();
+        assert_eq!(before, 2);
+
+        let stats = compact("Pdg", &mut db).unwrap();
+        assert_eq!((stats.entries_before, stats.entries_after), (2, 2));
+        assert_eq!(AzEngine::new(AzConfig::default()).authorize_dry("d1", "u1", 2, &mut db).unwrap(), before);
+    }
+
+    #[test]
+    fn stamping_keeps_validity_and_provenance() {
+        let until = DateTime::parse_from_rfc3339("2030-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let validity = Validity {
+            valid_from: None,
+            valid_until: Some(until),
+        };
+        let mut guest = ACLRecord::new_with_access("guest", 2);
+        guest.provenance = Some(Box::new(GrantProvenance {
+            granted_by: Some("admin".to_owned()),
+            ..GrantProvenance::default()
+        }));
+        let mut db = MemoryStorage::new();
+        db.put("Pdoc", &encode_record_auto([&guest], validity)).unwrap();
+        db.put("Mdoc", &encode_record_auto([&ACLRecord::new_with_access("folder", 15)], validity)).unwrap();
+
+        let mut template = NewResourceTemplate::new("owner");
+        template.groups.push(("type-group".to_owned(), 2));
+        stamp_new_resource("doc", &template, &mut db).unwrap();
+
+        for (key, ids) in [("Pdoc", vec!["guest", "owner"]), ("Mdoc", vec!["folder", "type-group"])] {
+            let (records, read) = read_record_set_until(key, &mut db).unwrap();
+            assert_eq!(read, validity, "{}", key);
+            assert_eq!(records.to_sorted_vec().iter().map(|r| r.id.as_str()).collect::<Vec<_>>(), ids);
+        }
+        let (records, _) = read_record_set_until("Pdoc", &mut db).unwrap();
+        assert_eq!(records.get("guest").and_then(|r| r.provenance.as_ref()).and_then(|p| p.granted_by.as_deref()), Some("admin"));
+    }
+
+    #[test]
+    fn stamping_encodes_ids() {
+        let mut db = MemoryStorage::new();
+        let mut template = NewResourceTemplate::new("td:user;1");
+        template.groups.push(("group;1".to_owned(), 15));
+        template.filter = Some(("filter;1".to_owned(), 2));
+        stamp_new_resource_with_schema("doc;1", &template, KeySchema::Escaped, &mut db).unwrap();
+
+        assert_eq!(db.get("Pdoc%3B1").unwrap().as_deref(), Some("td:user%3B1;15;;"));
+        assert_eq!(db.get("Mdoc%3B1").unwrap().as_deref(), Some("group%3B1;15;;"));
+        assert_eq!(db.get("Fdoc%3B1").unwrap().as_deref(), Some("filter%3B1;2;;"));
+    }
+
+    fn aggregate_epoch(db: &mut MemoryStorage) -> Option<u64> {
+        db.get("Adg").unwrap().and_then(|src| decode_aggregate(&src)).map(|(epoch, _)| epoch)
+    }
+
+    #[test]
+    fn aggregates_follow_writes() {
+        let engine = AzEngine::new(AzConfig {
+            use_permission_aggregates: true,
+            ..AzConfig::default()
+        });
+        let mut db = MemoryStorage::new();
+        db.add_permission("dg", "u1", 2).unwrap();
+        db.add_permission("dg", "u2", 2).unwrap();
+        rebuild_permission_aggregate("dg", 7, &mut db).unwrap();
+        assert_eq!(engine.authorize_dry("dg", "u1", 6, &mut db).unwrap(), 2);
+
+        add_grant("Pdg", "u1", 'U', &mut db).unwrap();
+        assert_eq!(engine.authorize_dry("dg", "u1", 6, &mut db).unwrap(), 6);
+        assert_eq!(aggregate_epoch(&mut db), Some(7));
+
+        revoke_all("u1", &mut db, false).unwrap();
+        assert_eq!(engine.authorize_dry("dg", "u1", 2, &mut db).unwrap(), 0);
+        assert_eq!(engine.authorize_dry("dg", "u2", 2, &mut db).unwrap(), 2);
+
+        db.put("Pdg", "u2;2;;u2;2;;").unwrap();
+        rebuild_permission_aggregate("dg", 7, &mut db).unwrap();
+        compact("Pdg", &mut db).unwrap();
+        remove_grant("Pdg", "u2", 'R', &mut db).unwrap();
+        assert_eq!(engine.authorize_dry("dg", "u2", 2, &mut db).unwrap(), 0);
+        assert_eq!(aggregate_epoch(&mut db), None);
+    }
+
+    #[test]
+    fn aggregates_go_with_revoked_resources() {
+        let mut db = MemoryStorage::new();
+        db.add_permission("dg", "u1", 2).unwrap();
+        rebuild_permission_aggregate("dg", 1, &mut db).unwrap();
+        revoke_resource("dg", &mut db, false).unwrap();
+        assert!(db.is_empty());
+    }
+
+    #[test]
+    fn summaries_go_stale_on_writes() {
+        let engine = AzEngine::new(AzConfig {
+            reachability_precheck: true,
+            ..AzConfig::default()
+        });
+        let mut db = MemoryStorage::new();
+        db.add_permission("doc", "u1", 2).unwrap();
+        rebuild_reachability("doc", 1, 8, &mut db).unwrap();
+        assert_eq!(engine.authorize_dry("doc", "u2", 2, &mut db).unwrap(), 0);
+
+        db.add_permission("doc", "u2", 2).unwrap();
+        assert_eq!(engine.authorize_dry("doc", "u2", 2, &mut db).unwrap(), 2);
+        db.add_membership("u3", "u2", 15).unwrap();
+        rebuild_reachability("doc", 1, 8, &mut db).unwrap();
+        assert_eq!(engine.authorize_dry("doc", "u3", 2, &mut db).unwrap(), 2);
+    }
+
+    #[test]
+    fn summaries_see_filtered_permissions_by_schema() {
+        let mut db = MemoryStorage::with_key_schema(KeySchema::Escaped);
+        db.add_filter("doc", "f1", 2).unwrap();
+        db.add_filtered_permission("f1", "doc", "u1", 2).unwrap();
+        let subjects = collect_reachable_subjects("doc", 8, KeySchema::Escaped, &mut db).unwrap();
+        assert!(subjects.contains("u1"));
+    }
+
+    #[test]
+    fn revoke_resource_takes_filtered_and_pattern_grants() {
+        for schema in [KeySchema::Legacy, KeySchema::Escaped] {
+            let mut db = MemoryStorage::with_key_schema(schema);
+            db.add_filter("d:1", "f1", 2).unwrap();
+            db.add_filter("e:1", "d:f", 2).unwrap();
+            for id in ["d:1", "e:1"] {
+                db.add_permission(id, "u1", 2).unwrap();
+                db.add_filtered_permission("f1", id, "u1", 2).unwrap();
+                db.add_filtered_permission("d:f", id, "u1", 2).unwrap();
+            }
+            apply_change(
+                &AclChange {
+                    key: "Wd:*".to_owned(),
+                    entries: vec![ACLRecord::new_with_access("u1", 2)],
+                    is_remove: false,
+                },
+                &mut db,
+            )
+            .unwrap();
+
+            let report = revoke_resource_with_schema("d:", schema, &mut db, false).unwrap();
+            let mut left: Vec<String> = db.scan_prefix("P").unwrap().into_iter().map(|(key, _)| key).collect();
+            left.sort();
+            // в схеме Legacy ключ фильтра d:f и группы e:1 не отличить от ключа группы d:fe:1
+            let (revoked, expected) = match schema {
+                KeySchema::Legacy => (5, vec!["Pe:1", "Pf1e:1"]),
+                KeySchema::Escaped => (4, vec!["Pd:f;e:1", "Pe:1", "Pf1;e:1"]),
+            };
+            assert_eq!((report.permissions, left.iter().map(String::as_str).collect::<Vec<_>>()), (revoked, expected));
+            assert_eq!(db.get("Wd:*").unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn grant_updates_respect_the_limit() {
+        let mut db = split_record();
+        add_grant("Pdoc", "user_with_a_rather_long_id", 'U', &mut db).unwrap();
+        let mut expected = users(0..10);
+        expected.push("user_with_a_rather_long_id".to_owned());
+        assert_eq!(subjects("Pdoc", &mut db), expected);
+
+        for user in users(1..10) {
+            remove_grant("Pdoc", &user, 'R', &mut db).unwrap();
+        }
+        assert_eq!(subjects("Pdoc", &mut db), vec!["user0".to_owned(), "user_with_a_rather_long_id".to_owned()]);
+        assert_eq!(db.0.iter().filter(|(key, _)| key.starts_with("Pdoc#")).count(), 0);
+    }
+
+    #[test]
+    fn revoke_all_rewrites_split_records() {
+        let mut db = split_record();
+        let report = revoke_all("user9", &mut db, false).unwrap();
+        assert_eq!(report.permissions, 1);
+        assert_eq!(report.resources, vec!["doc".to_owned()]);
+        assert_eq!(subjects("Pdoc", &mut db), users(0..9));
+
+        let report = revoke_resource("doc", &mut db, false).unwrap();
+        assert_eq!((report.permissions, report.resources), (9, vec!["doc".to_owned()]));
+        assert!(db.0.is_empty());
+    }
+
+    #[test]
+    fn revoke_all_leaves_tombstones() {
+        let engine = AzEngine::new(AzConfig {
+            key_schema: KeySchema::Escaped,
+            ..AzConfig::default()
+        });
+        let mut db = MemoryStorage::with_key_schema(KeySchema::Escaped);
+        db.add_membership("u#1", "g1", 15).unwrap();
+        db.add_permission("doc", "u#1", 2).unwrap();
+        db.add_permission("doc", "u2", 2).unwrap();
+        let grant = |access| vec![ACLRecord::new_with_access("u%231", access)];
+        apply_change(
+            &AclChange {
+                key: "Wd:*".to_owned(),
+                entries: grant(2),
+                is_remove: false,
+            },
+            &mut db,
+        )
+        .unwrap();
+        add_cosignature("doc", "u%231", 2, &mut db).unwrap();
+        assert_eq!(engine.authorize_dry("doc", "u#1", 2, &mut db).unwrap(), 2);
+
+        let report = revoke_all_with_schema("u#1", KeySchema::Escaped, &mut db, false).unwrap();
+        assert_eq!((report.memberships, report.permissions, report.cosignatures), (1, 2, 1));
+        for key in ["Mu%231", "Pdoc", "Wd:*", "Qdoc"] {
+            let mut records = ACLRecordVec::new();
+            let src = db.get(key).unwrap().unwrap();
+            db.decode_rec_to_rights(&src, &mut records);
+            assert!(records.iter().any(|r| r.is_deleted), "{}", key);
+        }
+        assert!(db.keys_referencing("u%231").unwrap().is_empty());
+        assert_eq!(engine.authorize_dry("doc", "u#1", 2, &mut db).unwrap(), 0);
+        assert_eq!(engine.authorize_dry("doc", "u2", 2, &mut db).unwrap(), 2);
+
+        apply_change(
+            &AclChange {
+                key: "Pdoc".to_owned(),
+                entries: grant(4),
+                is_remove: false,
+            },
+            &mut db,
+        )
+        .unwrap();
+        assert_eq!(engine.authorize_dry("doc", "u#1", 6, &mut db).unwrap(), 4);
+
+        compact("Mu%231", &mut db).unwrap();
+        assert_eq!(db.get("Mu%231").unwrap(), None);
+    }
+
+    #[test]
+    fn scans_join_parts() {
+        let mut db = split_record();
+        let records = scan_records("P", &mut db).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(db.keys_referencing("user9").unwrap(), vec!["Pdoc".to_owned()]);
+    }
+
+    #[test]
+    fn ids_ending_in_a_part_number_are_records() {
+        let mut db = SmallValues::default();
+        db.put("Pdoc", "user0;2;;").unwrap();
+        db.put("Pdoc#1", "user1;2;;").unwrap();
+
+        let keys: Vec<String> = scan_records("P", &mut db).unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["Pdoc".to_owned(), "Pdoc#1".to_owned()]);
+        assert_eq!(db.keys_referencing("user1").unwrap(), vec!["Pdoc#1".to_owned()]);
+
+        // запись doc не делится поверх записи doc#1
+        let refused = (2..10).find_map(|n| {
+            let change = AclChange {
+                key: "Pdoc".to_owned(),
+                entries: vec![ACLRecord::new_with_access(&format!("user{}", n), 2)],
+                is_remove: false,
+            };
+            apply_change(&change, &mut db).err()
+        });
+        assert_eq!(refused.map(|e| e.kind()), Some(io::ErrorKind::AlreadyExists));
+        assert_eq!(db.get("Pdoc#1").unwrap().as_deref(), Some("user1;2;;"));
+
+        let report = revoke_resource("doc", &mut db, false).unwrap();
+        assert_eq!(report.resources, vec!["doc".to_owned(), "doc#1".to_owned()]);
+        assert!(db.0.is_empty());
+    }
+}
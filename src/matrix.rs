@@ -0,0 +1,84 @@
+//! Effective rights of many users on many resources (who can do what), for access reviews and admin views.
+//!
+//! Each cell is a regular evaluation, so filters, exclusive groups and every other rule apply as in
+//! `authorize`, but the expensive parts are shared across the matrix: the groups of each user are walked
+//! once, and every record is read from the store once, whichever cells need it.
+
+use crate::common::{Storage, TraceBuffers};
+use crate::config::AzConfig;
+use crate::decision::Decision;
+use crate::presets::MANAGER;
+use crate::request_scope::SubjectMemoMap;
+use crate::{authorize_impl, ACLRecord, ACLRecordSet, ACLRecordVec, AzHooks};
+use chrono::{DateTime, Utc};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+
+/// Granted access by (user, resource)
+pub type RightsMatrix = HashMap<(String, String), u8>;
+
+pub fn compute_rights_matrix(resource_ids: &[&str], user_ids: &[&str], db: &mut dyn Storage) -> io::Result<RightsMatrix> {
+    compute_rights_matrix_with_config(resource_ids, user_ids, db, &AzConfig::default())
+}
+
+/// Same as `compute_rights_matrix`; `AzConfig::deny_min_duration` is not applied
+pub fn compute_rights_matrix_with_config(resource_ids: &[&str], user_ids: &[&str], db: &mut dyn Storage, cfg: &AzConfig) -> io::Result<RightsMatrix> {
+    let subjects = RefCell::new(SubjectMemoMap::new());
+    let hooks = AzHooks {
+        subject_memo: Some(&subjects),
+        ..AzHooks::default()
+    };
+    let mut mdb = MemoStorage {
+        inner: db,
+        values: HashMap::new(),
+    };
+
+    let mut res = RightsMatrix::with_capacity(resource_ids.len() * user_ids.len());
+    for user_id in user_ids {
+        for id in resource_ids {
+            let mut buf = TraceBuffers::default();
+            let access = authorize_impl(id, user_id, MANAGER, &mut mdb, &mut buf.trace(false, false, false), cfg, &hooks, &mut Decision::default())?;
+            res.insert((user_id.to_string(), id.to_string()), access);
+        }
+    }
+
+    Ok(res)
+}
+
+// Каждый ключ читается из хранилища один раз за расчет матрицы
+struct MemoStorage<'a> {
+    inner: &'a mut dyn Storage,
+    values: HashMap<String, Option<String>>,
+}
+
+impl Storage for MemoStorage<'_> {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        if let Some(value) = self.values.get(key) {
+            return Ok(value.clone());
+        }
+        let value = self.inner.get(key)?;
+        self.values.insert(key.to_owned(), value.clone());
+        Ok(value)
+    }
+
+    fn fiber_yield(&self) {
+        self.inner.fiber_yield()
+    }
+
+    fn decode_rec_to_rights(&self, src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+        self.inner.decode_rec_to_rights(src, result)
+    }
+
+    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+        self.inner.decode_rec_to_rightset(src, new_rights)
+    }
+
+    fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+        self.inner.decode_filter(filter_value)
+    }
+
+    fn decode_valid_from(&self, src: &str) -> Option<DateTime<Utc>> {
+        self.inner.decode_valid_from(src)
+    }
+}
@@ -0,0 +1,90 @@
+//! Decoded M-records of object groups shared by all calls.
+//!
+//! Memberships of object groups do not depend on the user, so concurrent checks of one document by
+//! different users decode them once. Entries are keyed by uri and valid for the storage epoch they
+//! were read at (`Storage::epoch`); storages without an epoch are never cached.
+
+use crate::cache_metrics::{CacheIntrospection, CacheMetrics};
+use crate::ACLRecordVec;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Decoded groups of a uri, `None` for a uri without M-record
+pub type CachedMemberships = Option<Arc<ACLRecordVec>>;
+
+pub struct MembershipCache {
+    capacity: usize,
+    entries: RwLock<HashMap<String, (u64, CachedMemberships)>>,
+    metrics: CacheMetrics,
+}
+
+impl MembershipCache {
+    pub fn new(capacity: usize) -> Self {
+        MembershipCache {
+            capacity: capacity.max(1),
+            entries: RwLock::default(),
+            metrics: CacheMetrics::default(),
+        }
+    }
+
+    /// `None` on a miss, including entries read at another epoch
+    pub fn get(&self, uri: &str, epoch: u64) -> Option<CachedMemberships> {
+        let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+        match entries.get(uri) {
+            Some((e, groups)) if *e == epoch => {
+                self.metrics.on_hit(uri);
+                Some(groups.clone())
+            },
+            _ => {
+                self.metrics.on_miss();
+                None
+            },
+        }
+    }
+
+    /// When full, entries of older epochs go first, then everything
+    pub fn put(&self, uri: &str, epoch: u64, groups: CachedMemberships) {
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+
+        if entries.len() >= self.capacity && !entries.contains_key(uri) {
+            let before = entries.len();
+            entries.retain(|_, (e, _)| *e >= epoch);
+            if entries.len() >= self.capacity {
+                entries.clear();
+            }
+            self.metrics.on_eviction((before - entries.len()) as u64);
+        }
+
+        entries.insert(uri.to_owned(), (epoch, groups));
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn clear(&self) {
+        self.entries.write().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    pub fn metrics(&self) -> &CacheMetrics {
+        &self.metrics
+    }
+}
+
+impl CacheIntrospection for MembershipCache {
+    fn entries(&self) -> usize {
+        self.entries.read().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    fn hit_rate(&self) -> f64 {
+        self.metrics.hit_rate()
+    }
+
+    fn hot_keys(&self, n: usize) -> Vec<(String, u64)> {
+        self.metrics.hot_keys(n)
+    }
+
+    fn evictions(&self) -> u64 {
+        self.metrics.evictions()
+    }
+}
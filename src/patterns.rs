@@ -0,0 +1,36 @@
+//! Grants on uri patterns: a pattern is a uri prefix ending with `*`, e.g. `prj:alpha/*`.
+//! Object patterns are stored as P-record values under `PATTERN_PREFIX` + pattern,
+//! subject patterns may appear as the subject id of any permission entry.
+
+use crate::ACLRecord;
+use std::collections::HashMap;
+
+pub const PATTERN_WILDCARD: char = '*';
+
+/// Patterns that can cover `uri`: one per `/` or `:` boundary, most general first
+pub fn pattern_candidates(uri: &str) -> Vec<String> {
+    uri.char_indices()
+        .filter(|(idx, c)| (*c == '/' || *c == ':') && idx + 1 < uri.len())
+        .map(|(idx, _)| format!("{}{}", &uri[..=idx], PATTERN_WILDCARD))
+        .collect()
+}
+
+pub fn is_pattern(id: &str) -> bool {
+    id.ends_with(PATTERN_WILDCARD)
+}
+
+pub fn pattern_matches(pattern: &str, uri: &str) -> bool {
+    match pattern.strip_suffix(PATTERN_WILDCARD) {
+        Some(prefix) => uri.starts_with(prefix),
+        None => pattern == uri,
+    }
+}
+
+/// Subject group covered by a subject pattern; with several matches the one with the widest access is taken
+pub(crate) fn find_pattern_subject<'a>(subject_groups: &'a HashMap<String, ACLRecord>, pattern: &str) -> Option<&'a ACLRecord> {
+    if !is_pattern(pattern) {
+        return None;
+    }
+
+    subject_groups.values().filter(|gr| pattern_matches(pattern, &gr.id)).max_by_key(|gr| gr.access.count_ones())
+}
@@ -1,22 +1,63 @@
 use crate::authorize_obj_group::authorize_obj_group;
-use crate::common::{Storage, Trace, MEMBERSHIP_PREFIX, M_IS_EXCLUSIVE};
-use crate::{ACLRecord, AzContext};
+use crate::common::{accumulate_access, decode_memberships, get_aliases, on_self_reference, Storage, Trace, MEMBERSHIP_PREFIX, M_IS_EXCLUSIVE};
+use crate::record_formats::read_continued;
+use crate::{ACLRecordVec, AzContext};
 use std::io;
+use std::sync::Arc;
 
 pub(crate) fn prepare_obj_group(azc: &mut AzContext, trace: &mut Trace, request_access: u8, uri: &str, access: u8, level: u8, db: &mut dyn Storage) -> io::Result<bool> {
-    if level > 32 {
+    if level > azc.cfg.max_object_depth {
         return Ok(false);
     }
+    azc.max_object_level = azc.max_object_level.max(level);
 
     db.fiber_yield();
 
     let mut is_contain_suffix_group = false;
     let groups_set_len;
 
-    match db.get(&(MEMBERSHIP_PREFIX.to_owned() + uri)) {
+    // Неявные группы не хранятся в M-записях, их возвращает подключенный провайдер
+    let mut implicit_groups = ACLRecordVec::new();
+    if let Some(provider) = azc.hooks.implicit_groups {
+        provider.implicit_groups(uri, db, &mut implicit_groups);
+    }
+    if azc.cfg.group_aliases {
+        get_aliases(uri, db, &mut implicit_groups)?;
+    }
+
+    // Разобранные M-записи общие для всех пользователей, пока не сменилась эпоха хранилища
+    let cache = azc.hooks.membership_cache.zip(db.epoch());
+    let cached = cache.and_then(|(cache, epoch)| cache.get(uri, epoch));
+
+    let membership = match &cached {
+        Some(Some(_)) => Ok(Some(String::new())),
+        Some(None) => Ok(None),
+        None => read_continued(&(MEMBERSHIP_PREFIX.to_owned() + uri), db),
+    };
+
+    if let (Some((cache, epoch)), None, Ok(None)) = (cache, &cached, &membership) {
+        cache.put(uri, epoch, None);
+    }
+
+    let membership = match membership {
+        Ok(None) if !implicit_groups.is_empty() => Ok(Some(String::new())),
+        res => res,
+    };
+
+    match membership {
         Ok(Some(groups_str)) => {
-            let groups_set: &mut Vec<ACLRecord> = &mut Vec::new();
-            db.decode_rec_to_rights(&groups_str, groups_set);
+            let groups_set = &mut match &cached {
+                Some(Some(groups)) => (**groups).clone(),
+                _ => ACLRecordVec::new(),
+            };
+            if !groups_str.is_empty() {
+                // запись со сроком действия не кэшируется, она истечет или вступит в силу раньше смены эпохи
+                let validity = decode_memberships(&groups_str, uri, db, azc.cfg, azc.clock, groups_set)?;
+                if let (Some((cache, epoch)), false) = (cache, validity.is_bounded()) {
+                    cache.put(uri, epoch, Some(Arc::new(groups_set.clone())));
+                }
+            }
+            groups_set.extend(implicit_groups);
 
             groups_set_len = groups_set.len();
 
@@ -26,7 +67,11 @@ pub(crate) fn prepare_obj_group(azc: &mut AzContext, trace: &mut Trace, request_
                     continue;
                 }
 
-                let new_access = group.access & access;
+                if !azc.cfg.is_in_scope(&group.id) {
+                    continue;
+                }
+
+                let new_access = accumulate_access(group.access, access, azc.cfg.access_accumulation);
                 group.access = new_access;
 
                 let key = group.id.clone();
@@ -46,8 +91,9 @@ pub(crate) fn prepare_obj_group(azc: &mut AzContext, trace: &mut Trace, request_
                         }
                     }
 
-                    if !azc.is_found_exclusive_az && (level == 0 || uri.contains("_group")) && azc.subject_groups.contains_key(&key) {
-                        if let Some(s_val) = azc.subject_groups.get(&key) {
+                    let subject_key = azc.cfg.subject_key(&key);
+                    if !azc.is_found_exclusive_az && (level == 0 || uri.contains("_group")) && azc.subject_groups.contains_key(subject_key.as_ref()) {
+                        if let Some(s_val) = azc.subject_groups.get(subject_key.as_ref()) {
                             if s_val.marker == M_IS_EXCLUSIVE {
                                 azc.is_found_exclusive_az = true;
                             }
@@ -76,9 +122,14 @@ pub(crate) fn prepare_obj_group(azc: &mut AzContext, trace: &mut Trace, request_
                 }
 
                 if uri == group.id {
+                    on_self_reference(azc.cfg, uri)?;
                     continue;
                 }
 
+                if let Some(info) = azc.hooks.trace_info {
+                    info.borrow_mut().add_object_edge(uri, &group.id);
+                }
+
                 match authorize_obj_group(azc, trace, request_access, &group.id, group.access, db) {
                     Ok(res) => {
                         if res {
@@ -96,7 +147,10 @@ pub(crate) fn prepare_obj_group(azc: &mut AzContext, trace: &mut Trace, request_
                     },
                 }
 
-                prepare_obj_group(azc, trace, request_access, &group.id, new_access, level + 1, db)?;
+                // при глубине 255 следующий уровень не представим, он считается превышением глубины
+                if let Some(next_level) = level.checked_add(1) {
+                    prepare_obj_group(azc, trace, request_access, &group.id, new_access, next_level, db)?;
+                }
             }
 
             if groups_set_len == 0 {
@@ -0,0 +1,111 @@
+//! Named access profiles and the `CRUD` letter notation used by operator tooling.
+//!
+//! Letters here are case-insensitive (`"ru"` is read + update); this is not the record format,
+//! where lower case letters stand for denials.
+//!
+//! Access expressions combine terms with `+` and `-`: a term is a preset name, `CRUD` letters, a decimal
+//! mask or `!` with letters for denials. `Editor+C` is read, update and create, `CRUD-D` leaves delete out,
+//! `CRUD-!D` also denies it explicitly: a denial clears the allow bit and is added whatever joins it.
+
+use crate::common::Access;
+use std::io;
+
+pub const VIEWER: u8 = Access::CanRead as u8;
+pub const EDITOR: u8 = VIEWER | Access::CanUpdate as u8;
+pub const CONTRIBUTOR: u8 = EDITOR | Access::CanCreate as u8;
+pub const MANAGER: u8 = CONTRIBUTOR | Access::CanDelete as u8;
+
+pub static PRESETS: [(&str, u8); 4] = [("Viewer", VIEWER), ("Editor", EDITOR), ("Contributor", CONTRIBUTOR), ("Manager", MANAGER)];
+
+static CRUD_LETTERS: [(char, u8); 4] =
+    [('C', Access::CanCreate as u8), ('R', Access::CanRead as u8), ('U', Access::CanUpdate as u8), ('D', Access::CanDelete as u8)];
+
+pub fn parse_preset(name: &str) -> Option<u8> {
+    PRESETS.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, access)| *access)
+}
+
+/// `"CRUD"`, `"ru"`; `None` on any other character or an empty string
+pub fn parse_crud(src: &str) -> Option<u8> {
+    if src.is_empty() {
+        return None;
+    }
+
+    let mut access = 0;
+    for c in src.chars() {
+        let c = c.to_ascii_uppercase();
+        access |= CRUD_LETTERS.iter().find(|(l, _)| *l == c)?.1;
+    }
+    Some(access)
+}
+
+/// Preset name, `CRUD` letters or a decimal mask
+pub fn parse_access_mask(src: &str) -> Option<u8> {
+    let src = src.trim();
+    parse_preset(src).or_else(|| parse_crud(src)).or_else(|| src.parse::<u8>().ok())
+}
+
+/// Allow bits in `CRUD` notation, `-` for an empty mask
+pub fn to_crud(access: u8) -> String {
+    let res: String = CRUD_LETTERS.iter().filter(|(_, bit)| access & bit != 0).map(|(l, _)| *l).collect();
+    if res.is_empty() {
+        "-".to_owned()
+    } else {
+        res
+    }
+}
+
+/// Mask of an access expression, see the module docs; `-` alone is the empty mask
+pub fn parse_access_expr(src: &str) -> io::Result<u8> {
+    let src = src.trim();
+    if src == "-" {
+        return Ok(0);
+    }
+
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, format!("access expression [{}]: {}", src, msg));
+
+    let mut access = 0u8;
+    let mut rest = src;
+    let mut is_sub = false;
+    loop {
+        let end = rest.find(['+', '-']).unwrap_or(rest.len());
+        let term = rest[..end].trim();
+        if term.is_empty() {
+            return Err(invalid("empty term".to_owned()));
+        }
+
+        if let Some(letters) = term.strip_prefix('!') {
+            let deny = parse_crud(letters.trim()).ok_or_else(|| invalid(format!("invalid denial [{}]", term)))?;
+            access = (access & !deny) | (deny << 4);
+        } else {
+            let bits = parse_access_mask(term).ok_or_else(|| invalid(format!("invalid term [{}]", term)))?;
+            if is_sub {
+                access &= !bits;
+            } else {
+                access |= bits;
+            }
+        }
+
+        if end == rest.len() {
+            return Ok(access);
+        }
+        is_sub = rest[end..].starts_with('-');
+        rest = &rest[end + 1..];
+    }
+}
+
+/// Canonical expression of `access`: allow letters, then `-!` with denied letters;
+/// a mask allowing and denying the same bit is written in decimal, so `parse_access_expr` always gives it back
+pub fn format_access_expr(access: u8) -> String {
+    let allow = access & 0x0F;
+    let deny = access >> 4;
+    if allow & deny != 0 {
+        return access.to_string();
+    }
+
+    match (allow, deny) {
+        (0, 0) => "-".to_owned(),
+        (_, 0) => to_crud(allow),
+        (0, _) => format!("!{}", to_crud(deny)),
+        _ => format!("{}-!{}", to_crud(allow), to_crud(deny)),
+    }
+}
@@ -0,0 +1,50 @@
+use crate::common::Storage;
+use std::collections::HashMap;
+
+/// Maps the identifier a service got from its caller (email, login, uri) to canonical user uris.
+/// Called once per authorize call, before the subject group traversal.
+pub trait PrincipalResolver: Send + Sync {
+    /// The first principal pushed is the user, the rest (app identity, ...) contribute their subject groups.
+    /// Nothing pushed means `external_id` is already canonical.
+    fn resolve(&self, external_id: &str, db: &mut dyn Storage, result: &mut Vec<String>);
+}
+
+/// Fixed table of external identifiers
+#[derive(Default)]
+pub struct StaticPrincipalResolver {
+    principals: HashMap<String, Vec<String>>,
+}
+
+impl StaticPrincipalResolver {
+    pub fn add(&mut self, external_id: &str, principal: &str) -> &mut Self {
+        self.principals.entry(external_id.to_owned()).or_default().push(principal.to_owned());
+        self
+    }
+}
+
+impl PrincipalResolver for StaticPrincipalResolver {
+    fn resolve(&self, external_id: &str, _db: &mut dyn Storage, result: &mut Vec<String>) {
+        if let Some(principals) = self.principals.get(external_id) {
+            result.extend(principals.iter().cloned());
+        }
+    }
+}
+
+/// Fixed set of principals evaluated together whatever the user id, e.g. the user, `cfg:Guest` and `cfg:AllUsers`
+pub struct PrincipalSet {
+    principals: Vec<String>,
+}
+
+impl PrincipalSet {
+    pub fn new(principals: &[&str]) -> Self {
+        PrincipalSet {
+            principals: principals.iter().map(|p| (*p).to_owned()).collect(),
+        }
+    }
+}
+
+impl PrincipalResolver for PrincipalSet {
+    fn resolve(&self, _external_id: &str, _db: &mut dyn Storage, result: &mut Vec<String>) {
+        result.extend(self.principals.iter().cloned());
+    }
+}
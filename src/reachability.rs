@@ -0,0 +1,174 @@
+//! Compressed reachability summary of a resource: a bloom filter over every subject that appears in a permission
+//! on any of the resource's object groups. If none of the user's groups may be in it, the user has no access.
+//! A summary remembers the generation of the records it was built from (`ACL_GENERATION_KEY`); the `manage`
+//! writers bump the generation on every M-, P- or F-record write, after which all summaries are stale until rebuilt.
+
+use crate::common::{get_filter, Storage, ACL_GENERATION_KEY, MEMBERSHIP_PREFIX, PERMISSION_PREFIX, REACHABILITY_PREFIX};
+use crate::keys::KeySchema;
+use crate::record_formats::read_continued;
+use crate::ACLRecordVec;
+use std::collections::{HashSet, VecDeque};
+use std::io;
+
+const HASH_COUNT: u32 = 7;
+const BITS_PER_ENTRY: usize = 10;
+
+pub struct GroupBloom {
+    bits: Vec<u64>,
+    hash_count: u32,
+}
+
+impl GroupBloom {
+    pub fn with_capacity(entries: usize) -> Self {
+        let words = (entries.max(1) * BITS_PER_ENTRY).div_ceil(64);
+        GroupBloom {
+            bits: vec![0; words],
+            hash_count: HASH_COUNT,
+        }
+    }
+
+    fn positions(&self, id: &str) -> impl Iterator<Item = usize> {
+        let (h1, h2) = hash_pair(id);
+        let nbits = (self.bits.len() * 64) as u64;
+        (0..self.hash_count as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+    }
+
+    pub fn insert(&mut self, id: &str) {
+        let pos: Vec<usize> = self.positions(id).collect();
+        for p in pos {
+            self.bits[p / 64] |= 1 << (p % 64);
+        }
+    }
+
+    pub fn may_contain(&self, id: &str) -> bool {
+        self.positions(id).all(|p| self.bits[p / 64] & (1 << (p % 64)) != 0)
+    }
+
+    pub fn encode(&self, epoch: u64) -> String {
+        let mut res = format!("{}\n{}\n", epoch, self.hash_count);
+        for w in &self.bits {
+            res.push_str(&format!("{:016x}", w));
+        }
+        res
+    }
+
+    pub fn decode(src: &str) -> Option<(u64, GroupBloom)> {
+        let mut lines = src.lines();
+        let epoch = lines.next()?.parse::<u64>().ok()?;
+        let hash_count = lines.next()?.parse::<u32>().ok()?;
+        let hex = lines.next()?;
+
+        if hex.is_empty() || !hex.len().is_multiple_of(16) {
+            return None;
+        }
+        let bits = (0..hex.len()).step_by(16).map(|i| u64::from_str_radix(&hex[i..i + 16], 16).ok()).collect::<Option<Vec<u64>>>()?;
+
+        Some((
+            epoch,
+            GroupBloom {
+                bits,
+                hash_count,
+            },
+        ))
+    }
+}
+
+fn hash_pair(id: &str) -> (u64, u64) {
+    let mut h1: u64 = 0xcbf29ce484222325;
+    let mut h2: u64 = 0x84222325cbf29ce4;
+    for b in id.as_bytes() {
+        h1 = (h1 ^ *b as u64).wrapping_mul(0x100000001b3);
+        h2 = (h2 ^ *b as u64).wrapping_mul(0x1000193).rotate_left(5);
+    }
+    (h1, h2 | 1)
+}
+
+/// Current generation of the M-, P- and F-records, `None` until a summary was built
+pub(crate) fn acl_generation(db: &mut dyn Storage) -> io::Result<Option<u64>> {
+    Ok(db.get(ACL_GENERATION_KEY)?.and_then(|val| val.parse::<u64>().ok()))
+}
+
+/// Value of the summary of records of `generation`
+pub(crate) fn encode_summary(bloom: &GroupBloom, epoch: u64, generation: u64) -> String {
+    format!("{}\n{}", bloom.encode(epoch), generation)
+}
+
+// поколение записей, из которых построена сводка; у сводок, построенных до появления поколений, его нет
+fn summary_generation(src: &str) -> Option<u64> {
+    src.lines().nth(3).and_then(|line| line.parse::<u64>().ok())
+}
+
+/// Summary of `id` if present, built at or after `min_epoch` and from the current generation of the records
+pub(crate) fn get_fresh_reachability(id: &str, min_epoch: u64, db: &mut dyn Storage) -> io::Result<Option<GroupBloom>> {
+    match db.get(&(REACHABILITY_PREFIX.to_owned() + id))? {
+        Some(val) => match GroupBloom::decode(&val) {
+            Some((epoch, bloom)) if epoch >= min_epoch && summary_generation(&val) == acl_generation(db)? => Ok(Some(bloom)),
+            Some(_) => Ok(None),
+            None => {
+                eprintln!("WARN! Authorize: invalid reachability summary, uri={}", id);
+                Ok(None)
+            },
+        },
+        None => Ok(None),
+    }
+}
+
+/// Collects every subject granted anything on an object group of `id`, filtered permissions included;
+/// `id` is encoded, filtered keys are built as `schema` builds them
+pub fn collect_reachable_subjects(id: &str, max_depth: u8, schema: KeySchema, db: &mut dyn Storage) -> io::Result<HashSet<String>> {
+    let mut object_groups: Vec<String> = vec!["v-s:AllResourcesGroup".to_owned(), id.to_owned()];
+    let mut visited: HashSet<String> = object_groups.iter().cloned().collect();
+    let mut filters = Vec::new();
+    if let (Some(f), _) = get_filter(id, db) {
+        if !f.id.is_empty() {
+            filters.push(f.id);
+        }
+    }
+
+    let mut queue = VecDeque::new();
+    queue.push_back((id.to_owned(), 0u8));
+    while let Some((uri, level)) = queue.pop_front() {
+        if level > max_depth {
+            continue;
+        }
+
+        if let Some(src) = read_continued(&(MEMBERSHIP_PREFIX.to_owned() + &uri), db)? {
+            let mut groups = ACLRecordVec::new();
+            db.decode_rec_to_rights(&src, &mut groups);
+
+            for gr in groups {
+                if level == 0 {
+                    if let (Some(f), _) = get_filter(&gr.id, db) {
+                        if !f.id.is_empty() {
+                            filters.push(f.id);
+                        }
+                    }
+                }
+                if !gr.id.is_empty() && visited.insert(gr.id.clone()) {
+                    object_groups.push(gr.id.clone());
+                    if let Some(next_level) = level.checked_add(1) {
+                        queue.push_back((gr.id, next_level));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut subjects = HashSet::new();
+    for gr in &object_groups {
+        let mut keys = vec![PERMISSION_PREFIX.to_owned() + gr];
+        for f in &filters {
+            keys.push(PERMISSION_PREFIX.to_owned() + &schema.permission_suffix(f, gr));
+        }
+
+        for key in keys {
+            if let Some(src) = read_continued(&key, db)? {
+                let mut permissions = ACLRecordVec::new();
+                db.decode_rec_to_rights(&src, &mut permissions);
+                subjects.extend(permissions.into_iter().map(|p| p.id));
+            }
+        }
+    }
+
+    Ok(subjects)
+}
@@ -0,0 +1,887 @@
+//! Text format of ACL record values: `id;access;marker` triples joined by `;`.
+//!
+//! `access` is the access byte in decimal, or, in records written by old indexers, a run of
+//! `ACCESS_C_FULL_LIST` characters, each optionally followed by its reference counter (`R2U1`, `Rr`).
+//! `marker` is empty, `X` or `N`; any other marker is kept for `AzConfig::unknown_marker_policy`, one longer than
+//! a char as U+FFFD. `encode_rights_counted` writes the letter form for entries that carry
+//! counters, so independent grants of the same right are kept apart.
+//!
+//! A record too big for the value size limit of a backend is split into a head under its key and
+//! continuation parts under `key#1`, `key#2`, ...; the head ends with the entry `#;<number of parts>;`.
+//! `read_continued` joins them back, so decoders never see the split. A key `key#<n>` is a part only while the
+//! head announces at least `n` parts; otherwise it is the record of an id ending in `#<n>`, possible under
+//! `KeySchema::Legacy` only, and writers refuse to split a record over it.
+//!
+//! Version 2 is a compact binary form, see `encode_v2`, that also carries the deletion flag, any marker,
+//! the provenance of each grant and the validity of the record, its expiry and activation time. In a string
+//! value it starts with `V2_TAG` and its bytes are stored as the chars U+0000..U+00FF. The decoders here read
+//! both versions; v2 values are never split. `encode_record_auto` picks v2 only for records that need it, so
+//! rewrites keep what was read.
+
+use crate::common::{counter_index, Storage, ACCESS_8_FULL_LIST, ACCESS_C_FULL_LIST, M_IGNORE_EXCLUSIVE, M_IS_EXCLUSIVE};
+use crate::record_set::{merge_marker, MarkerPrecedence};
+use crate::{ACLRecord, ACLRecordSet, ACLRecordVec, GrantProvenance, RightsCounters};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::io;
+
+/// Id of the entry closing the head of a split record, its access field holds the number of parts
+pub const CONTINUATION_ID: &str = "#";
+
+// Место под замыкающую запись головы
+const CONTINUATION_RESERVE: usize = 16;
+
+/// First byte of a v2 record, no v1 record starts with it
+pub const V2_TAG: u8 = 0x02;
+
+// Теги v2: записи целиком и отдельного элемента, список тегов завершается TAG_END
+const TAG_END: u8 = 0;
+const TAG_VALID_UNTIL: u8 = 1;
+const TAG_VALID_FROM: u8 = 2;
+const TAG_MARKER: u8 = 1;
+const TAG_DELETED: u8 = 2;
+const TAG_COUNTERS: u8 = 3;
+const TAG_GRANTED_BY: u8 = 4;
+const TAG_GRANTED_AT: u8 = 5;
+const TAG_REASON: u8 = 6;
+
+// Наибольшая длина заголовка v2: тег версии, два срока и TAG_END
+const V2_HEADER_MAX: usize = 20;
+
+/// Time a record is in force: from `valid_from` on and before `valid_until`, `None` leaves that side open
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Validity {
+    pub valid_from: Option<DateTime<Utc>>,
+    pub valid_until: Option<DateTime<Utc>>,
+}
+
+impl Validity {
+    pub fn is_bounded(&self) -> bool {
+        self.valid_from.is_some() || self.valid_until.is_some()
+    }
+
+    pub fn is_in_force(&self, now: DateTime<Utc>) -> bool {
+        self.valid_from.is_none_or(|t| t <= now) && self.valid_until.is_none_or(|t| now < t)
+    }
+
+    /// In force whenever `other` is
+    pub fn covers(&self, other: &Validity) -> bool {
+        self.valid_from.is_none_or(|t| other.valid_from.is_some_and(|o| t <= o)) && self.valid_until.is_none_or(|t| other.valid_until.is_some_and(|o| o <= t))
+    }
+}
+
+/// Layout of a record value
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RecordVersion {
+    /// `id;access;marker` text
+    #[default]
+    V1,
+    /// Tagged binary, see `encode_v2`
+    V2,
+}
+
+pub fn record_version(src: &str) -> RecordVersion {
+    if src.as_bytes().first() == Some(&V2_TAG) {
+        RecordVersion::V2
+    } else {
+        RecordVersion::V1
+    }
+}
+
+/// Parses the access token, legacy letter form included; `None` if it is neither
+pub fn parse_access(src: &str, counters: &mut RightsCounters) -> Option<u8> {
+    if let Ok(access) = src.parse::<u8>() {
+        return Some(access);
+    }
+
+    let mut access = 0;
+    let mut chars = src.chars().peekable();
+    while let Some(c) = chars.next() {
+        let idx = counter_index(c)?;
+        access |= ACCESS_8_FULL_LIST[idx];
+
+        let mut count: u16 = 0;
+        while let Some(d) = chars.peek().and_then(|d| d.to_digit(10)) {
+            count = count.saturating_mul(10).saturating_add(d as u16);
+            chars.next();
+        }
+        for _ in 0..count.max(1) {
+            counters.increment(c);
+        }
+    }
+
+    if access == 0 {
+        None
+    } else {
+        Some(access)
+    }
+}
+
+pub fn parse_marker(src: &str) -> Option<char> {
+    match src {
+        "" => Some(0 as char),
+        "X" => Some(M_IS_EXCLUSIVE),
+        "N" => Some(M_IGNORE_EXCLUSIVE),
+        _ => None,
+    }
+}
+
+// Неизвестная метка не отбрасывается, иначе к ней не применится UnknownMarkerPolicy
+fn unknown_marker(src: &str) -> char {
+    let mut chars = src.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => c,
+        _ => char::REPLACEMENT_CHARACTER,
+    }
+}
+
+fn decode_entries(src: &str, mut push: impl FnMut(ACLRecord)) -> (bool, Option<DateTime<Utc>>) {
+    if record_version(src) == RecordVersion::V2 {
+        let Some(bytes) = src.chars().map(|c| u8::try_from(c).ok()).collect::<Option<Vec<u8>>>() else {
+            eprintln!("WARN! record_formats: v2 record {:?} holds chars above U+00FF", src);
+            return (false, None);
+        };
+        return decode_v2_with(&bytes, push);
+    }
+    if src.is_empty() {
+        return (true, None);
+    }
+
+    let tokens: Vec<&str> = src.split(';').collect();
+    let mut is_ok = true;
+
+    for chunk in tokens.chunks(3) {
+        if chunk.len() == 1 && chunk[0].is_empty() {
+            // завершающий разделитель
+            continue;
+        }
+
+        let id = chunk[0];
+        let mut counters = RightsCounters::None;
+        let access = chunk.get(1).and_then(|a| parse_access(a, &mut counters));
+        let marker = chunk.get(2).map_or(0 as char, |m| parse_marker(m).unwrap_or_else(|| unknown_marker(m)));
+
+        match (id.is_empty(), access) {
+            (false, Some(access)) => {
+                let mut rec = ACLRecord::new_with_access(id, access);
+                rec.marker = marker;
+                rec.counters = counters;
+                push(rec);
+            },
+            _ => {
+                eprintln!("WARN! record_formats: invalid entry {:?} in {:?}", chunk, src);
+                is_ok = false;
+            },
+        }
+    }
+
+    (is_ok, None)
+}
+
+/// What counts when a record lists the same id more than once
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DuplicateEntries {
+    /// Access masks are OR-ed, the first non-zero marker is kept
+    #[default]
+    Or,
+    FirstWins,
+    LastWins,
+}
+
+/// Entries as stored, duplicates included; the traversal OR-s them
+pub fn decode_rec_to_rights(src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+    decode_entries(src, |rec| result.push(rec))
+}
+
+/// Same as `decode_rec_to_rights` with duplicates merged as `DuplicateEntries::Or`. A right allowed by one entry
+/// of an id and denied by another ends up with both bits in one mask, where the deny cancels it; the traversal,
+/// which checks entries one by one, reads such records entry by entry
+pub fn decode_rec_to_rightset(src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+    decode_rec_to_rightset_with(src, new_rights, DuplicateEntries::Or)
+}
+
+/// Decodes into `result` with one entry per id, in the order ids first appear
+pub fn decode_rec_to_rights_with(src: &str, result: &mut ACLRecordVec, policy: DuplicateEntries) -> (bool, Option<DateTime<Utc>>) {
+    let start = result.len();
+    let res = decode_entries(src, |rec| result.push(rec));
+    let mut decoded: ACLRecordVec = result.drain(start..).collect();
+    merge_duplicates(&mut decoded, policy);
+    result.extend(decoded);
+    res
+}
+
+pub fn decode_rec_to_rightset_with(src: &str, new_rights: &mut ACLRecordSet, policy: DuplicateEntries) -> (bool, Option<DateTime<Utc>>) {
+    let mut decoded = ACLRecordVec::new();
+    let res = decode_rec_to_rights_with(src, &mut decoded, policy);
+    for rec in decoded {
+        new_rights.insert(rec.id.clone(), rec);
+    }
+    res
+}
+
+/// Leaves one entry per id in `records` as `policy` says, each at the position of the first entry of its id
+pub fn merge_duplicates(records: &mut ACLRecordVec, policy: DuplicateEntries) {
+    let mut first_of: HashMap<String, usize> = HashMap::new();
+    let mut res = ACLRecordVec::new();
+    for rec in records.drain(..) {
+        let Some(idx) = first_of.get(&rec.id).copied() else {
+            first_of.insert(rec.id.clone(), res.len());
+            res.push(rec);
+            continue;
+        };
+        let cur = &mut res[idx];
+        match policy {
+            DuplicateEntries::Or => {
+                cur.access |= rec.access;
+                cur.marker = merge_marker(cur.marker, rec.marker, MarkerPrecedence::FirstWins);
+                cur.counters.merge(&rec.counters);
+            },
+            DuplicateEntries::FirstWins => {},
+            DuplicateEntries::LastWins => *cur = rec,
+        }
+    }
+    *records = res;
+}
+
+/// Filter value: a single entry whose id is the filter marker and access the allowed mask
+pub fn decode_filter(filter_value: &str) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+    let mut res = None;
+    let (_, valid_until) = decode_entries(filter_value, |rec| {
+        if res.is_none() {
+            res = Some(rec);
+        }
+    });
+    (res, valid_until)
+}
+
+pub fn encode_rights<'a>(records: impl IntoIterator<Item = &'a ACLRecord>) -> String {
+    encode_with(records, |rec| rec.access.to_string())
+}
+
+/// Same as `encode_rights`, entries carrying counters are written in the letter form so the counters survive a round trip
+pub fn encode_rights_counted<'a>(records: impl IntoIterator<Item = &'a ACLRecord>) -> String {
+    encode_with(records, encode_access_counted)
+}
+
+/// `R2U` for read granted twice and update once; decimal for records without counters.
+/// A set bit with a zero counter is written as a single reference.
+pub fn encode_access_counted(rec: &ACLRecord) -> String {
+    if rec.counters == RightsCounters::None || rec.access == 0 {
+        return rec.access.to_string();
+    }
+
+    let mut res = String::new();
+    for (c, bit) in ACCESS_C_FULL_LIST.iter().zip(ACCESS_8_FULL_LIST.iter()) {
+        if rec.access & bit == 0 {
+            continue;
+        }
+        res.push(*c);
+        let count = rec.counters.get(*c);
+        if count > 1 {
+            res.push_str(&count.to_string());
+        }
+    }
+    res
+}
+
+fn encode_with<'a>(records: impl IntoIterator<Item = &'a ACLRecord>, access: impl Fn(&ACLRecord) -> String) -> String {
+    let mut res = String::new();
+    for rec in records {
+        if rec.id.contains(';') {
+            eprintln!("ERR! record_formats: id [{}] contains the entry separator, the record will not decode; see KeySchema::Escaped", rec.id);
+        }
+        let marker = if rec.marker == M_IS_EXCLUSIVE || rec.marker == M_IGNORE_EXCLUSIVE {
+            rec.marker.to_string()
+        } else {
+            String::new()
+        };
+        res.push_str(&format!("{};{};{};", rec.id, access(rec), marker));
+    }
+    res
+}
+
+/// Record value in the layout of `version`; v1 has no place for the validity, the deletion flag and the provenance,
+/// they are dropped
+pub fn encode_record<'a>(records: impl IntoIterator<Item = &'a ACLRecord>, validity: Validity, version: RecordVersion) -> String {
+    match version {
+        RecordVersion::V1 => {
+            if validity.is_bounded() {
+                eprintln!("WARN! record_formats: validity {:?} is not kept in a v1 record", validity);
+            }
+            encode_rights_counted(records)
+        },
+        RecordVersion::V2 => encode_v2(records, validity).into_iter().map(char::from).collect(),
+    }
+}
+
+/// v1 text, or v2 when the record holds what v1 cannot: a validity, provenance, deleted entries or other markers
+pub fn encode_record_auto<'a>(records: impl IntoIterator<Item = &'a ACLRecord>, validity: Validity) -> String {
+    let records: Vec<&ACLRecord> = records.into_iter().collect();
+    let needs_v2 = validity.is_bounded()
+        || records.iter().any(|r| r.provenance.is_some() || r.is_deleted || ![0 as char, M_IS_EXCLUSIVE, M_IGNORE_EXCLUSIVE].contains(&r.marker));
+    let version = if needs_v2 {
+        RecordVersion::V2
+    } else {
+        RecordVersion::V1
+    };
+    encode_record(records, validity, version)
+}
+
+/// Binary v2 record: `V2_TAG`, record tags, the number of entries, then for each entry the length of the id,
+/// the id, the access byte and entry tags. Tag lists end with a zero byte; numbers are LEB128, timestamps
+/// milliseconds since the epoch as big-endian i64.
+///
+/// Record tags: 1 expiry, 2 activation. Entry tags: 1 marker (a char code), 2 deleted, 3 counters (eight numbers),
+/// 4 granted by, 5 granted at, 6 reason; strings are their length followed by UTF-8.
+pub fn encode_v2<'a>(records: impl IntoIterator<Item = &'a ACLRecord>, validity: Validity) -> Vec<u8> {
+    let records: Vec<&ACLRecord> = records.into_iter().collect();
+    let mut res = vec![V2_TAG];
+    if let Some(t) = validity.valid_until {
+        res.push(TAG_VALID_UNTIL);
+        res.extend_from_slice(&t.timestamp_millis().to_be_bytes());
+    }
+    if let Some(t) = validity.valid_from {
+        res.push(TAG_VALID_FROM);
+        res.extend_from_slice(&t.timestamp_millis().to_be_bytes());
+    }
+    res.push(TAG_END);
+
+    put_varint(&mut res, records.len() as u64);
+    for rec in records {
+        put_str(&mut res, &rec.id);
+        res.push(rec.access);
+        if rec.marker != 0 as char {
+            res.push(TAG_MARKER);
+            put_varint(&mut res, rec.marker as u64);
+        }
+        if rec.is_deleted {
+            res.push(TAG_DELETED);
+        }
+        if let RightsCounters::Inline(counters) = rec.counters {
+            res.push(TAG_COUNTERS);
+            for count in counters {
+                put_varint(&mut res, count as u64);
+            }
+        }
+        if let Some(p) = &rec.provenance {
+            if let Some(granted_by) = &p.granted_by {
+                res.push(TAG_GRANTED_BY);
+                put_str(&mut res, granted_by);
+            }
+            if let Some(t) = p.granted_at {
+                res.push(TAG_GRANTED_AT);
+                res.extend_from_slice(&t.timestamp_millis().to_be_bytes());
+            }
+            if let Some(reason) = &p.reason {
+                res.push(TAG_REASON);
+                put_str(&mut res, reason);
+            }
+        }
+        res.push(TAG_END);
+    }
+    res
+}
+
+/// Decodes a binary v2 record into `result`; entries before a malformed one are kept
+pub fn decode_v2(src: &[u8], result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+    decode_v2_with(src, |rec| result.push(rec))
+}
+
+fn decode_v2_with(src: &[u8], mut push: impl FnMut(ACLRecord)) -> (bool, Option<DateTime<Utc>>) {
+    let mut rd = Reader {
+        src,
+        pos: 0,
+    };
+    let mut valid_until = None;
+    let is_ok = (|| {
+        valid_until = rd.header()?.valid_until;
+
+        for _ in 0..rd.varint()? {
+            let mut rec = ACLRecord::new_with_access(rd.str()?, rd.byte()?);
+            loop {
+                match rd.byte()? {
+                    TAG_END => break,
+                    TAG_MARKER => rec.marker = char::from_u32(u32::try_from(rd.varint()?).ok()?)?,
+                    TAG_DELETED => rec.is_deleted = true,
+                    TAG_COUNTERS => {
+                        let mut counters = [0; 8];
+                        for count in counters.iter_mut() {
+                            *count = u16::try_from(rd.varint()?).ok()?;
+                        }
+                        rec.counters = RightsCounters::Inline(counters);
+                    },
+                    TAG_GRANTED_BY => rec.provenance.get_or_insert_with(Box::<GrantProvenance>::default).granted_by = Some(rd.str()?.to_owned()),
+                    TAG_GRANTED_AT => rec.provenance.get_or_insert_with(Box::<GrantProvenance>::default).granted_at = Some(rd.time()?),
+                    TAG_REASON => rec.provenance.get_or_insert_with(Box::<GrantProvenance>::default).reason = Some(rd.str()?.to_owned()),
+                    _ => return None,
+                }
+            }
+            push(rec);
+        }
+        (rd.pos == src.len()).then_some(())
+    })()
+    .is_some();
+
+    if !is_ok {
+        eprintln!("WARN! record_formats: invalid v2 record at byte {} of {}", rd.pos, src.len());
+    }
+    (is_ok, valid_until)
+}
+
+/// Activation time of a record, `None` for v1 records and v2 ones in force from the start
+pub fn record_valid_from(src: &str) -> Option<DateTime<Utc>> {
+    if record_version(src) != RecordVersion::V2 {
+        return None;
+    }
+    let bytes: Vec<u8> = src.chars().take(V2_HEADER_MAX).map_while(|c| u8::try_from(c).ok()).collect();
+    Reader {
+        src: &bytes,
+        pos: 0,
+    }
+    .header()?
+    .valid_from
+}
+
+fn put_varint(dst: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        dst.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    dst.push(value as u8);
+}
+
+fn put_str(dst: &mut Vec<u8>, src: &str) {
+    put_varint(dst, src.len() as u64);
+    dst.extend_from_slice(src.as_bytes());
+}
+
+struct Reader<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        let b = *self.src.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let res = self.src.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(res)
+    }
+
+    fn header(&mut self) -> Option<Validity> {
+        if self.byte()? != V2_TAG {
+            return None;
+        }
+        let mut res = Validity::default();
+        loop {
+            match self.byte()? {
+                TAG_END => return Some(res),
+                TAG_VALID_UNTIL => res.valid_until = Some(self.time()?),
+                TAG_VALID_FROM => res.valid_from = Some(self.time()?),
+                _ => return None,
+            }
+        }
+    }
+
+    fn str(&mut self) -> Option<&'a str> {
+        let len = usize::try_from(self.varint()?).ok()?;
+        std::str::from_utf8(self.bytes(len)?).ok()
+    }
+
+    fn time(&mut self) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp_millis(i64::from_be_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut res = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            res |= u64::from(b & 0x7F) << shift;
+            if b & 0x80 == 0 {
+                return Some(res);
+            }
+        }
+        None
+    }
+}
+
+pub fn continuation_key(key: &str, part: usize) -> String {
+    format!("{}#{}", key, part)
+}
+
+/// Number of continuation parts announced by the head of a record and the head without the closing entry
+pub fn continuation_parts(head: &str) -> (&str, usize) {
+    if record_version(head) == RecordVersion::V2 {
+        return (head, 0);
+    }
+    let trimmed = head.strip_suffix(";;").or_else(|| head.strip_suffix(';')).unwrap_or(head);
+    if let Some(pos) = trimmed.rfind(CONTINUATION_ID) {
+        let (entries, tail) = trimmed.split_at(pos);
+        if entries.is_empty() || entries.ends_with(';') {
+            if let Some(Ok(parts)) = tail.strip_prefix("#;").map(|n| n.parse::<usize>()) {
+                return (entries, parts);
+            }
+        }
+    }
+    (head, 0)
+}
+
+/// Value of the record under `key` with its continuation parts joined; a missing part is an error
+pub fn read_continued(key: &str, db: &mut dyn Storage) -> io::Result<Option<String>> {
+    let Some(head) = db.get(key)? else {
+        return Ok(None);
+    };
+
+    let (entries, parts) = continuation_parts(&head);
+    if parts == 0 {
+        return Ok(Some(head));
+    }
+
+    let mut res = entries.to_owned();
+    for part in 1..=parts {
+        match db.get(&continuation_key(key, part))? {
+            Some(src) => res.push_str(&src),
+            None => return Err(missing_part(key, part, parts)),
+        }
+    }
+    Ok(Some(res))
+}
+
+pub(crate) fn missing_part(key: &str, part: usize, parts: usize) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("record {}: continuation part {} of {} is missing", key, part, parts))
+}
+
+/// Splits an encoded record into values of at most `max_len` bytes: the head under `key`, then the parts.
+/// Entries are never cut, an entry longer than `max_len` gets a value of its own.
+pub fn split_continued(key: &str, value: &str, max_len: usize) -> Vec<(String, String)> {
+    if value.len() <= max_len || record_version(value) == RecordVersion::V2 {
+        return vec![(key.to_owned(), value.to_owned())];
+    }
+
+    let tokens: Vec<&str> = value.split(';').collect();
+    let mut chunks: Vec<String> = vec![String::new()];
+    for entry in tokens.chunks(3).filter(|c| c.len() == 3) {
+        let entry = entry.join(";") + ";";
+        let limit = if chunks.len() == 1 {
+            max_len.saturating_sub(CONTINUATION_RESERVE)
+        } else {
+            max_len
+        };
+        if let Some(last) = chunks.last_mut() {
+            if last.is_empty() || last.len() + entry.len() <= limit {
+                last.push_str(&entry);
+                continue;
+            }
+        }
+        chunks.push(entry);
+    }
+
+    let parts = chunks.len() - 1;
+    if parts == 0 {
+        return vec![(key.to_owned(), value.to_owned())];
+    }
+
+    let mut res = Vec::with_capacity(chunks.len());
+    for (n, chunk) in chunks.into_iter().enumerate() {
+        if n == 0 {
+            res.push((key.to_owned(), format!("{}{};{};;", chunk, CONTINUATION_ID, parts)));
+        } else {
+            res.push((continuation_key(key, n), chunk));
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorize_with_config;
+    use crate::common::Trace;
+    use crate::config::AzConfig;
+    use crate::manage::MutableStorage;
+    use crate::storage::memory::MemoryStorage;
+    use chrono::TimeZone;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(secs, 0).unwrap()
+    }
+
+    fn decode(src: &str) -> (ACLRecordVec, bool, Validity) {
+        let mut res = ACLRecordVec::new();
+        let (is_ok, valid_until) = decode_rec_to_rights(src, &mut res);
+        let validity = Validity {
+            valid_from: record_valid_from(src),
+            valid_until,
+        };
+        (res, is_ok, validity)
+    }
+
+    fn summary(records: &[ACLRecord]) -> Vec<(String, u8, char, bool, RightsCounters, Option<GrantProvenance>)> {
+        records.iter().map(|r| (r.id.clone(), r.access, r.marker, r.is_deleted, r.counters, r.provenance.as_deref().cloned())).collect()
+    }
+
+    fn sample() -> Vec<ACLRecord> {
+        let mut plain = ACLRecord::new_with_access("td:user", 2);
+        plain.marker = M_IS_EXCLUSIVE;
+
+        let mut counted = ACLRecord::new_with_access("cfg:Group", 0);
+        counted.add_right('R');
+        counted.add_right('R');
+        counted.add_right('U');
+
+        let mut deleted = ACLRecord::new_with_access("td:gone", 8);
+        deleted.is_deleted = true;
+
+        let mut provenance = ACLRecord::new_with_access("td:other", 6);
+        provenance.provenance = Some(Box::new(GrantProvenance {
+            granted_by: Some("td:admin".to_owned()),
+            granted_at: Some(at(1_700_000_000)),
+            reason: Some("ticket 42; urgent".to_owned()),
+        }));
+
+        vec![plain, counted, deleted, provenance]
+    }
+
+    #[test]
+    fn v1_round_trip() {
+        let mut records = sample();
+        records.retain(|r| !r.is_deleted && r.provenance.is_none());
+
+        let src = encode_rights_counted(&records);
+        assert_eq!(record_version(&src), RecordVersion::V1);
+        let (decoded, is_ok, validity) = decode(&src);
+        assert!(is_ok);
+        assert_eq!(validity, Validity::default());
+        assert_eq!(summary(&decoded), summary(&records));
+        assert_eq!(decoded[1].counters.get('R'), 2);
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        let records = sample();
+        for validity in [
+            Validity::default(),
+            Validity {
+                valid_from: Some(at(1_600_000_000)),
+                valid_until: None,
+            },
+            Validity {
+                valid_from: Some(at(1_600_000_000)),
+                valid_until: Some(at(1_900_000_000)),
+            },
+        ] {
+            let src = encode_record(&records, validity, RecordVersion::V2);
+            assert_eq!(record_version(&src), RecordVersion::V2);
+            let (decoded, is_ok, decoded_validity) = decode(&src);
+            assert!(is_ok);
+            assert_eq!(decoded_validity, validity);
+            assert_eq!(summary(&decoded), summary(&records));
+
+            let mut binary = ACLRecordVec::new();
+            assert_eq!(decode_v2(&encode_v2(&records, validity), &mut binary), (true, validity.valid_until));
+            assert_eq!(summary(&binary), summary(&records));
+        }
+    }
+
+    #[test]
+    fn auto_picks_the_version() {
+        let records = sample();
+        let plain: Vec<ACLRecord> = records.iter().filter(|r| !r.is_deleted && r.provenance.is_none()).cloned().collect();
+        assert_eq!(record_version(&encode_record_auto(&plain, Validity::default())), RecordVersion::V1);
+        assert_eq!(record_version(&encode_record_auto(&records, Validity::default())), RecordVersion::V2);
+
+        let bounded = Validity {
+            valid_from: None,
+            valid_until: Some(at(1_900_000_000)),
+        };
+        let src = encode_record_auto(&plain, bounded);
+        assert_eq!(record_version(&src), RecordVersion::V2);
+        assert_eq!(decode(&src).2, bounded);
+    }
+
+    #[test]
+    fn truncated_v2_keeps_complete_entries() {
+        let records = sample();
+        let bytes = encode_v2(&records, Validity::default());
+        let mut decoded = ACLRecordVec::new();
+        let (is_ok, _) = decode_v2(&bytes[..bytes.len() - 3], &mut decoded);
+        assert!(!is_ok);
+        assert_eq!(summary(&decoded), summary(&records[..3]));
+    }
+
+    #[test]
+    fn validity_bounds() {
+        let validity = Validity {
+            valid_from: Some(at(100)),
+            valid_until: Some(at(200)),
+        };
+        assert!(!validity.is_in_force(at(99)));
+        assert!(validity.is_in_force(at(100)));
+        assert!(validity.is_in_force(at(199)));
+        assert!(!validity.is_in_force(at(200)));
+        assert!(Validity::default().is_in_force(at(0)));
+    }
+
+    #[test]
+    fn continuation_trailer() {
+        assert_eq!(continuation_parts("a;2;;b;4;;#;3;;"), ("a;2;;b;4;;", 3));
+        assert_eq!(continuation_parts("a;2;;#;12;"), ("a;2;;", 12));
+        assert_eq!(continuation_parts("a;2;;b;4;;"), ("a;2;;b;4;;", 0));
+        assert_eq!(continuation_parts(""), ("", 0));
+        // '#' внутри идентификатора или без числа частей не замыкает голову
+        assert_eq!(continuation_parts("a#;2;;"), ("a#;2;;", 0));
+        assert_eq!(continuation_parts("doc#1;2;;"), ("doc#1;2;;", 0));
+        assert_eq!(continuation_parts("a;2;;#;R;;"), ("a;2;;#;R;;", 0));
+        assert_eq!(continuation_parts("a;2;;#;;"), ("a;2;;#;;", 0));
+
+        let v2 = encode_record(&sample(), Validity::default(), RecordVersion::V2);
+        assert_eq!(continuation_parts(&v2).1, 0);
+    }
+
+    fn store(parts: &[(String, String)]) -> MemoryStorage {
+        let mut db = MemoryStorage::new();
+        for (key, value) in parts {
+            db.put(key, value).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn split_and_read_back() {
+        let records: Vec<ACLRecord> = (0..40).map(|n| ACLRecord::new_with_access(&format!("td:user{}", n), 2)).collect();
+        let value = encode_rights(&records);
+
+        for max_len in [40, 64, 100, 1000, value.len()] {
+            let parts = split_continued("Pdoc", &value, max_len);
+            assert!(parts.iter().all(|(_, v)| v.len() <= max_len), "max_len {}", max_len);
+            assert_eq!(parts[0].0, "Pdoc");
+            for (n, (key, _)) in parts.iter().enumerate().skip(1) {
+                assert_eq!(*key, continuation_key("Pdoc", n));
+            }
+            assert_eq!(continuation_parts(&parts[0].1).1, parts.len() - 1);
+
+            let mut db = store(&parts);
+            let joined = read_continued("Pdoc", &mut db).unwrap().unwrap();
+            assert_eq!(joined, value);
+            let (decoded, is_ok, _) = decode(&joined);
+            assert!(is_ok);
+            assert!(decoded.iter().all(|r| r.id != CONTINUATION_ID));
+            assert_eq!(decoded.len(), records.len());
+        }
+    }
+
+    #[test]
+    fn split_keeps_long_entries_whole() {
+        let long = "x".repeat(80);
+        let value = format!("a;2;;{};2;;b;2;;", long);
+        let parts = split_continued("Pdoc", &value, 40);
+        assert!(parts.iter().any(|(_, v)| v.contains(&long)));
+
+        let mut db = store(&parts);
+        assert_eq!(read_continued("Pdoc", &mut db).unwrap().as_deref(), Some(value.as_str()));
+    }
+
+    #[test]
+    fn v2_is_never_split() {
+        let src = encode_record(&sample(), Validity::default(), RecordVersion::V2);
+        assert_eq!(split_continued("Pdoc", &src, 8), vec![("Pdoc".to_owned(), src)]);
+    }
+
+    #[test]
+    fn missing_part_is_an_error() {
+        let value = encode_rights(&(0..20).map(|n| ACLRecord::new_with_access(&format!("td:user{}", n), 2)).collect::<Vec<_>>());
+        let mut parts = split_continued("Pdoc", &value, 40);
+        assert!(parts.len() > 2);
+        parts.remove(1);
+
+        let mut db = store(&parts);
+        assert_eq!(read_continued("Pdoc", &mut db).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_continued("Pnone", &mut db).unwrap(), None);
+    }
+
+    fn entries(src: &str, policy: DuplicateEntries) -> Vec<(String, u8, char)> {
+        let mut res = ACLRecordVec::new();
+        decode_rec_to_rights_with(src, &mut res, policy);
+        res.into_iter().map(|r| (r.id, r.access, r.marker)).collect()
+    }
+
+    const DUPLICATES: &str = "u1;2;;u2;4;;u1;8;X;u1;32;;";
+
+    #[test]
+    fn duplicates_or() {
+        assert_eq!(entries(DUPLICATES, DuplicateEntries::Or), vec![("u1".to_owned(), 42, M_IS_EXCLUSIVE), ("u2".to_owned(), 4, 0 as char)]);
+
+        let mut set = ACLRecordSet::new();
+        decode_rec_to_rightset(DUPLICATES, &mut set);
+        assert_eq!(set.len(), 2);
+        assert_eq!((set["u1"].access, set["u1"].marker), (42, M_IS_EXCLUSIVE));
+
+        let mut counted = ACLRecordVec::new();
+        decode_rec_to_rights_with("u1;R;;u1;R2U;;", &mut counted, DuplicateEntries::Or);
+        assert_eq!((counted.len(), counted[0].counters.get('R'), counted[0].counters.get('U')), (1, 3, 1));
+    }
+
+    #[test]
+    fn duplicates_first_wins() {
+        assert_eq!(entries(DUPLICATES, DuplicateEntries::FirstWins), vec![("u1".to_owned(), 2, 0 as char), ("u2".to_owned(), 4, 0 as char)]);
+    }
+
+    #[test]
+    fn duplicates_last_wins() {
+        assert_eq!(entries(DUPLICATES, DuplicateEntries::LastWins), vec![("u1".to_owned(), 32, 0 as char), ("u2".to_owned(), 4, 0 as char)]);
+    }
+
+    // Разрешение и запрет из разных записей субъекта: решение не зависит от размера записи
+    fn decide(record: &str, cfg: &AzConfig) -> u8 {
+        let mut db = MemoryStorage::new();
+        db.put("Pdg", record).unwrap();
+        db.put("Md1", "dg;15;;").unwrap();
+
+        let (mut acl, mut group, mut info) = (String::new(), String::new(), String::new());
+        let mut trace = Trace {
+            acl: &mut acl,
+            is_acl: false,
+            group: &mut group,
+            is_group: false,
+            info: &mut info,
+            is_info: false,
+            str_num: 0,
+        };
+        authorize_with_config("d1", "u1", 2, &mut db, &mut trace, cfg).unwrap()
+    }
+
+    #[test]
+    fn duplicates_with_deny_bits_do_not_depend_on_record_size() {
+        let fillers: String = (0..10_000).map(|n| format!("x{};2;;", n)).collect();
+        let small = "u1;2;;u1;32;;".to_owned();
+        let large = format!("u1;2;;{}u1;32;;", fillers);
+
+        let set_path = AzConfig {
+            rightset_min_len: Some(0),
+            ..AzConfig::default()
+        };
+        let scan_path = AzConfig {
+            rightset_min_len: None,
+            ..AzConfig::default()
+        };
+        assert!(large.len() >= AzConfig::default().rightset_min_len.unwrap());
+
+        for record in [&small, &large] {
+            assert_eq!(decide(record, &AzConfig::default()), 2);
+            assert_eq!(decide(record, &set_path), 2);
+            assert_eq!(decide(record, &scan_path), 2);
+            for deny_override in [&set_path, &scan_path] {
+                let cfg = AzConfig {
+                    deny_override: true,
+                    ..deny_override.clone()
+                };
+                assert_eq!(decide(record, &cfg), 0);
+            }
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use crate::common::{M_IGNORE_EXCLUSIVE, M_IS_EXCLUSIVE};
+use crate::{ACLRecord, ACLRecordSet};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Which marker survives when the same group is reached by several paths
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum MarkerPrecedence {
+    /// The first non-zero marker is kept, the result depends on traversal order
+    #[default]
+    FirstWins,
+    /// `X` on any path makes the group exclusive
+    ExclusiveWins,
+    /// `N` on any path lifts exclusivity
+    IgnoreExclusiveWins,
+}
+
+/// Marker of a group already collected (`existing`) merged with one found on another path (`incoming`)
+pub fn merge_marker(existing: char, incoming: char, precedence: MarkerPrecedence) -> char {
+    match precedence {
+        MarkerPrecedence::ExclusiveWins if existing == M_IS_EXCLUSIVE || incoming == M_IS_EXCLUSIVE => M_IS_EXCLUSIVE,
+        MarkerPrecedence::IgnoreExclusiveWins if existing == M_IGNORE_EXCLUSIVE || incoming == M_IGNORE_EXCLUSIVE => M_IGNORE_EXCLUSIVE,
+        _ => {
+            if existing == 0 as char {
+                incoming
+            } else {
+                existing
+            }
+        },
+    }
+}
+
+/// Set of ACL records keyed by id with set operations over their access masks
+#[derive(Default, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RecordSet {
+    records: ACLRecordSet,
+}
+
+impl From<ACLRecordSet> for RecordSet {
+    fn from(records: ACLRecordSet) -> Self {
+        RecordSet {
+            records,
+        }
+    }
+}
+
+impl From<Vec<ACLRecord>> for RecordSet {
+    fn from(src: Vec<ACLRecord>) -> Self {
+        let mut set = RecordSet::default();
+        for rec in src {
+            set.insert(rec);
+        }
+        set
+    }
+}
+
+impl RecordSet {
+    pub fn new() -> Self {
+        RecordSet::default()
+    }
+
+    pub fn into_inner(self) -> ACLRecordSet {
+        self.records
+    }
+
+    pub fn as_inner(&self) -> &ACLRecordSet {
+        &self.records
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ACLRecord> {
+        self.records.get(id)
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.records.contains_key(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ACLRecord> {
+        self.records.values()
+    }
+
+    /// Records ordered by id, for stable output
+    pub fn to_sorted_vec(&self) -> Vec<ACLRecord> {
+        let mut res: Vec<ACLRecord> = self.records.values().cloned().collect();
+        res.sort_by(|a, b| a.id.cmp(&b.id));
+        res
+    }
+
+    /// Adds a record, merging it with an existing one of the same id
+    pub fn insert(&mut self, rec: ACLRecord) {
+        self.insert_with(rec, MarkerPrecedence::FirstWins)
+    }
+
+    pub fn insert_with(&mut self, rec: ACLRecord, precedence: MarkerPrecedence) {
+        match self.records.get_mut(&rec.id) {
+            Some(cur) => merge_record(cur, &rec, precedence),
+            None => {
+                self.records.insert(rec.id.clone(), rec);
+            },
+        }
+    }
+
+    /// Union: access masks are OR-ed, the first non-zero marker is kept
+    pub fn merge(&mut self, other: &RecordSet) {
+        self.merge_with(other, MarkerPrecedence::FirstWins)
+    }
+
+    pub fn merge_with(&mut self, other: &RecordSet, precedence: MarkerPrecedence) {
+        for rec in other.records.values() {
+            self.insert_with(rec.clone(), precedence);
+        }
+    }
+
+    /// Keeps ids present in both sets with the common part of their masks
+    pub fn intersect(&mut self, other: &RecordSet) {
+        self.records.retain(|id, rec| match other.records.get(id) {
+            Some(o) => {
+                rec.access &= o.access;
+                rec.access != 0
+            },
+            None => false,
+        });
+    }
+
+    /// Removes from each record the access bits granted by the same id in `other`
+    pub fn subtract(&mut self, other: &RecordSet) {
+        self.records.retain(|id, rec| match other.records.get(id) {
+            Some(o) => {
+                rec.access &= !o.access;
+                rec.access != 0
+            },
+            None => true,
+        });
+    }
+}
+
+fn merge_record(dst: &mut ACLRecord, src: &ACLRecord, precedence: MarkerPrecedence) {
+    // надгробие уступает действующей записи
+    if dst.is_deleted != src.is_deleted {
+        if dst.is_deleted {
+            *dst = src.clone();
+        }
+        return;
+    }
+    dst.access |= src.access;
+    dst.marker = merge_marker(dst.marker, src.marker, precedence);
+    dst.level = dst.level.min(src.level);
+    dst.counters.merge(&src.counters);
+    if dst.provenance.is_none() {
+        dst.provenance = src.provenance.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AzConfig;
+    use crate::get_user_groups_with_config;
+    use crate::storage::memory::MemoryStorage;
+
+    const NONE: char = 0 as char;
+    const X: char = M_IS_EXCLUSIVE;
+    const N: char = M_IGNORE_EXCLUSIVE;
+
+    #[test]
+    fn first_wins() {
+        for (existing, incoming, expected) in [(NONE, NONE, NONE), (NONE, X, X), (NONE, N, N), (X, NONE, X), (X, N, X), (N, X, N), (N, NONE, N), (X, X, X)] {
+            assert_eq!(merge_marker(existing, incoming, MarkerPrecedence::FirstWins), expected, "{:?} + {:?}", existing, incoming);
+        }
+    }
+
+    #[test]
+    fn exclusive_wins() {
+        for (existing, incoming, expected) in [(NONE, NONE, NONE), (NONE, X, X), (NONE, N, N), (X, NONE, X), (X, N, X), (N, X, X), (N, NONE, N), (N, N, N)] {
+            assert_eq!(merge_marker(existing, incoming, MarkerPrecedence::ExclusiveWins), expected, "{:?} + {:?}", existing, incoming);
+        }
+    }
+
+    #[test]
+    fn ignore_exclusive_wins() {
+        for (existing, incoming, expected) in [(NONE, NONE, NONE), (NONE, X, X), (NONE, N, N), (X, NONE, X), (X, N, N), (N, X, N), (N, NONE, N), (X, X, X)] {
+            assert_eq!(merge_marker(existing, incoming, MarkerPrecedence::IgnoreExclusiveWins), expected, "{:?} + {:?}", existing, incoming);
+        }
+    }
+
+    #[test]
+    fn winning_markers_do_not_depend_on_order() {
+        for precedence in [MarkerPrecedence::ExclusiveWins, MarkerPrecedence::IgnoreExclusiveWins] {
+            assert_eq!(merge_marker(X, N, precedence), merge_marker(N, X, precedence), "{:?}", precedence);
+        }
+        assert_ne!(merge_marker(X, N, MarkerPrecedence::FirstWins), merge_marker(N, X, MarkerPrecedence::FirstWins));
+    }
+
+    #[test]
+    fn set_insert_applies_precedence() {
+        let marked = |marker| {
+            let mut rec = ACLRecord::new_with_access("g", 2);
+            rec.marker = marker;
+            rec
+        };
+
+        for (precedence, expected) in [(MarkerPrecedence::FirstWins, N), (MarkerPrecedence::ExclusiveWins, X), (MarkerPrecedence::IgnoreExclusiveWins, N)] {
+            let mut set = RecordSet::new();
+            set.insert_with(marked(NONE), precedence);
+            set.insert_with(marked(N), precedence);
+            set.insert_with(marked(X), precedence);
+            assert_eq!(set.get("g").map(|r| r.marker), Some(expected), "{:?}", precedence);
+        }
+    }
+
+    #[test]
+    fn traversal_applies_precedence() {
+        // u1 достигает g двумя путями: через a с маркером N и через b с маркером X
+        let mut db = MemoryStorage::new();
+        db.add_membership("u1", "a", 15).unwrap();
+        db.add_membership("u1", "b", 15).unwrap();
+        db.add_marked_membership("a", "g", 15, N).unwrap();
+        db.add_marked_membership("b", "g", 15, X).unwrap();
+
+        for (precedence, expected) in [(MarkerPrecedence::ExclusiveWins, X), (MarkerPrecedence::IgnoreExclusiveWins, N)] {
+            let cfg = AzConfig {
+                marker_precedence: precedence,
+                ..AzConfig::default()
+            };
+            let groups = get_user_groups_with_config("u1", &mut db, &cfg).unwrap();
+            assert_eq!(groups.get("g").map(|r| r.marker), Some(expected), "{:?}", precedence);
+        }
+    }
+}
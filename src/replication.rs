@@ -0,0 +1,381 @@
+//! Reads spread over replicas of the store, with the primary as the fallback when a replica may be stale.
+//!
+//! A replica is read only while its `Storage::epoch` is within `max_lag` of the primary's and at least the
+//! epoch last required with `require_epoch`; otherwise the next replica is tried, then the primary. Writes
+//! go to the primary and require its new epoch, so the handle that wrote reads its own writes. A grant
+//! written elsewhere, e.g. by the indexer, is waited for with `manage::await_visibility` on `primary_only`,
+//! followed by `require_primary_epoch`. Replicas without epochs are read only when no bound is set.
+//!
+//! Replicas apply writes at their own pace, so the reads of one decision must come from one store: a
+//! membership read from one replica and a permission from another may show a state the primary never had.
+//! `pinned` gives a view that reads one store for its whole life, to be made per authorize call; the next
+//! view takes the next fresh replica. Reads made on the storage itself stay on one replica while it is fresh.
+
+use crate::common::Storage;
+use crate::manage::MutableStorage;
+use crate::{ACLRecord, ACLRecordSet, ACLRecordVec};
+use chrono::{DateTime, Utc};
+use std::io;
+
+pub struct ReplicatedStorage<S: Storage> {
+    primary: S,
+    replicas: Vec<S>,
+    // следующая реплика по кругу
+    next: usize,
+    // реплика прямых чтений, пока она свежая
+    current: Option<usize>,
+    max_lag: Option<u64>,
+    min_epoch: u64,
+}
+
+impl<S: Storage> ReplicatedStorage<S> {
+    pub fn new(primary: S, replicas: Vec<S>) -> Self {
+        ReplicatedStorage {
+            primary,
+            replicas,
+            next: 0,
+            current: None,
+            max_lag: None,
+            min_epoch: 0,
+        }
+    }
+
+    pub fn primary(&self) -> &S {
+        &self.primary
+    }
+
+    /// Writes through the returned reference do not require the new epoch from the replicas
+    pub fn primary_mut(&mut self) -> &mut S {
+        &mut self.primary
+    }
+
+    pub fn replicas(&self) -> &[S] {
+        &self.replicas
+    }
+
+    /// Replicas more than `max_lag` epochs behind the primary are not read
+    pub fn set_max_lag(&mut self, max_lag: Option<u64>) {
+        self.max_lag = max_lag;
+    }
+
+    /// Replicas are read only once they have reached `epoch`; a lower value than the one required before is ignored
+    pub fn require_epoch(&mut self, epoch: u64) {
+        self.min_epoch = self.min_epoch.max(epoch);
+    }
+
+    /// Requires from the replicas everything the primary holds now
+    pub fn require_primary_epoch(&mut self) {
+        if let Some(epoch) = self.primary.epoch() {
+            self.require_epoch(epoch);
+        }
+    }
+
+    /// Storage reading the primary only, for a call that must see the latest writes
+    pub fn primary_only(&mut self) -> PrimaryReads<'_, S> {
+        PrimaryReads {
+            primary: &mut self.primary,
+        }
+    }
+
+    /// Storage reading one store, the next fresh replica or the primary when none is, for one decision
+    pub fn pinned(&mut self) -> PinnedReads<'_, S> {
+        let replica = self.pick_next();
+        PinnedReads {
+            storage: self,
+            replica,
+        }
+    }
+
+    fn is_fresh(&self, replica: &S, primary_epoch: Option<u64>) -> bool {
+        match replica.epoch() {
+            None => self.min_epoch == 0 && self.max_lag.is_none(),
+            Some(epoch) => {
+                epoch >= self.min_epoch && self.max_lag.is_none_or(|max_lag| primary_epoch.is_some_and(|primary| primary.saturating_sub(epoch) <= max_lag))
+            },
+        }
+    }
+
+    fn primary_epoch(&self) -> Option<u64> {
+        if self.max_lag.is_some() {
+            self.primary.epoch()
+        } else {
+            None
+        }
+    }
+
+    // Индекс следующей по кругу свежей реплики; None - читать с основного хранилища
+    fn pick_next(&mut self) -> Option<usize> {
+        let primary_epoch = self.primary_epoch();
+        let count = self.replicas.len();
+        for shift in 0..count {
+            let idx = (self.next + shift) % count;
+            if self.is_fresh(&self.replicas[idx], primary_epoch) {
+                self.next = (idx + 1) % count;
+                return Some(idx);
+            }
+        }
+        None
+    }
+
+    // Реплика прямых чтений меняется, только когда отстала
+    fn pick_current(&mut self) -> Option<usize> {
+        let primary_epoch = self.primary_epoch();
+        if self.current.is_none_or(|idx| !self.is_fresh(&self.replicas[idx], primary_epoch)) {
+            self.current = self.pick_next();
+        }
+        self.current
+    }
+
+    // Чтение с реплики `replica`; при ошибке - с основного хранилища, дальше тоже с него
+    fn read_from(&mut self, replica: &mut Option<usize>, key: &str, uncached: bool) -> io::Result<Option<String>> {
+        if let Some(idx) = *replica {
+            let store = &mut self.replicas[idx];
+            let res = if uncached {
+                store.get_uncached(key)
+            } else {
+                store.get(key)
+            };
+            match res {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    eprintln!("WARN! replica {}: fail read {}, fallback to primary, err={:?}", idx, key, e);
+                    *replica = None;
+                },
+            }
+        }
+
+        if uncached {
+            self.primary.get_uncached(key)
+        } else {
+            self.primary.get(key)
+        }
+    }
+
+    fn read(&mut self, key: &str, uncached: bool) -> io::Result<Option<String>> {
+        let mut replica = self.pick_current();
+        let res = self.read_from(&mut replica, key, uncached);
+        self.current = replica;
+        res
+    }
+}
+
+/// Reads of a `ReplicatedStorage` going to one store, see `ReplicatedStorage::pinned`. A replica that fails
+/// a read is left for the primary, which is never behind it
+pub struct PinnedReads<'a, S: Storage> {
+    storage: &'a mut ReplicatedStorage<S>,
+    // None - основное хранилище
+    replica: Option<usize>,
+}
+
+impl<S: Storage> PinnedReads<'_, S> {
+    /// Index of the replica read, `None` for the primary
+    pub fn replica(&self) -> Option<usize> {
+        self.replica
+    }
+
+    fn store(&self) -> &S {
+        match self.replica {
+            Some(idx) => &self.storage.replicas[idx],
+            None => &self.storage.primary,
+        }
+    }
+}
+
+// Все чтения идут с одного хранилища, поэтому его эпоха сообщается
+impl<S: Storage> Storage for PinnedReads<'_, S> {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.storage.read_from(&mut self.replica, key, false)
+    }
+
+    fn fiber_yield(&self) {
+        self.storage.primary.fiber_yield()
+    }
+
+    fn decode_rec_to_rights(&self, src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+        self.storage.primary.decode_rec_to_rights(src, result)
+    }
+
+    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+        self.storage.primary.decode_rec_to_rightset(src, new_rights)
+    }
+
+    fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+        self.storage.primary.decode_filter(filter_value)
+    }
+
+    fn decode_valid_from(&self, src: &str) -> Option<DateTime<Utc>> {
+        self.storage.primary.decode_valid_from(src)
+    }
+
+    fn epoch(&self) -> Option<u64> {
+        self.store().epoch()
+    }
+
+    fn get_uncached(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.storage.read_from(&mut self.replica, key, true)
+    }
+}
+
+/// Reads of a `ReplicatedStorage` going to the primary only, see `ReplicatedStorage::primary_only`
+pub struct PrimaryReads<'a, S: Storage> {
+    primary: &'a mut S,
+}
+
+impl<S: Storage> Storage for PrimaryReads<'_, S> {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.primary.get(key)
+    }
+
+    fn fiber_yield(&self) {
+        self.primary.fiber_yield()
+    }
+
+    fn decode_rec_to_rights(&self, src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+        self.primary.decode_rec_to_rights(src, result)
+    }
+
+    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+        self.primary.decode_rec_to_rightset(src, new_rights)
+    }
+
+    fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+        self.primary.decode_filter(filter_value)
+    }
+
+    fn decode_valid_from(&self, src: &str) -> Option<DateTime<Utc>> {
+        self.primary.decode_valid_from(src)
+    }
+
+    fn epoch(&self) -> Option<u64> {
+        self.primary.epoch()
+    }
+
+    fn get_uncached(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.primary.get_uncached(key)
+    }
+}
+
+// Эпоха не сообщается: реплика прямых чтений может смениться между ними
+impl<S: Storage> Storage for ReplicatedStorage<S> {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.read(key, false)
+    }
+
+    fn fiber_yield(&self) {
+        self.primary.fiber_yield()
+    }
+
+    fn decode_rec_to_rights(&self, src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+        self.primary.decode_rec_to_rights(src, result)
+    }
+
+    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+        self.primary.decode_rec_to_rightset(src, new_rights)
+    }
+
+    fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+        self.primary.decode_filter(filter_value)
+    }
+
+    fn decode_valid_from(&self, src: &str) -> Option<DateTime<Utc>> {
+        self.primary.decode_valid_from(src)
+    }
+
+    fn get_uncached(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.read(key, true)
+    }
+}
+
+impl<S: MutableStorage> MutableStorage for ReplicatedStorage<S> {
+    fn put(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.primary.put(key, value)?;
+        self.require_primary_epoch();
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        self.primary.remove(key)?;
+        self.require_primary_epoch();
+        Ok(())
+    }
+
+    fn apply_batch(&mut self, batch: &[(String, Option<String>)]) -> io::Result<()> {
+        self.primary.apply_batch(batch)?;
+        self.require_primary_epoch();
+        Ok(())
+    }
+
+    fn max_value_len(&self) -> Option<usize> {
+        self.primary.max_value_len()
+    }
+
+    fn emit_invalidation(&mut self, keys: &[String]) {
+        self.primary.emit_invalidation(keys);
+        for replica in &mut self.replicas {
+            replica.emit_invalidation(keys);
+        }
+    }
+
+    // Массовые операции работают с основным хранилищем
+    fn scan_prefix(&mut self, prefix: &str) -> io::Result<Vec<(String, String)>> {
+        self.primary.scan_prefix(prefix)
+    }
+
+    fn keys_referencing(&mut self, subject_id: &str) -> io::Result<Vec<String>> {
+        self.primary.keys_referencing(subject_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AzConfig;
+    use crate::engine::AzEngine;
+    use crate::storage::memory::MemoryStorage;
+
+    // Реплики с разными снимками: в каждой членство и право своего поколения
+    fn replicated() -> ReplicatedStorage<MemoryStorage> {
+        let mut replicas = Vec::new();
+        for gen in ["g0", "g1"] {
+            let mut replica = MemoryStorage::new();
+            replica.add_membership("u1", gen, 15).unwrap();
+            replica.add_permission("doc", gen, 2).unwrap();
+            replicas.push(replica);
+        }
+        ReplicatedStorage::new(MemoryStorage::new(), replicas)
+    }
+
+    #[test]
+    fn pinned_reads_stay_on_one_replica() {
+        let mut db = replicated();
+        let engine = AzEngine::new(AzConfig::default());
+        for expected in [Some(0), Some(1), Some(0)] {
+            let mut pinned = db.pinned();
+            assert_eq!(pinned.replica(), expected);
+            assert_eq!(engine.authorize_dry("doc", "u1", 2, &mut pinned).unwrap(), 2);
+        }
+    }
+
+    #[test]
+    fn direct_reads_stay_on_one_replica() {
+        let mut db = replicated();
+        let engine = AzEngine::new(AzConfig::default());
+        for _ in 0..4 {
+            assert_eq!(engine.authorize_dry("doc", "u1", 2, &mut db).unwrap(), 2);
+        }
+    }
+
+    #[test]
+    fn stale_replicas_are_passed_over() {
+        let mut db = replicated();
+        let epoch = db.replicas()[1].epoch().unwrap();
+        db.replicas[0].add_permission("other", "u2", 2).unwrap();
+        db.require_epoch(epoch + 1);
+        assert_eq!(db.pinned().replica(), Some(0));
+        assert_eq!(db.pinned().replica(), Some(0));
+
+        db.require_epoch(u64::MAX);
+        let pinned = db.pinned();
+        assert_eq!((pinned.replica(), pinned.epoch()), (None, Some(0)));
+    }
+}
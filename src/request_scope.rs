@@ -0,0 +1,69 @@
+//! Memo of decisions and subject groups for the lifetime of one caller request (e.g. an HTTP request).
+//!
+//! Nothing outlives the scope, so there is no invalidation to get wrong: create one per incoming request,
+//! pass it to every authorize call made while serving it and drop it with the request.
+
+use crate::common::{Storage, TraceBuffers};
+use crate::config::AzConfig;
+use crate::decision::Decision;
+use crate::{authorize_with_hooks, ACLRecord, AzHooks};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+
+#[derive(Clone)]
+pub(crate) struct SubjectMemo {
+    pub(crate) groups: HashMap<String, ACLRecord>,
+    pub(crate) is_need_exclusive_az: bool,
+    pub(crate) exclusive_groups: Vec<String>,
+    pub(crate) individual_hops: Vec<(String, String)>,
+    pub(crate) principal_of: HashMap<String, usize>,
+}
+
+/// Subject groups by the principals they were collected for
+pub(crate) type SubjectMemoMap = HashMap<Vec<String>, SubjectMemo>;
+
+#[derive(Default)]
+pub struct RequestScope {
+    cfg: AzConfig,
+    decisions: HashMap<(String, String, u8), u8>,
+    subjects: RefCell<SubjectMemoMap>,
+}
+
+impl RequestScope {
+    pub fn new(cfg: AzConfig) -> Self {
+        RequestScope {
+            cfg,
+            decisions: HashMap::new(),
+            subjects: RefCell::default(),
+        }
+    }
+
+    /// Same as `authorize` without trace; repeated requests are answered from the memo,
+    /// the user's groups are read once per scope. Errors are not memoized.
+    pub fn authorize(&mut self, id: &str, user_id: &str, request_access: u8, db: &mut dyn Storage) -> io::Result<u8> {
+        let key = (id.to_owned(), user_id.to_owned(), request_access);
+        if let Some(res) = self.decisions.get(&key) {
+            return Ok(*res);
+        }
+
+        let hooks = AzHooks {
+            subject_memo: Some(&self.subjects),
+            ..AzHooks::default()
+        };
+        let mut buf = TraceBuffers::default();
+        let res = authorize_with_hooks(id, user_id, request_access, db, &mut buf.trace(false, false, false), &self.cfg, &hooks, &mut Decision::default())?;
+
+        self.decisions.insert(key, res);
+        Ok(res)
+    }
+
+    /// Decisions memoized so far
+    pub fn len(&self) -> usize {
+        self.decisions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.decisions.is_empty()
+    }
+}
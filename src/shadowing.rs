@@ -0,0 +1,218 @@
+//! Permission entries made redundant by a broader grant to the same subject.
+//!
+//! An entry on an object group is shadowed when the same subject holds a superset mask in the same record,
+//! or on an ancestor group reached through edges that let those bits through: every resource reaching the
+//! group reaches the ancestor too, so removing the entry changes no decision. The covering grant must be
+//! in force whenever the entry is; exclusive edges and memberships with a validity are not followed, and
+//! filtered P-records are left out.
+
+use crate::common::{accumulate_access, MEMBERSHIP_PREFIX, M_IS_EXCLUSIVE, PERMISSION_PREFIX};
+use crate::config::AccessAccumulation;
+use crate::manage::{filter_ids, scan_records, MutableStorage};
+use crate::presets::to_crud;
+use crate::record_formats::{read_continued, Validity};
+use crate::{ACLRecord, ACLRecordVec};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShadowedGrant {
+    /// P-record key without the prefix
+    pub object_group: String,
+    pub subject: String,
+    pub access: u8,
+    /// Group whose grant covers the entry, equal to `object_group` for an entry covered within the same record
+    pub shadowed_by: String,
+}
+
+impl ShadowedGrant {
+    /// Cleanup suggestion for an operator
+    pub fn suggestion(&self) -> String {
+        if self.shadowed_by == self.object_group {
+            format!("remove duplicate grant of {} to {} on {}", to_crud(self.access), self.subject, self.object_group)
+        } else {
+            format!("remove grant of {} to {} on {}, covered by the grant on {}", to_crud(self.access), self.subject, self.object_group, self.shadowed_by)
+        }
+    }
+}
+
+// Только разрешающие записи без маркеров: запреты и исключительность сравнивать нельзя
+fn is_plain_grant(rec: &ACLRecord) -> bool {
+    !rec.is_deleted && rec.marker == 0 as char && rec.access & 0xF0 == 0 && rec.access != 0
+}
+
+/// Scans all P-records; ancestors are followed up to `max_depth` M-record levels
+pub fn find_shadowed_grants(max_depth: u8, db: &mut dyn MutableStorage) -> io::Result<Vec<ShadowedGrant>> {
+    // ключ фильтрованного права: фильтр и id группы подряд (Legacy) или через ';' (Escaped)
+    let filters = filter_ids(db)?;
+    let is_filtered = |suffix: &str| suffix.contains(';') || filters.iter().any(|f| suffix.len() > f.len() && suffix.starts_with(f.as_str()));
+
+    let mut grants: HashMap<String, (ACLRecordVec, Validity)> = HashMap::new();
+    for (key, value) in scan_records(PERMISSION_PREFIX, db)? {
+        let suffix = &key[PERMISSION_PREFIX.len()..];
+        if is_filtered(suffix) {
+            continue;
+        }
+        let mut records = ACLRecordVec::new();
+        let validity = Validity {
+            valid_until: db.decode_rec_to_rights(&value, &mut records).1,
+            valid_from: db.decode_valid_from(&value),
+        };
+        grants.insert(suffix.to_owned(), (records, validity));
+    }
+
+    let mut groups: Vec<&String> = grants.keys().collect();
+    groups.sort();
+
+    let mut res = Vec::new();
+    for group in groups {
+        let (records, validity) = &grants[group];
+        let mut ancestors = None;
+
+        for (idx, rec) in records.iter().enumerate().filter(|(_, r)| is_plain_grant(r)) {
+            // Равные записи: лишними считаются все, кроме первой
+            let in_record = records.iter().enumerate().any(|(other_idx, other)| {
+                other_idx != idx
+                    && other.id == rec.id
+                    && is_plain_grant(other)
+                    && rec.access & !other.access == 0
+                    && (other.access != rec.access || other_idx < idx)
+            });
+            if in_record {
+                res.push(ShadowedGrant {
+                    object_group: group.clone(),
+                    subject: rec.id.clone(),
+                    access: rec.access,
+                    shadowed_by: group.clone(),
+                });
+                continue;
+            }
+
+            let ancestors = match &ancestors {
+                Some(a) => a,
+                None => ancestors.insert(collect_ancestors(group, max_depth, db)?),
+            };
+
+            let covering = ancestors.iter().find(|(ancestor, path_access)| {
+                grants.get(ancestor).is_some_and(|(recs, ancestor_validity)| {
+                    ancestor_validity.covers(validity)
+                        && recs.iter().any(|other| other.id == rec.id && is_plain_grant(other) && rec.access & !(other.access & path_access) == 0)
+                })
+            });
+            if let Some((ancestor, _)) = covering {
+                res.push(ShadowedGrant {
+                    object_group: group.clone(),
+                    subject: rec.id.clone(),
+                    access: rec.access,
+                    shadowed_by: ancestor.clone(),
+                });
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+// Группы-предки в порядке обхода в ширину; маска группы объединяет все найденные пути до нее
+fn collect_ancestors(group: &str, max_depth: u8, db: &mut dyn MutableStorage) -> io::Result<Vec<(String, u8)>> {
+    let mut res: Vec<(String, u8)> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut queue = VecDeque::from([(group.to_owned(), 15u8, 0u8)]);
+
+    while let Some((uri, access, level)) = queue.pop_front() {
+        if level >= max_depth {
+            continue;
+        }
+        let Some(src) = read_continued(&(MEMBERSHIP_PREFIX.to_owned() + &uri), db)? else {
+            continue;
+        };
+
+        // членство со сроком действия связывает группы не всегда
+        if db.decode_valid_from(&src).is_some() {
+            continue;
+        }
+        let mut parents = ACLRecordVec::new();
+        if db.decode_rec_to_rights(&src, &mut parents).1.is_some() {
+            continue;
+        }
+        // исключительная группа меняет решение для ресурсов в ней, ее права не заменяют права потомка
+        for parent in parents.iter().filter(|p| !p.is_deleted && p.marker != M_IS_EXCLUSIVE && !p.id.is_empty() && p.id != group) {
+            let path_access = accumulate_access(parent.access, access, AccessAccumulation::Intersect);
+            match index.get(&parent.id) {
+                Some(&idx) => {
+                    if res[idx].1 & path_access == path_access {
+                        continue;
+                    }
+                    res[idx].1 |= path_access;
+                },
+                None => {
+                    index.insert(parent.id.clone(), res.len());
+                    res.push((parent.id.clone(), path_access));
+                },
+            }
+            queue.push_back((parent.id.clone(), path_access, level + 1));
+        }
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manage::{set_validity, MutableStorage};
+    use crate::storage::memory::MemoryStorage;
+    use chrono::{TimeZone, Utc};
+
+    fn shadowed(db: &mut MemoryStorage) -> Vec<(String, String)> {
+        find_shadowed_grants(8, db).unwrap().into_iter().map(|g| (g.object_group, g.shadowed_by)).collect()
+    }
+
+    fn nested() -> MemoryStorage {
+        let mut db = MemoryStorage::new();
+        db.add_permission("g1", "u1", 2).unwrap();
+        db.add_permission("g2", "u1", 6).unwrap();
+        db
+    }
+
+    #[test]
+    fn grants_on_ancestors_cover() {
+        let mut db = nested();
+        db.add_membership("g1", "g2", 15).unwrap();
+        assert_eq!(shadowed(&mut db), vec![("g1".to_owned(), "g2".to_owned())]);
+    }
+
+    #[test]
+    fn exclusive_edges_are_not_followed() {
+        let mut db = nested();
+        db.add_marked_membership("g1", "g2", 15, M_IS_EXCLUSIVE).unwrap();
+        assert!(shadowed(&mut db).is_empty());
+    }
+
+    #[test]
+    fn covering_grants_outlive_the_entry() {
+        let until = Validity {
+            valid_from: None,
+            valid_until: Some(Utc.with_ymd_and_hms(2030, 1, 1, 0, 0, 0).unwrap()),
+        };
+        let mut db = nested();
+        db.add_membership("g1", "g2", 15).unwrap();
+        set_validity("Pg2", until, &mut db).unwrap();
+        assert!(shadowed(&mut db).is_empty());
+
+        set_validity("Pg1", until, &mut db).unwrap();
+        assert_eq!(shadowed(&mut db), vec![("g1".to_owned(), "g2".to_owned())]);
+
+        set_validity("Mg1", until, &mut db).unwrap();
+        assert!(shadowed(&mut db).is_empty());
+    }
+
+    #[test]
+    fn filtered_records_are_not_groups() {
+        let mut db = MemoryStorage::new();
+        db.add_filter("doc", "f1", 2).unwrap();
+        db.put("Pf1g1", "u1;2;;u1;2;;").unwrap();
+        db.put("Pg1", "u1;2;;u1;2;;").unwrap();
+        assert_eq!(shadowed(&mut db), vec![("g1".to_owned(), "g1".to_owned())]);
+    }
+}
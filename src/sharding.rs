@@ -0,0 +1,417 @@
+//! Spreads the keyspace over several stores by consistent hashing, behind one `Storage`.
+//!
+//! Each shard is placed on a hash ring at `vnodes` points derived from its name, and a key belongs to the
+//! first point at or after its own hash. Adding or retiring a shard only moves the keys between it and its
+//! neighbours on the ring. Until `rebalance` has moved them, the shards keep every ring they had since the
+//! last rebalance: a key missing on its owner is read from its owners on the earlier rings, newest first,
+//! and removals reach all of them.
+//!
+//! Keys are placed one by one, so the continuation parts of a record may land on different shards and
+//! `MutableStorage::apply_batch` is not atomic across shards.
+
+use crate::common::Storage;
+use crate::manage::MutableStorage;
+use crate::record_formats::{decode_filter, decode_rec_to_rights, decode_rec_to_rightset, record_valid_from};
+use crate::{ACLRecord, ACLRecordSet, ACLRecordVec};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+/// Ring points per shard
+pub const DEFAULT_VNODES: usize = 64;
+
+struct Shard<S> {
+    name: String,
+    storage: S,
+    // снят с кольца, хранится до конца перебалансировки
+    is_retired: bool,
+}
+
+#[derive(Clone, Default)]
+struct Ring {
+    // (точка, индекс шарда), по возрастанию точек
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    fn owner(&self, key: &str) -> Option<usize> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let h = hash(key);
+        let idx = self.points.partition_point(|(point, _)| *point < h);
+        Some(self.points[idx % self.points.len()].1)
+    }
+}
+
+// Место на кольце не зависит от версии компилятора и платформы
+fn hash(value: &str) -> u64 {
+    let digest = Sha256::digest(value.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap_or_default())
+}
+
+/// What `rebalance` did
+pub struct RebalanceReport<S> {
+    /// Keys copied to their new owner and removed from the old one
+    pub moved: usize,
+    /// Retired shards, now empty of keys they owned, with their names
+    pub retired: Vec<(String, S)>,
+}
+
+pub struct ShardedStorage<S: Storage> {
+    shards: Vec<Shard<S>>,
+    vnodes: usize,
+    ring: Ring,
+    // кольца со времени последней перебалансировки, от старых к новым
+    prev_rings: Vec<Ring>,
+}
+
+impl<S: Storage> ShardedStorage<S> {
+    pub fn new(vnodes: usize) -> Self {
+        ShardedStorage {
+            shards: Vec::new(),
+            vnodes: vnodes.max(1),
+            ring: Ring::default(),
+            prev_rings: Vec::new(),
+        }
+    }
+
+    /// Puts a shard on the ring; false if a shard of that name is there already.
+    /// Keys of other shards that now belong to it are read from them until `rebalance`
+    pub fn add_shard(&mut self, name: &str, storage: S) -> bool {
+        if self.shards.iter().any(|s| s.name == name) {
+            return false;
+        }
+        self.shards.push(Shard {
+            name: name.to_owned(),
+            storage,
+            is_retired: false,
+        });
+        self.rebuild_ring();
+        true
+    }
+
+    /// Takes a shard off the ring; it stays readable until `rebalance` moves its keys out
+    pub fn retire_shard(&mut self, name: &str) -> bool {
+        let Some(shard) = self.shards.iter_mut().find(|s| s.name == name && !s.is_retired) else {
+            return false;
+        };
+        shard.is_retired = true;
+        self.rebuild_ring();
+        true
+    }
+
+    /// Name of the shard that owns `key`
+    pub fn shard_for(&self, key: &str) -> Option<&str> {
+        self.ring.owner(key).map(|idx| self.shards[idx].name.as_str())
+    }
+
+    /// Names of the shards on the ring
+    pub fn shard_names(&self) -> Vec<&str> {
+        self.shards.iter().filter(|s| !s.is_retired).map(|s| s.name.as_str()).collect()
+    }
+
+    pub fn shard(&self, name: &str) -> Option<&S> {
+        self.shards.iter().find(|s| s.name == name).map(|s| &s.storage)
+    }
+
+    pub fn shard_mut(&mut self, name: &str) -> Option<&mut S> {
+        self.shards.iter_mut().find(|s| s.name == name).map(|s| &mut s.storage)
+    }
+
+    /// Some keys may still be on a shard that owned them before a change of the ring
+    pub fn is_rebalancing(&self) -> bool {
+        !self.prev_rings.is_empty()
+    }
+
+    fn rebuild_ring(&mut self) {
+        let mut ring = Ring::default();
+        for (idx, shard) in self.shards.iter().enumerate().filter(|(_, s)| !s.is_retired) {
+            for vnode in 0..self.vnodes {
+                ring.points.push((hash(&format!("{}#{}", shard.name, vnode)), idx));
+            }
+        }
+        ring.points.sort_unstable();
+
+        // каждое изменение кольца до перебалансировки запоминает, где ключи могли остаться
+        let prev = std::mem::replace(&mut self.ring, ring);
+        if !prev.points.is_empty() {
+            self.prev_rings.push(prev);
+        }
+    }
+
+    // Владелец ключа, затем его владельцы на прежних кольцах, от новых к старым
+    fn owners(&self, key: &str) -> io::Result<Vec<usize>> {
+        let Some(owner) = self.ring.owner(key) else {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "sharded storage has no shards"));
+        };
+        let mut res = vec![owner];
+        for prev in self.prev_rings.iter().rev().filter_map(|r| r.owner(key)) {
+            if !res.contains(&prev) {
+                res.push(prev);
+            }
+        }
+        Ok(res)
+    }
+
+    fn read(&mut self, key: &str, uncached: bool) -> io::Result<Option<String>> {
+        for idx in self.owners(key)? {
+            let shard = &mut self.shards[idx];
+            let value = if uncached {
+                shard.storage.get_uncached(key)?
+            } else {
+                shard.storage.get(key)?
+            };
+            if value.is_some() {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<S: MutableStorage> ShardedStorage<S> {
+    /// Moves every key to the shard that owns it now, then forgets the previous ring and hands back the
+    /// retired shards. A key already written to its new owner is kept there
+    pub fn rebalance(&mut self) -> io::Result<RebalanceReport<S>> {
+        let mut moved = 0;
+        for idx in 0..self.shards.len() {
+            for (key, value) in self.shards[idx].storage.scan_prefix("")? {
+                let Some(owner) = self.ring.owner(&key) else {
+                    continue;
+                };
+                if owner == idx {
+                    continue;
+                }
+                if self.shards[owner].storage.get_uncached(&key)?.is_none() {
+                    // из нескольких прежних копий берется та, что вернуло бы чтение
+                    let value = self.read(&key, true)?.unwrap_or(value);
+                    self.shards[owner].storage.put(&key, &value)?;
+                }
+                self.shards[idx].storage.remove(&key)?;
+                moved += 1;
+            }
+        }
+
+        self.prev_rings.clear();
+        let (retired, shards): (Vec<_>, Vec<_>) = std::mem::take(&mut self.shards).into_iter().partition(|s| s.is_retired);
+        self.shards = shards;
+        self.ring = Ring::default();
+        self.rebuild_ring();
+
+        Ok(RebalanceReport {
+            moved,
+            retired: retired.into_iter().map(|s| (s.name, s.storage)).collect(),
+        })
+    }
+}
+
+impl<S: Storage> Storage for ShardedStorage<S> {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.read(key, false)
+    }
+
+    fn fiber_yield(&self) {
+        if let Some(shard) = self.shards.first() {
+            shard.storage.fiber_yield()
+        }
+    }
+
+    fn decode_rec_to_rights(&self, src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+        match self.shards.first() {
+            Some(shard) => shard.storage.decode_rec_to_rights(src, result),
+            None => decode_rec_to_rights(src, result),
+        }
+    }
+
+    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+        match self.shards.first() {
+            Some(shard) => shard.storage.decode_rec_to_rightset(src, new_rights),
+            None => decode_rec_to_rightset(src, new_rights),
+        }
+    }
+
+    fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+        match self.shards.first() {
+            Some(shard) => shard.storage.decode_filter(filter_value),
+            None => decode_filter(&filter_value),
+        }
+    }
+
+    fn decode_valid_from(&self, src: &str) -> Option<DateTime<Utc>> {
+        match self.shards.first() {
+            Some(shard) => shard.storage.decode_valid_from(src),
+            None => record_valid_from(src),
+        }
+    }
+
+    fn get_uncached(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.read(key, true)
+    }
+}
+
+impl<S: MutableStorage> MutableStorage for ShardedStorage<S> {
+    fn put(&mut self, key: &str, value: &str) -> io::Result<()> {
+        let owner = self.owners(key)?[0];
+        self.shards[owner].storage.put(key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        // иначе чтение вернет ключ со старого шарда
+        for idx in self.owners(key)?.into_iter().rev() {
+            self.shards[idx].storage.remove(key)?;
+        }
+        Ok(())
+    }
+
+    fn max_value_len(&self) -> Option<usize> {
+        self.shards.iter().filter_map(|s| s.storage.max_value_len()).min()
+    }
+
+    fn emit_invalidation(&mut self, keys: &[String]) {
+        for shard in &mut self.shards {
+            shard.storage.emit_invalidation(keys);
+        }
+    }
+
+    /// The value of a key present on several shards during rebalancing is the one `get` gives
+    fn scan_prefix(&mut self, prefix: &str) -> io::Result<Vec<(String, String)>> {
+        // ключ -> (место шарда в порядке чтения, значение)
+        let mut res: HashMap<String, (usize, String)> = HashMap::new();
+        for idx in 0..self.shards.len() {
+            for (key, value) in self.shards[idx].storage.scan_prefix(prefix)? {
+                let rank = self.owners(&key)?.iter().position(|owner| *owner == idx).unwrap_or(usize::MAX);
+                if res.get(&key).is_none_or(|(best, _)| rank < *best) {
+                    res.insert(key, (rank, value));
+                }
+            }
+        }
+        Ok(res.into_iter().map(|(key, (_, value))| (key, value)).collect())
+    }
+
+    /// Union of the answers of the shards, so their own reverse indexes are used
+    fn keys_referencing(&mut self, subject_id: &str) -> io::Result<Vec<String>> {
+        let mut seen = HashSet::new();
+        let mut res = Vec::new();
+        for shard in &mut self.shards {
+            for key in shard.storage.keys_referencing(subject_id)? {
+                if seen.insert(key.clone()) {
+                    res.push(key);
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manage::revoke_all;
+    use crate::storage::memory::MemoryStorage;
+
+    // Хранилище с обратным индексом: считает обращения к нему
+    #[derive(Default)]
+    struct Indexed {
+        db: MemoryStorage,
+        lookups: usize,
+    }
+
+    impl Storage for Indexed {
+        fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+            self.db.get(key)
+        }
+
+        fn fiber_yield(&self) {}
+    }
+
+    impl MutableStorage for Indexed {
+        fn put(&mut self, key: &str, value: &str) -> io::Result<()> {
+            self.db.put(key, value)
+        }
+
+        fn remove(&mut self, key: &str) -> io::Result<()> {
+            self.db.remove(key)
+        }
+
+        fn scan_prefix(&mut self, prefix: &str) -> io::Result<Vec<(String, String)>> {
+            self.db.scan_prefix(prefix)
+        }
+
+        fn keys_referencing(&mut self, subject_id: &str) -> io::Result<Vec<String>> {
+            self.lookups += 1;
+            self.db.keys_referencing(subject_id)
+        }
+    }
+
+    fn keys() -> Vec<String> {
+        (0..200).map(|n| format!("Pdoc{}", n)).collect()
+    }
+
+    fn sharded(names: &[&str]) -> ShardedStorage<Indexed> {
+        let mut db = ShardedStorage::new(DEFAULT_VNODES);
+        for name in names {
+            db.add_shard(name, Indexed::default());
+        }
+        db
+    }
+
+    #[test]
+    fn keys_are_found_over_every_ring_change() {
+        let mut db = sharded(&["a"]);
+        db.add_shard("b", Indexed::default());
+        for key in keys() {
+            db.put(&key, "u1;2;;").unwrap();
+        }
+        db.add_shard("c", Indexed::default());
+        db.retire_shard("a");
+        for key in keys() {
+            assert_eq!(db.get(&key).unwrap().as_deref(), Some("u1;2;;"), "{}", key);
+        }
+
+        db.remove("Pdoc0").unwrap();
+        assert_eq!(db.get("Pdoc0").unwrap(), None);
+        db.put("Pdoc1", "u2;2;;").unwrap();
+
+        let report = db.rebalance().unwrap();
+        assert!(!db.is_rebalancing());
+        assert_eq!(report.retired.len(), 1);
+        assert_eq!(db.get("Pdoc1").unwrap().as_deref(), Some("u2;2;;"));
+        for name in ["b", "c"] {
+            for (key, _) in db.shard_mut(name).unwrap().scan_prefix("").unwrap() {
+                assert_eq!(db.shard_for(&key), Some(name));
+            }
+        }
+        assert_eq!(db.scan_prefix("P").unwrap().len(), keys().len() - 1);
+    }
+
+    #[test]
+    fn scans_take_the_value_reads_give() {
+        let mut db = sharded(&["a"]);
+        db.put("Pdoc", "u1;2;;").unwrap();
+        db.add_shard("b", Indexed::default());
+        db.add_shard("c", Indexed::default());
+        db.put("Pdoc", "u2;2;;").unwrap();
+        assert_eq!(db.scan_prefix("P").unwrap(), vec![("Pdoc".to_owned(), db.get("Pdoc").unwrap().unwrap())]);
+    }
+
+    #[test]
+    fn reverse_lookups_go_to_every_shard() {
+        let mut db = sharded(&["a", "b", "c"]);
+        for key in keys() {
+            db.put(&key, "u1;2;;u2;2;;").unwrap();
+        }
+        let mut found = db.keys_referencing("u1").unwrap();
+        found.sort();
+        let mut expected = keys();
+        expected.sort();
+        assert_eq!(found, expected);
+        for name in ["a", "b", "c"] {
+            assert_eq!(db.shard(name).unwrap().lookups, 1);
+        }
+
+        revoke_all("u1", &mut db, false).unwrap();
+        assert!(db.keys_referencing("u1").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,94 @@
+use crate::closure::{verify_closure_epoch, SubjectClosure};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::collections::HashMap;
+use std::io;
+
+const SIGNATURE_MARK: &str = "\n~";
+
+/// Signs exported blobs with the currently active key
+pub struct BlobSigner {
+    key_id: String,
+    key: SigningKey,
+}
+
+impl BlobSigner {
+    pub fn new(key_id: &str, secret: &[u8; 32]) -> Self {
+        BlobSigner {
+            key_id: key_id.to_owned(),
+            key: SigningKey::from_bytes(secret),
+        }
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    pub fn verifying_key(&self) -> [u8; 32] {
+        self.key.verifying_key().to_bytes()
+    }
+
+    /// Appends `~key_id:signature` to the blob
+    pub fn sign(&self, blob: &str) -> String {
+        let sig = self.key.sign(blob.as_bytes());
+        format!("{}{}{}:{}", blob, SIGNATURE_MARK, self.key_id, to_hex(&sig.to_bytes()))
+    }
+}
+
+/// Holds every public key that may still have signed blobs in circulation.
+/// On rotation the new key is added first and the old one is removed once its blobs have expired.
+#[derive(Default)]
+pub struct BlobVerifier {
+    keys: HashMap<String, VerifyingKey>,
+}
+
+impl BlobVerifier {
+    pub fn add_key(&mut self, key_id: &str, public: &[u8; 32]) -> io::Result<()> {
+        let key = VerifyingKey::from_bytes(public).map_err(|e| invalid(&format!("bad public key {}: {}", key_id, e)))?;
+        self.keys.insert(key_id.to_owned(), key);
+        Ok(())
+    }
+
+    pub fn remove_key(&mut self, key_id: &str) {
+        self.keys.remove(key_id);
+    }
+
+    /// Checks the signature and returns the original blob
+    pub fn verify<'a>(&self, signed: &'a str) -> io::Result<&'a str> {
+        let pos = signed.rfind(SIGNATURE_MARK).ok_or_else(|| invalid("blob is not signed"))?;
+        let (blob, tail) = (&signed[..pos], &signed[pos + SIGNATURE_MARK.len()..]);
+
+        let (key_id, sig_hex) = tail.split_once(':').ok_or_else(|| invalid("malformed signature"))?;
+        let key = self.keys.get(key_id).ok_or_else(|| invalid(&format!("unknown signing key {}", key_id)))?;
+
+        let sig_bytes: [u8; 64] = from_hex(sig_hex).and_then(|b| b.try_into().ok()).ok_or_else(|| invalid("malformed signature"))?;
+        key.verify(blob.as_bytes(), &Signature::from_bytes(&sig_bytes)).map_err(|_| invalid("signature mismatch"))?;
+
+        Ok(blob)
+    }
+
+    /// Verifies a signed closure and rejects it if the revocation epoch has advanced since export
+    pub fn verify_closure(&self, signed: &str, current_epoch: u64) -> io::Result<SubjectClosure> {
+        let closure = SubjectClosure::from_blob(self.verify(signed)?)?;
+
+        if !verify_closure_epoch(&closure, current_epoch) {
+            return Err(invalid(&format!("closure epoch {} is older than {}", closure.epoch, current_epoch)));
+        }
+
+        Ok(closure)
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(src: &str) -> Option<Vec<u8>> {
+    if !src.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..src.len()).step_by(2).map(|i| u8::from_str_radix(src.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
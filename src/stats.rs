@@ -0,0 +1,324 @@
+//! Decision counters.
+//!
+//! Threads keep their own `AzStats` and fold them into a shared `StatsAggregator` from time to time,
+//! so a call never contends on a common lock. The aggregator also keeps exemplars: the correlation id of
+//! the latest decision of each outcome made with one, to go from a counter to a request that moved it.
+//!
+//! With the `bench` feature the engine also times the phases of every decision (`PhaseTimes`),
+//! so optimization work can start from where a deployment actually spends its time.
+
+#[cfg(feature = "bench")]
+use crate::common::Storage;
+use crate::decision::Decision;
+#[cfg(feature = "bench")]
+use crate::{ACLRecord, ACLRecordSet, ACLRecordVec};
+#[cfg(feature = "bench")]
+use chrono::{DateTime, Utc};
+#[cfg(all(feature = "serde", feature = "bench"))]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "bench")]
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::{Add, AddAssign};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+#[cfg(feature = "bench")]
+use std::time::Instant;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AzStats {
+    pub calls: u64,
+    /// All requested bits granted
+    pub granted: u64,
+    /// Some, but not all requested bits granted
+    pub partial: u64,
+    pub denied: u64,
+    pub errors: u64,
+    pub subject_groups_truncated: u64,
+    /// Deepest traversals seen, for tuning `AzConfig::max_subject_depth` and `max_object_depth`
+    pub max_subject_depth: u8,
+    pub max_object_depth: u8,
+    /// Time spent in each phase, summed over the decisions
+    #[cfg(feature = "bench")]
+    pub decode_ns: u64,
+    #[cfg(feature = "bench")]
+    pub traversal_ns: u64,
+    #[cfg(feature = "bench")]
+    pub hash_ns: u64,
+    #[cfg(feature = "bench")]
+    pub storage_ns: u64,
+}
+
+/// Time one decision spent in each phase
+#[cfg(feature = "bench")]
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PhaseTimes {
+    /// Decoding records (`Storage::decode_*`)
+    pub decode_ns: u64,
+    /// Walking the groups, everything but the other phases
+    pub traversal_ns: u64,
+    /// Provenance hashing of the values read and of the record
+    pub hash_ns: u64,
+    /// `Storage::get`
+    pub storage_ns: u64,
+}
+
+// Исход решения: 0 - выданы все запрошенные биты, 1 - часть, 2 - отказ, 3 - ошибка
+fn outcome(decision: &Decision, result: &io::Result<u8>) -> usize {
+    match result {
+        Ok(r) if *r & decision.requested == decision.requested => 0,
+        Ok(r) if *r & decision.requested != 0 => 1,
+        Ok(_) => 2,
+        Err(_) => 3,
+    }
+}
+
+impl AzStats {
+    /// Counts one decision
+    pub fn record(&mut self, decision: &Decision, result: &io::Result<u8>) {
+        self.calls += 1;
+        match outcome(decision, result) {
+            0 => self.granted += 1,
+            1 => self.partial += 1,
+            2 => self.denied += 1,
+            _ => self.errors += 1,
+        }
+        if decision.subject_groups_truncated {
+            self.subject_groups_truncated += 1;
+        }
+        self.max_subject_depth = self.max_subject_depth.max(decision.max_subject_depth);
+        self.max_object_depth = self.max_object_depth.max(decision.max_object_depth);
+        #[cfg(feature = "bench")]
+        {
+            self.decode_ns += decision.phases.decode_ns;
+            self.traversal_ns += decision.phases.traversal_ns;
+            self.hash_ns += decision.phases.hash_ns;
+            self.storage_ns += decision.phases.storage_ns;
+        }
+    }
+
+    pub fn merge(&mut self, other: &AzStats) {
+        self.calls += other.calls;
+        self.granted += other.granted;
+        self.partial += other.partial;
+        self.denied += other.denied;
+        self.errors += other.errors;
+        self.subject_groups_truncated += other.subject_groups_truncated;
+        self.max_subject_depth = self.max_subject_depth.max(other.max_subject_depth);
+        self.max_object_depth = self.max_object_depth.max(other.max_object_depth);
+        #[cfg(feature = "bench")]
+        {
+            self.decode_ns += other.decode_ns;
+            self.traversal_ns += other.traversal_ns;
+            self.hash_ns += other.hash_ns;
+            self.storage_ns += other.storage_ns;
+        }
+    }
+}
+
+impl Add for AzStats {
+    type Output = AzStats;
+
+    fn add(mut self, other: AzStats) -> AzStats {
+        self.merge(&other);
+        self
+    }
+}
+
+impl AddAssign for AzStats {
+    fn add_assign(&mut self, other: AzStats) {
+        self.merge(&other);
+    }
+}
+
+/// Correlation ids of the latest decisions of each outcome, as counted in `AzStats`
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct Exemplars {
+    pub granted: Option<String>,
+    pub partial: Option<String>,
+    pub denied: Option<String>,
+    pub errors: Option<String>,
+}
+
+// Образцы шарда по исходам, с порядковым номером записи
+type ShardExemplars = [Option<(u64, String)>; 4];
+
+/// Shared sink for per-thread stats; every thread maps to one shard
+pub struct StatsAggregator {
+    shards: Vec<Mutex<AzStats>>,
+    exemplars: Vec<Mutex<ShardExemplars>>,
+    exemplar_seq: AtomicU64,
+}
+
+impl Default for StatsAggregator {
+    fn default() -> Self {
+        StatsAggregator::new(thread::available_parallelism().map(|n| n.get()).unwrap_or(1))
+    }
+}
+
+impl StatsAggregator {
+    pub fn new(shards: usize) -> Self {
+        StatsAggregator {
+            shards: (0..shards.max(1)).map(|_| Mutex::new(AzStats::default())).collect(),
+            exemplars: (0..shards.max(1)).map(|_| Mutex::new(ShardExemplars::default())).collect(),
+            exemplar_seq: AtomicU64::new(0),
+        }
+    }
+
+    fn shard_index(&self) -> usize {
+        let mut h = DefaultHasher::new();
+        thread::current().id().hash(&mut h);
+        h.finish() as usize % self.shards.len()
+    }
+
+    fn shard(&self) -> &Mutex<AzStats> {
+        &self.shards[self.shard_index()]
+    }
+
+    /// Folds the thread-local stats into the aggregator and resets them
+    pub fn flush(&self, local: &mut AzStats) {
+        self.shard().lock().unwrap_or_else(|e| e.into_inner()).merge(local);
+        *local = AzStats::default();
+    }
+
+    /// Counts one decision directly, for callers without thread-local stats
+    pub fn record(&self, decision: &Decision, result: &io::Result<u8>) {
+        self.shard().lock().unwrap_or_else(|e| e.into_inner()).record(decision, result);
+    }
+
+    /// Same as `record`, `correlation_id` becomes the exemplar of the decision's outcome
+    pub fn record_correlated(&self, decision: &Decision, result: &io::Result<u8>, correlation_id: Option<&str>) {
+        self.record(decision, result);
+        if let Some(cid) = correlation_id {
+            let seq = self.exemplar_seq.fetch_add(1, Ordering::Relaxed);
+            self.exemplars[self.shard_index()].lock().unwrap_or_else(|e| e.into_inner())[outcome(decision, result)] = Some((seq, cid.to_owned()));
+        }
+    }
+
+    /// Latest exemplar of each outcome over all shards
+    pub fn exemplars(&self) -> Exemplars {
+        let mut latest = ShardExemplars::default();
+        for shard in &self.exemplars {
+            for (idx, exemplar) in shard.lock().unwrap_or_else(|e| e.into_inner()).iter().enumerate() {
+                if exemplar.as_ref().is_some_and(|(seq, _)| latest[idx].as_ref().is_none_or(|(last, _)| seq > last)) {
+                    latest[idx] = exemplar.clone();
+                }
+            }
+        }
+        let [granted, partial, denied, errors] = latest.map(|exemplar| exemplar.map(|(_, cid)| cid));
+        Exemplars {
+            granted,
+            partial,
+            denied,
+            errors,
+        }
+    }
+
+    /// Sum over all shards
+    pub fn snapshot(&self) -> AzStats {
+        self.shards.iter().fold(AzStats::default(), |acc, s| acc + *s.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Returns the sum over all shards and resets them, for interval reporting
+    pub fn take(&self) -> AzStats {
+        self.shards.iter().fold(AzStats::default(), |acc, s| acc + std::mem::take(&mut *s.lock().unwrap_or_else(|e| e.into_inner())))
+    }
+}
+
+/// Times reads and decoding of the wrapped storage
+#[cfg(feature = "bench")]
+pub(crate) struct TimingStorage<'a> {
+    pub(crate) inner: &'a mut dyn Storage,
+    pub(crate) storage_ns: u64,
+    pub(crate) decode_ns: Cell<u64>,
+}
+
+#[cfg(feature = "bench")]
+impl<'a> TimingStorage<'a> {
+    pub(crate) fn new(inner: &'a mut dyn Storage) -> Self {
+        TimingStorage {
+            inner,
+            storage_ns: 0,
+            decode_ns: Cell::new(0),
+        }
+    }
+
+    fn timed_decode<T>(&self, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let res = f();
+        self.decode_ns.set(self.decode_ns.get() + start.elapsed().as_nanos() as u64);
+        res
+    }
+}
+
+#[cfg(feature = "bench")]
+impl Storage for TimingStorage<'_> {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        let start = Instant::now();
+        let res = self.inner.get(key);
+        self.storage_ns += start.elapsed().as_nanos() as u64;
+        res
+    }
+
+    fn fiber_yield(&self) {
+        self.inner.fiber_yield()
+    }
+
+    fn decode_rec_to_rights(&self, src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+        self.timed_decode(|| self.inner.decode_rec_to_rights(src, result))
+    }
+
+    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+        self.timed_decode(|| self.inner.decode_rec_to_rightset(src, new_rights))
+    }
+
+    fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+        self.timed_decode(|| self.inner.decode_filter(filter_value))
+    }
+
+    fn decode_valid_from(&self, src: &str) -> Option<DateTime<Utc>> {
+        self.timed_decode(|| self.inner.decode_valid_from(src))
+    }
+
+    fn epoch(&self) -> Option<u64> {
+        self.inner.epoch()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::TraceBuffers;
+    use crate::config::AzConfig;
+    use crate::engine::AzEngine;
+    use crate::storage::memory::MemoryStorage;
+    use std::sync::Arc;
+
+    #[test]
+    fn correlation_ids_are_exemplars() {
+        let stats = Arc::new(StatsAggregator::new(4));
+        let mut engine = AzEngine::new(AzConfig::default());
+        engine.set_stats_aggregator(stats.clone());
+        let mut db = MemoryStorage::new();
+        db.add_permission("doc", "u1", 2).unwrap();
+
+        let mut buf = TraceBuffers::default();
+        for (user, cid) in [("u1", Some("req-1")), ("u2", Some("req-2")), ("u1", None), ("u1", Some("req-3"))] {
+            engine.authorize_correlated("doc", user, 2, cid, &mut db, &mut buf.trace(false, false, false)).unwrap();
+        }
+
+        assert_eq!(stats.snapshot().calls, 4);
+        assert_eq!(
+            stats.exemplars(),
+            Exemplars {
+                granted: Some("req-3".to_owned()),
+                denied: Some("req-2".to_owned()),
+                ..Exemplars::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,6 @@
+//! Storage backends shipped with the crate; those over external stores are behind a feature of the same name
+#[cfg(feature = "lmdb")]
+pub mod lmdb;
+pub mod memory;
+#[cfg(feature = "redis")]
+pub mod redis;
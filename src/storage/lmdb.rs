@@ -0,0 +1,164 @@
+//! `Storage` over the LMDB environment the Veda ACL indexer writes: P-, M- and F-prefixed keys with the
+//! records as string values, in the unnamed database unless another one is set.
+//!
+//! Reads share one read transaction, so the keys of an authorization come from one snapshot and the
+//! reader slot is not taken and released on every key. The transaction is recycled after a number of reads
+//! or an age, whichever comes first, so the writer can reuse freed pages and new grants become visible.
+//! On a read error (the writer grew the map, the environment was replaced) the environment is reopened
+//! and the read is tried once more. Transactions are not bound to threads (`MDB_NOTLS`), so a thread may
+//! hold several storages and a storage may move between threads.
+
+use crate::common::Storage;
+use heed::types::Str;
+use heed::{Database, Env, EnvFlags, EnvOpenOptions, RoTxn};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Reads a read transaction serves before it is recycled
+pub const DEFAULT_TXN_MAX_READS: usize = 1000;
+/// Age after which a read transaction is recycled
+pub const DEFAULT_TXN_MAX_AGE: Duration = Duration::from_millis(500);
+
+const MAX_DBS: u32 = 16;
+
+struct ReadTxn {
+    txn: RoTxn<'static>,
+    opened_at: Instant,
+    reads: usize,
+}
+
+struct Connection {
+    // поля удаляются по порядку: транзакция раньше окружения
+    txn: Option<ReadTxn>,
+    // None, пока индексатор не создал базу
+    db: Option<Database<Str, Str>>,
+    env: Env,
+}
+
+pub struct LmdbStorage {
+    path: PathBuf,
+    db_name: Option<String>,
+    txn_max_reads: usize,
+    txn_max_age: Duration,
+    conn: Option<Connection>,
+}
+
+impl LmdbStorage {
+    /// The environment at `path` is opened on the first read
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        LmdbStorage {
+            path: path.into(),
+            db_name: None,
+            txn_max_reads: DEFAULT_TXN_MAX_READS,
+            txn_max_age: DEFAULT_TXN_MAX_AGE,
+            conn: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads from the named database instead of the unnamed one
+    pub fn set_database(&mut self, name: &str) {
+        self.db_name = Some(name.to_owned());
+        self.conn = None;
+    }
+
+    pub fn set_txn_limits(&mut self, max_reads: usize, max_age: Duration) {
+        self.txn_max_reads = max_reads.max(1);
+        self.txn_max_age = max_age;
+    }
+
+    /// Ends the current read transaction: the next read sees everything committed by then
+    pub fn refresh(&mut self) {
+        if let Some(conn) = &mut self.conn {
+            conn.txn = None;
+        }
+    }
+
+    /// Closes the environment; the next read opens it again
+    pub fn close(&mut self) {
+        self.conn = None;
+    }
+
+    fn connect(&self) -> heed::Result<Connection> {
+        let mut options = EnvOpenOptions::new();
+        options.max_dbs(MAX_DBS);
+        // SAFETY: окружение открывается только через heed, который не дает открыть его в процессе дважды;
+        // только чтение: отсутствующее окружение не создается
+        let env = match unsafe { options.flags(EnvFlags::READ_ONLY).open(&self.path) } {
+            Ok(env) => env,
+            // окружение уже открыто в процессе, например индексатором, с другими настройками
+            Err(heed::Error::BadOpenOptions {
+                env,
+                ..
+            }) => env,
+            Err(e) => return Err(e),
+        };
+        if let Err(e) = env.clear_stale_readers() {
+            eprintln!("WARN! lmdb {}: fail clear stale readers, err={:?}", self.path.display(), e);
+        }
+
+        Ok(Connection {
+            txn: None,
+            db: None,
+            env,
+        })
+    }
+
+    fn read(&mut self, key: &str) -> heed::Result<Option<String>> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => self.conn.insert(self.connect()?),
+        };
+
+        if conn.txn.as_ref().is_some_and(|t| t.reads >= self.txn_max_reads || t.opened_at.elapsed() >= self.txn_max_age) {
+            conn.txn = None;
+        }
+
+        if conn.db.is_none() {
+            // дескриптор базы, открытый в транзакции чтения, доступен другим транзакциям после ее фиксации
+            let rtxn = conn.env.read_txn()?;
+            conn.db = conn.env.open_database(&rtxn, self.db_name.as_deref())?;
+            rtxn.commit()?;
+        }
+        let Some(db) = conn.db else {
+            return Ok(None);
+        };
+
+        let txn = match &mut conn.txn {
+            Some(txn) => txn,
+            None => conn.txn.insert(ReadTxn {
+                txn: conn.env.clone().static_read_txn()?,
+                opened_at: Instant::now(),
+                reads: 0,
+            }),
+        };
+        txn.reads += 1;
+
+        Ok(db.get(&txn.txn, key)?.map(|v| v.to_owned()))
+    }
+}
+
+impl Storage for LmdbStorage {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        match self.read(key) {
+            Ok(value) => Ok(value),
+            Err(e) => {
+                eprintln!("WARN! lmdb {}: fail read {}, reconnect, err={:?}", self.path.display(), key, e);
+                self.conn = None;
+                self.read(key).map_err(|e| io::Error::other(format!("lmdb {}: fail read {}, err={}", self.path.display(), key, e)))
+            },
+        }
+    }
+
+    fn fiber_yield(&self) {}
+
+    // Чтение мимо общей транзакции, чтобы увидеть последние записи
+    fn get_uncached(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.refresh();
+        self.get(key)
+    }
+}
@@ -0,0 +1,156 @@
+//! `Storage` holding the records in memory, for tests, examples and small embedded setups.
+//!
+//! Grants are added and removed the way the indexer applies individuals (`manage::apply_change`): each
+//! added right is one more reference, and a removal drops one. Every write bumps the epoch, so a shared
+//! `MembershipCache` stays correct in front of it.
+
+use crate::common::{Storage, FILTER_PREFIX, MEMBERSHIP_PREFIX, PERMISSION_PREFIX};
+use crate::keys::KeySchema;
+use crate::manage::{apply_change, revoke_all_with_schema, write_record, AclChange, MutableStorage, RevokeReport};
+use crate::ACLRecord;
+use std::collections::BTreeMap;
+use std::io;
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    // упорядочены для scan_prefix
+    records: BTreeMap<String, String>,
+    schema: KeySchema,
+    epoch: u64,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+
+    /// Keys are written in the form `schema` gives them; use the `key_schema` of the config that reads them
+    pub fn with_key_schema(schema: KeySchema) -> Self {
+        MemoryStorage {
+            schema,
+            ..MemoryStorage::default()
+        }
+    }
+
+    /// Grants `access` to `subject_id` on `object_id`, an object group or a resource
+    pub fn add_permission(&mut self, object_id: &str, subject_id: &str, access: u8) -> io::Result<()> {
+        self.change_permission("", object_id, subject_id, access, false)
+    }
+
+    /// Grants `access` to `subject_id` on `object_id` for resources filtered with `filter_id`
+    pub fn add_filtered_permission(&mut self, filter_id: &str, object_id: &str, subject_id: &str, access: u8) -> io::Result<()> {
+        self.change_permission(filter_id, object_id, subject_id, access, false)
+    }
+
+    pub fn remove_permission(&mut self, object_id: &str, subject_id: &str, access: u8) -> io::Result<()> {
+        self.change_permission("", object_id, subject_id, access, true)
+    }
+
+    pub fn remove_filtered_permission(&mut self, filter_id: &str, object_id: &str, subject_id: &str, access: u8) -> io::Result<()> {
+        self.change_permission(filter_id, object_id, subject_id, access, true)
+    }
+
+    /// Makes `member_id`, a user, a resource or a group, a member of `group_id` passing `access`
+    pub fn add_membership(&mut self, member_id: &str, group_id: &str, access: u8) -> io::Result<()> {
+        self.add_marked_membership(member_id, group_id, access, 0 as char)
+    }
+
+    /// Same as `add_membership` with a marker, `M_IS_EXCLUSIVE` or `M_IGNORE_EXCLUSIVE`
+    pub fn add_marked_membership(&mut self, member_id: &str, group_id: &str, access: u8, marker: char) -> io::Result<()> {
+        let mut group = ACLRecord::new_with_access(&self.schema.encode_id(group_id), access);
+        group.marker = marker;
+        self.apply(self.schema.key(MEMBERSHIP_PREFIX, member_id), group, false)
+    }
+
+    pub fn remove_membership(&mut self, member_id: &str, group_id: &str, access: u8) -> io::Result<()> {
+        let group = ACLRecord::new_with_access(&self.schema.encode_id(group_id), access);
+        self.apply(self.schema.key(MEMBERSHIP_PREFIX, member_id), group, true)
+    }
+
+    /// Sets the filter of `object_id`, replacing the previous one
+    pub fn add_filter(&mut self, object_id: &str, filter_id: &str, access: u8) -> io::Result<()> {
+        let filter = ACLRecord::new_with_access(&self.schema.encode_id(filter_id), access);
+        self.apply(self.schema.key(FILTER_PREFIX, object_id), filter, false)
+    }
+
+    pub fn remove_filter(&mut self, object_id: &str) -> io::Result<()> {
+        let key = self.schema.key(FILTER_PREFIX, object_id);
+        write_record(&key, None, self)
+    }
+
+    /// Drops the records of `id`, its memberships and the grants given to it
+    pub fn remove_id(&mut self, id: &str) -> io::Result<RevokeReport> {
+        let report = revoke_all_with_schema(id, self.schema, self, false)?;
+        for prefix in [PERMISSION_PREFIX, FILTER_PREFIX] {
+            let key = self.schema.key(prefix, id);
+            write_record(&key, None, self)?;
+        }
+        Ok(report)
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.epoch += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Records in key order, with the key prefix included
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.records.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    fn change_permission(&mut self, filter_id: &str, object_id: &str, subject_id: &str, access: u8, is_remove: bool) -> io::Result<()> {
+        let suffix = self.schema.permission_suffix(&self.schema.encode_id(filter_id), &self.schema.encode_id(object_id));
+        let subject = ACLRecord::new_with_access(&self.schema.encode_id(subject_id), access);
+        self.apply(PERMISSION_PREFIX.to_owned() + &suffix, subject, is_remove)
+    }
+
+    fn apply(&mut self, key: String, entry: ACLRecord, is_remove: bool) -> io::Result<()> {
+        apply_change(
+            &AclChange {
+                key,
+                entries: vec![entry],
+                is_remove,
+            },
+            self,
+        )
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        Ok(self.records.get(key).cloned())
+    }
+
+    fn fiber_yield(&self) {}
+
+    fn epoch(&self) -> Option<u64> {
+        Some(self.epoch)
+    }
+}
+
+impl MutableStorage for MemoryStorage {
+    fn put(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.records.insert(key.to_owned(), value.to_owned());
+        self.epoch += 1;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        if self.records.remove(key).is_some() {
+            self.epoch += 1;
+        }
+        Ok(())
+    }
+
+    fn scan_prefix(&mut self, prefix: &str) -> io::Result<Vec<(String, String)>> {
+        Ok(self.records.range(prefix.to_owned()..).take_while(|(k, _)| k.starts_with(prefix)).map(|(k, v)| (k.clone(), v.clone())).collect())
+    }
+}
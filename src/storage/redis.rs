@@ -0,0 +1,175 @@
+//! `Storage` over a Redis copy of the ACL index: P-, M- and F-prefixed keys with the records as string
+//! values, optionally under a common key prefix.
+//!
+//! `prefetch` fetches a set of keys in one pipelined round trip, and pattern permissions are probed the
+//! same way. With the client cache on, fetched values are kept locally for its ttl, so grants changed in
+//! Redis are seen after at most that long; with it off, a prefetched value serves one read. A connection
+//! that failed is opened again and the request is repeated once.
+
+use crate::common::{Storage, PATTERN_PREFIX};
+use crate::patterns::pattern_candidates;
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Timeout of connecting, reading and writing
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Entries of the client cache
+pub const DEFAULT_CLIENT_CACHE_CAPACITY: usize = 10_000;
+
+pub struct RedisStorage {
+    client: ::redis::Client,
+    conn: Option<::redis::Connection>,
+    key_prefix: String,
+    cache_ttl: Option<Duration>,
+    cache_capacity: usize,
+    // полученные значения, отсутствующие ключи тоже
+    local: HashMap<String, (Option<String>, Instant)>,
+}
+
+impl RedisStorage {
+    /// `url` as accepted by the redis crate, e.g. `redis://127.0.0.1:6379/0`; connects on the first read
+    pub fn open(url: &str) -> io::Result<Self> {
+        let client = ::redis::Client::open(url).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("redis {}: {}", url, e)))?;
+        Ok(RedisStorage {
+            client,
+            conn: None,
+            key_prefix: String::new(),
+            cache_ttl: None,
+            cache_capacity: DEFAULT_CLIENT_CACHE_CAPACITY,
+            local: HashMap::new(),
+        })
+    }
+
+    /// Namespace of the ACL keys in a shared Redis
+    pub fn set_key_prefix(&mut self, prefix: &str) {
+        self.key_prefix = prefix.to_owned();
+        self.local.clear();
+    }
+
+    /// Keeps fetched values for `ttl`, up to `capacity` of them; `None` turns the cache off
+    pub fn set_client_cache(&mut self, ttl: Option<Duration>, capacity: usize) {
+        self.cache_ttl = ttl;
+        self.cache_capacity = capacity.max(1);
+        self.local.clear();
+    }
+
+    pub fn clear_client_cache(&mut self) {
+        self.local.clear();
+    }
+
+    /// Fetches `keys` in one round trip; the values are served from memory by the following reads
+    pub fn prefetch(&mut self, keys: &[&str]) -> io::Result<()> {
+        let missing: Vec<&str> = keys.iter().copied().filter(|key| !self.is_local(key)).collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let values = self.fetch(&missing)?;
+        if self.local.len() + missing.len() > self.cache_capacity {
+            self.evict();
+        }
+        let now = Instant::now();
+        for (key, value) in missing.into_iter().zip(values) {
+            self.local.insert(key.to_owned(), (value, now));
+        }
+        Ok(())
+    }
+
+    fn is_local(&self, key: &str) -> bool {
+        self.local.get(key).is_some_and(|(_, at)| self.cache_ttl.is_none_or(|ttl| at.elapsed() < ttl))
+    }
+
+    // Сначала удаляются устаревшие значения, если места все равно нет - все
+    fn evict(&mut self) {
+        if let Some(ttl) = self.cache_ttl {
+            self.local.retain(|_, (_, at)| at.elapsed() < ttl);
+        }
+        if self.local.len() >= self.cache_capacity {
+            self.local.clear();
+        }
+    }
+
+    fn take_local(&mut self, key: &str) -> Option<Option<String>> {
+        match self.cache_ttl {
+            Some(ttl) => match self.local.get(key) {
+                Some((value, at)) if at.elapsed() < ttl => Some(value.clone()),
+                Some(_) => {
+                    self.local.remove(key);
+                    None
+                },
+                None => None,
+            },
+            // без кэша значение из prefetch используется один раз
+            None => self.local.remove(key).map(|(value, _)| value),
+        }
+    }
+
+    fn connect(&self) -> ::redis::RedisResult<::redis::Connection> {
+        let conn = self.client.get_connection_with_timeout(DEFAULT_TIMEOUT)?;
+        conn.set_read_timeout(Some(DEFAULT_TIMEOUT))?;
+        conn.set_write_timeout(Some(DEFAULT_TIMEOUT))?;
+        Ok(conn)
+    }
+
+    fn query(&mut self, keys: &[&str]) -> ::redis::RedisResult<Vec<Option<String>>> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => self.conn.insert(self.connect()?),
+        };
+
+        let mut pipe = ::redis::pipe();
+        for key in keys {
+            pipe.cmd("GET").arg(self.key_prefix.clone() + key);
+        }
+        pipe.query(conn)
+    }
+
+    fn fetch(&mut self, keys: &[&str]) -> io::Result<Vec<Option<String>>> {
+        match self.query(keys) {
+            Ok(values) => Ok(values),
+            Err(e) => {
+                eprintln!("WARN! redis: fail read {:?}, reconnect, err={}", keys.first(), e);
+                self.conn = None;
+                self.query(keys).map_err(|e| io::Error::other(format!("redis: fail read {:?}, err={}", keys.first(), e)))
+            },
+        }
+    }
+}
+
+impl Storage for RedisStorage {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        if let Some(value) = self.take_local(key) {
+            return Ok(value);
+        }
+
+        let value = self.fetch(&[key])?.pop().flatten();
+        if self.cache_ttl.is_some() {
+            if self.local.len() >= self.cache_capacity {
+                self.evict();
+            }
+            self.local.insert(key.to_owned(), (value.clone(), Instant::now()));
+        }
+        Ok(value)
+    }
+
+    fn fiber_yield(&self) {}
+
+    // Все шаблоны запрашиваются одним конвейером
+    fn get_pattern_permissions(&mut self, uri: &str) -> io::Result<Vec<(String, String)>> {
+        let keys: Vec<String> = pattern_candidates(uri).into_iter().map(|pattern| PATTERN_PREFIX.to_owned() + &pattern).collect();
+        self.prefetch(&keys.iter().map(|key| key.as_str()).collect::<Vec<_>>())?;
+
+        let mut res = Vec::new();
+        for key in keys {
+            if let Some(value) = self.get(&key)? {
+                res.push((key, value));
+            }
+        }
+        Ok(res)
+    }
+
+    fn get_uncached(&mut self, key: &str) -> io::Result<Option<String>> {
+        Ok(self.fetch(&[key])?.pop().flatten())
+    }
+}
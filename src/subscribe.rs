@@ -0,0 +1,215 @@
+//! Notifications about changes of a user's effective rights on a resource, for live permission updates in UIs.
+//!
+//! Subscribing evaluates the rights once and remembers every key the evaluation read, absent keys included:
+//! only a change to one of them can change the answer. The changes themselves come from the caller: pass the
+//! keys written by each batch to `Subscriptions::on_keys_changed`, e.g. from `MutableStorage::emit_invalidation`
+//! of the store wrapper or from the indexer's output. Affected subscriptions are evaluated again, with reads
+//! bypassing caches, and their callback fires when the mask differs from the one last reported.
+
+use crate::common::Storage;
+use crate::engine::AzEngine;
+use crate::presets::MANAGER;
+use crate::{ACLRecord, ACLRecordSet, ACLRecordVec};
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+pub type SubscriptionId = u64;
+
+/// Rights of `user_id` on `id` went from `before` to `after`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RightsChange {
+    pub subscription: SubscriptionId,
+    pub user_id: String,
+    pub id: String,
+    pub before: u8,
+    pub after: u8,
+}
+
+pub type RightsCallback = Arc<dyn Fn(&RightsChange) + Send + Sync>;
+
+struct Subscription {
+    user_id: String,
+    id: String,
+    rights: u8,
+    keys: HashSet<String>,
+    callback: RightsCallback,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: SubscriptionId,
+    subscriptions: HashMap<SubscriptionId, Subscription>,
+    // ключ хранилища -> подписки, чей ответ от него зависит
+    by_key: HashMap<String, HashSet<SubscriptionId>>,
+}
+
+impl Registry {
+    fn index(&mut self, sid: SubscriptionId, keys: &HashSet<String>) {
+        for key in keys {
+            self.by_key.entry(key.clone()).or_default().insert(sid);
+        }
+    }
+
+    fn unindex(&mut self, sid: SubscriptionId, keys: &HashSet<String>) {
+        for key in keys {
+            if let Some(ids) = self.by_key.get_mut(key) {
+                ids.remove(&sid);
+                if ids.is_empty() {
+                    self.by_key.remove(key);
+                }
+            }
+        }
+    }
+}
+
+/// Registry of subscriptions, evaluated with the configuration and hooks of `engine`
+pub struct Subscriptions {
+    engine: AzEngine,
+    registry: Mutex<Registry>,
+}
+
+impl Subscriptions {
+    pub fn new(engine: AzEngine) -> Self {
+        Subscriptions {
+            engine,
+            registry: Mutex::default(),
+        }
+    }
+
+    /// Registers `callback` for changes of the rights of `user_id` on `id`; returns the subscription and
+    /// the current rights
+    pub fn subscribe(&self, user_id: &str, id: &str, callback: RightsCallback, db: &mut dyn Storage) -> io::Result<(SubscriptionId, u8)> {
+        let (rights, keys) = self.evaluate(user_id, id, db)?;
+
+        let mut registry = self.registry.lock().unwrap_or_else(|e| e.into_inner());
+        registry.next_id += 1;
+        let sid = registry.next_id;
+        registry.index(sid, &keys);
+        registry.subscriptions.insert(
+            sid,
+            Subscription {
+                user_id: user_id.to_owned(),
+                id: id.to_owned(),
+                rights,
+                keys,
+                callback,
+            },
+        );
+
+        Ok((sid, rights))
+    }
+
+    /// False if there was no such subscription
+    pub fn unsubscribe(&self, sid: SubscriptionId) -> bool {
+        let mut registry = self.registry.lock().unwrap_or_else(|e| e.into_inner());
+        match registry.subscriptions.remove(&sid) {
+            Some(sub) => {
+                registry.unindex(sid, &sub.keys);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.registry.lock().unwrap_or_else(|e| e.into_inner()).subscriptions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Re-evaluates the subscriptions depending on `keys` and calls back those whose rights changed;
+    /// returns the number of callbacks made. Callbacks run after the registry is unlocked and may subscribe
+    pub fn on_keys_changed(&self, keys: &[String], db: &mut dyn Storage) -> io::Result<usize> {
+        let affected: Vec<(SubscriptionId, String, String)> = {
+            let registry = self.registry.lock().unwrap_or_else(|e| e.into_inner());
+            let mut sids: Vec<SubscriptionId> = keys.iter().filter_map(|key| registry.by_key.get(key)).flatten().copied().collect();
+            sids.sort_unstable();
+            sids.dedup();
+            sids.into_iter().filter_map(|sid| registry.subscriptions.get(&sid).map(|sub| (sid, sub.user_id.clone(), sub.id.clone()))).collect()
+        };
+
+        let mut notify = Vec::new();
+        for (sid, user_id, id) in affected {
+            let (rights, keys) = self.evaluate(&user_id, &id, db)?;
+
+            let mut registry = self.registry.lock().unwrap_or_else(|e| e.into_inner());
+            // подписка могла быть снята, пока шла проверка
+            let Some(sub) = registry.subscriptions.get_mut(&sid) else {
+                continue;
+            };
+            let before = sub.rights;
+            sub.rights = rights;
+            let prev_keys = std::mem::replace(&mut sub.keys, keys.clone());
+            if before != rights {
+                notify.push((
+                    sub.callback.clone(),
+                    RightsChange {
+                        subscription: sid,
+                        user_id,
+                        id,
+                        before,
+                        after: rights,
+                    },
+                ));
+            }
+            registry.unindex(sid, &prev_keys);
+            registry.index(sid, &keys);
+        }
+
+        for (callback, change) in &notify {
+            callback(change);
+        }
+        Ok(notify.len())
+    }
+
+    fn evaluate(&self, user_id: &str, id: &str, db: &mut dyn Storage) -> io::Result<(u8, HashSet<String>)> {
+        let mut ddb = DependencyStorage {
+            inner: db,
+            keys: HashSet::new(),
+        };
+        let rights = self.engine.authorize_dry(id, user_id, MANAGER, &mut ddb)?;
+        Ok((rights, ddb.keys))
+    }
+}
+
+// Запоминает все запрошенные ключи, в том числе отсутствующие в хранилище
+struct DependencyStorage<'a> {
+    inner: &'a mut dyn Storage,
+    keys: HashSet<String>,
+}
+
+impl Storage for DependencyStorage<'_> {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.keys.insert(key.to_owned());
+        self.inner.get(key)
+    }
+
+    fn fiber_yield(&self) {
+        self.inner.fiber_yield()
+    }
+
+    fn decode_rec_to_rights(&self, src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+        self.inner.decode_rec_to_rights(src, result)
+    }
+
+    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+        self.inner.decode_rec_to_rightset(src, new_rights)
+    }
+
+    fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+        self.inner.decode_filter(filter_value)
+    }
+
+    fn decode_valid_from(&self, src: &str) -> Option<DateTime<Utc>> {
+        self.inner.decode_valid_from(src)
+    }
+
+    fn get_uncached(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.keys.insert(key.to_owned());
+        self.inner.get_uncached(key)
+    }
+}
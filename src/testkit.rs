@@ -0,0 +1,2 @@
+//! Support for tests of the crate and of the services built on it
+pub mod fixture;
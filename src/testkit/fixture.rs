@@ -0,0 +1,149 @@
+//! Canonical ACL graphs shipped with the crate, so a report like "fixture nested-03, user u2, doc d7"
+//! reproduces the same way in every project.
+//!
+//! A fixture file holds one record per line, `key value` with the key prefix included (`Mu1 g1;15;;`),
+//! and expected results as `? user_id;resource_id;access;expected` in the corpus syntax of `integration`.
+//! Lines starting with `#` are comments, the first of them describes the fixture.
+
+use crate::common::Storage;
+use crate::config::AzConfig;
+use crate::integration::{parse_corpus, run_corpus_with_config, CorpusCheck, IntegrationReport};
+use std::collections::HashMap;
+use std::io;
+
+static FIXTURES: [(&str, &str); 7] = [
+    ("flat-01", include_str!("fixtures/flat-01.acl")),
+    ("nested-03", include_str!("fixtures/nested-03.acl")),
+    ("cyclic-01", include_str!("fixtures/cyclic-01.acl")),
+    ("exclusive-01", include_str!("fixtures/exclusive-01.acl")),
+    ("filtered-01", include_str!("fixtures/filtered-01.acl")),
+    ("duplicates-01", include_str!("fixtures/duplicates-01.acl")),
+    ("denials-01", include_str!("fixtures/denials-01.acl")),
+];
+
+/// ACL graph loaded into memory; usable as the storage of an authorize call
+pub struct Fixture {
+    pub name: String,
+    pub description: String,
+    pub records: HashMap<String, String>,
+    pub checks: Vec<CorpusCheck>,
+}
+
+impl Fixture {
+    pub fn parse(name: &str, src: &str) -> io::Result<Fixture> {
+        let mut description = String::new();
+        let mut records = HashMap::new();
+        let mut checks = String::new();
+
+        for (n, line) in src.lines().enumerate() {
+            let line = line.trim();
+            if let Some(comment) = line.strip_prefix('#') {
+                if description.is_empty() {
+                    description = comment.trim().to_owned();
+                }
+            } else if let Some(check) = line.strip_prefix('?') {
+                checks.push_str(check.trim());
+                checks.push('\n');
+            } else if !line.is_empty() {
+                let Some((key, value)) = line.split_once(char::is_whitespace) else {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("fixture {} line {}: expected key and value", name, n + 1)));
+                };
+                records.insert(key.to_owned(), value.trim().to_owned());
+            }
+        }
+
+        Ok(Fixture {
+            name: name.to_owned(),
+            description,
+            records,
+            checks: parse_corpus(&checks)?,
+        })
+    }
+
+    /// Runs the expected results of the fixture against its own records
+    pub fn verify(&mut self) -> IntegrationReport {
+        self.verify_with_config(&AzConfig::default())
+    }
+
+    /// The expected results hold for the default config; other settings are checked with `check_with_config`
+    pub fn verify_with_config(&mut self, cfg: &AzConfig) -> IntegrationReport {
+        let checks = std::mem::take(&mut self.checks);
+        let report = run_corpus_with_config(&checks, self, cfg);
+        self.checks = checks;
+        report
+    }
+
+    /// Runs `checks`, in the corpus syntax, against the records of the fixture
+    pub fn check_with_config(&mut self, checks: &str, cfg: &AzConfig) -> io::Result<IntegrationReport> {
+        Ok(run_corpus_with_config(&parse_corpus(checks)?, self, cfg))
+    }
+}
+
+/// Names of the bundled fixtures
+pub fn names() -> impl Iterator<Item = &'static str> {
+    FIXTURES.iter().map(|(name, _)| *name)
+}
+
+/// `None` for an unknown name
+pub fn load(name: &str) -> Option<Fixture> {
+    let (_, src) = FIXTURES.iter().find(|(n, _)| *n == name)?;
+    match Fixture::parse(name, src) {
+        Ok(fixture) => Some(fixture),
+        Err(e) => {
+            eprintln!("ERR! testkit: fixture {} is broken, err={:?}", name, e);
+            None
+        },
+    }
+}
+
+impl Storage for Fixture {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        Ok(self.records.get(key).cloned())
+    }
+
+    fn fiber_yield(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_fixtures_pass() {
+        assert_eq!(names().count(), FIXTURES.len());
+        for name in names() {
+            let mut fixture = load(name).unwrap_or_else(|| panic!("fixture {} does not parse", name));
+            assert!(!fixture.description.is_empty(), "{}", name);
+            assert!(!fixture.checks.is_empty(), "{}", name);
+
+            let report = fixture.verify();
+            assert_eq!(report.total, fixture.checks.len());
+            assert!(report.is_ok(), "{}: {:?}", name, report.mismatches);
+        }
+    }
+
+    #[test]
+    fn broken_fixture_is_an_error() {
+        assert!(Fixture::parse("broken", "Mu1").is_err());
+        assert!(Fixture::parse("broken", "? u1;d1;2").is_err());
+        assert!(load("missing-01").is_none());
+    }
+
+    #[test]
+    fn denials_with_deny_override() {
+        let mut fixture = load("denials-01").unwrap();
+        // Cant R на группе F из g2 снимает R, выданный g1 на d1; на d2 запрет R не касается U
+        let checks = "u1;d1;2;0\nu1;d2;6;U\nu1;d2;4;U\n";
+
+        let cfg = AzConfig {
+            deny_override: true,
+            ..AzConfig::default()
+        };
+        let report = fixture.check_with_config(checks, &cfg).unwrap();
+        assert!(report.is_ok(), "{:?}", report.mismatches);
+
+        let report = fixture.check_with_config(checks, &AzConfig::default()).unwrap();
+        assert_eq!(report.mismatches.len(), 2, "{:?}", report.mismatches);
+        assert!(fixture.verify().is_ok());
+    }
+}
@@ -0,0 +1,180 @@
+//! Structured form of a fully traced decision, and the difference between two of them.
+//!
+//! The traversal records into a `TraceInfo` alongside the text traces, `to_json` renders it with the subject
+//! and object group trees. Meant for behavior changes after an upgrade or after putting a cache in front of
+//! the store: trace the same request with both setups and `diff` the results.
+
+use crate::common::{AclTraceEntry, Storage, TraceBuffers};
+use crate::config::AzConfig;
+use crate::decision::Decision;
+use crate::heatmap::json_string;
+use crate::{authorize_with_hooks, AzHooks};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TraceInfo {
+    pub id: String,
+    pub user_id: String,
+    pub request_access: u8,
+    /// `None` when the decision ended with a storage error
+    pub result: Option<u8>,
+    /// Lines of the info trace, without their numbers
+    pub steps: Vec<String>,
+    /// Object groups in the order they were visited
+    pub groups: Vec<String>,
+    /// Permissions found, as in the ACL trace
+    pub permissions: Vec<AclTraceEntry>,
+    /// Membership edges followed from the user, (member, group)
+    pub subject_edges: Vec<(String, String)>,
+    /// Membership edges followed from the resource, (member, group)
+    pub object_edges: Vec<(String, String)>,
+}
+
+impl TraceInfo {
+    pub fn start_step(&mut self, step: &str) {
+        self.steps.push(step.to_owned());
+    }
+
+    pub fn add_group(&mut self, group: &str) {
+        self.groups.push(group.to_owned());
+    }
+
+    pub fn add_permission(&mut self, permission: AclTraceEntry) {
+        self.permissions.push(permission);
+    }
+
+    pub fn add_subject_edge(&mut self, member: &str, group: &str) {
+        self.subject_edges.push((member.to_owned(), group.to_owned()));
+    }
+
+    pub fn add_object_edge(&mut self, member: &str, group: &str) {
+        self.object_edges.push((member.to_owned(), group.to_owned()));
+    }
+
+    /// Decision tree: `subjects` and `objects` nest each group under the member it was reached from
+    pub fn to_json(&self) -> String {
+        let strings = |items: &mut dyn Iterator<Item = &String>| items.map(|s| json_string(s)).collect::<Vec<_>>().join(",");
+        let permissions: Vec<String> = self
+            .permissions
+            .iter()
+            .map(|p| {
+                format!(
+                    "{{\"object_group\":{},\"subject_group\":{},\"predicate\":{},\"is_deny\":{}}}",
+                    json_string(&p.object_group),
+                    json_string(&p.subject_group),
+                    json_string(&p.predicate),
+                    p.is_deny
+                )
+            })
+            .collect();
+
+        format!(
+            "{{\"id\":{},\"user_id\":{},\"request_access\":{},\"result\":{},\"steps\":[{}],\"subjects\":{},\"objects\":{},\"groups\":[{}],\"permissions\":[{}]}}",
+            json_string(&self.id),
+            json_string(&self.user_id),
+            self.request_access,
+            self.result.map_or("null".to_owned(), |r| r.to_string()),
+            strings(&mut self.steps.iter()),
+            json_tree(&self.user_id, &self.subject_edges),
+            json_tree(&self.id, &self.object_edges),
+            strings(&mut self.groups.iter()),
+            permissions.join(",")
+        )
+    }
+}
+
+// Узел дерева с вложенными группами; группа, уже встреченная выше по пути, не раскрывается
+fn json_tree(root: &str, edges: &[(String, String)]) -> String {
+    let mut children: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (member, group) in edges {
+        let groups = children.entry(member.as_str()).or_default();
+        if !groups.contains(&group.as_str()) {
+            groups.push(group);
+        }
+    }
+    json_node(root, &children, &mut Vec::new())
+}
+
+fn json_node<'a>(id: &'a str, children: &HashMap<&'a str, Vec<&'a str>>, path: &mut Vec<&'a str>) -> String {
+    let mut groups = Vec::new();
+    if !path.contains(&id) {
+        path.push(id);
+        for group in children.get(id).into_iter().flatten() {
+            groups.push(json_node(group, children, path));
+        }
+        path.pop();
+    }
+    format!("{{\"id\":{},\"groups\":[{}]}}", json_string(id), groups.join(","))
+}
+
+/// Authorizes with every trace enabled and collects the result into a `TraceInfo`
+pub fn trace(id: &str, user_id: &str, request_access: u8, db: &mut dyn Storage) -> TraceInfo {
+    trace_with_config(id, user_id, request_access, db, &AzConfig::default())
+}
+
+pub fn trace_with_config(id: &str, user_id: &str, request_access: u8, db: &mut dyn Storage, cfg: &AzConfig) -> TraceInfo {
+    let info = RefCell::new(TraceInfo {
+        id: id.to_owned(),
+        user_id: user_id.to_owned(),
+        request_access,
+        ..TraceInfo::default()
+    });
+    let hooks = AzHooks {
+        trace_info: Some(&info),
+        ..AzHooks::default()
+    };
+
+    let mut buf = TraceBuffers::default();
+    let result = authorize_with_hooks(id, user_id, request_access, db, &mut buf.trace(true, true, true), cfg, &hooks, &mut Decision::default()).ok();
+
+    let mut info = info.into_inner();
+    info.result = result;
+    info
+}
+
+/// Items present in one run only; `a` and `b` as passed to `diff`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TraceDiff {
+    pub result: Option<(Option<u8>, Option<u8>)>,
+    pub steps_only_a: Vec<String>,
+    pub steps_only_b: Vec<String>,
+    pub groups_only_a: Vec<String>,
+    pub groups_only_b: Vec<String>,
+    pub permissions_only_a: Vec<AclTraceEntry>,
+    pub permissions_only_b: Vec<AclTraceEntry>,
+}
+
+impl TraceDiff {
+    pub fn is_empty(&self) -> bool {
+        *self == TraceDiff::default()
+    }
+}
+
+pub fn diff(a: &TraceInfo, b: &TraceInfo) -> TraceDiff {
+    TraceDiff {
+        result: if a.result != b.result {
+            Some((a.result, b.result))
+        } else {
+            None
+        },
+        steps_only_a: only_in(&a.steps, &b.steps),
+        steps_only_b: only_in(&b.steps, &a.steps),
+        groups_only_a: only_in(&a.groups, &b.groups),
+        groups_only_b: only_in(&b.groups, &a.groups),
+        permissions_only_a: only_in(&a.permissions, &b.permissions),
+        permissions_only_b: only_in(&b.permissions, &a.permissions),
+    }
+}
+
+// Элементы `from`, которых нет в `other`, в исходном порядке
+fn only_in<T: Clone + Eq + Hash>(from: &[T], other: &[T]) -> Vec<T> {
+    let other: HashSet<&T> = other.iter().collect();
+    let mut seen = HashSet::new();
+    from.iter().filter(|item| !other.contains(item) && seen.insert(*item)).cloned().collect()
+}
@@ -0,0 +1,310 @@
+//! Write path that validates every record write.
+//!
+//! `ValidatingStorage` turns each put, remove or batch of M-, P-, F-, W- and Q-records into the `AclChange`s
+//! it makes (bits added and bits removed per subject) and runs them through a `ChangeValidator` before anything
+//! is written; a refused write fails with `PermissionDenied` carrying `ChangeRejected`. Given to the indexer and
+//! to the `manage` functions in place of the store, it makes validation part of every write. Other keys pass through.
+
+use crate::common::{Storage, FILTER_PREFIX};
+use crate::manage::{rejected, validate_changes, AclChange, ChangeValidator, MutableStorage, SUBJECT_RECORD_PREFIXES};
+use crate::record_formats::{continuation_parts, read_continued};
+use crate::{ACLRecord, ACLRecordSet, ACLRecordVec};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+pub struct ValidatingStorage<S: MutableStorage> {
+    inner: S,
+    validator: Box<dyn ChangeValidator>,
+}
+
+impl<S: MutableStorage> ValidatingStorage<S> {
+    pub fn new(inner: S, validator: impl ChangeValidator + 'static) -> Self {
+        ValidatingStorage {
+            inner,
+            validator: Box::new(validator),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Writes through the returned reference are not validated
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn check(&mut self, batch: &[(String, Option<String>)]) -> io::Result<()> {
+        let changes = self.changes(batch)?;
+        let rejections = validate_changes(&changes, &*self.validator, &mut self.inner);
+        if rejections.is_empty() {
+            Ok(())
+        } else {
+            Err(rejected(rejections))
+        }
+    }
+
+    // Изменения записей, которые сделает пакет; части длинной записи разбираются вместе с ее головой
+    fn changes(&mut self, batch: &[(String, Option<String>)]) -> io::Result<Vec<AclChange>> {
+        let pending: HashMap<&str, Option<&str>> = batch.iter().map(|(key, value)| (key.as_str(), value.as_deref())).collect();
+
+        let mut res = Vec::new();
+        for (key, _) in batch {
+            if !is_validated(key) || self.is_part(key, &pending)? {
+                continue;
+            }
+
+            let before = read_continued(key, &mut self.inner)?;
+            let after = read_continued(
+                key,
+                &mut Pending {
+                    inner: &mut self.inner,
+                    pending: &pending,
+                },
+            )?;
+            if before != after {
+                res.extend(self.diff(key, before.as_deref(), after.as_deref()));
+            }
+        }
+        Ok(res)
+    }
+
+    // `X#n` из пакета - часть, если голова X в том же пакете объявляет n частей до или после записи
+    fn is_part(&mut self, key: &str, pending: &HashMap<&str, Option<&str>>) -> io::Result<bool> {
+        let Some((head, n)) = key.rsplit_once('#').and_then(|(head, n)| Some((head, n.parse::<usize>().ok()?))) else {
+            return Ok(false);
+        };
+        let Some(new_head) = pending.get(head) else {
+            return Ok(false);
+        };
+        let announced_after = new_head.map_or(0, |v| continuation_parts(v).1);
+        let announced_before = self.inner.get(head)?.map_or(0, |v| continuation_parts(&v).1);
+        Ok(n > 0 && n <= announced_after.max(announced_before))
+    }
+
+    // Биты, которые запись добавляет субъектам, и биты, которые она у них отнимает
+    fn diff(&self, key: &str, before: Option<&str>, after: Option<&str>) -> Vec<AclChange> {
+        let before = self.live_entries(before);
+        let after = self.live_entries(after);
+
+        let mut added = Vec::new();
+        for (id, rec) in &after {
+            let bits = rec.access & !before.get(id).map_or(0, |r| r.access);
+            if bits != 0 {
+                let mut entry = rec.clone();
+                entry.access = bits;
+                added.push(entry);
+            }
+        }
+        let mut removed = Vec::new();
+        for (id, rec) in &before {
+            let bits = rec.access & !after.get(id).map_or(0, |r| r.access);
+            if bits != 0 {
+                removed.push(ACLRecord::new_with_access(id, bits));
+            }
+        }
+
+        [(added, false), (removed, true)]
+            .into_iter()
+            .filter(|(entries, _)| !entries.is_empty())
+            .map(|(entries, is_remove)| AclChange {
+                key: key.to_owned(),
+                entries,
+                is_remove,
+            })
+            .collect()
+    }
+
+    fn live_entries(&self, src: Option<&str>) -> BTreeMap<String, ACLRecord> {
+        let mut res: BTreeMap<String, ACLRecord> = BTreeMap::new();
+        let Some(src) = src else {
+            return res;
+        };
+
+        let mut records = ACLRecordVec::new();
+        self.inner.decode_rec_to_rights(src, &mut records);
+        for rec in records.into_iter().filter(|r| !r.is_deleted) {
+            match res.get_mut(&rec.id) {
+                Some(prev) => prev.access |= rec.access,
+                None => {
+                    res.insert(rec.id.clone(), rec);
+                },
+            }
+        }
+        res
+    }
+}
+
+fn is_validated(key: &str) -> bool {
+    key.starts_with(FILTER_PREFIX) || SUBJECT_RECORD_PREFIXES.iter().any(|prefix| key.starts_with(prefix))
+}
+
+// Хранилище, каким оно станет после пакета
+struct Pending<'a> {
+    inner: &'a mut dyn Storage,
+    pending: &'a HashMap<&'a str, Option<&'a str>>,
+}
+
+impl Storage for Pending<'_> {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        match self.pending.get(key) {
+            Some(value) => Ok(value.map(str::to_owned)),
+            None => self.inner.get(key),
+        }
+    }
+
+    fn fiber_yield(&self) {
+        self.inner.fiber_yield()
+    }
+}
+
+impl<S: MutableStorage> Storage for ValidatingStorage<S> {
+    fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.inner.get(key)
+    }
+
+    fn fiber_yield(&self) {
+        self.inner.fiber_yield()
+    }
+
+    fn decode_rec_to_rights(&self, src: &str, result: &mut ACLRecordVec) -> (bool, Option<DateTime<Utc>>) {
+        self.inner.decode_rec_to_rights(src, result)
+    }
+
+    fn decode_rec_to_rightset(&self, src: &str, new_rights: &mut ACLRecordSet) -> (bool, Option<DateTime<Utc>>) {
+        self.inner.decode_rec_to_rightset(src, new_rights)
+    }
+
+    fn decode_filter(&self, filter_value: String) -> (Option<ACLRecord>, Option<DateTime<Utc>>) {
+        self.inner.decode_filter(filter_value)
+    }
+
+    fn decode_valid_from(&self, src: &str) -> Option<DateTime<Utc>> {
+        self.inner.decode_valid_from(src)
+    }
+
+    fn epoch(&self) -> Option<u64> {
+        self.inner.epoch()
+    }
+
+    fn get_uncached(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.inner.get_uncached(key)
+    }
+}
+
+impl<S: MutableStorage> MutableStorage for ValidatingStorage<S> {
+    fn put(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.check(&[(key.to_owned(), Some(value.to_owned()))])?;
+        self.inner.put(key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        self.check(&[(key.to_owned(), None)])?;
+        self.inner.remove(key)
+    }
+
+    fn apply_batch(&mut self, batch: &[(String, Option<String>)]) -> io::Result<()> {
+        self.check(batch)?;
+        self.inner.apply_batch(batch)
+    }
+
+    fn max_value_len(&self) -> Option<usize> {
+        self.inner.max_value_len()
+    }
+
+    fn emit_invalidation(&mut self, keys: &[String]) {
+        self.inner.emit_invalidation(keys)
+    }
+
+    fn scan_prefix(&mut self, prefix: &str) -> io::Result<Vec<(String, String)>> {
+        self.inner.scan_prefix(prefix)
+    }
+
+    fn keys_referencing(&mut self, subject_id: &str) -> io::Result<Vec<String>> {
+        self.inner.keys_referencing(subject_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manage::{add_grant, apply_change, remove_grant, revoke_all, ChangeRejected, PermissionQuota, QuotaAction, Rejection};
+    use crate::storage::memory::MemoryStorage;
+
+    fn quota() -> ValidatingStorage<MemoryStorage> {
+        ValidatingStorage::new(
+            MemoryStorage::new(),
+            PermissionQuota {
+                max_entries: 1,
+                action: QuotaAction::Block,
+            },
+        )
+    }
+
+    // Запрещает отнимать права у admin
+    fn keep_admin(change: &AclChange, _db: &mut dyn Storage, rejections: &mut Vec<Rejection>) {
+        if change.is_remove && change.entries.iter().any(|e| e.id == "admin") {
+            rejections.push(Rejection {
+                key: change.key.clone(),
+                entry: Some("admin".to_owned()),
+                rule: "keep-admin".to_owned(),
+                reason: "admin keeps its rights".to_owned(),
+            });
+        }
+    }
+
+    #[test]
+    fn writes_of_manage_are_validated() {
+        let mut db = quota();
+        add_grant("Pdoc", "u1", 'R', &mut db).unwrap();
+        add_grant("Pdoc", "u1", 'U', &mut db).unwrap();
+
+        let err = add_grant("Pdoc", "u2", 'R', &mut db).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let rejected = err.get_ref().and_then(|e| e.downcast_ref::<ChangeRejected>()).unwrap();
+        assert_eq!(rejected.rejections[0].rule, "permission-quota");
+        assert_eq!(db.get("Pdoc").unwrap(), Some("u1;RU;;".to_owned()));
+
+        let change = AclChange {
+            key: "Pdoc".to_owned(),
+            entries: vec![ACLRecord::new_with_access("u2", 2)],
+            is_remove: false,
+        };
+        assert!(apply_change(&change, &mut db).is_err());
+        assert!(db.inner_mut().get("Pdoc").unwrap().is_some_and(|v| !v.contains("u2")));
+    }
+
+    #[test]
+    fn removals_are_validated() {
+        let mut db = ValidatingStorage::new(MemoryStorage::new(), keep_admin);
+        add_grant("Pdoc", "admin", 'R', &mut db).unwrap();
+        add_grant("Mu1", "admin", 'R', &mut db).unwrap();
+        add_grant("Pdoc", "u1", 'R', &mut db).unwrap();
+
+        assert!(remove_grant("Pdoc", "admin", 'R', &mut db).is_err());
+        assert!(revoke_all("admin", &mut db, false).is_err());
+        remove_grant("Pdoc", "u1", 'R', &mut db).unwrap();
+        assert_eq!(db.get("Pdoc").unwrap(), Some("admin;R;;".to_owned()));
+        assert!(db.get("Mu1").unwrap().is_some());
+    }
+
+    #[test]
+    fn other_keys_pass() {
+        let mut db = ValidatingStorage::new(MemoryStorage::new(), |_: &AclChange, _: &mut dyn Storage, rejections: &mut Vec<Rejection>| {
+            rejections.push(Rejection {
+                key: String::new(),
+                entry: None,
+                rule: "none".to_owned(),
+                reason: "no record writes".to_owned(),
+            })
+        });
+        db.put("Bdoc", "1\n7\n0000000000000000").unwrap();
+        db.put("Pdoc", "").unwrap();
+        assert!(db.put("Pdoc", "u1;2;;").is_err());
+    }
+}
@@ -0,0 +1,83 @@
+//! Workload classes of an engine: interactive requests and batch jobs (re-verification, exports) run in
+//! separate lanes, each with its own concurrency limit, membership cache partition and stats, so a batch job
+//! neither evicts the cache entries of interactive users nor takes all the threads of the service.
+
+use crate::membership_cache::MembershipCache;
+use crate::stats::StatsAggregator;
+use std::sync::{Arc, Condvar, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Workload {
+    Interactive,
+    Batch,
+}
+
+impl Workload {
+    pub const ALL: [Workload; 2] = [Workload::Interactive, Workload::Batch];
+
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Workload::Interactive => "interactive",
+            Workload::Batch => "batch",
+        }
+    }
+}
+
+/// Settings of a lane; what is not set is shared with the engine
+#[derive(Clone, Default)]
+pub struct LaneConfig {
+    /// Calls of the lane running at once; further calls wait for a free slot
+    pub max_concurrent: Option<usize>,
+    pub membership_cache: Option<Arc<MembershipCache>>,
+    pub stats: Option<Arc<StatsAggregator>>,
+}
+
+pub(crate) struct Lane {
+    pub(crate) cfg: LaneConfig,
+    in_flight: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Lane {
+    pub(crate) fn new(cfg: LaneConfig) -> Self {
+        Lane {
+            cfg,
+            in_flight: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks while the lane is full
+    pub(crate) fn acquire(&self) -> LanePermit<'_> {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(max) = self.cfg.max_concurrent {
+            while *in_flight >= max.max(1) {
+                in_flight = self.released.wait(in_flight).unwrap_or_else(|e| e.into_inner());
+            }
+        }
+        *in_flight += 1;
+        LanePermit {
+            lane: self,
+        }
+    }
+
+    pub(crate) fn in_flight(&self) -> usize {
+        *self.in_flight.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub(crate) struct LanePermit<'a> {
+    lane: &'a Lane,
+}
+
+impl Drop for LanePermit<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.lane.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        *in_flight -= 1;
+        self.lane.released.notify_one();
+    }
+}